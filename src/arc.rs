@@ -0,0 +1,770 @@
+//! `Arc` (atomically reference-counted) is the thread-safe counterpart to
+//! `Rc`, sharing ownership of a heap-allocated value between threads.
+//!
+//! The only difference from `Rc` is that the reference count is an
+//! `AtomicUsize`, so clones and drops racing on different threads cannot lose
+//! an update or free the allocation while another thread is still using it.
+
+use std::alloc::{self, Layout};
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
+use std::process;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+
+use crate::atomics::Backoff;
+
+/// Thread-safe, reference-counted smart pointer allowing multiple shared
+/// references to a value across threads.
+#[derive(Debug)]
+pub struct Arc<T> {
+    /// Needs to be heap-allocated since it can be referenced from multiple
+    /// threads.
+    inner: NonNull<ArcInner<T>>,
+    /// Need to indicate to the compiler that we logically own `T`, since there
+    /// is only a pointer to `T`, which is non-owning. Indicates to `dropck`
+    /// that `Arc<T>` will drop a `T` when dropping.
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+// SAFETY: The reference count is updated atomically, so clones and drops of
+// `Arc` can happen concurrently on different threads.
+//
+// `T` needs to be `Sync` because every clone hands out a `&T` which may now be
+// used from multiple threads at once. `T` also needs to be `Send` because the
+// last `Arc` to be dropped (which drops `T`) can be on any thread, not
+// necessarily the one that created the value.
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+/// Enables the reference counts to also be shared between cloned Arc's and
+/// Weak's.
+#[derive(Debug)]
+struct ArcInner<T> {
+    value: T,
+    /// Atomic so it can be updated through a shared reference from multiple
+    /// threads.
+    strong: AtomicUsize,
+    /// Number of `Weak`s, plus one implicit weak reference held collectively
+    /// by all `Arc`s. The allocation is freed only once this reaches zero, so
+    /// a `Weak` can still read `strong` after the value has been dropped.
+    weak: AtomicUsize,
+}
+
+/// Past this, a count is considered overflowed.
+///
+/// Unlike `Rc`, the check can't happen before the increment since the count
+/// may change concurrently. Instead the increment always happens, and the
+/// previous value is checked. Leaving `isize::MAX` of headroom means that even
+/// if many threads race past the limit before any of them aborts, the count
+/// still can't wrap around to zero.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+/// Value of the weak count while `Arc::get_mut` checks for uniqueness.
+const WEAK_LOCKED: usize = usize::MAX;
+
+/// Increments a reference count with `Relaxed` ordering, aborting the process
+/// if it would overflow.
+///
+/// Wrapping around to zero would let the next drop free the allocation while
+/// references to it are still live, and `mem::forget` on clones makes that
+/// reachable. Aborting rather than panicking matches std, since a caught panic
+/// would leave the overflowed count reachable by other threads.
+fn increment(count: &AtomicUsize) {
+    if count.fetch_add(1, Ordering::Relaxed) > MAX_REFCOUNT {
+        process::abort();
+    }
+}
+
+impl<T> Arc<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            // SAFETY: `Box::new` either returns a valid non-null pointer or
+            // panics on OOM.
+            inner: unsafe {
+                NonNull::new_unchecked(Box::into_raw(Box::new(ArcInner {
+                    value,
+                    // Creating an `Arc` counts as a reference.
+                    strong: AtomicUsize::new(1),
+                    weak: AtomicUsize::new(1),
+                })))
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates a new `Weak` to the allocation, which does not keep the value
+    /// alive.
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        let mut backoff = Backoff::new();
+        let mut n = this.weak().load(Ordering::Relaxed);
+        loop {
+            // `get_mut` on another `Arc` briefly locks the weak count to check
+            // for uniqueness, wait for it to be unlocked.
+            if n == WEAK_LOCKED {
+                backoff.spin();
+                n = this.weak().load(Ordering::Relaxed);
+                continue;
+            }
+
+            if n > MAX_REFCOUNT {
+                process::abort();
+            }
+
+            // `Acquire` on success pairs with the `Release` store that unlocks
+            // the weak count in `get_mut`, so the new `Weak` can't be upgraded
+            // to observe the value before the mutation made through `get_mut`.
+            match this
+                .weak()
+                .compare_exchange_weak(n, n + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Weak { inner: this.inner },
+                Err(current) => n = current,
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the inner value if `this` is the only
+    /// `Arc` or `Weak` to it.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if !this.is_unique() {
+            return None;
+        }
+
+        // SAFETY: This is the only `Arc` and there are no `Weak`s, and it is
+        // mutably borrowed for the lifetime of the returned reference, so no
+        // other reference to `T` can be created.
+        Some(unsafe { &mut (*this.inner.as_ptr()).value })
+    }
+
+    /// Returns `true` if there are no other `Arc`s or `Weak`s.
+    ///
+    /// Checking the two counts one after the other isn't enough: between
+    /// reading `weak == 1` and `strong == 1`, another `Arc` could downgrade
+    /// itself and then be dropped, leaving a `Weak` that can upgrade while we
+    /// hand out a `&mut T`. To prevent this, the weak count is "locked" (set to
+    /// `WEAK_LOCKED`) while the strong count is read, which `downgrade` waits
+    /// on.
+    fn is_unique(&mut self) -> bool {
+        // `Acquire` pairs with the `Release` decrement in `Weak::drop`, so any
+        // access made through a (now dropped) `Weak` happens-before we hand out
+        // a `&mut T`.
+        if self
+            .weak()
+            .compare_exchange(1, WEAK_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+
+        // `Acquire` pairs with the `Release` decrement in `Arc::drop`, for the
+        // same reason as above but for other (now dropped) `Arc`s.
+        let unique = self.strong().load(Ordering::Acquire) == 1;
+
+        // `Release` pairs with the `Acquire` in `downgrade`, so a `Weak` created
+        // after this point sees any mutation made through the `&mut T`.
+        self.weak().store(1, Ordering::Release);
+
+        unique
+    }
+
+    /// Returns the inner value if `this` is the only `Arc` to it, otherwise
+    /// gives back the same `Arc` in `Err`.
+    ///
+    /// Like `Rc`, any `Weak`s can no longer upgrade once the value has been
+    /// moved out.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        // Dropping the count from 1 to 0 in a single CAS both checks that we
+        // are the only `Arc` and prevents any `Weak` from upgrading, since
+        // `upgrade` refuses to increment a count of 0. `Relaxed` is enough for
+        // the CAS itself, the fence below does the synchronizing.
+        if this
+            .strong()
+            .compare_exchange(1, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(this);
+        }
+
+        // Same as the final decrement in `Arc::drop`, every access made
+        // through other (now dropped) `Arc`s must happen-before we take the
+        // value.
+        atomic::fence(Ordering::Acquire);
+
+        // Prevent `Drop` from running, the strong count has already been
+        // released above.
+        let this = ManuallyDrop::new(this);
+
+        // SAFETY: The strong count is zero, so nothing else can access the
+        // value, and it is only read once.
+        let value = unsafe { ptr::read(&(*this.inner.as_ptr()).value) };
+
+        // Release the implicit weak reference held by the `Arc`s, freeing the
+        // allocation if there are no other `Weak`s.
+        drop(Weak { inner: this.inner });
+
+        Ok(value)
+    }
+
+    /// Consumes the `Arc` without decrementing the strong count, returning a
+    /// pointer to the value. `from_raw` turns it back into an `Arc`.
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        // SAFETY: We currently have an `Arc`, so the allocation is live. No
+        // reference to the value is created, only a pointer to the field.
+        unsafe { &raw const (*this.inner.as_ptr()).value }
+    }
+
+    /// Reconstructs an `Arc` from a pointer returned by `into_raw`, taking
+    /// over the strong count that `into_raw` didn't release.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Arc::into_raw`, and each such pointer may only be
+    /// turned back into an `Arc` once (unless its count is incremented again,
+    /// see `increment_strong_count`).
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // SAFETY: `ptr` points at the `value` field of an `ArcInner<T>`, so
+        // walking back by the field offset stays inside the allocation.
+        let inner = unsafe { ptr.byte_sub(mem::offset_of!(ArcInner<T>, value)) };
+
+        Self {
+            // SAFETY: Derived from a live, non-null allocation.
+            inner: unsafe { NonNull::new_unchecked(inner as *mut ArcInner<T>) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Increments the strong count of the `Arc` behind a pointer from
+    /// `into_raw`, as if it were cloned and the clone turned into a raw
+    /// pointer again.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Arc::into_raw`, and its strong count must be at
+    /// least one for the duration of the call.
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // SAFETY: Guaranteed by the caller. The `Arc` is never dropped, so the
+        // count borrowed from the caller is left untouched.
+        let this = ManuallyDrop::new(unsafe { Arc::from_raw(ptr) });
+        increment(this.strong());
+    }
+
+    // Only the count fields are borrowed, never the whole `ArcInner`, so these
+    // references don't alias the value while it is being dropped.
+
+    fn strong(&self) -> &AtomicUsize {
+        // SAFETY: We currently have an `Arc`, so the allocation is live.
+        unsafe { &(*self.inner.as_ptr()).strong }
+    }
+
+    fn weak(&self) -> &AtomicUsize {
+        // SAFETY: We currently have an `Arc`, so the allocation is live.
+        unsafe { &(*self.inner.as_ptr()).weak }
+    }
+}
+
+impl<T> Arc<T>
+where
+    T: Clone,
+{
+    /// Returns a mutable reference to the inner value, cloning it into a new
+    /// allocation first if other `Arc`s point to it (clone-on-write).
+    ///
+    /// If only `Weak`s remain, the value is moved into a new allocation
+    /// instead of being cloned, and the `Weak`s can no longer upgrade.
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        // Temporarily dropping the strong count from 1 to 0 checks for other
+        // `Arc`s, and at the same time stops any `Weak` from upgrading while
+        // we decide what to do. `Acquire` pairs with the `Release` decrements
+        // of other (now dropped) `Arc`s, like `get_mut`.
+        if this
+            .strong()
+            .compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Other `Arc`s exist, they keep the original value. Assigning
+            // drops our `Arc` to it.
+            *this = Arc::new(T::clone(this));
+        } else if this.weak().load(Ordering::Relaxed) != 1 {
+            // Only `Weak`s remain. The strong count is now zero so none of
+            // them can upgrade again, which means the value can be moved out
+            // from under them instead of cloned.
+            //
+            // SAFETY: No `Arc` or upgraded `Weak` can access the value, and
+            // the old `Arc` is overwritten without being dropped since its
+            // strong count was already released.
+            unsafe {
+                let weak = Weak { inner: this.inner };
+                let value = ptr::read(&(*this.inner.as_ptr()).value);
+                ptr::write(this, Arc::new(value));
+
+                // Release the implicit weak reference held by the old `Arc`.
+                drop(weak);
+            }
+        } else {
+            // We were the only reference of either kind, so the value can be
+            // handed out in place. Restore the strong count, `Release` so a
+            // later `Weak` (created through `downgrade`) sees the mutation.
+            this.strong().store(1, Ordering::Release);
+        }
+
+        // SAFETY: `this` is now the only `Arc` to its allocation, with no
+        // `Weak`s, and it is mutably borrowed for the lifetime of the returned
+        // reference.
+        unsafe { &mut (*this.inner.as_ptr()).value }
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        // `Relaxed` is enough when incrementing: we already hold a reference,
+        // so the allocation cannot be freed underneath us, and creating a new
+        // reference does not need to observe (or publish) any other memory.
+        increment(self.strong());
+
+        Self {
+            inner: self.inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The value is only dropped when the last `Arc` is dropped,
+        // but we currently have an `Arc`.
+        unsafe { &(*self.inner.as_ptr()).value }
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        // `Release` ensures every use of the value made through this `Arc`
+        // happens-before the decrement. Whichever thread ends up performing the
+        // final decrement must not drop `T` while another thread could still
+        // be reading it through its (now dropped) `Arc`.
+        if self.strong().fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // This was the last `Arc`. The `Acquire` fence synchronizes with every
+        // `Release` decrement above, so all accesses made by other threads
+        // through their clones are visible before the value is destroyed.
+        //
+        // A fence is used rather than making every decrement `AcqRel`, since
+        // only the final decrement needs to acquire.
+        atomic::fence(Ordering::Acquire);
+
+        // SAFETY: This was the last `Arc` and `strong` is now zero, so no
+        // `Weak` can upgrade to access the value again.
+        unsafe { ptr::drop_in_place(&mut (*self.inner.as_ptr()).value) };
+
+        // Release the implicit weak reference held by the `Arc`s, the
+        // allocation itself may still be needed by `Weak`s.
+        drop(Weak { inner: self.inner });
+    }
+}
+
+/// Non-owning reference to an `Arc` allocation, which can be upgraded to an
+/// `Arc` while the value is still alive.
+#[derive(Debug)]
+pub struct Weak<T> {
+    /// The allocation (not the value) stays live while any `Weak` exists.
+    inner: NonNull<ArcInner<T>>,
+}
+
+// SAFETY: Same reasoning as `Arc`, since a `Weak` can be upgraded into an
+// `Arc` on whichever thread it ends up on.
+unsafe impl<T: Send + Sync> Send for Weak<T> {}
+unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+
+impl<T> Weak<T> {
+    /// Returns a new `Arc` if the value has not been dropped yet.
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        // Unlike `Arc::clone`, we don't already hold a strong reference, so
+        // blindly incrementing could resurrect a value whose last `Arc` is
+        // concurrently being dropped (strong went 1 -> 0, the value is being
+        // destroyed, and we'd bump it back to 1).
+        //
+        // Instead, only increment if the count is not zero, and do the check
+        // and the increment as a single CAS so no other thread can drop the
+        // count to zero in between. If the count changed, retry with the value
+        // we observed.
+        let mut n = self.strong().load(Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+
+            if n > MAX_REFCOUNT {
+                process::abort();
+            }
+
+            // `Acquire` on success pairs with the `Release` decrements of other
+            // `Arc`s, the same way acquiring a lock would, so we see the value
+            // as every other holder left it. A failed CAS acquires nothing and
+            // only retries, so it can be `Relaxed`.
+            match self.strong().compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(Arc {
+                        inner: self.inner,
+                        _marker: PhantomData,
+                    });
+                }
+                Err(current) => n = current,
+            }
+        }
+    }
+
+    fn strong(&self) -> &AtomicUsize {
+        // SAFETY: We currently have a `Weak`, so the allocation is live.
+        unsafe { &(*self.inner.as_ptr()).strong }
+    }
+
+    fn weak(&self) -> &AtomicUsize {
+        // SAFETY: We currently have a `Weak`, so the allocation is live.
+        unsafe { &(*self.inner.as_ptr()).weak }
+    }
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        increment(self.weak());
+
+        Self { inner: self.inner }
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        // Same `Release`/`Acquire` pairing as dropping an `Arc`, the thread
+        // freeing the allocation must see every other thread's last access to
+        // the counts.
+        if self.weak().fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        atomic::fence(Ordering::Acquire);
+
+        // SAFETY: The allocation came from a `Box<ArcInner<T>>`, so it has the
+        // same layout. Deallocating directly (rather than through
+        // `Box::from_raw`) avoids dropping the value a second time.
+        unsafe { alloc::dealloc(self.inner.as_ptr() as *mut u8, Layout::new::<ArcInner<T>>()) };
+    }
+}
+
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use crust_of_rust::arc::Arc;
+///
+/// fn require_sync<T: Sync>(_: T) {}
+///
+/// require_sync(Arc::new(Cell::new(42)));
+/// ```
+fn assert_non_sync() {}
+
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use crust_of_rust::arc::Arc;
+///
+/// fn require_send<T: Send>(_: T) {}
+///
+/// require_send(Arc::new(Cell::new(42)));
+/// ```
+fn assert_non_send() {}
+
+/// ```
+/// use crust_of_rust::arc::Arc;
+///
+/// fn require_send_sync<T: Send + Sync>(_: T) {}
+///
+/// require_send_sync(Arc::new(42));
+/// ```
+fn assert_send_sync() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atomics::WaitGroup;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    struct DropCounter<'a> {
+        dropped: &'a AtomicBool,
+    }
+
+    impl<'a> Drop for DropCounter<'a> {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_arc_mem_leak() {
+        let arc = Arc::new(Box::new(10));
+        assert_eq!(**arc, 10);
+    }
+
+    #[test]
+    fn test_arc_clone() {
+        let arc1 = Arc::new(String::from("hello"));
+        let arc2 = arc1.clone();
+
+        assert_eq!(&*arc1, "hello");
+        assert_eq!(&*arc2, "hello");
+    }
+
+    #[test]
+    fn test_arc_drop_deallocate() {
+        let dropped = AtomicBool::new(false);
+
+        let arc1 = Arc::new(DropCounter { dropped: &dropped });
+        let arc2 = arc1.clone();
+        let arc3 = arc2.clone();
+
+        assert!(!dropped.load(Ordering::Relaxed));
+
+        drop(arc3);
+        drop(arc2);
+        assert!(!dropped.load(Ordering::Relaxed));
+
+        drop(arc1);
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_arc_across_threads() {
+        let dropped: &'static _ = Box::leak(Box::new(AtomicBool::new(false)));
+        let arc = Arc::new(DropCounter { dropped });
+
+        let wg = WaitGroup::new();
+        for _ in 0..10 {
+            let arc = arc.clone();
+            let wg = wg.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    drop(arc.clone());
+                }
+                // Our clone has to be gone by the time `wait` returns.
+                drop(arc);
+                drop(wg);
+            });
+        }
+        wg.wait();
+
+        assert!(!dropped.load(Ordering::Relaxed));
+        drop(arc);
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_arc_weak_upgrade() {
+        let dropped = AtomicBool::new(false);
+
+        let arc = Arc::new(DropCounter { dropped: &dropped });
+        let weak = Arc::downgrade(&arc);
+        let weak2 = weak.clone();
+
+        let upgraded = weak.upgrade().unwrap();
+        drop(upgraded);
+
+        // The value is dropped with the last `Arc`, even though `Weak`s keep
+        // the allocation alive.
+        drop(arc);
+        assert!(dropped.load(Ordering::Relaxed));
+        assert!(weak.upgrade().is_none());
+        assert!(weak2.upgrade().is_none());
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_arc_weak_no_resurrection() {
+        let drops: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+
+        struct CountDrops(&'static AtomicUsize);
+
+        impl Drop for CountDrops {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        for _ in 0..100 {
+            let arc = Arc::new(CountDrops(drops));
+            let weak = Arc::downgrade(&arc);
+
+            // Race upgrades against the last `Arc` being dropped.
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let weak = weak.clone();
+                    thread::spawn(move || {
+                        for _ in 0..100 {
+                            drop(weak.upgrade());
+                        }
+                    })
+                })
+                .collect();
+
+            drop(arc);
+
+            for handle in handles {
+                handle.join().unwrap();
+            }
+
+            // Once every upgraded `Arc` is gone, the value is dropped exactly
+            // once and can't be upgraded again.
+            assert!(weak.upgrade().is_none());
+        }
+
+        assert_eq!(drops.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn test_arc_overflow_aborts() {
+        use std::process::Command;
+
+        const CHILD: &str = "CRUST_ARC_OVERFLOW_CHILD";
+
+        // Re-run only this test in a child process, since the expected abort
+        // would otherwise take down the whole test harness.
+        if std::env::var_os(CHILD).is_some() {
+            let arc = Arc::new(());
+            arc.strong().store(MAX_REFCOUNT + 1, Ordering::Relaxed);
+            let _ = arc.clone();
+            unreachable!();
+        }
+
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "arc::tests::test_arc_overflow_aborts"])
+            .env(CHILD, "1")
+            .status()
+            .unwrap();
+        // A panic (e.g. the `unreachable!`) exits with 101 instead.
+        assert!(!status.success());
+        assert_ne!(status.code(), Some(101));
+    }
+
+    #[test]
+    fn test_arc_get_mut() {
+        let mut arc = Arc::new(5);
+        *Arc::get_mut(&mut arc).unwrap() += 1;
+
+        let clone = arc.clone();
+        assert!(Arc::get_mut(&mut arc).is_none());
+        drop(clone);
+
+        let weak = Arc::downgrade(&arc);
+        assert!(Arc::get_mut(&mut arc).is_none());
+        drop(weak);
+
+        assert_eq!(Arc::get_mut(&mut arc), Some(&mut 6));
+    }
+
+    #[test]
+    fn test_arc_try_unwrap() {
+        let arc = Arc::new(String::from("hello"));
+        let clone = arc.clone();
+        let weak = Arc::downgrade(&arc);
+
+        let arc = Arc::try_unwrap(arc).unwrap_err();
+        drop(clone);
+
+        assert_eq!(Arc::try_unwrap(arc).unwrap(), "hello");
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_arc_get_mut_stress() {
+        let mut arc = Arc::new(0_usize);
+        let weak = Arc::downgrade(&arc);
+        drop(weak);
+
+        // Hammer the counts from other threads, using every way of creating
+        // and dropping a reference, while the main thread keeps asking for
+        // unique access. Any `&mut` handed out while another thread reads the
+        // value would be a data race.
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let arc = arc.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let clone = arc.clone();
+                        let weak = Arc::downgrade(&clone);
+                        assert_eq!(*weak.upgrade().unwrap(), *clone);
+                        drop(clone);
+                    }
+                })
+            })
+            .collect();
+
+        let mut unique = 0;
+        while !handles.iter().all(|h| h.is_finished()) {
+            if let Some(v) = Arc::get_mut(&mut arc) {
+                *v += 1;
+                unique += 1;
+            }
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Every other reference is gone now.
+        assert!(Arc::get_mut(&mut arc).is_some());
+        assert_eq!(*arc, unique);
+
+        let value = *arc;
+        assert_eq!(Arc::try_unwrap(arc).unwrap(), value);
+    }
+
+    #[test]
+    fn test_arc_make_mut() {
+        let mut arc1 = Arc::new(String::from("hello"));
+        let arc2 = arc1.clone();
+
+        // Shared, so the value is cloned before being mutated.
+        Arc::make_mut(&mut arc1).push_str(" world");
+        assert_eq!(&*arc1, "hello world");
+        assert_eq!(&*arc2, "hello");
+
+        // Unique, so the value is mutated in place.
+        let before = &*arc1 as *const String;
+        Arc::make_mut(&mut arc1).push('!');
+        assert_eq!(&*arc1 as *const String, before);
+
+        // Only a `Weak` left, so the value is moved and the `Weak` is
+        // disassociated.
+        let weak = Arc::downgrade(&arc1);
+        Arc::make_mut(&mut arc1).push('!');
+        assert_eq!(&*arc1, "hello world!!");
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_arc_raw_roundtrip() {
+        let a = Arc::new(String::from("raw"));
+        let b = a.clone();
+
+        let ptr = Arc::into_raw(b);
+        // SAFETY: `ptr` came from `into_raw` and its count is still held.
+        unsafe { Arc::increment_strong_count(ptr) };
+        // SAFETY: Two counts are owned through `ptr` now.
+        let (c, d) = unsafe { (Arc::from_raw(ptr), Arc::from_raw(ptr)) };
+
+        assert_eq!(*c, "raw");
+        assert_eq!(a.strong().load(Ordering::Relaxed), 3);
+        drop((c, d));
+        assert_eq!(Arc::try_unwrap(a).unwrap(), "raw");
+    }
+}
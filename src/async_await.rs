@@ -1,6 +1,26 @@
 use std::future::Future;
 use std::pin::Pin;
-use std::task::Poll;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::atomics::{Parker, Unparker};
+
+pub mod ext;
+pub mod io;
+pub mod join;
+pub mod local;
+pub mod mutex;
+#[cfg(target_os = "linux")]
+pub mod net;
+pub mod race;
+#[cfg(target_os = "linux")]
+mod reactor;
+pub mod runtime;
+pub mod stream;
+pub mod test_util;
+pub mod time;
+pub mod unordered;
+pub mod waker;
 
 /// The `async` qualifier on this function `foo` essentially desugars to:
 ///
@@ -103,3 +123,156 @@ async fn bar() {
     // should not be executed until the Future resolves to it's output type.
     let x = foo1().await;
 }
+
+/// Wakes the thread in `block_on` by handing its `Parker` a token.
+struct ParkWaker(Unparker);
+
+impl Wake for ParkWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `fut` to completion on the current thread: the smallest possible
+/// executor.
+///
+/// The future is polled once, and whenever it returns `Poll::Pending` the
+/// thread parks until the `Waker` it was given is used, then polls again.
+/// Since the `Parker` keeps a wakeup that arrives before the thread parks
+/// (e.g. from another thread, while the future is still being polled), none
+/// are lost. A spurious wakeup only costs an extra poll, which futures must
+/// tolerate anyway.
+///
+/// There is nothing else to run while waiting, so a future that waits on
+/// another task of the same thread never completes.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let parker = Parker::new();
+    let waker = Waker::from(Arc::new(ParkWaker(parker.unparker())));
+    let mut cx = Context::from_waker(&waker);
+
+    // Pinned on the stack: it is never moved again, and dropped before we
+    // return.
+    let mut fut = std::pin::pin!(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => parker.park(),
+        }
+    }
+}
+
+/// Future returned by `yield_now`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        // Woken before it even returned `Pending`, the task is queued again
+        // right away, behind whatever else is waiting to run.
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Gives the executor a chance to run other tasks before continuing.
+///
+/// A task only gives up its thread at an `.await` that returns
+/// `Poll::Pending`, so a long loop that never waits on anything would keep
+/// it for as long as it runs. Awaiting `yield_now` in the loop suspends the
+/// task once, scheduling it again at the same time.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_block_on_ready() {
+        assert_eq!(block_on(foo()), 42);
+        assert_eq!(block_on(foo1()), 42);
+        block_on(bar());
+    }
+
+    #[test]
+    fn test_block_on_parks_until_woken() {
+        let (tx, mut rx) = channel();
+
+        let sender = thread::spawn(move || {
+            for i in 0..3 {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(i).unwrap();
+            }
+        });
+
+        let sum = block_on(async {
+            let mut sum = 0;
+            while let Ok(i) = rx.recv_async().await {
+                sum += i;
+            }
+            sum
+        });
+        assert_eq!(sum, 3);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_yield_now_block_on() {
+        let mut polls = 0;
+        let mut yielding = std::pin::pin!(yield_now());
+        block_on(std::future::poll_fn(|cx| {
+            polls += 1;
+            yielding.as_mut().poll(cx)
+        }));
+        // Woke itself, so `block_on` didn't park for good.
+        assert_eq!(polls, 2);
+    }
+
+    #[test]
+    fn test_yield_now_interleaves_tasks() {
+        let rt = runtime::Runtime::new(1);
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let handles: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                let log = Arc::clone(&log);
+                rt.spawn(async move {
+                    for i in 0..3 {
+                        log.lock().unwrap().push((name, i));
+                        yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        rt.block_on(async {
+            for handle in handles {
+                handle.await.unwrap();
+            }
+        });
+
+        // Without yielding, the single worker would run `a` to completion
+        // before starting `b`.
+        let log = log.lock().unwrap();
+        assert_eq!(
+            *log,
+            [("a", 0), ("b", 0), ("a", 1), ("b", 1), ("a", 2), ("b", 2)]
+        );
+    }
+}
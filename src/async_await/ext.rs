@@ -0,0 +1,233 @@
+//! `FutureExt`: adapters that build a future out of another one, like the ones
+//! `Iterator` has for iterators.
+//!
+//! Each adapter is a small state machine, the same thing the compiler
+//! generates for an `async` block, written out by hand. The inner future is
+//! `!Unpin` in general, so the adapter can only reach it through `Pin`
+//! projection: turning a `Pin<&mut Adapter>` into a `Pin<&mut Inner>` for the
+//! future field, which is *structurally* pinned, and a plain `&mut` for the
+//! rest (the closures), which isn't. That takes `unsafe`, and it is sound as
+//! long as:
+//!
+//!  - the pinned field is never moved out of, or swapped, while pinned. It may
+//!    be dropped in place, which is how `Then` replaces its first future with
+//!    the second one;
+//!  - the adapter has no `Drop` impl that moves the field, and is only `Unpin`
+//!    when the future is. The auto trait gives exactly that, a `!Unpin`
+//!    closure only making it stricter.
+//!
+//! The closures are in an `Option` and taken out when called, since they are
+//! `FnOnce`. A `None` then also tells an adapter polled after completion.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A boxed future, for when the type of a future can't be named or differs
+/// between branches.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Adapters for every `Future`, see the module docs.
+pub trait FutureExt: Future {
+    /// Maps the output of the future with `f`.
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        F: FnOnce(Self::Output) -> T,
+        Self: Sized,
+    {
+        Map {
+            future: self,
+            f: Some(f),
+        }
+    }
+
+    /// Chains another future, created by `f` from the output of this one.
+    fn then<Fut, F>(self, f: F) -> Then<Self, Fut, F>
+    where
+        F: FnOnce(Self::Output) -> Fut,
+        Fut: Future,
+        Self: Sized,
+    {
+        Then {
+            state: ThenState::First {
+                future: self,
+                f: Some(f),
+            },
+        }
+    }
+
+    /// Calls `f` with a reference to the output, before passing it on.
+    fn inspect<F>(self, f: F) -> Inspect<Self, F>
+    where
+        F: FnOnce(&Self::Output),
+        Self: Sized,
+    {
+        Inspect {
+            future: self,
+            f: Some(f),
+        }
+    }
+
+    /// Boxes the future, erasing its type.
+    fn boxed<'a>(self) -> BoxFuture<'a, Self::Output>
+    where
+        Self: Sized + Send + 'a,
+    {
+        Box::pin(self)
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+/// Future returned by `FutureExt::map`.
+pub struct Map<Fut, F> {
+    /// Structurally pinned.
+    future: Fut,
+    /// Not pinned, and taken out when called.
+    f: Option<F>,
+}
+
+impl<Fut, F, T> Future for Map<Fut, F>
+where
+    Fut: Future,
+    F: FnOnce(Fut::Output) -> T,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: Pin projection, see the module docs: `future` is never
+        // moved, `f` isn't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                let f = this.f.take().expect("`Map` polled after completion");
+                Poll::Ready(f(output))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Future returned by `FutureExt::then`.
+pub struct Then<Fut1, Fut2, F> {
+    state: ThenState<Fut1, Fut2, F>,
+}
+
+enum ThenState<Fut1, Fut2, F> {
+    First { future: Fut1, f: Option<F> },
+    Second(Fut2),
+    Done,
+}
+
+impl<Fut1, Fut2, F> Future for Then<Fut1, Fut2, F>
+where
+    Fut1: Future,
+    Fut2: Future,
+    F: FnOnce(Fut1::Output) -> Fut2,
+{
+    type Output = Fut2::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: Pin projection, see the module docs. Whichever future is in
+        // `state` is structurally pinned, and only ever dropped in place, by
+        // overwriting `state`.
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            match &mut this.state {
+                ThenState::First { future, f } => {
+                    let future = unsafe { Pin::new_unchecked(future) };
+                    let Poll::Ready(output) = future.poll(cx) else {
+                        return Poll::Pending;
+                    };
+                    let f = f.take().expect("`Then` closure called once");
+                    // Drops the first future in place, and the second one is
+                    // pinned from here on.
+                    this.state = ThenState::Second(f(output));
+                }
+                ThenState::Second(future) => {
+                    let future = unsafe { Pin::new_unchecked(future) };
+                    let Poll::Ready(output) = future.poll(cx) else {
+                        return Poll::Pending;
+                    };
+                    this.state = ThenState::Done;
+                    return Poll::Ready(output);
+                }
+                ThenState::Done => panic!("`Then` polled after completion"),
+            }
+        }
+    }
+}
+
+/// Future returned by `FutureExt::inspect`.
+pub struct Inspect<Fut, F> {
+    /// Structurally pinned.
+    future: Fut,
+    /// Not pinned, and taken out when called.
+    f: Option<F>,
+}
+
+impl<Fut, F> Future for Inspect<Fut, F>
+where
+    Fut: Future,
+    F: FnOnce(&Fut::Output),
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: As in `Map`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let Poll::Ready(output) = future.poll(cx) else {
+            return Poll::Pending;
+        };
+        let f = this.f.take().expect("`Inspect` polled after completion");
+        f(&output);
+        Poll::Ready(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::channels::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_future_ext_chain() {
+        let (tx, mut rx) = channel();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx.send(20).unwrap();
+        });
+
+        let mut seen = None;
+        let output = block_on(
+            rx.recv_async()
+                .map(Result::unwrap)
+                .inspect(|n| seen = Some(*n))
+                .then(|n| async move { n * 2 })
+                .map(|n| n + 2),
+        );
+        assert_eq!((output, seen), (42, Some(20)));
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_future_ext_boxed() {
+        let futures: Vec<BoxFuture<'_, u32>> = vec![
+            async { 1 }.boxed(),
+            async { 2 }.map(|n| n * 10).boxed(),
+            std::future::ready(3)
+                .then(|n| async move { n * 100 })
+                .boxed(),
+        ];
+
+        let outputs: Vec<_> = futures.into_iter().map(block_on).collect();
+        assert_eq!(outputs, [1, 20, 300]);
+    }
+}
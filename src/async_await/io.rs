@@ -0,0 +1,586 @@
+//! `AsyncRead` and `AsyncWrite`: `std::io::Read` and `Write` for sources and
+//! sinks that may have to be waited for, like the sockets of `net`.
+//!
+//! The traits only have the `poll_*` functions, which try the operation and
+//! otherwise register the task's `Waker`, like `Future::poll`. The methods to
+//! `.await` are in the extension traits, each returning a small future:
+//! `read` and `write` wait for one syscall's worth, `read_exact` and
+//! `write_all` loop until the whole buffer is done.
+//!
+//! `BufReader` and `BufWriter` are the buffered adapters, as in `std`: a
+//! `BufReader` reads ahead into its buffer, which also lets it implement
+//! `AsyncBufRead`, and so `read_line`. A `BufWriter` collects small writes and
+//! passes them on in one go, once its buffer is full or it's flushed.
+//!
+//! The extension methods and the adapters need the wrapped reader or writer
+//! to be `Unpin`, so that they can poll it through a plain `&mut` (which all of
+//! the ones here are). A `!Unpin` one can be pinned in a `Box` first.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Reads bytes asynchronously, see the module docs.
+pub trait AsyncRead {
+    /// Reads into `buf`, returning how many bytes were read (0 at the end of
+    /// the stream), or `Poll::Pending` after registering `cx`'s `Waker` to be
+    /// woken once there is something to read.
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>;
+}
+
+/// Writes bytes asynchronously, see the module docs.
+pub trait AsyncWrite {
+    /// Writes from `buf`, returning how many bytes were written, or
+    /// `Poll::Pending` after registering `cx`'s `Waker` to be woken once there
+    /// is room.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>>;
+
+    /// Writes out anything buffered.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// An `AsyncRead` with a buffer of its own, like `std::io::BufRead`.
+pub trait AsyncBufRead: AsyncRead {
+    /// Returns the buffered bytes, filling the buffer first if it's empty. An
+    /// empty slice means the end of the stream.
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>>;
+
+    /// Marks `amt` bytes of the buffer as read.
+    fn consume(self: Pin<&mut Self>, amt: usize);
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> AsyncRead for &mut R {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self).poll_read(cx, buf)
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for &mut W {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut **self).poll_flush(cx)
+    }
+}
+
+/// Reading from a slice never waits, and advances the slice, like
+/// `std::io::Read` for `&[u8]`.
+impl AsyncRead for &[u8] {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(io::Read::read(&mut *self, buf))
+    }
+}
+
+/// Writing to a `Vec` never waits, it appends.
+impl AsyncWrite for Vec<u8> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The futures to `.await` for every `AsyncRead`.
+pub trait AsyncReadExt: AsyncRead {
+    /// Reads into `buf`, waiting until at least one byte is available.
+    /// Returns the number of bytes read, 0 at the end of the stream.
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> Read<'a, Self>
+    where
+        Self: Unpin,
+    {
+        Read { reader: self, buf }
+    }
+
+    /// Fills all of `buf`, failing with `UnexpectedEof` if the stream ends
+    /// first (in which case what was read is lost).
+    fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExact<'a, Self>
+    where
+        Self: Unpin,
+    {
+        ReadExact { reader: self, buf }
+    }
+}
+
+impl<R: AsyncRead + ?Sized> AsyncReadExt for R {}
+
+/// The futures to `.await` for every `AsyncWrite`.
+pub trait AsyncWriteExt: AsyncWrite {
+    /// Writes some of `buf`, waiting until there is room for at least one
+    /// byte. Returns the number of bytes written.
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> Write<'a, Self>
+    where
+        Self: Unpin,
+    {
+        Write { writer: self, buf }
+    }
+
+    /// Writes all of `buf`, failing with `WriteZero` if the writer stops
+    /// taking bytes.
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAll<'a, Self>
+    where
+        Self: Unpin,
+    {
+        WriteAll { writer: self, buf }
+    }
+
+    /// Writes out anything buffered.
+    fn flush(&mut self) -> Flush<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Flush { writer: self }
+    }
+}
+
+impl<W: AsyncWrite + ?Sized> AsyncWriteExt for W {}
+
+/// The futures to `.await` for every `AsyncBufRead`.
+pub trait AsyncBufReadExt: AsyncBufRead {
+    /// Reads up to and including the next `\n`, or to the end of the stream,
+    /// and appends it to `buf`. Returns the number of bytes read, 0 at the end
+    /// of the stream.
+    ///
+    /// Fails with `InvalidData` if the line isn't UTF-8, leaving `buf` as it
+    /// was.
+    fn read_line<'a>(&'a mut self, buf: &'a mut String) -> ReadLine<'a, Self>
+    where
+        Self: Unpin,
+    {
+        ReadLine {
+            reader: self,
+            buf,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<R: AsyncBufRead + ?Sized> AsyncBufReadExt for R {}
+
+/// Future returned by `AsyncReadExt::read`.
+pub struct Read<'a, R: ?Sized> {
+    reader: &'a mut R,
+    buf: &'a mut [u8],
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> Future for Read<'_, R> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.reader).poll_read(cx, this.buf)
+    }
+}
+
+/// Future returned by `AsyncReadExt::read_exact`.
+pub struct ReadExact<'a, R: ?Sized> {
+    reader: &'a mut R,
+    /// What's left to fill.
+    buf: &'a mut [u8],
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> Future for ReadExact<'_, R> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        while !this.buf.is_empty() {
+            let n = match Pin::new(&mut *this.reader).poll_read(cx, this.buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            // Taken out, so that the rest can be stored with the same lifetime.
+            this.buf = &mut std::mem::take(&mut this.buf)[n..];
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by `AsyncWriteExt::write`.
+pub struct Write<'a, W: ?Sized> {
+    writer: &'a mut W,
+    buf: &'a [u8],
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Future for Write<'_, W> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.writer).poll_write(cx, this.buf)
+    }
+}
+
+/// Future returned by `AsyncWriteExt::write_all`.
+pub struct WriteAll<'a, W: ?Sized> {
+    writer: &'a mut W,
+    /// What's left to write.
+    buf: &'a [u8],
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Future for WriteAll<'_, W> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        while !this.buf.is_empty() {
+            let n = match Pin::new(&mut *this.writer).poll_write(cx, this.buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            this.buf = &this.buf[n..];
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by `AsyncWriteExt::flush`.
+pub struct Flush<'a, W: ?Sized> {
+    writer: &'a mut W,
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Future for Flush<'_, W> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.get_mut().writer).poll_flush(cx)
+    }
+}
+
+/// Future returned by `AsyncBufReadExt::read_line`.
+pub struct ReadLine<'a, R: ?Sized> {
+    reader: &'a mut R,
+    buf: &'a mut String,
+    /// The bytes of the line so far, only checked for UTF-8 once it's whole.
+    line: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin + ?Sized> Future for ReadLine<'_, R> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let mut reader = Pin::new(&mut *this.reader);
+            let available = match reader.as_mut().poll_fill_buf(cx) {
+                Poll::Ready(Ok(available)) => available,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let (used, done) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (available.len(), available.is_empty()),
+            };
+            this.line.extend_from_slice(&available[..used]);
+            reader.consume(used);
+
+            if done {
+                let line = std::mem::take(&mut this.line);
+                let n = line.len();
+                return Poll::Ready(match String::from_utf8(line) {
+                    Ok(line) => {
+                        this.buf.push_str(&line);
+                        Ok(n)
+                    }
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "line is not valid UTF-8",
+                    )),
+                });
+            }
+        }
+    }
+}
+
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Adds a read-ahead buffer to an `AsyncRead`, see the module docs.
+pub struct BufReader<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    /// The unread part of the buffer is `buf[pos..filled]`.
+    pos: usize,
+    filled: usize,
+}
+
+impl<R> BufReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The reader, and with it whatever is still buffered, is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The bytes read ahead, but not read from this `BufReader` yet.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BufReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        // Nothing buffered, and a read at least as large as the buffer: going
+        // through the buffer would only be an extra copy.
+        if self.pos == self.filled && buf.len() >= self.buf.len() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        let available = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for BufReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.filled {
+            match Pin::new(&mut this.inner).poll_read(cx, &mut this.buf) {
+                Poll::Ready(Ok(n)) => {
+                    this.pos = 0;
+                    this.filled = n;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.filled]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+/// Collects writes to an `AsyncWrite` in a buffer, see the module docs.
+///
+/// Unlike `std`'s, it doesn't flush when dropped, since that may have to wait:
+/// anything still buffered then is lost, so flush it first.
+pub struct BufWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+    /// How much of `buf` was already written to `inner`, by an unfinished
+    /// `poll_flush_buf`.
+    written: usize,
+}
+
+impl<W> BufWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            written: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The writer, and with it whatever is still buffered, is lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// The bytes written to this `BufWriter`, but not to the writer yet.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.written..]
+    }
+}
+
+impl<W: AsyncWrite + Unpin> BufWriter<W> {
+    /// Writes the whole buffer out to the writer, without flushing it.
+    fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => self.written += n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for BufWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() + buf.len() > this.buf.capacity() {
+            match this.poll_flush_buf(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        // Too large to be worth buffering, it goes straight through.
+        if buf.len() >= this.buf.capacity() {
+            Pin::new(&mut this.inner).poll_write(cx, buf)
+        } else {
+            this.buf.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_flush_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+
+    /// Hands out at most `chunk` bytes per read, and returns `Poll::Pending`
+    /// before every one of them.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        ready: bool,
+    }
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let ready = self.ready;
+            self.ready = !ready;
+            if !ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.chunk);
+            let mut data = self.data;
+            let n = io::Read::read(&mut data, &mut buf[..n]).unwrap();
+            self.data = data;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn test_io_buf_reader_lines_and_exact() {
+        let trickle = Trickle {
+            data: b"first line\nsecond\nHEADtail",
+            chunk: 3,
+            ready: false,
+        };
+        let mut reader = BufReader::with_capacity(4, trickle);
+
+        block_on(async {
+            let mut line = String::new();
+            assert_eq!(reader.read_line(&mut line).await.unwrap(), 11);
+            assert_eq!(reader.read_line(&mut line).await.unwrap(), 7);
+            assert_eq!(line, "first line\nsecond\n");
+
+            let mut head = [0; 4];
+            reader.read_exact(&mut head).await.unwrap();
+            assert_eq!(&head, b"HEAD");
+
+            let mut rest = String::new();
+            assert_eq!(reader.read_line(&mut rest).await.unwrap(), 4);
+            assert_eq!(rest, "tail");
+            assert_eq!(reader.read_line(&mut rest).await.unwrap(), 0);
+
+            let err = reader.read_exact(&mut head).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+
+    #[test]
+    fn test_io_buf_writer() {
+        let mut writer = BufWriter::with_capacity(8, Vec::new());
+
+        block_on(async {
+            writer.write_all(b"abc").await.unwrap();
+            writer.write_all(b"def").await.unwrap();
+            assert!(writer.get_ref().is_empty());
+            assert_eq!(writer.buffer(), b"abcdef");
+
+            // Doesn't fit, so the buffer goes out first.
+            writer.write_all(b"ghi").await.unwrap();
+            assert_eq!(writer.get_ref(), b"abcdef");
+
+            // Large writes skip the buffer, after flushing it.
+            writer.write_all(b"0123456789").await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        assert_eq!(writer.into_inner(), b"abcdefghi0123456789");
+    }
+}
@@ -0,0 +1,199 @@
+//! Awaiting several futures at once, on one task: `join(a, b)` and `join!`.
+//!
+//! Awaiting `a` and then `b` runs them one after the other, and `b` doesn't
+//! even start until `a` is done. Joining them polls both every time the task is
+//! woken, until both are done, and returns both outputs. No threads are
+//! involved: the futures just take turns on the task they were joined in, and
+//! any of them waking the task's `Waker` gets all of them polled again.
+//!
+//! Each future sits in a `MaybeDone`, which keeps the output of a future that
+//! finished early until the others catch up. The futures are `!Unpin` in
+//! general (an `async` block may hold references into itself), so the
+//! `MaybeDone`s have to be pinned where they are, and `Join` reaches them
+//! through `Pin` projection: from a `Pin<&mut Join>`, it hands out a
+//! `Pin<&mut MaybeDone>` for each field. That is sound as long as the fields
+//! are never moved out of while pinned, which only `unsafe` code could do, and
+//! `Join` is only `Unpin` if the fields are too (which the auto trait takes
+//! care of).
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future, or its output once it's done. See the module docs.
+// Only public for `join!`.
+#[doc(hidden)]
+pub enum MaybeDone<F: Future> {
+    Future(F),
+    Done(F::Output),
+    /// The output was taken.
+    Gone,
+}
+
+impl<F: Future> MaybeDone<F> {
+    pub fn new(future: F) -> Self {
+        Self::Future(future)
+    }
+
+    /// Polls the future unless it's done already, and returns whether it is
+    /// now.
+    ///
+    /// Panics if the output was taken.
+    pub fn poll_done(self: Pin<&mut Self>, cx: &mut Context<'_>) -> bool {
+        // SAFETY: The future is never moved: it's polled in place, and
+        // dropped in place by `set` once it's done.
+        let this = unsafe { self.get_unchecked_mut() };
+        match this {
+            Self::Future(future) => {
+                // SAFETY: See above.
+                let future = unsafe { Pin::new_unchecked(future) };
+                match future.poll(cx) {
+                    Poll::Ready(output) => {
+                        *this = Self::Done(output);
+                        true
+                    }
+                    Poll::Pending => false,
+                }
+            }
+            Self::Done(_) => true,
+            Self::Gone => panic!("`MaybeDone` polled after its output was taken"),
+        }
+    }
+
+    /// Takes the output of a future `poll_done` said is done.
+    ///
+    /// Panics if it isn't, or if the output was taken already.
+    pub fn take_output(self: Pin<&mut Self>) -> F::Output {
+        // SAFETY: Only `Done` is moved out of, and the output isn't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        match std::mem::replace(this, Self::Gone) {
+            Self::Done(output) => output,
+            _ => panic!("`MaybeDone` has no output to take"),
+        }
+    }
+}
+
+/// Future returned by `join`.
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: Pin projection, see the module docs. Neither field is moved
+        // out of, and `Join` has no `Drop` impl that could.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let mut b = unsafe { Pin::new_unchecked(&mut this.b) };
+
+        // Both are polled, even if the first isn't done.
+        let a_done = a.as_mut().poll_done(cx);
+        let b_done = b.as_mut().poll_done(cx);
+        if a_done && b_done {
+            Poll::Ready((a.take_output(), b.take_output()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Awaits `a` and `b` concurrently, see the module docs.
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a: MaybeDone::new(a),
+        b: MaybeDone::new(b),
+    }
+}
+
+/// Awaits any number of futures concurrently, returning a tuple of their
+/// outputs. Only usable in `async` code, like `.await`.
+///
+/// ```
+/// use crust_of_rust::async_await::block_on;
+/// use crust_of_rust::join;
+///
+/// let (a, b, c) = block_on(async { join!(async { 1 }, async { "two" }, async { 3.0 }) });
+/// assert_eq!((a, b, c), (1, "two", 3.0));
+/// ```
+///
+/// Each future is pinned on the stack of the enclosing `async` block, in its
+/// own `MaybeDone`.
+#[macro_export]
+macro_rules! join {
+    // Every recursion step names its future `fut`, but each expansion has its
+    // own hygiene, so they are all different variables.
+    (@pin [$($pinned:ident)*] $fut:expr, $($rest:expr,)*) => {{
+        let mut fut = ::std::pin::pin!($crate::async_await::join::MaybeDone::new($fut));
+        $crate::join!(@pin [$($pinned)* fut] $($rest,)*)
+    }};
+    (@pin [$($pinned:ident)*]) => {
+        ::std::future::poll_fn(|cx| {
+            let mut done = true;
+            $(done &= $pinned.as_mut().poll_done(cx);)*
+            if done {
+                ::std::task::Poll::Ready(($($pinned.as_mut().take_output(),)*))
+            } else {
+                ::std::task::Poll::Pending
+            }
+        })
+        .await
+    };
+    ($($fut:expr),+ $(,)?) => {
+        $crate::join!(@pin [] $($fut,)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::channels::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_join_runs_concurrently() {
+        let (ping_tx, mut ping_rx) = channel();
+        let (pong_tx, mut pong_rx) = channel();
+
+        // Awaited one after the other, `a` would wait for `b` forever.
+        let a = async {
+            let mut count = 0;
+            while let Ok(i) = ping_rx.recv_async().await {
+                pong_tx.send(i + 1).unwrap();
+                count += 1;
+            }
+            count
+        };
+        let b = async move {
+            let mut i = 0;
+            while i < 10 {
+                ping_tx.send(i).unwrap();
+                i = pong_rx.recv_async().await.unwrap();
+            }
+            i
+        };
+
+        assert_eq!(block_on(join(a, b)), (10, 10));
+    }
+
+    #[test]
+    fn test_join_keeps_early_output() {
+        let (tx, mut rx) = channel();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            tx.send("late").unwrap();
+        });
+
+        let output = block_on(async {
+            let s = String::from("early");
+            // Borrows from the enclosing future, only fine because it's pinned.
+            let borrowed = async { s.len() };
+            join!(borrowed, rx.recv_async(), async { 3 })
+        });
+        assert_eq!(output, (5, Ok("late"), 3));
+    }
+}
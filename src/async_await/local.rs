@@ -0,0 +1,356 @@
+//! A single-threaded executor, for futures that aren't `Send`.
+//!
+//! `Runtime::spawn` needs `Send` futures, since a task may be stolen by any
+//! worker and resumed there. A future that keeps an `Rc`, or a `RefCell`
+//! borrow, across an `.await` isn't, and doesn't compile there:
+//!
+//! ```compile_fail
+//! use crust_of_rust::async_await::runtime::Runtime;
+//! use crust_of_rust::async_await::yield_now;
+//! use crust_of_rust::rc::Rc;
+//!
+//! let rt = Runtime::new(1);
+//! let shared = Rc::new(42);
+//! rt.spawn(async move {
+//!     yield_now().await;
+//!     drop(shared);
+//! });
+//! ```
+//!
+//! A `LocalExecutor` runs every task on the thread that calls `run_until`, so
+//! tasks never move between threads and don't need to be `Send`:
+//!
+//! ```
+//! use crust_of_rust::async_await::local::LocalExecutor;
+//! use crust_of_rust::async_await::yield_now;
+//! use crust_of_rust::rc::Rc;
+//! use crust_of_rust::refcell::RefCell;
+//!
+//! let executor = LocalExecutor::new();
+//! let log = Rc::new(RefCell::new(Vec::new()));
+//! let handles: Vec<_> = (0..3)
+//!     .map(|i| {
+//!         let log = log.clone();
+//!         executor.spawn(async move {
+//!             yield_now().await;
+//!             log.borrow_mut().push(i);
+//!         })
+//!     })
+//!     .collect();
+//!
+//! executor.run_until(async {
+//!     for handle in handles {
+//!         handle.await;
+//!     }
+//! });
+//! assert_eq!(*log.borrow(), [0, 1, 2]);
+//! ```
+//!
+//! The tasks are kept by id in the executor, where only its thread gets to
+//! them. Their `Waker`s, on the other hand, must be `Send` and `Sync` like any
+//! other (a task may wait on a channel fed by another thread), so all they
+//! hold is the id: waking one pushes the id onto a run queue behind a `Mutex`,
+//! and unparks the executor's thread if it's waiting. `run_until` polls the
+//! tasks whose ids come off the queue, and the future it was given whenever
+//! that is woken, until the latter is done.
+//!
+//! Tasks only make progress while some `run_until` is running, and the ones
+//! still around when the executor is dropped are dropped with it. A panic in a
+//! task isn't caught, it unwinds out of `run_until`.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use super::waker::{self, ArcWake};
+use crate::arc::Arc;
+use crate::atomics::{Parker, Unparker};
+
+/// A boxed future that isn't necessarily `Send`.
+type LocalBoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// What the `Waker`s share with the executor.
+struct Queue {
+    /// Ids of woken tasks, oldest first. An id may be in here more than once,
+    /// or belong to a task that's gone already: a spurious poll, or none.
+    ready: Mutex<VecDeque<usize>>,
+    /// Whether the future of `run_until` was woken.
+    main_woken: AtomicBool,
+    unparker: Unparker,
+}
+
+/// The `Waker` of one task, or of the `run_until` future for `None`.
+struct TaskWaker {
+    id: Option<usize>,
+    queue: Arc<Queue>,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(this: &Arc<Self>) {
+        match this.id {
+            Some(id) => this.queue.ready.lock().unwrap().push_back(id),
+            None => this.queue.main_woken.store(true, Ordering::Release),
+        }
+        this.queue.unparker.unpark();
+    }
+}
+
+/// Runs `!Send` futures on the current thread, see the module docs.
+pub struct LocalExecutor {
+    /// `None` while the task is being polled, so that it can spawn.
+    tasks: RefCell<HashMap<usize, Option<LocalBoxFuture<'static, ()>>>>,
+    next_id: Cell<usize>,
+    queue: Arc<Queue>,
+    parker: Parker,
+}
+
+impl LocalExecutor {
+    pub fn new() -> Self {
+        let parker = Parker::new();
+        Self {
+            tasks: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0),
+            queue: Arc::new(Queue {
+                ready: Mutex::new(VecDeque::new()),
+                main_woken: AtomicBool::new(false),
+                unparker: parker.unparker(),
+            }),
+            parker,
+        }
+    }
+
+    /// Queues `future` to run on this executor, during `run_until`. Its output
+    /// can be awaited through the returned handle.
+    ///
+    /// Tasks can spawn more tasks, with a reference to the executor (e.g. in
+    /// an `Rc`).
+    pub fn spawn<F>(&self, future: F) -> LocalJoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let slot = Rc::new(RefCell::new(LocalSlot::Running(None)));
+        let task_slot = slot.clone();
+        let future = async move {
+            let output = future.await;
+            let prev = std::mem::replace(&mut *task_slot.borrow_mut(), LocalSlot::Finished(output));
+            if let LocalSlot::Running(Some(waker)) = prev {
+                waker.wake();
+            }
+        };
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.tasks.borrow_mut().insert(id, Some(Box::pin(future)));
+        self.queue.ready.lock().unwrap().push_back(id);
+        LocalJoinHandle { slot }
+    }
+
+    /// Runs the spawned tasks on the current thread until `future` completes,
+    /// and returns its output. `future` itself is polled here, not spawned, so
+    /// it may borrow from the caller.
+    pub fn run_until<F: Future>(&self, future: F) -> F::Output {
+        let main_waker = waker::waker(Arc::new(TaskWaker {
+            id: None,
+            queue: self.queue.clone(),
+        }));
+        let mut future = std::pin::pin!(future);
+        self.queue.main_woken.store(true, Ordering::Relaxed);
+
+        loop {
+            if self.queue.main_woken.swap(false, Ordering::Acquire)
+                && let Poll::Ready(output) =
+                    future.as_mut().poll(&mut Context::from_waker(&main_waker))
+            {
+                return output;
+            }
+
+            // Only what's queued now: tasks woken while we're at it wait for
+            // the next round, after the `run_until` future had its turn.
+            let ready = std::mem::take(&mut *self.queue.ready.lock().unwrap());
+            if ready.is_empty() && !self.queue.main_woken.load(Ordering::Acquire) {
+                // A wakeup since the checks above left a token, so this
+                // returns right away.
+                self.parker.park();
+                continue;
+            }
+            for id in ready {
+                self.run_task(id);
+            }
+        }
+    }
+
+    fn run_task(&self, id: usize) {
+        // Out of the map while it's polled, so it can spawn (and is skipped if
+        // it's woken again meanwhile, which queues another poll anyway).
+        let Some(mut task) = self.tasks.borrow_mut().get_mut(&id).and_then(Option::take) else {
+            return;
+        };
+
+        let waker = waker::waker(Arc::new(TaskWaker {
+            id: Some(id),
+            queue: self.queue.clone(),
+        }));
+        match task.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Pending => {
+                self.tasks.borrow_mut().insert(id, Some(task));
+            }
+            Poll::Ready(()) => {
+                self.tasks.borrow_mut().remove(&id);
+            }
+        }
+    }
+}
+
+impl Default for LocalExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a `LocalJoinHandle` shares with its task.
+enum LocalSlot<T> {
+    /// With the `Waker` of the task awaiting the handle, if any.
+    Running(Option<Waker>),
+    Finished(T),
+    /// Handed out by `LocalJoinHandle::poll`.
+    Taken,
+}
+
+/// A future resolving to the output of a task spawned on a `LocalExecutor`.
+///
+/// Dropping the handle detaches the task: it keeps running, and its output is
+/// dropped.
+pub struct LocalJoinHandle<T> {
+    slot: Rc<RefCell<LocalSlot<T>>>,
+}
+
+impl<T> LocalJoinHandle<T> {
+    /// Whether the task is done, so that awaiting the handle won't wait.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.slot.borrow(), LocalSlot::Running(_))
+    }
+}
+
+impl<T> Future for LocalJoinHandle<T> {
+    type Output = T;
+
+    /// Panics if polled again after returning `Poll::Ready`.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.borrow_mut();
+        match std::mem::replace(&mut *slot, LocalSlot::Taken) {
+            LocalSlot::Running(mut waker) => {
+                match &mut waker {
+                    Some(waker) => waker.clone_from(cx.waker()),
+                    None => waker = Some(cx.waker().clone()),
+                }
+                *slot = LocalSlot::Running(waker);
+                Poll::Pending
+            }
+            LocalSlot::Finished(output) => Poll::Ready(output),
+            LocalSlot::Taken => panic!("`LocalJoinHandle` polled after completion"),
+        }
+    }
+}
+
+/// A `RefCell` borrow held across an `.await` makes a future `!Send` just as
+/// well, since the `RefCell` isn't `Sync`:
+///
+/// ```compile_fail
+/// use crust_of_rust::arc::Arc;
+/// use crust_of_rust::async_await::runtime::Runtime;
+/// use crust_of_rust::async_await::yield_now;
+/// use crust_of_rust::refcell::RefCell;
+///
+/// let rt = Runtime::new(1);
+/// let cell = Arc::new(RefCell::new(0));
+/// rt.spawn(async move {
+///     let mut n = cell.borrow_mut();
+///     yield_now().await;
+///     *n += 1;
+/// });
+/// ```
+fn assert_runtime_rejects_refcell() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::yield_now;
+    use crate::channels::channel;
+    use crate::rc::Rc;
+    use crate::refcell;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_local_executor_non_send_tasks() {
+        let executor = Rc::new(LocalExecutor::new());
+        let counter = Rc::new(refcell::RefCell::new(0));
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let counter = counter.clone();
+                let spawner = executor.clone();
+                executor.spawn(async move {
+                    // Spawned from inside a task.
+                    let inner = spawner.spawn(async { 1 });
+                    yield_now().await;
+                    let n = inner.await;
+                    *counter.borrow_mut() += n;
+                })
+            })
+            .collect();
+
+        executor.run_until(async {
+            for handle in handles {
+                handle.await;
+            }
+        });
+        assert_eq!(*counter.borrow(), 10);
+    }
+
+    #[test]
+    fn test_local_executor_woken_from_other_thread() {
+        let executor = LocalExecutor::new();
+        let (tx, mut rx) = channel();
+        let sender = thread::spawn(move || {
+            for i in 1..=3 {
+                thread::sleep(Duration::from_millis(5));
+                tx.send(i).unwrap();
+            }
+        });
+
+        let sum = Rc::new(Cell::new(0));
+        let task_sum = sum.clone();
+        let handle = executor.spawn(async move {
+            while let Ok(i) = rx.recv_async().await {
+                task_sum.set(task_sum.get() + i);
+            }
+        });
+        executor.run_until(handle);
+        assert_eq!(sum.get(), 6);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_local_executor_drops_unfinished_tasks() {
+        let executor = LocalExecutor::new();
+        let token = Rc::new(());
+        let held = token.clone();
+        executor.spawn(async move {
+            let _held = held;
+            std::future::pending::<()>().await
+        });
+
+        // Borrows from the caller, and returns as soon as it's done, whatever
+        // the tasks are up to.
+        let local = String::from("borrowed");
+        assert_eq!(executor.run_until(async { local.len() }), 8);
+        drop(executor);
+        assert_eq!(Rc::strong_count(&token), 1);
+    }
+}
@@ -0,0 +1,332 @@
+//! A `Mutex` for `async` code, whose `lock` is a future.
+//!
+//! The spinning `atomics::Mutex` (or `std::sync::Mutex`, which blocks) is fine
+//! in `async` code as long as the guard is dropped before the next `.await`.
+//! Held across one, the task is suspended with the lock held, and any other
+//! task that then tries to lock it spins (or blocks) the worker thread it runs
+//! on. With a single worker, that's the very thread the holder needs to be
+//! resumed on, and nothing ever makes progress again.
+//!
+//! Here a contended `lock` returns `Poll::Pending` instead, and the task is
+//! queued, with its `Waker`, in a FIFO list. Unlocking doesn't just clear the
+//! flag for whoever comes next: if a task is waiting, the lock is handed to the
+//! oldest one directly, still locked, and that task is woken to pick it up.
+//! Nobody can barge in between, so every waiter gets its turn in order. A
+//! `lock` future dropped before it got the lock leaves the queue, and one that
+//! was handed the lock but never picked it up hands it on in turn.
+//!
+//! The queue itself is guarded by a short, non-`async` critical section (a
+//! `std::sync::Mutex` that is never held across an `.await`), and wakers are
+//! woken after it's released.
+
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+
+/// Ids for `Lock` futures, so a dropped one can find itself in the queue.
+static NEXT_WAITER: AtomicUsize = AtomicUsize::new(0);
+
+struct State {
+    locked: bool,
+    /// Pending `Lock` futures, by id, oldest first.
+    waiters: VecDeque<(usize, Waker)>,
+    /// The waiter the lock was handed to, which hasn't picked it up yet.
+    handed_to: Option<usize>,
+}
+
+pub struct Mutex<T> {
+    state: std::sync::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: Only the holder of the lock gets to the value, as with
+// `atomics::Mutex`, so `T: Send` is enough.
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(val: T) -> Self {
+        Self {
+            state: std::sync::Mutex::new(State {
+                locked: false,
+                waiters: VecDeque::new(),
+                handed_to: None,
+            }),
+            value: UnsafeCell::new(val),
+        }
+    }
+
+    /// Acquires the lock, waiting in line behind earlier callers if it's held.
+    /// The lock is released when the returned guard is dropped.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            id: None,
+        }
+    }
+
+    /// Acquires the lock only if it's free and nobody is waiting for it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock().unwrap();
+        // While the lock is being handed over, it stays locked.
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard::new(self))
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Hands the lock to the oldest waiter, or releases it if there is none.
+    fn unlock(&self) {
+        let mut state = self.state.lock().unwrap();
+        let waker = match state.waiters.pop_front() {
+            Some((id, waker)) => {
+                state.handed_to = Some(id);
+                Some(waker)
+            }
+            None => {
+                state.locked = false;
+                None
+            }
+        };
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// Set once the future has queued up.
+    id: Option<usize>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.mutex.state.lock().unwrap();
+
+        match this.id {
+            None if !state.locked => {
+                // Free means nobody is waiting, see `unlock`.
+                state.locked = true;
+                Poll::Ready(MutexGuard::new(this.mutex))
+            }
+            Some(id) if state.handed_to == Some(id) => {
+                state.handed_to = None;
+                this.id = None;
+                Poll::Ready(MutexGuard::new(this.mutex))
+            }
+            _ => {
+                let id = *this
+                    .id
+                    .get_or_insert_with(|| NEXT_WAITER.fetch_add(1, Ordering::Relaxed));
+                // The task may have moved to another `Waker` since the last
+                // poll.
+                match state.waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+                    Some((_, waker)) => waker.clone_from(cx.waker()),
+                    None => state.waiters.push_back((id, cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+
+        let mut state = self.mutex.state.lock().unwrap();
+        if state.handed_to == Some(id) {
+            // We were given the lock, but won't take it: the next in line
+            // gets it instead.
+            state.handed_to = None;
+            drop(state);
+            self.mutex.unlock();
+        } else {
+            state.waiters.retain(|&(waiter, _)| waiter != id);
+        }
+    }
+}
+
+/// Gives access to the value of a locked `Mutex`, unlocking it on drop.
+///
+/// Unlike a guard of `atomics::Mutex`, this one may be held across an
+/// `.await`.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// `Sync` only for `T: Sync`, and `Send` only for `T: Send`, like the
+    /// `&mut T` the guard stands for.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// The lock of `mutex` must be held by the caller, and is handed over to
+    /// the guard.
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        Self {
+            mutex,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The guard holds the lock, see `new`.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The guard holds the lock, see `new`.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::async_await::runtime::{Runtime, spawn};
+    use crate::atomics;
+    use crate::channels::channel;
+    use crate::join;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_async_mutex_held_across_await() {
+        let rt = Runtime::new(4);
+        let counter = Arc::new(Mutex::new(0));
+
+        let handles: Vec<_> = (0..50)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                rt.spawn(async move {
+                    let mut guard = counter.lock().await;
+                    let n = *guard;
+                    // Suspends the task with the lock held.
+                    spawn(async {}).await.unwrap();
+                    *guard = n + 1;
+                })
+            })
+            .collect();
+        rt.block_on(async {
+            for handle in handles {
+                handle.await.unwrap();
+            }
+        });
+        assert_eq!(*counter.try_lock().unwrap(), 50);
+    }
+
+    #[test]
+    fn test_async_mutex_fifo_handoff() {
+        let mutex = Mutex::new(Vec::new());
+        let guard = mutex.try_lock().unwrap();
+        let (tx, mut rx) = channel();
+
+        block_on(async {
+            join!(
+                async {
+                    let mut guard = mutex.lock().await;
+                    guard.push(1);
+                    tx.send(()).unwrap();
+                },
+                async {
+                    // Queued second, and dropped after it's been handed the
+                    // lock, which then goes on to the third in line.
+                    let mut lock = std::pin::pin!(mutex.lock());
+                    std::future::poll_fn(|cx| {
+                        assert!(lock.as_mut().poll(cx).is_pending());
+                        Poll::Ready(())
+                    })
+                    .await;
+                    rx.recv_async().await.unwrap();
+                },
+                async { mutex.lock().await.push(3) },
+                async {
+                    drop(guard);
+                    // Handed to the first waiter, nobody barges in.
+                    assert!(mutex.try_lock().is_none());
+                },
+            )
+        });
+
+        assert_eq!(*mutex.try_lock().unwrap(), [1, 3]);
+    }
+
+    #[test]
+    fn test_async_mutex_vs_spinning_mutex() {
+        let (tx, mut rx) = channel();
+        let spinning = atomics::Mutex::new(0);
+        block_on(async {
+            join!(
+                async {
+                    let mut guard = spinning.lock().unwrap();
+                    rx.recv_async().await.unwrap();
+                    *guard += 1;
+                },
+                async {
+                    tx.send(()).unwrap();
+                    // Still held by the suspended branch. `lock` would spin
+                    // forever, since the holder only resumes on this thread.
+                    assert!(spinning.try_lock().is_err());
+                },
+            )
+        });
+
+        let (tx, mut rx) = channel();
+        let mutex = Mutex::new(0);
+        block_on(async {
+            join!(
+                async {
+                    let mut guard = mutex.lock().await;
+                    rx.recv_async().await.unwrap();
+                    *guard += 1;
+                },
+                async {
+                    tx.send(()).unwrap();
+                    // Yields to the holder instead, and gets the lock next.
+                    *mutex.lock().await += 1;
+                },
+            )
+        });
+        assert_eq!(mutex.into_inner(), 2);
+    }
+}
@@ -0,0 +1,278 @@
+//! TCP sockets whose operations are futures: accepting, reading and writing
+//! return `Poll::Pending` instead of blocking, and the reactor wakes the task
+//! once the socket is ready (see `reactor`). Streams are read and written
+//! through `io::AsyncRead` and `AsyncWrite`.
+//!
+//! An echo server, with a blocking client on another thread:
+//!
+//! ```
+//! use std::io::{Read, Write};
+//! use std::net::TcpStream;
+//! use std::thread;
+//!
+//! use crust_of_rust::async_await::io::{AsyncReadExt, AsyncWriteExt};
+//! use crust_of_rust::async_await::net::AsyncTcpListener;
+//! use crust_of_rust::async_await::runtime::{Runtime, spawn};
+//!
+//! let rt = Runtime::new(2);
+//! let listener = AsyncTcpListener::bind("127.0.0.1:0").unwrap();
+//! let addr = listener.local_addr().unwrap();
+//!
+//! let client = thread::spawn(move || {
+//!     let mut stream = TcpStream::connect(addr).unwrap();
+//!     stream.write_all(b"hello").unwrap();
+//!     let mut buf = [0; 5];
+//!     stream.read_exact(&mut buf).unwrap();
+//!     buf
+//! });
+//!
+//! rt.block_on(async move {
+//!     let (mut stream, _) = listener.accept().await.unwrap();
+//!     spawn(async move {
+//!         let mut buf = [0; 1024];
+//!         loop {
+//!             let n = stream.read(&mut buf).await.unwrap();
+//!             if n == 0 {
+//!                 break;
+//!             }
+//!             stream.write_all(&buf[..n]).await.unwrap();
+//!         }
+//!     });
+//! });
+//! assert_eq!(&client.join().unwrap(), b"hello");
+//! ```
+
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::io::{AsyncRead, AsyncWrite};
+use super::reactor::{Interest, Registration};
+
+/// A TCP listener whose `accept` is a future.
+pub struct AsyncTcpListener {
+    /// Before `listener`, so it's deregistered before the socket is closed.
+    registration: Registration,
+    listener: TcpListener,
+}
+
+impl AsyncTcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            registration: Registration::new(listener.as_raw_fd())?,
+            listener,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for a connection.
+    pub fn accept(&self) -> Accept<'_> {
+        Accept { listener: self }
+    }
+
+    /// Accepts a connection, or registers the task to be woken for one.
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(AsyncTcpStream, SocketAddr)>> {
+        self.registration
+            .poll_io(Interest::Read, cx, || self.listener.accept())
+            .map(|result| {
+                let (stream, addr) = result?;
+                Ok((AsyncTcpStream::from_std(stream)?, addr))
+            })
+    }
+}
+
+/// Future returned by `AsyncTcpListener::accept`.
+pub struct Accept<'a> {
+    listener: &'a AsyncTcpListener,
+}
+
+impl Future for Accept<'_> {
+    type Output = io::Result<(AsyncTcpStream, SocketAddr)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.listener.poll_accept(cx)
+    }
+}
+
+/// A TCP stream that is read and written asynchronously, through `AsyncRead`
+/// and `AsyncWrite`.
+pub struct AsyncTcpStream {
+    /// Before `stream`, see `AsyncTcpListener`.
+    registration: Registration,
+    stream: TcpStream,
+}
+
+impl AsyncTcpStream {
+    /// Connects to `addr`.
+    ///
+    /// Only reading and writing are asynchronous: connecting blocks the thread
+    /// until the connection is made, since `std` can't start a connect without
+    /// waiting for it.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_std(TcpStream::connect(addr)?)
+    }
+
+    /// Turns a connected `std` stream into an asynchronous one.
+    pub fn from_std(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            registration: Registration::new(stream.as_raw_fd())?,
+            stream,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Shuts down the reading, writing, or both halves of the connection. Not
+    /// a future: it never blocks.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    /// Reads into `buf`, or registers the task to be woken once there is
+    /// something to read.
+    pub fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.registration
+            .poll_io(Interest::Read, cx, || (&self.stream).read(buf))
+    }
+
+    /// Writes from `buf`, or registers the task to be woken once there is
+    /// room to write.
+    pub fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.registration
+            .poll_io(Interest::Write, cx, || (&self.stream).write(buf))
+    }
+}
+
+// Through `&self`, since the reactor needs no exclusive access. A second
+// reader (or writer) would take the place of the first one's `Waker` though,
+// which `&mut` in the extension methods rules out.
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_read(&self, cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncTcpStream::poll_write(&self, cx, buf)
+    }
+
+    /// Nothing to do, writes go straight to the socket.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::async_await::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::async_await::runtime::{Runtime, spawn};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_net_echo_server() {
+        const CLIENTS: usize = 8;
+        let rt = Runtime::new(2);
+        let listener = AsyncTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = rt.spawn(async move {
+            let mut connections = Vec::new();
+            for _ in 0..CLIENTS {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                connections.push(spawn(async move {
+                    let mut buf = [0; 16];
+                    let mut echoed = 0;
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return echoed;
+                        }
+                        stream.write_all(&buf[..n]).await.unwrap();
+                        echoed += n;
+                    }
+                }));
+            }
+            let mut total = 0;
+            for connection in connections {
+                total += connection.await.unwrap();
+            }
+            total
+        });
+
+        // Async clients, from a task of the same runtime.
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|i| {
+                rt.spawn(async move {
+                    let mut stream = AsyncTcpStream::connect(addr).unwrap();
+                    let msg = format!("hello from client {i}, in a few pieces");
+                    let mut reply = vec![0; msg.len()];
+                    for chunk in msg.as_bytes().chunks(7) {
+                        stream.write(chunk).await.unwrap();
+                    }
+                    stream.read_exact(&mut reply).await.unwrap();
+                    stream.shutdown(Shutdown::Write).unwrap();
+                    assert_eq!(reply, msg.as_bytes());
+                    msg.len()
+                })
+            })
+            .collect();
+
+        let sent: usize = rt.block_on(async {
+            let mut sent = 0;
+            for client in clients {
+                sent += client.await.unwrap();
+            }
+            sent
+        });
+        assert_eq!(rt.block_on(server).unwrap(), sent);
+    }
+
+    #[test]
+    fn test_net_read_waits_for_data_and_eof() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let writer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(b"late").unwrap();
+        });
+
+        let mut stream = AsyncTcpStream::connect(addr).unwrap();
+        let mut buf = [0; 8];
+        let n = block_on(stream.read(&mut buf)).unwrap();
+        assert_eq!(&buf[..n], b"late");
+        writer.join().unwrap();
+        // The writer is gone, and its stream closed.
+        assert_eq!(block_on(stream.read(&mut buf)).unwrap(), 0);
+    }
+}
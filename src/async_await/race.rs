@@ -0,0 +1,234 @@
+//! Awaiting whichever of several futures finishes first: `race(a, b)` and
+//! `race!`, the async counterpart of the channels' `select!` (hence the
+//! different name, both being exported at the crate root).
+//!
+//! Like `join`, the futures run on the task they are raced in and share its
+//! `Waker`: whichever of them wakes it, all of them are polled again. The
+//! first one to return `Poll::Ready` wins, and the others are cancelled, which
+//! for a future simply means it's dropped without being polled to completion.
+//! `Race` drops them with itself, `race!` as soon as the winner is known.
+//!
+//! Fairness is the subtle part. If the futures were always polled in the same
+//! order, the first one would win every tie, and in a loop over a `race!` a
+//! busy first branch would starve the others. So the order rotates: `Race`
+//! alternates which future it polls first on every poll, and each `race!`
+//! starts with a different branch, taken from a global counter, and rotates
+//! from there.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+/// The output of a `Race`: which future won, and its output.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Future returned by `race`.
+pub struct Race<A, B> {
+    a: A,
+    b: B,
+    /// Which one is polled first next time, flipped on every poll.
+    left_first: bool,
+}
+
+impl<A: Future, B: Future> Future for Race<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: Pin projection, as in `Join`: neither future is ever moved
+        // out of, and `Race` has no `Drop` impl that could.
+        let this = unsafe { self.get_unchecked_mut() };
+        let a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let b = unsafe { Pin::new_unchecked(&mut this.b) };
+
+        let left_first = this.left_first;
+        this.left_first = !left_first;
+        if left_first {
+            if let Poll::Ready(output) = a.poll(cx) {
+                return Poll::Ready(Either::Left(output));
+            }
+            b.poll(cx).map(Either::Right)
+        } else {
+            if let Poll::Ready(output) = b.poll(cx) {
+                return Poll::Ready(Either::Right(output));
+            }
+            a.poll(cx).map(Either::Left)
+        }
+    }
+}
+
+/// Awaits whichever of `a` and `b` finishes first, see the module docs.
+pub fn race<A: Future, B: Future>(a: A, b: B) -> Race<A, B> {
+    Race {
+        a,
+        b,
+        left_first: next_start().is_multiple_of(2),
+    }
+}
+
+/// Where a race starts polling, so that no future or branch is always first.
+// Only public for `race!`.
+#[doc(hidden)]
+pub fn next_start() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Awaits whichever of several futures finishes first, and runs the branch
+/// that goes with it. Only usable in `async` code, like `.await`.
+///
+/// Each branch is `pattern = future => body`, where the irrefutable `pattern`
+/// binds the future's output for `body`. The losing futures are dropped before
+/// `body` runs, and the `race!` evaluates to `body`, so all the bodies must have
+/// the same type.
+///
+/// ```
+/// use crust_of_rust::async_await::block_on;
+/// use crust_of_rust::channels::channel;
+/// use crust_of_rust::race;
+///
+/// let (tx1, mut rx1) = channel::<i32>();
+/// let (tx2, mut rx2) = channel();
+/// tx2.send("hi").unwrap();
+///
+/// let msg = block_on(async {
+///     race! {
+///         msg = rx1.recv_async() => format!("rx1: {msg:?}"),
+///         msg = rx2.recv_async() => format!("rx2: {}", msg.unwrap()),
+///     }
+/// });
+/// assert_eq!(msg, "rx2: hi");
+/// # drop(tx1);
+/// ```
+#[macro_export]
+macro_rules! race {
+    // Every branch gets its own `fut` and `out`, see `join!`.
+    (@pin [$($done:tt)*] $pat:pat = $fut:expr => $body:expr, $($rest:tt)*) => {{
+        // In an `Option`, so that it can be dropped in place once it lost.
+        let mut fut = ::std::pin::pin!(::std::option::Option::Some($fut));
+        let mut out = ::std::option::Option::None;
+        $crate::race!(@pin [$($done)* (fut out ($pat) ($body))] $($rest)*)
+    }};
+    (@pin [$($done:tt)*] $pat:pat = $fut:expr => $body:expr) => {
+        $crate::race!(@pin [$($done)*] $pat = $fut => $body,)
+    };
+    (@pin [$(($fut:ident $out:ident ($pat:pat) ($body:expr)))+]) => {{
+        let branches = 0 $(+ $crate::race!(@one $fut))+;
+        let mut start = $crate::async_await::race::next_start();
+        ::std::future::poll_fn(|cx| {
+            start += 1;
+            for offset in 0..branches {
+                let branch = (start + offset) % branches;
+                let mut after = branches;
+                $(
+                    after -= 1;
+                    if branches - 1 - after == branch {
+                        let fut = $fut.as_mut().as_pin_mut().expect("raced future");
+                        if let ::std::task::Poll::Ready(output) =
+                            ::std::future::Future::poll(fut, cx)
+                        {
+                            $out = ::std::option::Option::Some(output);
+                            return ::std::task::Poll::Ready(());
+                        }
+                    }
+                )+
+            }
+            ::std::task::Poll::Pending
+        })
+        .await;
+
+        // The losers are cancelled here, before the winning branch runs.
+        $($fut.set(::std::option::Option::None);)+
+        $(
+            if let ::std::option::Option::Some($pat) = $out {
+                $body
+            } else
+        )+
+        {
+            ::std::unreachable!()
+        }
+    }};
+    (@one $fut:ident) => {
+        1
+    };
+    ($($tokens:tt)+) => {
+        $crate::race!(@pin [] $($tokens)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::channels::channel;
+    use std::future;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_race_cancels_loser() {
+        let token = Arc::new(());
+        let held = token.clone();
+        let loser = async move {
+            let _held = held;
+            future::pending::<()>().await
+        };
+
+        assert_eq!(block_on(race(loser, async { 42 })), Either::Right(42));
+        assert_eq!(Arc::strong_count(&token), 1);
+    }
+
+    #[test]
+    fn test_race_fair_on_ties() {
+        let mut left = 0;
+        for _ in 0..100 {
+            if let Either::Left(()) = block_on(race(async {}, async {})) {
+                left += 1;
+            }
+        }
+        assert!((1..100).contains(&left), "left won {left} times");
+
+        let mut wins = [0; 3];
+        for _ in 0..99 {
+            let branch = block_on(async {
+                race! {
+                    a = async { 0 } => a,
+                    b = async { 1 } => b,
+                    c = async { 2 } => c,
+                }
+            });
+            wins[branch] += 1;
+        }
+        assert!(wins.iter().all(|&n| n > 0), "{wins:?}");
+    }
+
+    #[test]
+    fn test_race_macro_waits_and_drops_losers() {
+        let (tx1, mut rx1) = channel::<i32>();
+        let (tx2, mut rx2) = channel();
+        let token = Arc::new(());
+        let held = token.clone();
+
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            tx2.send(7).unwrap();
+        });
+        let out = block_on(async {
+            race! {
+                _ = async move { let _held = held; future::pending::<()>().await } => 0,
+                msg = rx1.recv_async() => msg.unwrap(),
+                msg = rx2.recv_async() => {
+                    // Losers are gone before the body runs.
+                    assert_eq!(Arc::strong_count(&token), 1);
+                    msg.unwrap() * 2
+                },
+            }
+        });
+        assert_eq!(out, 14);
+        handle.join().unwrap();
+        drop(tx1);
+    }
+}
@@ -0,0 +1,295 @@
+//! The I/O reactor: one thread waiting on `epoll` for every registered socket,
+//! and waking the tasks that wait for them to become readable or writable.
+//!
+//! A socket is put in non-blocking mode and registered once, edge-triggered:
+//! `epoll` reports it when it *becomes* ready, not for as long as it stays
+//! ready. So the reactor remembers readiness per direction, and I/O goes like
+//! this (see `Registration::poll_io`):
+//!
+//!  1. If the socket isn't known to be ready, store the task's `Waker` and
+//!     return `Poll::Pending`. The reactor sets the flag and wakes the task on
+//!     the next event.
+//!  2. Otherwise try the syscall. Anything but `WouldBlock` is the result.
+//!  3. `WouldBlock` means the readiness was used up: clear the flag and go
+//!     back to 1, where the `Waker` is stored.
+//!
+//! Clearing races with the reactor setting the flag for a new event that came
+//! in after the syscall, which would then be lost, with nothing left to wake
+//! the task. Every event bumps a tick, and the flag is only cleared if the
+//! tick is still the one read before the syscall; otherwise the syscall is
+//! simply tried again.
+//!
+//! Like the timer thread (see `channels::timer`), the reactor thread is
+//! spawned the first time a socket is registered, and lives for the rest of
+//! the process. It is only here for Linux: other systems would need `kqueue`
+//! or the like instead of `epoll`.
+
+use std::collections::HashMap;
+use std::ffi::c_int;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+/// The parts of `<sys/epoll.h>` used here.
+mod sys {
+    use std::ffi::c_int;
+
+    pub const EPOLL_CLOEXEC: c_int = 0o2000000;
+    pub const EPOLL_CTL_ADD: c_int = 1;
+    pub const EPOLL_CTL_DEL: c_int = 2;
+
+    pub const EPOLLIN: u32 = 0x1;
+    pub const EPOLLOUT: u32 = 0x4;
+    pub const EPOLLERR: u32 = 0x8;
+    pub const EPOLLHUP: u32 = 0x10;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+    pub const EPOLLET: u32 = 1 << 31;
+
+    /// `struct epoll_event`, which is packed on x86-64 only.
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    #[derive(Clone, Copy)]
+    pub struct EpollEvent {
+        pub events: u32,
+        pub data: u64,
+    }
+
+    unsafe extern "C" {
+        // Provided by libc, which std already links against.
+        pub fn epoll_create1(flags: c_int) -> c_int;
+        pub fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+        pub fn epoll_wait(
+            epfd: c_int,
+            events: *mut EpollEvent,
+            maxevents: c_int,
+            timeout: c_int,
+        ) -> c_int;
+    }
+}
+
+/// Which readiness an operation waits for.
+#[derive(Clone, Copy)]
+pub(super) enum Interest {
+    Read,
+    Write,
+}
+
+#[derive(Default)]
+struct IoState {
+    readable: bool,
+    writable: bool,
+    /// Bumped by every event, see the module docs.
+    tick: u64,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl IoState {
+    fn direction(&mut self, interest: Interest) -> (&mut bool, &mut Option<Waker>) {
+        match interest {
+            Interest::Read => (&mut self.readable, &mut self.read_waker),
+            Interest::Write => (&mut self.writable, &mut self.write_waker),
+        }
+    }
+}
+
+struct Reactor {
+    epoll: OwnedFd,
+    /// The state of every registered socket, by the token `epoll` reports it
+    /// with. Tokens are never reused, so an event still in flight for a socket
+    /// that is gone finds nothing.
+    sources: Mutex<HashMap<u64, Arc<Mutex<IoState>>>>,
+    next_token: AtomicU64,
+}
+
+impl Reactor {
+    fn run(&self) {
+        let mut events = [sys::EpollEvent { events: 0, data: 0 }; 64];
+        loop {
+            // SAFETY: `events` is valid for writes of `events.len()` entries.
+            let n = unsafe {
+                sys::epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    events.len() as c_int,
+                    -1,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("`epoll_wait` failed: {err}");
+            }
+
+            for event in &events[..n as usize] {
+                // Copied out, since a packed field can't be borrowed.
+                let (flags, token) = (event.events, event.data);
+                let Some(source) = self.sources.lock().unwrap().get(&token).cloned() else {
+                    continue;
+                };
+
+                let mut state = source.lock().unwrap();
+                state.tick += 1;
+                // An error or a hangup is reported to both directions: the
+                // next syscall returns it (or the end of the stream).
+                let closed = flags & (sys::EPOLLERR | sys::EPOLLHUP) != 0;
+                let mut read_waker = None;
+                let mut write_waker = None;
+                if closed || flags & (sys::EPOLLIN | sys::EPOLLRDHUP) != 0 {
+                    state.readable = true;
+                    read_waker = state.read_waker.take();
+                }
+                if closed || flags & sys::EPOLLOUT != 0 {
+                    state.writable = true;
+                    write_waker = state.write_waker.take();
+                }
+                drop(state);
+
+                // Outside the lock, a `Waker` may run arbitrary code.
+                read_waker
+                    .into_iter()
+                    .chain(write_waker)
+                    .for_each(Waker::wake);
+            }
+        }
+    }
+}
+
+fn reactor() -> &'static Reactor {
+    static REACTOR: OnceLock<Reactor> = OnceLock::new();
+
+    REACTOR.get_or_init(|| {
+        // SAFETY: No pointers involved, the result is checked below.
+        let fd = unsafe { sys::epoll_create1(sys::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            panic!(
+                "failed to create the epoll instance: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        thread::Builder::new()
+            .name("reactor".into())
+            // Waits for the `OnceLock` to be initialized.
+            .spawn(|| reactor().run())
+            .expect("failed to spawn the reactor thread");
+
+        Reactor {
+            // SAFETY: A fresh file descriptor, owned by nobody else.
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+            sources: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+        }
+    })
+}
+
+/// A socket registered with the reactor, deregistered on drop.
+///
+/// It must be dropped before the socket is closed, so it goes before the
+/// socket in the fields of its owner.
+pub(super) struct Registration {
+    fd: RawFd,
+    token: u64,
+    state: Arc<Mutex<IoState>>,
+}
+
+impl Registration {
+    /// Registers `fd`, which must be in non-blocking mode.
+    pub(super) fn new(fd: RawFd) -> io::Result<Self> {
+        let reactor = reactor();
+        let token = reactor.next_token.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(Mutex::new(IoState::default()));
+        // Before `epoll` knows the socket, so no event can miss it.
+        reactor.sources.lock().unwrap().insert(token, state.clone());
+
+        let mut event = sys::EpollEvent {
+            events: sys::EPOLLIN | sys::EPOLLOUT | sys::EPOLLRDHUP | sys::EPOLLET,
+            data: token,
+        };
+        // SAFETY: `event` is a valid `epoll_event`, only read by the call.
+        let ret = unsafe {
+            sys::epoll_ctl(
+                reactor.epoll.as_raw_fd(),
+                sys::EPOLL_CTL_ADD,
+                fd,
+                &mut event,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            reactor.sources.lock().unwrap().remove(&token);
+            return Err(err);
+        }
+
+        Ok(Self { fd, token, state })
+    }
+
+    /// Ready if the socket is known to be ready for `interest`, with the tick
+    /// that readiness was last seen at. Otherwise stores `cx`'s `Waker` for
+    /// the reactor to wake.
+    fn poll_ready(&self, interest: Interest, cx: &mut Context<'_>) -> Poll<u64> {
+        let mut state = self.state.lock().unwrap();
+        let tick = state.tick;
+        let (ready, waker) = state.direction(interest);
+        if *ready {
+            return Poll::Ready(tick);
+        }
+        match waker {
+            Some(waker) => waker.clone_from(cx.waker()),
+            None => *waker = Some(cx.waker().clone()),
+        }
+        Poll::Pending
+    }
+
+    /// Forgets the readiness for `interest`, unless an event came in since
+    /// `tick`.
+    fn clear_ready(&self, interest: Interest, tick: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.tick == tick {
+            *state.direction(interest).0 = false;
+        }
+    }
+
+    /// Runs the non-blocking `op` once the socket is ready for `interest`,
+    /// and again after every event, until it doesn't return `WouldBlock`.
+    pub(super) fn poll_io<R>(
+        &self,
+        interest: Interest,
+        cx: &mut Context<'_>,
+        mut op: impl FnMut() -> io::Result<R>,
+    ) -> Poll<io::Result<R>> {
+        loop {
+            let Poll::Ready(tick) = self.poll_ready(interest, cx) else {
+                return Poll::Pending;
+            };
+            match op() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.clear_ready(interest, tick)
+                }
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let reactor = reactor();
+        // SAFETY: A null event is allowed for `EPOLL_CTL_DEL`. Failing is
+        // harmless: the socket leaves the set when it's closed anyway.
+        unsafe {
+            sys::epoll_ctl(
+                reactor.epoll.as_raw_fd(),
+                sys::EPOLL_CTL_DEL,
+                self.fd,
+                std::ptr::null_mut(),
+            );
+        }
+        reactor.sources.lock().unwrap().remove(&self.token);
+    }
+}
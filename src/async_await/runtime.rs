@@ -0,0 +1,870 @@
+//! A multi-threaded, work-stealing executor: a fixed set of worker threads
+//! running spawned tasks, each worker from its own queue.
+//!
+//! Every worker owns a Chase-Lev deque (see `lock_free::deque`). A task spawned
+//! or woken on a worker thread goes onto that worker's deque, where its data is
+//! likely still in the cache, and the other workers only get it by stealing.
+//! Everything else (tasks spawned or woken from outside the runtime, and the
+//! overflow of a full deque) goes onto a global injector queue behind a
+//! `Mutex`. A worker looks for work in that order: its own deque, the injector,
+//! then the other deques. Every `GLOBAL_EVERY` tasks it checks the injector
+//! first, so tasks that keep spawning locally can't starve the injector.
+//!
+//! A task is its boxed future plus a state word, and the `Waker` for it (see
+//! `waker`) is a reference to the task itself. Waking a task queues it only if
+//! it's idle: if it's already queued, or still being polled, the wakeup is just
+//! recorded in the state, and the worker polling it queues it again once the
+//! poll returns. That requeue goes to the injector rather than the local deque,
+//! whose owner pops the newest task first, so a task that keeps waking itself
+//! can't monopolize its worker.
+//!
+//! `spawn` wraps the future so that its output, or its panic, ends up in a slot
+//! shared with the returned `JoinHandle`, which wakes whoever awaits the
+//! handle. A panic is thus caught inside the task and can't take its worker
+//! down; awaiting the handle gives a `JoinError` instead.
+//!
+//! Cancellation is cooperative, in that a task is never interrupted in the
+//! middle of a poll. Aborting it sets a flag in the task and wakes it, and the
+//! worker that picks it up next drops the future instead of polling it. The
+//! wrapper completes the `JoinHandle` as cancelled when it's dropped before the
+//! future finished, so the same goes for the tasks dropped when the runtime
+//! shuts down.
+//!
+//! Idle workers park (see `atomics::Parker`) after adding themselves to a list
+//! of sleepers and checking for work one last time. Scheduling a task queues it
+//! before waking a sleeper, so either the worker finds the task in its last
+//! check, or the scheduler finds the worker in the list.
+//!
+//! A closure that blocks would take its worker out for as long as it does, so
+//! `spawn_blocking` runs it on a separate pool of threads instead (see
+//! `blocking`), still with a `JoinHandle` to await.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{Pin, pin};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use super::ext::BoxFuture;
+use super::waker::{self, ArcWake};
+use crate::arc::Arc;
+use crate::atomics::{Parker, Unparker};
+use crate::lock_free::deque::{self, Steal, Stealer};
+
+mod blocking;
+
+/// The capacity of each worker's deque, the rest goes to the injector.
+const LOCAL_QUEUE_CAP: usize = 256;
+
+/// How often (in tasks run) a worker checks the injector before its own deque.
+const GLOBAL_EVERY: u32 = 61;
+
+// Task states. `NOTIFIED` may be set together with `RUNNING` or `DONE`.
+
+/// Not queued, waiting for a wakeup.
+const IDLE: u8 = 0;
+/// Being polled by a worker.
+const RUNNING: u8 = 1;
+/// Woken: queued, or to be queued again once the poll in progress returns.
+const NOTIFIED: u8 = 2;
+/// Completed, the future is gone.
+const DONE: u8 = 4;
+
+struct Task {
+    /// Only locked by the worker polling the task, the state makes sure there
+    /// is never more than one.
+    future: Mutex<Option<BoxFuture<'static, ()>>>,
+    state: AtomicU8,
+    /// Set by `AbortHandle::abort`, see the module docs.
+    cancelled: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Task {
+    fn run(this: Arc<Task>) {
+        // `AcqRel`, like every other access: a wakeup that came before this is
+        // visible to the poll, one that comes after is seen below.
+        this.state.swap(RUNNING, Ordering::AcqRel);
+
+        let waker = waker::waker(this.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = this.future.lock().unwrap();
+        let Some(fut) = future.as_mut() else {
+            return;
+        };
+
+        if this.cancelled.load(Ordering::Acquire) {
+            drop(future);
+            // Dropped here on the worker, like a finished future.
+            this.cancel();
+            return;
+        }
+
+        // Can't panic, see `Spawned`.
+        if fut.as_mut().poll(&mut cx).is_pending() {
+            drop(future);
+            if this
+                .state
+                .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                // Woken during the poll, and not queued yet, see the module
+                // docs.
+                this.state.store(NOTIFIED, Ordering::Release);
+                let shared = this.shared.clone();
+                shared.schedule(this, false);
+            }
+        } else {
+            let fut = future.take();
+            this.state.store(DONE, Ordering::Release);
+            drop(future);
+            // Outside the lock, since dropping it may wake the task.
+            drop(fut);
+        }
+    }
+}
+
+impl Task {
+    /// Drops the future without running it any further, which completes the
+    /// `JoinHandle` as cancelled (see `Spawned`). The task itself may live on,
+    /// in its `Waker`s and `AbortHandle`s.
+    fn cancel(&self) {
+        let fut = self.future.lock().unwrap().take();
+        self.state.store(DONE, Ordering::Release);
+        // Outside the lock, since dropping it may wake the task.
+        drop(fut);
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(this: &Arc<Self>) {
+        if this.state.fetch_or(NOTIFIED, Ordering::AcqRel) == IDLE {
+            this.shared.schedule(this.clone(), true);
+        }
+    }
+
+    fn wake(this: Arc<Self>) {
+        if this.state.fetch_or(NOTIFIED, Ordering::AcqRel) == IDLE {
+            let shared = this.shared.clone();
+            shared.schedule(this, true);
+        }
+    }
+}
+
+struct Shared {
+    /// Tasks from outside the workers, and the overflow of their deques.
+    injector: Mutex<VecDeque<Arc<Task>>>,
+    /// One per worker, by index.
+    stealers: Vec<Stealer<Arc<Task>>>,
+    /// One per worker, by index.
+    unparkers: Vec<Unparker>,
+    /// Workers parked (or about to park) for lack of work.
+    sleepers: Mutex<Vec<usize>>,
+    /// Set by `Runtime::drop`, under the `injector` lock.
+    shutdown: AtomicBool,
+    blocking: blocking::Pool,
+}
+
+impl Shared {
+    fn spawn<F>(this: &Arc<Shared>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Shared::task(this, future);
+        this.schedule(task, true);
+        handle
+    }
+
+    fn spawn_blocking<F, T>(this: &Arc<Shared>, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (task, handle) = Shared::task(this, blocking::Blocking(Some(f)));
+        this.blocking.push(this, task);
+        handle
+    }
+
+    /// A task for `future`, marked as queued, to be queued by the caller.
+    fn task<F>(this: &Arc<Shared>, future: F) -> (Arc<Task>, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(JoinSlot::Running(None)));
+        let future = Spawned {
+            future: Some(future),
+            slot: slot.clone(),
+        };
+
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            state: AtomicU8::new(NOTIFIED),
+            cancelled: AtomicBool::new(false),
+            shared: this.clone(),
+        });
+        let handle = JoinHandle {
+            slot,
+            abort: AbortHandle { task: task.clone() },
+        };
+        (task, handle)
+    }
+
+    /// Queues a woken task, on the current worker's deque if `local` and we're
+    /// on one of our workers, and wakes a sleeping worker for it.
+    fn schedule(&self, task: Arc<Task>, local: bool) {
+        let overflow = if local {
+            CURRENT.with_borrow(|current| match current {
+                Some(current) if ptr::eq(&*current.shared, self) => match &current.local {
+                    Some(local) => local.push(task),
+                    None => Err(task),
+                },
+                _ => Err(task),
+            })
+        } else {
+            Err(task)
+        };
+
+        if let Err(task) = overflow {
+            let mut injector = self.injector.lock().unwrap();
+            if self.shutdown.load(Ordering::Relaxed) {
+                // Nobody will run it. Cancelled outside the lock, in case its
+                // future wakes another task.
+                drop(injector);
+                task.cancel();
+                return;
+            }
+            injector.push_back(task);
+        }
+
+        let sleeper = self.sleepers.lock().unwrap().pop();
+        if let Some(index) = sleeper {
+            self.unparkers[index].unpark();
+        }
+    }
+
+    /// The next task for worker `index`.
+    fn find_task(&self, index: usize, global_first: bool) -> Option<Arc<Task>> {
+        let pop_injector = || self.injector.lock().unwrap().pop_front();
+        let pop_local = || {
+            CURRENT.with_borrow(|current| {
+                current
+                    .as_ref()
+                    .and_then(|current| current.local.as_ref())
+                    .and_then(|local| local.pop())
+            })
+        };
+
+        let task = if global_first {
+            pop_injector().or_else(pop_local)
+        } else {
+            pop_local().or_else(pop_injector)
+        };
+        task.or_else(|| self.steal(index))
+    }
+
+    /// Steals a task from another worker, starting with the next one over.
+    fn steal(&self, index: usize) -> Option<Arc<Task>> {
+        let n = self.stealers.len();
+        loop {
+            let mut retry = false;
+            for i in 1..n {
+                match self.stealers[(index + i) % n].steal() {
+                    Steal::Success(task) => return Some(task),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        !self.injector.lock().unwrap().is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+}
+
+/// The runtime the current thread belongs to, if any.
+struct Current {
+    shared: Arc<Shared>,
+    /// The worker's deque, `None` in `Runtime::block_on`.
+    local: Option<deque::Worker<Arc<Task>>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+/// Makes `current` the current thread's runtime until dropped.
+struct Enter {
+    prev: Option<Current>,
+}
+
+impl Enter {
+    fn new(current: Current) -> Self {
+        Self {
+            prev: CURRENT.replace(Some(current)),
+        }
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        // Taken out first: dropping it may drop tasks, which may spawn.
+        let current = CURRENT.replace(self.prev.take());
+        drop(current);
+    }
+}
+
+fn run_worker(shared: Arc<Shared>, index: usize, local: deque::Worker<Arc<Task>>, parker: Parker) {
+    let _enter = Enter::new(Current {
+        shared: shared.clone(),
+        local: Some(local),
+    });
+
+    let mut ticks: u32 = 0;
+    while !shared.shutdown.load(Ordering::Acquire) {
+        ticks = ticks.wrapping_add(1);
+        if let Some(task) = shared.find_task(index, ticks.is_multiple_of(GLOBAL_EVERY)) {
+            Task::run(task);
+            continue;
+        }
+
+        shared.sleepers.lock().unwrap().push(index);
+        // The last check, see the module docs.
+        if !shared.has_work() && !shared.shutdown.load(Ordering::Acquire) {
+            parker.park();
+        }
+        // Whoever unparked us already took us off the list, unless this was a
+        // leftover token.
+        shared.sleepers.lock().unwrap().retain(|&i| i != index);
+    }
+    // Tasks left in our deque are dropped by `Runtime::drop`.
+}
+
+/// A pool of worker threads running spawned futures, see the module docs.
+pub struct Runtime {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Runtime {
+    /// Starts `workers` worker threads.
+    ///
+    /// Panics if `workers` is 0.
+    pub fn new(workers: usize) -> Self {
+        assert!(workers > 0, "a runtime needs at least one worker");
+
+        let (locals, stealers): (Vec<_>, Vec<_>) =
+            (0..workers).map(|_| deque::new(LOCAL_QUEUE_CAP)).unzip();
+        let parkers: Vec<_> = (0..workers).map(|_| Parker::new()).collect();
+        let shared = Arc::new(Shared {
+            injector: Mutex::new(VecDeque::new()),
+            stealers,
+            unparkers: parkers.iter().map(Parker::unparker).collect(),
+            sleepers: Mutex::new(Vec::new()),
+            shutdown: AtomicBool::new(false),
+            blocking: blocking::Pool::default(),
+        });
+
+        let workers = locals
+            .into_iter()
+            .zip(parkers)
+            .enumerate()
+            .map(|(index, (local, parker))| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{index}"))
+                    .spawn(move || run_worker(shared, index, local, parker))
+                    .expect("failed to spawn a worker thread")
+            })
+            .collect();
+
+        Self { shared, workers }
+    }
+
+    /// Runs `future` on one of the workers, without waiting for it. Its
+    /// output can be awaited through the returned `JoinHandle`.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        Shared::spawn(&self.shared, future)
+    }
+
+    /// Runs the blocking `f` on a thread of the blocking pool, rather than on
+    /// a worker. Its output can be awaited through the returned `JoinHandle`.
+    ///
+    /// Aborting it only has an effect before it started, see `blocking`.
+    pub fn spawn_blocking<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Shared::spawn_blocking(&self.shared, f)
+    }
+
+    /// Runs `future` to completion on the current thread, with `spawn`
+    /// available to it. Spawned tasks run on the workers meanwhile.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let _enter = Enter::new(Current {
+            shared: self.shared.clone(),
+            local: None,
+        });
+        super::block_on(future)
+    }
+}
+
+impl Drop for Runtime {
+    /// Stops the workers and drops every task still queued. Tasks waiting for
+    /// a wakeup are dropped once they're woken, or with their `Waker`s, and
+    /// blocking closures already running are left to finish on their threads.
+    fn drop(&mut self) {
+        let injector = self.shared.injector.lock().unwrap();
+        self.shared.shutdown.store(true, Ordering::Release);
+        drop(injector);
+
+        for unparker in &self.shared.unparkers {
+            unparker.unpark();
+        }
+        for worker in self.workers.drain(..) {
+            // Tasks can't panic a worker, see `Task::run`.
+            worker.join().unwrap();
+        }
+
+        // Queued tasks hold the `Shared` that holds the queues, a cycle only
+        // emptying the queues breaks.
+        let mut tasks: Vec<_> = std::mem::take(&mut *self.shared.injector.lock().unwrap()).into();
+        for stealer in &self.shared.stealers {
+            while let Steal::Success(task) = stealer.steal() {
+                tasks.push(task);
+            }
+        }
+        for task in tasks {
+            task.cancel();
+        }
+        self.shared.blocking.shutdown();
+    }
+}
+
+/// Runs `future` on the current runtime's workers, like `Runtime::spawn`.
+///
+/// Panics if called outside a runtime, that is, outside `Runtime::block_on`
+/// and the tasks it runs.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let shared = CURRENT.with_borrow(|current| {
+        let current = current
+            .as_ref()
+            .expect("`spawn` called outside of a runtime");
+        current.shared.clone()
+    });
+    Shared::spawn(&shared, future)
+}
+
+/// Runs the blocking `f` on the current runtime's blocking pool, like
+/// `Runtime::spawn_blocking`.
+///
+/// Panics if called outside a runtime, see `spawn`.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let shared = CURRENT.with_borrow(|current| {
+        let current = current
+            .as_ref()
+            .expect("`spawn_blocking` called outside of a runtime");
+        current.shared.clone()
+    });
+    Shared::spawn_blocking(&shared, f)
+}
+
+/// The future of a spawned task: runs the spawned future, catching its panic,
+/// and hands the result to the `JoinHandle`. Dropped before that, it tells the
+/// handle the task was cancelled.
+struct Spawned<F: Future> {
+    /// Structurally pinned, and dropped in place once it's done.
+    future: Option<F>,
+    slot: Arc<Mutex<JoinSlot<F::Output>>>,
+}
+
+impl<F: Future> Spawned<F> {
+    fn complete(&self, result: Result<F::Output, JoinError>) {
+        let prev = std::mem::replace(&mut *self.slot.lock().unwrap(), JoinSlot::Finished(result));
+        if let JoinSlot::Running(Some(waker)) = prev {
+            waker.wake();
+        }
+    }
+}
+
+impl<F: Future> Future for Spawned<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: Pin projection, see `ext`: `future` is never moved, only
+        // dropped in place, and `slot` isn't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let Some(future) = this.future.as_mut() else {
+            return Poll::Ready(());
+        };
+        let future = unsafe { Pin::new_unchecked(future) };
+
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Pending) => return Poll::Pending,
+            Ok(Poll::Ready(output)) => Ok(output),
+            Err(payload) => Err(JoinError::Panicked(payload)),
+        };
+        // Gone before anyone awaiting the handle sees it's done.
+        this.future = None;
+        this.complete(result);
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Drop for Spawned<F> {
+    fn drop(&mut self) {
+        if self.future.is_some() {
+            // In place, see `poll`.
+            self.future = None;
+            self.complete(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// Why a task has no output for its `JoinHandle`.
+pub enum JoinError {
+    /// The task was aborted, or dropped with its runtime, before it finished.
+    Cancelled,
+    /// The task panicked, with this payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+}
+
+impl JoinError {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panicked(_))
+    }
+
+    /// The payload of the task's panic, to resume it with
+    /// `panic::resume_unwind`.
+    ///
+    /// Panics if the task was cancelled instead.
+    pub fn into_panic(self) -> Box<dyn Any + Send + 'static> {
+        match self {
+            JoinError::Panicked(payload) => payload,
+            JoinError::Cancelled => panic!("`into_panic` called on a cancelled task"),
+        }
+    }
+}
+
+// Not derived, since the payload isn't `Debug`.
+impl std::fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::Cancelled => f.write_str("Cancelled"),
+            JoinError::Panicked(_) => f.debug_tuple("Panicked").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "JoinError: task was cancelled"),
+            JoinError::Panicked(_) => write!(f, "JoinError: task panicked"),
+        }
+    }
+}
+
+/// What a `JoinHandle` shares with its task.
+enum JoinSlot<T> {
+    /// With the `Waker` of the task awaiting the handle, if any.
+    Running(Option<Waker>),
+    /// The output of the task, or why there is none.
+    Finished(Result<T, JoinError>),
+    /// Handed out by `JoinHandle::poll`.
+    Taken,
+}
+
+/// A future resolving to the output of a spawned task, or to a `JoinError` if
+/// it panicked or was cancelled.
+///
+/// Dropping the handle detaches the task: it keeps running, and its output is
+/// dropped.
+pub struct JoinHandle<T> {
+    slot: Arc<Mutex<JoinSlot<T>>>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Whether the task is done, so that awaiting the handle won't wait.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.slot.lock().unwrap(), JoinSlot::Running(_))
+    }
+
+    /// Cancels the task, see `AbortHandle::abort`.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// A handle that can cancel the task, but not await it, e.g. to hand out
+    /// while the `JoinHandle` is being awaited.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    /// Panics if polled again after returning `Poll::Ready`.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        match std::mem::replace(&mut *slot, JoinSlot::Taken) {
+            JoinSlot::Running(mut waker) => {
+                match &mut waker {
+                    Some(waker) => waker.clone_from(cx.waker()),
+                    None => waker = Some(cx.waker().clone()),
+                }
+                *slot = JoinSlot::Running(waker);
+                Poll::Pending
+            }
+            JoinSlot::Finished(result) => Poll::Ready(result),
+            JoinSlot::Taken => panic!("`JoinHandle` polled after completion"),
+        }
+    }
+}
+
+/// Cancels a spawned task, see `JoinHandle::abort_handle`.
+#[derive(Clone)]
+pub struct AbortHandle {
+    task: Arc<Task>,
+}
+
+impl AbortHandle {
+    /// Cancels the task: unless it's done already, it won't be polled again,
+    /// and its `JoinHandle` resolves to `JoinError::Cancelled`.
+    ///
+    /// A task in the middle of a poll finishes that poll first. Its future is
+    /// dropped by the worker that next picks it up, which the wakeup here
+    /// makes sure happens.
+    pub fn abort(&self) {
+        // `Release` pairs with the `Acquire` in `Task::run`.
+        self.task.cancelled.store(true, Ordering::Release);
+        ArcWake::wake_by_ref(&self.task);
+    }
+
+    /// Whether the task is done, by finishing or by being cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.task.state.load(Ordering::Acquire) & DONE != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::channel;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn test_runtime_block_on_spawned() {
+        let rt = Runtime::new(4);
+        let (tx, mut rx) = channel();
+        for i in 0..100 {
+            let tx = tx.clone();
+            rt.spawn(async move { tx.send(i).unwrap() });
+        }
+        drop(tx);
+
+        let sum = rt.block_on(async {
+            let mut sum = 0;
+            while let Ok(i) = rx.recv_async().await {
+                sum += i;
+            }
+            sum
+        });
+        assert_eq!(sum, 4950);
+    }
+
+    #[test]
+    fn test_runtime_steals() {
+        let rt = Runtime::new(4);
+        let (tx, mut rx) = channel();
+
+        let threads = rt.block_on(async move {
+            // All on one worker's deque, the other workers have to steal.
+            spawn(async move {
+                for _ in 0..8 {
+                    let tx = tx.clone();
+                    spawn(async move {
+                        thread::sleep(Duration::from_millis(20));
+                        tx.send(thread::current().id()).unwrap();
+                    });
+                }
+            });
+
+            let mut threads = HashSet::new();
+            while let Ok(id) = rx.recv_async().await {
+                threads.insert(id);
+            }
+            threads
+        });
+        assert!(threads.len() > 1, "ran on {} worker(s)", threads.len());
+    }
+
+    #[test]
+    fn test_runtime_ping_pong_and_shutdown() {
+        let rt = Runtime::new(2);
+        let (ping_tx, mut ping_rx) = channel();
+        let (pong_tx, mut pong_rx) = channel();
+
+        rt.spawn(async move {
+            while let Ok(i) = ping_rx.recv_async().await {
+                pong_tx.send(i + 1).unwrap();
+            }
+        });
+        let last = rt.block_on(async {
+            let mut i = 0;
+            while i < 100 {
+                ping_tx.send(i).unwrap();
+                i = pong_rx.recv_async().await.unwrap();
+            }
+            i
+        });
+        assert_eq!(last, 100);
+
+        // A task still waiting is dropped once woken after the shutdown.
+        let token = std::sync::Arc::new(());
+        let (tx, mut rx) = channel::<()>();
+        let held = token.clone();
+        rt.spawn(async move {
+            let _held = held;
+            let _ = rx.recv_async().await;
+            unreachable!("the runtime is gone");
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(rt);
+        assert_eq!(std::sync::Arc::strong_count(&token), 2);
+        drop((tx, ping_tx));
+        assert_eq!(std::sync::Arc::strong_count(&token), 1);
+    }
+
+    #[test]
+    fn test_runtime_join_handles() {
+        let rt = Runtime::new(2);
+        let handles: Vec<_> = (0..10).map(|i| rt.spawn(async move { i * 2 })).collect();
+
+        let sum = rt.block_on(async {
+            let mut sum = 0;
+            for handle in handles {
+                sum += handle.await.unwrap();
+            }
+            // Awaited from another task, rather than from `block_on`.
+            let nested = spawn(async { spawn(async { "nested" }).await.unwrap() });
+            assert_eq!(nested.await.unwrap(), "nested");
+            sum
+        });
+        assert_eq!(sum, 90);
+    }
+
+    #[test]
+    fn test_runtime_join_handle_detach() {
+        let rt = Runtime::new(1);
+        let (tx, mut rx) = channel();
+
+        // Dropped right away, the task still runs.
+        drop(rt.spawn(async move { tx.send("detached").unwrap() }));
+        assert_eq!(rt.block_on(rx.recv_async()), Ok("detached"));
+    }
+
+    #[test]
+    fn test_runtime_join_handle_panic() {
+        let rt = Runtime::new(1);
+        let handle = rt.spawn(async { panic!("task panicked") });
+
+        let err = rt.block_on(handle).unwrap_err();
+        assert!(err.is_panic());
+        let payload = err.into_panic();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"task panicked"));
+
+        // The worker survived it.
+        assert_eq!(rt.block_on(rt.spawn(async { 42 })).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_runtime_abort() {
+        let rt = Runtime::new(2);
+
+        // Waiting for a value that never comes.
+        let token = std::sync::Arc::new(());
+        let held = token.clone();
+        let (tx, mut rx) = channel::<()>();
+        let waiting = rt.spawn(async move {
+            let _held = held;
+            let _ = rx.recv_async().await;
+        });
+        // Busy, yielding in a loop.
+        let busy = rt.spawn(async {
+            loop {
+                crate::async_await::yield_now().await;
+            }
+        });
+        let done = rt.spawn(async { 42 });
+        let done_abort = done.abort_handle();
+
+        thread::sleep(Duration::from_millis(10));
+        waiting.abort();
+        busy.abort();
+        rt.block_on(async {
+            assert!(waiting.await.unwrap_err().is_cancelled());
+            // The future was gone by the time the handle resolved.
+            assert_eq!(std::sync::Arc::strong_count(&token), 1);
+            assert!(busy.await.unwrap_err().is_cancelled());
+
+            // Too late to cancel a finished task.
+            assert_eq!(done.await.unwrap(), 42);
+            done_abort.abort();
+            assert!(done_abort.is_finished());
+        });
+        drop(tx);
+    }
+
+    #[test]
+    fn test_runtime_shutdown_cancels() {
+        let rt = Runtime::new(1);
+        let (tx, mut rx) = channel::<()>();
+        let handle = rt.spawn(async move {
+            let _ = rx.recv_async().await;
+        });
+        thread::sleep(Duration::from_millis(10));
+
+        // Woken after the shutdown, the task is dropped rather than run.
+        drop(rt);
+        drop(tx);
+        assert!(
+            crate::async_await::block_on(handle)
+                .unwrap_err()
+                .is_cancelled()
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! The blocking pool: threads for closures that block, so that they don't
+//! hold up a worker (and every task queued behind it) while they do.
+//!
+//! A blocking closure is still a `Task`, whose future calls the closure on
+//! its first poll and is done. That gives it a `JoinHandle`, with the panic
+//! caught and the output handed over like for any task, but instead of going
+//! onto a worker's queue, the task goes onto the pool's queue and is run by
+//! one of the pool's threads. Nothing ever wakes it onto the workers: it's
+//! queued from the start, and done after its one poll.
+//!
+//! Threads are spawned on demand, when a closure comes in and none is idle,
+//! up to `MAX_THREADS`, and exit once idle for `KEEP_ALIVE`. Handing a closure
+//! to an idle thread is counted in `wakeups` under the lock, so that the next
+//! closure doesn't count on the same thread, which may not have woken up yet,
+//! and each wakeup is claimed by exactly one thread.
+//!
+//! A closure can't be interrupted, so aborting one only helps before it
+//! started. Dropping the runtime cancels the closures still queued, and
+//! doesn't wait for the running ones: their threads exit once they're done.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Condvar, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use super::{Current, Enter, Shared, Task};
+use crate::arc::Arc;
+
+/// The most threads the pool runs at once, closures beyond that wait.
+const MAX_THREADS: usize = 64;
+
+/// How long an idle thread waits for a closure before it exits.
+#[cfg(not(test))]
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+/// Short, so that tests get to see idle threads exit.
+#[cfg(test)]
+const KEEP_ALIVE: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Arc<Task>>,
+    threads: usize,
+    /// Threads waiting for a closure, and not handed one yet.
+    idle: usize,
+    /// Closures handed to idle threads, and not claimed yet.
+    wakeups: usize,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+pub(super) struct Pool {
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+impl Pool {
+    /// Queues a blocking task, and finds a thread to run it.
+    pub(super) fn push(&self, shared: &Arc<Shared>, task: Arc<Task>) {
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown {
+            // Cancelled outside the lock, see `Shared::schedule`.
+            drop(state);
+            task.cancel();
+            return;
+        }
+        state.queue.push_back(task);
+
+        if state.idle > 0 {
+            state.idle -= 1;
+            state.wakeups += 1;
+            drop(state);
+            self.condvar.notify_one();
+        } else if state.threads < MAX_THREADS {
+            state.threads += 1;
+            let index = state.threads;
+            drop(state);
+
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("blocking-{index}"))
+                .spawn(move || run_thread(shared))
+                .expect("failed to spawn a blocking thread");
+        }
+    }
+
+    /// Cancels the queued tasks, and lets the threads exit once they're done.
+    pub(super) fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        // The tasks hold the `Shared` that holds the pool.
+        let tasks = std::mem::take(&mut state.queue);
+        drop(state);
+        self.condvar.notify_all();
+
+        for task in tasks {
+            task.cancel();
+        }
+    }
+}
+
+fn run_thread(shared: Arc<Shared>) {
+    // So that the closures can `spawn`.
+    let _enter = Enter::new(Current {
+        shared: shared.clone(),
+        local: None,
+    });
+    let pool = &shared.blocking;
+
+    let mut state = pool.state.lock().unwrap();
+    'run: loop {
+        if let Some(task) = state.queue.pop_front() {
+            drop(state);
+            // Can't panic, the task catches it.
+            Task::run(task);
+            state = pool.state.lock().unwrap();
+            continue;
+        }
+        if state.shutdown {
+            break;
+        }
+
+        state.idle += 1;
+        loop {
+            let (guard, result) = pool.condvar.wait_timeout(state, KEEP_ALIVE).unwrap();
+            state = guard;
+            if state.wakeups > 0 {
+                // `push` took us off `idle` already.
+                state.wakeups -= 1;
+                continue 'run;
+            }
+            if state.shutdown {
+                state.idle -= 1;
+                continue 'run;
+            }
+            if result.timed_out() {
+                state.idle -= 1;
+                // Every queued closure has a wakeup or a thread of its own,
+                // but there's no harm in taking one that's there anyway.
+                if state.queue.is_empty() {
+                    break 'run;
+                }
+                continue 'run;
+            }
+            // Spurious, keep waiting.
+        }
+    }
+    state.threads -= 1;
+}
+
+/// The future of a blocking task: calls the closure the first time it's
+/// polled.
+pub(super) struct Blocking<F>(pub(super) Option<F>);
+
+// The closure is never pinned, only called.
+impl<F> Unpin for Blocking<F> {}
+
+impl<F, T> Future for Blocking<F>
+where
+    F: FnOnce() -> T,
+{
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<T> {
+        let f = self.0.take().expect("`Blocking` polled after completion");
+        Poll::Ready(f())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Runtime, spawn, spawn_blocking};
+    use super::*;
+    use crate::async_await::block_on;
+    use std::sync::Barrier;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_blocking_keeps_workers_free() {
+        let rt = Runtime::new(1);
+        let (tx, rx) = mpsc::channel();
+
+        // Blocks until the task below runs, which it couldn't on the only
+        // worker if the closure were taking it up.
+        let blocking = rt.spawn_blocking(move || rx.recv().unwrap() * 2);
+        let task = rt.spawn(async move { tx.send(21).unwrap() });
+
+        rt.block_on(async {
+            task.await.unwrap();
+            assert_eq!(blocking.await.unwrap(), 42);
+        });
+    }
+
+    #[test]
+    fn test_blocking_threads_and_errors() {
+        const CLOSURES: usize = 8;
+        let rt = Runtime::new(1);
+        let barrier = std::sync::Arc::new(Barrier::new(CLOSURES));
+
+        let sum = rt.block_on(async {
+            // All blocked at once, on threads of their own.
+            let handles: Vec<_> = (0..CLOSURES)
+                .map(|i| {
+                    let barrier = barrier.clone();
+                    spawn_blocking(move || {
+                        barrier.wait();
+                        // Inside the runtime, like its tasks.
+                        block_on(spawn(async move { i }))
+                    })
+                })
+                .collect();
+
+            let mut sum = 0;
+            for handle in handles {
+                sum += handle.await.unwrap().unwrap();
+            }
+            sum
+        });
+        assert_eq!(sum, (0..CLOSURES).sum());
+
+        let err = rt
+            .block_on(rt.spawn_blocking(|| -> i32 { panic!("closure panicked") }))
+            .unwrap_err();
+        assert!(err.is_panic());
+    }
+
+    #[test]
+    fn test_blocking_idle_threads_exit() {
+        let rt = Runtime::new(1);
+        let threads = || rt.shared.blocking.state.lock().unwrap().threads;
+        let barrier = std::sync::Arc::new(Barrier::new(3));
+
+        // Three at once, so three threads.
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                rt.spawn_blocking(move || {
+                    barrier.wait();
+                })
+            })
+            .collect();
+        for handle in handles {
+            rt.block_on(handle).unwrap();
+        }
+
+        // Gone once idle for `KEEP_ALIVE`.
+        let start = Instant::now();
+        while threads() > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{} left",
+                threads()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // And spawned again when needed.
+        assert_eq!(rt.block_on(rt.spawn_blocking(|| 42)).unwrap(), 42);
+    }
+}
@@ -0,0 +1,238 @@
+//! `Stream`: the async counterpart of `Iterator`, a source of values that may
+//! have to be waited for, like the ones sent on a channel.
+//!
+//! Where a future's `poll` eventually returns its one output, `poll_next`
+//! returns `Poll::Ready(Some(item))` for every value, and `Poll::Ready(None)`
+//! once there are no more. `Poll::Pending` means the same as for a future: the
+//! task's `Waker` was registered, and will be woken once there is something
+//! new. A channel `Receiver` is a stream of the values sent (and so are the
+//! timers of `channels::timer`, `tick` giving a stream of `Instant`s).
+//!
+//! `StreamExt` is to streams what `FutureExt` is to futures: `next` turns the
+//! next item into a future, to `.await` it in a loop, `map` and `filter` adapt
+//! the items, and `collect` gathers all of them. The adapters reach the inner
+//! stream through `Pin` projection, as explained in `ext`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An asynchronous sequence of values, see the module docs.
+pub trait Stream {
+    type Item;
+
+    /// Returns the next item if there is one, `None` if the stream has ended,
+    /// or `Poll::Pending` after registering `cx`'s `Waker` to be woken once
+    /// either is the case.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+}
+
+impl<S: Stream + Unpin + ?Sized> Stream for &mut S {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut **self).poll_next(cx)
+    }
+}
+
+/// Adapters for every `Stream`, see the module docs.
+pub trait StreamExt: Stream {
+    /// Waits for the next item, `None` once the stream has ended.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+
+    /// Maps every item with `f`.
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        F: FnMut(Self::Item) -> T,
+        Self: Sized,
+    {
+        Map { stream: self, f }
+    }
+
+    /// Only keeps the items for which `f` returns `true`.
+    fn filter<F>(self, f: F) -> Filter<Self, F>
+    where
+        F: FnMut(&Self::Item) -> bool,
+        Self: Sized,
+    {
+        Filter { stream: self, f }
+    }
+
+    /// Waits for all the items, until the stream ends, and gathers them in a
+    /// collection.
+    fn collect<C>(self) -> Collect<Self, C>
+    where
+        C: Default + Extend<Self::Item>,
+        Self: Sized,
+    {
+        Collect {
+            stream: self,
+            items: C::default(),
+        }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// Future returned by `StreamExt::next`.
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// Stream returned by `StreamExt::map`.
+pub struct Map<S, F> {
+    /// Structurally pinned.
+    stream: S,
+    /// Not pinned.
+    f: F,
+}
+
+impl<S, F, T> Stream for Map<S, F>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> T,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        // SAFETY: Pin projection, see `ext`: `stream` is never moved, `f`
+        // isn't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+
+        stream.poll_next(cx).map(|item| item.map(&mut this.f))
+    }
+}
+
+/// Stream returned by `StreamExt::filter`.
+pub struct Filter<S, F> {
+    /// Structurally pinned.
+    stream: S,
+    /// Not pinned.
+    f: F,
+}
+
+impl<S, F> Stream for Filter<S, F>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> bool,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        // SAFETY: As in `Map`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+
+        // Rejected items don't make us wait: the stream is polled again until
+        // it has nothing ready, and has registered the waker.
+        loop {
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) if !(this.f)(&item) => continue,
+                poll => return poll,
+            }
+        }
+    }
+}
+
+/// Future returned by `StreamExt::collect`.
+pub struct Collect<S, C> {
+    /// Structurally pinned.
+    stream: S,
+    /// Not pinned, and taken out once the stream has ended.
+    items: C,
+}
+
+impl<S, C> Future for Collect<S, C>
+where
+    S: Stream,
+    C: Default + Extend<S::Item>,
+{
+    type Output = C;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<C> {
+        // SAFETY: As in `Map`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut stream = unsafe { Pin::new_unchecked(&mut this.stream) };
+
+        loop {
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => this.items.extend([item]),
+                Poll::Ready(None) => return Poll::Ready(std::mem::take(&mut this.items)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::channels::{channel, timer};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_stream_channel_adapters() {
+        let (tx, rx) = channel();
+        let sender = thread::spawn(move || {
+            for i in 0..10 {
+                thread::sleep(Duration::from_millis(1));
+                tx.send(i).unwrap();
+            }
+        });
+
+        let evens: Vec<_> = block_on(rx.filter(|i| i % 2 == 0).map(|i| i * 10).collect());
+        assert_eq!(evens, [0, 20, 40, 60, 80]);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_stream_next_keeps_registration() {
+        let (tx, mut rx) = channel();
+        let mut other = rx.clone();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx.send(1).unwrap();
+            tx.send(2).unwrap();
+        });
+
+        // Both `next`s wait, and each gets woken for its own value.
+        let (a, b) = block_on(async { crate::join!(rx.next(), other.next()) });
+        let mut got = [a.unwrap(), b.unwrap()];
+        got.sort();
+        assert_eq!(got, [1, 2]);
+        sender.join().unwrap();
+        assert_eq!(block_on(rx.next()), None);
+    }
+
+    #[test]
+    fn test_stream_tick() {
+        let start = Instant::now();
+        let mut ticks = timer::tick(Duration::from_millis(5));
+
+        let mut last = start;
+        block_on(async {
+            for _ in 0..3 {
+                let now = ticks.next().await.unwrap();
+                assert!(now > last);
+                last = now;
+            }
+        });
+        assert!(start.elapsed() >= Duration::from_millis(15));
+    }
+}
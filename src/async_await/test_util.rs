@@ -0,0 +1,187 @@
+//! Polling futures by hand, for tests: a `Waker` that does nothing, one that
+//! counts its wakeups, and `assert_pending!` / `assert_ready!` to poll once.
+//!
+//! `block_on` only says whether a future finishes, not how it gets there. A
+//! combinator is better tested a poll at a time: pending at first, woken when
+//! its inner future is, and ready right after. That takes no executor, only a
+//! `Context` to pass to `poll`, and a `Waker` to build it from. A `noop_waker`
+//! will do when nothing is woken (or it doesn't matter), a `CountingWaker`
+//! tells whether, and how often, the future used it.
+//!
+//! ```
+//! use std::pin::pin;
+//!
+//! use crust_of_rust::async_await::test_util::CountingWaker;
+//! use crust_of_rust::async_await::yield_now;
+//! use crust_of_rust::{assert_pending, assert_ready};
+//!
+//! let waker = CountingWaker::new();
+//! let mut yielding = pin!(yield_now());
+//! assert_pending!(yielding.as_mut(), &mut waker.context());
+//! // Woken right away, to be polled again.
+//! assert_eq!(waker.count(), 1);
+//! assert_ready!(yielding.as_mut());
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use super::waker::{self, ArcWake};
+use crate::arc::Arc;
+
+/// A `Waker` that does nothing when woken (std has `Waker::noop` for this).
+///
+/// There is nothing behind it, so the data pointer is null and every function
+/// of the vtable ignores it. Compare `waker`, whose functions own a count.
+pub fn noop_waker() -> Waker {
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(
+        |_| RawWaker::new(std::ptr::null(), &VTABLE),
+        |_| {},
+        |_| {},
+        |_| {},
+    );
+    // SAFETY: The vtable never touches the data pointer, and is fine to call
+    // from any thread.
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
+
+#[derive(Default)]
+struct Counter {
+    wakes: AtomicUsize,
+}
+
+impl ArcWake for Counter {
+    fn wake_by_ref(this: &Arc<Self>) {
+        this.wakes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A `Waker` counting how many times it (or any clone of it) was woken.
+pub struct CountingWaker {
+    counter: Arc<Counter>,
+    waker: Waker,
+}
+
+impl CountingWaker {
+    pub fn new() -> Self {
+        let counter = Arc::new(Counter::default());
+        Self {
+            waker: waker::waker(counter.clone()),
+            counter,
+        }
+    }
+
+    pub fn waker(&self) -> &Waker {
+        &self.waker
+    }
+
+    /// A `Context` to poll with, so that wakeups are counted here.
+    pub fn context(&self) -> Context<'_> {
+        Context::from_waker(&self.waker)
+    }
+
+    /// How many times it was woken so far, through `wake` or `wake_by_ref`.
+    pub fn count(&self) -> usize {
+        self.counter.wakes.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for CountingWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Polls `future` once, with a `noop_waker`.
+pub fn poll_once<F: Future + ?Sized>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(&noop_waker()))
+}
+
+/// Polls a pinned future once, and panics unless it's pending.
+///
+/// The future is polled with a `noop_waker`, or with the `&mut Context` given
+/// as a second argument.
+#[macro_export]
+macro_rules! assert_pending {
+    ($fut:expr $(,)?) => {
+        $crate::assert_pending!(
+            $fut,
+            &mut ::std::task::Context::from_waker(&$crate::async_await::test_util::noop_waker()),
+        )
+    };
+    ($fut:expr, $cx:expr $(,)?) => {
+        if ::std::future::Future::poll($fut, $cx).is_ready() {
+            panic!("expected `Poll::Pending`, got `Poll::Ready`");
+        }
+    };
+}
+
+/// Polls a pinned future once, and returns its output, or panics if it's
+/// pending.
+///
+/// The future is polled with a `noop_waker`, or with the `&mut Context` given
+/// as a second argument.
+#[macro_export]
+macro_rules! assert_ready {
+    ($fut:expr $(,)?) => {
+        $crate::assert_ready!(
+            $fut,
+            &mut ::std::task::Context::from_waker(&$crate::async_await::test_util::noop_waker()),
+        )
+    };
+    ($fut:expr, $cx:expr $(,)?) => {
+        match ::std::future::Future::poll($fut, $cx) {
+            ::std::task::Poll::Ready(output) => output,
+            ::std::task::Poll::Pending => panic!("expected `Poll::Ready`, got `Poll::Pending`"),
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::join::join;
+    use crate::channels::channel;
+    use std::pin::pin;
+
+    #[test]
+    fn test_test_util_wakers() {
+        let noop = noop_waker();
+        let clone = noop.clone();
+        assert!(clone.will_wake(&noop));
+        clone.wake();
+        noop.wake_by_ref();
+
+        let counting = CountingWaker::new();
+        let clone = counting.waker().clone();
+        clone.wake_by_ref();
+        clone.wake();
+        counting.waker().wake_by_ref();
+        assert_eq!(counting.count(), 3);
+    }
+
+    #[test]
+    fn test_test_util_poll_join_by_hand() {
+        let (tx, mut rx) = channel();
+        let waker = CountingWaker::new();
+        let mut joined = pin!(join(async { 1 }, rx.recv_async()));
+
+        assert!(poll_once(joined.as_mut()).is_pending());
+        assert_pending!(joined.as_mut(), &mut waker.context());
+        assert_eq!(waker.count(), 0);
+
+        // The send wakes the `Waker` registered last.
+        tx.send("two").unwrap();
+        assert_eq!(waker.count(), 1);
+        assert_eq!(assert_ready!(joined.as_mut()), (1, Ok("two")));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `Poll::Ready`, got `Poll::Pending`")]
+    fn test_test_util_assert_ready_panics() {
+        let mut pending = pin!(std::future::pending::<()>());
+        assert_ready!(pending.as_mut());
+    }
+}
@@ -0,0 +1,180 @@
+//! Waiting for time to pass in `async` code: `sleep`, and `timeout`, which
+//! gives up on a future that takes too long.
+//!
+//! A `Sleep` is a `timer::after` channel, received from as a stream: the timer
+//! thread sends on it once the duration has passed, which wakes the task like
+//! any other send. Nothing here blocks a thread, so any number of tasks can
+//! sleep on a runtime's few workers.
+//!
+//! `Timeout` polls its future first and the `Sleep` second, so a future that
+//! is ready just as the deadline passes still wins. Once the deadline wins,
+//! the future is dropped right away, before the `Err(Elapsed)` is returned,
+//! which is how the work it was doing gets cancelled.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use super::stream::Stream;
+use crate::channels::{Receiver, timer};
+
+/// Future returned by `sleep`.
+pub struct Sleep {
+    timer: Receiver<Instant>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // The timer sends once and then disconnects, either ends the wait.
+        Pin::new(&mut self.timer).poll_next(cx).map(|_| ())
+    }
+}
+
+/// Waits until `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        timer: timer::after(duration),
+    }
+}
+
+/// Returned by `Timeout` when the deadline passed first.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Elapsed {}
+
+impl std::error::Error for Elapsed {}
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Elapsed: deadline has elapsed")
+    }
+}
+
+/// Future returned by `timeout`.
+pub struct Timeout<F> {
+    /// Structurally pinned, and dropped in place once the deadline passed.
+    future: Option<F>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    /// Panics if polled again after returning `Poll::Ready`.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: Pin projection, see `ext`: `future` is never moved, only
+        // dropped in place, and `sleep` is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = this
+            .future
+            .as_mut()
+            .expect("`Timeout` polled after completion");
+        // SAFETY: See above.
+        let future = unsafe { Pin::new_unchecked(future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            // In place, see above. Gone, so that polling again panics.
+            this.future = None;
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                // In place, see above.
+                this.future = None;
+                Poll::Ready(Err(Elapsed {}))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Awaits `future`, unless `duration` passes first, in which case `future` is
+/// dropped and the result is `Err(Elapsed)`.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use crust_of_rust::async_await::block_on;
+/// use crust_of_rust::async_await::time::{Elapsed, sleep, timeout};
+///
+/// let slow = sleep(Duration::from_secs(60));
+/// let result = block_on(timeout(Duration::from_millis(10), slow));
+/// assert_eq!(result, Err(Elapsed {}));
+/// ```
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Some(future),
+        sleep: sleep(duration),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::async_await::runtime::Runtime;
+    use crate::channels::channel;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_time_sleep_on_runtime() {
+        let rt = Runtime::new(1);
+        let start = Instant::now();
+
+        // All asleep at once, on a single worker.
+        let handles: Vec<_> = (0..10)
+            .map(|_| rt.spawn(sleep(Duration::from_millis(30))))
+            .collect();
+        rt.block_on(async {
+            for handle in handles {
+                handle.await.unwrap();
+            }
+        });
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(30));
+        assert!(elapsed < Duration::from_millis(300), "{elapsed:?}");
+    }
+
+    #[test]
+    fn test_time_timeout() {
+        let (tx, mut rx) = channel();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx.send(42).unwrap();
+        });
+        let result = block_on(timeout(Duration::from_secs(10), rx.recv_async()));
+        assert_eq!(result, Ok(Ok(42)));
+        sender.join().unwrap();
+
+        // A deadline too far off to represent is never reached.
+        assert_eq!(block_on(timeout(Duration::MAX, async { 1 })), Ok(1));
+
+        let token = Arc::new(());
+        let held = token.clone();
+        let start = Instant::now();
+        let result = block_on(async {
+            let mut timed = std::pin::pin!(timeout(Duration::from_millis(20), async move {
+                let _held = held;
+                std::future::pending::<()>().await
+            }));
+            let outcome = timed.as_mut().await;
+            // Dropped as soon as the deadline passed, while the `Timeout` is
+            // still around.
+            assert_eq!(Arc::strong_count(&token), 1);
+            outcome
+        });
+        assert_eq!(result, Err(Elapsed {}));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    #[should_panic(expected = "`Timeout` polled after completion")]
+    fn test_time_timeout_polled_after_completion() {
+        let mut timed = std::pin::pin!(timeout(Duration::from_secs(10), async { 1 }));
+        assert_eq!(crate::assert_ready!(timed.as_mut()), Ok(1));
+        let _ = crate::assert_ready!(timed.as_mut());
+    }
+}
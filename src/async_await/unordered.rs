@@ -0,0 +1,391 @@
+//! `FuturesUnordered`: a set of futures driven on one task, and a `Stream` of
+//! their outputs in the order they finish.
+//!
+//! `join!` polls every future each time the task is woken, which is fine for
+//! two or three of them, but with a thousand of them one message arriving
+//! costs a thousand polls. Here every future gets a `Waker` of its own, which
+//! records that *this* future was woken before waking the task, and the set
+//! only polls the futures recorded since its last poll.
+//!
+//! The record is an intrusive list: each future's `Entry` carries a `next`
+//! pointer, so waking one just pushes its `Entry` with a CAS on the list's
+//! head, without allocating. Like `channels::park`, the set is the only
+//! consumer, and swaps the whole list out at once rather than popping, which
+//! keeps it clear of ABA. A `queued` flag makes sure an `Entry` is in the list
+//! at most once: it's set by the waker that pushes it, and cleared by the set
+//! right before the future is polled, so a wakeup during the poll queues it
+//! again.
+//!
+//! An `Entry` in the list is owned by the list (one strong count, from
+//! `Arc::into_raw`). Entries only hold a `Weak` to the list, so a future's
+//! `Waker` outliving the set doesn't keep the list, and the entries in it,
+//! alive in a cycle. Whoever drops the list last frees what's left in it.
+//!
+//! Since outputs come out one at a time, as the futures finish, a set can
+//! also bound how much is in flight at once:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use crust_of_rust::async_await::block_on;
+//! use crust_of_rust::async_await::stream::StreamExt;
+//! use crust_of_rust::async_await::time::sleep;
+//! use crust_of_rust::async_await::unordered::FuturesUnordered;
+//!
+//! let mut done = Vec::new();
+//! block_on(async {
+//!     let mut in_flight = FuturesUnordered::new();
+//!     for job in 0..20 {
+//!         // At most 4 jobs at a time: wait for one before starting another.
+//!         if in_flight.len() == 4 {
+//!             done.push(in_flight.next().await.unwrap());
+//!         }
+//!         in_flight.push(async move {
+//!             sleep(Duration::from_millis(job % 3)).await;
+//!             job
+//!         });
+//!     }
+//!     while let Some(job) = in_flight.next().await {
+//!         done.push(job);
+//!     }
+//! });
+//! done.sort();
+//! assert_eq!(done, (0..20).collect::<Vec<_>>());
+//! ```
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use super::stream::Stream;
+use super::waker::{self, ArcWake};
+use crate::arc::{Arc, Weak};
+
+/// The entries of woken futures, shared with their `Waker`s.
+struct ReadyList {
+    /// Most recently woken first.
+    head: AtomicPtr<Entry>,
+    /// The `Waker` of the task the set was last polled on.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl ReadyList {
+    fn push(&self, entry: Arc<Entry>) {
+        let entry = Arc::into_raw(entry).cast_mut();
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: `entry` is alive, we own a count. Its `next` is only
+            // read by whoever unlinks it, after the CAS below.
+            unsafe { (*entry).next.store(head, Ordering::Relaxed) };
+            match self
+                .head
+                .compare_exchange(head, entry, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Unlinks every entry pushed so far, oldest first.
+    fn take_all(&self, buf: &mut VecDeque<Arc<Entry>>) {
+        // Pairs with the CAS in `push`.
+        let mut entry = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        let start = buf.len();
+        while !entry.is_null() {
+            // SAFETY: The swap above unlinked the list, and with it the count
+            // every entry in it owns.
+            let owned = unsafe { Arc::from_raw(entry) };
+            entry = owned.next.load(Ordering::Relaxed);
+            buf.push_back(owned);
+        }
+        buf.make_contiguous()[start..].reverse();
+    }
+}
+
+impl Drop for ReadyList {
+    fn drop(&mut self) {
+        // Releases the counts of the entries woken since the last poll.
+        self.take_all(&mut VecDeque::new());
+    }
+}
+
+/// What a future's `Waker` points at.
+struct Entry {
+    /// Where the future is in `FuturesUnordered::futures`.
+    slot: usize,
+    /// Whether the entry is waiting to be polled, see the module docs.
+    queued: AtomicBool,
+    /// The next entry in the `ReadyList`, while in there.
+    next: AtomicPtr<Entry>,
+    ready: Weak<ReadyList>,
+}
+
+impl ArcWake for Entry {
+    fn wake_by_ref(this: &Arc<Self>) {
+        // The set is gone, and the future with it.
+        let Some(ready) = this.ready.upgrade() else {
+            return;
+        };
+        // Already queued means the task was woken for it already.
+        if this.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        ready.push(this.clone());
+        let waker = ready.waker.lock().unwrap().clone();
+        // Outside the lock, a `Waker` may run arbitrary code.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// A future in the set, boxed so it stays put while the `Vec` grows, with
+/// the entry its `Waker`s point at.
+type Slot<F> = (Arc<Entry>, Pin<Box<F>>);
+
+/// A set of futures, polled only once woken, see the module docs.
+///
+/// As a `Stream`, it yields the outputs in the order the futures finish, and
+/// ends once the set is empty. Futures may be pushed in between, even after
+/// it ended.
+pub struct FuturesUnordered<F> {
+    /// `None` for a free slot.
+    futures: Vec<Option<Slot<F>>>,
+    free: Vec<usize>,
+    len: usize,
+    /// Unlinked from `ready`, and still to be polled.
+    queue: VecDeque<Arc<Entry>>,
+    ready: Arc<ReadyList>,
+}
+
+impl<F: Future> FuturesUnordered<F> {
+    pub fn new() -> Self {
+        Self {
+            futures: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            queue: VecDeque::new(),
+            ready: Arc::new(ReadyList {
+                head: AtomicPtr::new(ptr::null_mut()),
+                waker: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Adds `future` to the set. It's first polled by the next `poll_next`,
+    /// which the caller is expected to get to: nothing is woken.
+    pub fn push(&mut self, future: F) {
+        let slot = self.free.pop().unwrap_or(self.futures.len());
+        let entry = Arc::new(Entry {
+            slot,
+            // Queued right away, below.
+            queued: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+            ready: Arc::downgrade(&self.ready),
+        });
+        self.queue.push_back(entry.clone());
+
+        let future = Some((entry, Box::pin(future)));
+        match self.futures.get_mut(slot) {
+            Some(free) => *free = future,
+            None => self.futures.push(future),
+        }
+        self.len += 1;
+    }
+
+    /// The number of futures in the set that haven't finished yet.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<F: Future> Default for FuturesUnordered<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Future> FromIterator<F> for FuturesUnordered<F> {
+    fn from_iter<I: IntoIterator<Item = F>>(iter: I) -> Self {
+        let mut set = Self::new();
+        iter.into_iter().for_each(|future| set.push(future));
+        set
+    }
+}
+
+impl<F: Future> Stream for FuturesUnordered<F> {
+    type Item = F::Output;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<F::Output>> {
+        // The futures are boxed, so nothing in here is pinned.
+        let this = self.get_mut();
+        if this.len == 0 {
+            return Poll::Ready(None);
+        }
+
+        // Before looking at the list, so that a future woken after that wakes
+        // this task.
+        match &mut *this.ready.waker.lock().unwrap() {
+            Some(waker) => waker.clone_from(cx.waker()),
+            waker => *waker = Some(cx.waker().clone()),
+        }
+
+        // The list is taken at most once: a future that wakes itself while
+        // polled is queued for the next call, instead of keeping this one
+        // going forever (the task was woken for it).
+        let mut taken = false;
+        loop {
+            let entry = match this.queue.pop_front() {
+                Some(entry) => entry,
+                None if taken => return Poll::Pending,
+                None => {
+                    this.ready.take_all(&mut this.queue);
+                    taken = true;
+                    continue;
+                }
+            };
+
+            // Cleared before the poll, so a wakeup during it isn't missed.
+            entry.queued.store(false, Ordering::Release);
+            // The slot may have been freed since the wakeup, or even reused
+            // by another future, which has its own entry.
+            let Some((current, future)) = &mut this.futures[entry.slot] else {
+                continue;
+            };
+            if !ptr::eq::<Entry>(&**current, &*entry) {
+                continue;
+            }
+
+            let waker = waker::waker(entry.clone());
+            if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                this.futures[entry.slot] = None;
+                this.free.push(entry.slot);
+                this.len -= 1;
+                return Poll::Ready(Some(output));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_await::block_on;
+    use crate::async_await::stream::StreamExt;
+    use crate::async_await::test_util::{CountingWaker, poll_once};
+    use crate::async_await::time::sleep;
+    use crate::channels::{Receiver, Sender, channel};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[test]
+    fn test_unordered_polls_only_woken_futures() {
+        let polls: std::sync::Arc<Vec<AtomicUsize>> =
+            std::sync::Arc::new((0..10).map(|_| AtomicUsize::new(0)).collect());
+        let (senders, receivers): (Vec<Sender<usize>>, Vec<Receiver<usize>>) =
+            (0..10).map(|_| channel()).unzip();
+
+        let mut set: FuturesUnordered<_> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut rx)| {
+                let polls = polls.clone();
+                std::future::poll_fn(move |cx| {
+                    polls[i].fetch_add(1, Ordering::SeqCst);
+                    Pin::new(&mut rx).poll_next(cx)
+                })
+            })
+            .collect();
+
+        let task = CountingWaker::new();
+        let mut cx = task.context();
+        let mut pinned = Pin::new(&mut set);
+        assert_eq!(pinned.as_mut().poll_next(&mut cx), Poll::Pending);
+        assert!(polls.iter().all(|n| n.load(Ordering::SeqCst) == 1));
+
+        senders[3].send(30).unwrap();
+        assert_eq!(task.count(), 1);
+        assert_eq!(
+            pinned.as_mut().poll_next(&mut cx),
+            Poll::Ready(Some(Some(30)))
+        );
+        assert_eq!(pinned.as_mut().poll_next(&mut cx), Poll::Pending);
+        let polled: Vec<_> = polls.iter().map(|n| n.load(Ordering::SeqCst)).collect();
+        assert_eq!(polled, [1, 1, 1, 2, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(set.len(), 9);
+
+        // Disconnecting wakes (and finishes) them all.
+        drop(senders);
+        let rest = block_on(set.collect::<Vec<_>>());
+        assert_eq!(rest, [None; 9]);
+    }
+
+    #[test]
+    fn test_unordered_bounded_concurrency() {
+        const LIMIT: usize = 4;
+        let running = std::sync::Arc::new(AtomicUsize::new(0));
+        let peak = std::sync::Arc::new(AtomicUsize::new(0));
+
+        let job = |i: u64| {
+            let running = running.clone();
+            let peak = peak.clone();
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                sleep(Duration::from_millis(i % 4)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        };
+
+        let mut finished = block_on(async {
+            let mut finished = Vec::new();
+            let mut set = FuturesUnordered::new();
+            for i in 0..32 {
+                if set.len() == LIMIT {
+                    finished.push(set.next().await.unwrap());
+                }
+                set.push(job(i));
+            }
+            while let Some(i) = set.next().await {
+                finished.push(i);
+            }
+            finished
+        });
+        finished.sort();
+        assert_eq!(finished, (0..32).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), LIMIT);
+    }
+
+    #[test]
+    fn test_unordered_waker_outlives_set() {
+        let stored = std::sync::Arc::new(Mutex::new(None::<Waker>));
+        let token = std::sync::Arc::new(());
+
+        let mut set = FuturesUnordered::new();
+        let (slot, held) = (stored.clone(), token.clone());
+        set.push(std::future::poll_fn(move |cx| {
+            let _held = &held;
+            *slot.lock().unwrap() = Some(cx.waker().clone());
+            Poll::<()>::Pending
+        }));
+        assert!(poll_once(Pin::new(&mut set.next())).is_pending());
+
+        // Queued, then the set is dropped with the entry in its list.
+        let waker = stored.lock().unwrap().take().unwrap();
+        waker.wake_by_ref();
+        drop(set);
+        assert_eq!(std::sync::Arc::strong_count(&token), 1);
+        // Nowhere to go anymore.
+        waker.wake();
+    }
+}
@@ -0,0 +1,166 @@
+//! A `Waker` assembled by hand from a `RawWakerVTable`, the way executors do it
+//! under the hood (and what `std::task::Wake` does for `std::sync::Arc`).
+//!
+//! A `Waker` is a type-erased pointer plus a table of four functions that know
+//! the pointer's real type. Here the pointer comes from `Arc::into_raw` on the
+//! crate's own `Arc`, and every `RawWaker` owns exactly one strong count:
+//!
+//!  - `clone` increments the count and returns a new `RawWaker` with the same
+//!    pointer, now owning the new count.
+//!  - `wake` consumes the `Waker`, so it turns the pointer back into an `Arc`
+//!    and hands it, count and all, to `ArcWake::wake`.
+//!  - `wake_by_ref` only borrows the `Waker`: it rebuilds the `Arc` in a
+//!    `ManuallyDrop`, so the count is left alone.
+//!  - `drop` rebuilds the `Arc` and drops it, releasing the count.
+//!
+//! Getting any of these wrong compiles fine: an `Arc` rebuilt in `wake_by_ref`
+//! and then dropped frees the target while other wakers still point at it, and
+//! a `wake` that forgets its `Arc` leaks it. The tests are meant for Miri,
+//! which catches both.
+
+use std::mem::ManuallyDrop;
+use std::task::{RawWaker, RawWakerVTable, Waker};
+
+use crate::arc::Arc;
+
+/// Something a `Waker` can wake, shared through an `Arc`.
+///
+/// The functions take the `Arc` explicitly rather than as `self`, since a
+/// method receiver can't be a user-defined pointer type.
+pub trait ArcWake: Send + Sync + Sized + 'static {
+    /// Wakes the target without consuming the caller's reference.
+    fn wake_by_ref(this: &Arc<Self>);
+
+    /// Wakes the target, consuming a reference. Override it if owning the
+    /// `Arc` saves work, like a clone to push it onto a run queue.
+    fn wake(this: Arc<Self>) {
+        Self::wake_by_ref(&this);
+    }
+}
+
+/// Creates a `Waker` that wakes `target`, holding one reference to it.
+pub fn waker<W: ArcWake>(target: Arc<W>) -> Waker {
+    let data = Arc::into_raw(target).cast::<()>();
+    // SAFETY: `data` comes from `Arc::into_raw` and owns one strong count,
+    // which is what every function in the vtable expects. `W` is `Send + Sync`,
+    // so the `Waker` may be used from any thread.
+    unsafe { Waker::from_raw(RawWaker::new(data, vtable::<W>())) }
+}
+
+fn vtable<W: ArcWake>() -> &'static RawWakerVTable {
+    // Promoted to a `'static`, one per `W`.
+    &RawWakerVTable::new(
+        clone_waker::<W>,
+        wake_waker::<W>,
+        wake_by_ref_waker::<W>,
+        drop_waker::<W>,
+    )
+}
+
+// SAFETY (all four): `data` comes from `waker` or `clone_waker`, so it is a
+// pointer from `Arc::into_raw` owning one strong count, which is live until
+// `wake_waker` or `drop_waker` is called on it.
+
+unsafe fn clone_waker<W: ArcWake>(data: *const ()) -> RawWaker {
+    // SAFETY: See above. The new count belongs to the new `RawWaker`.
+    unsafe { Arc::increment_strong_count(data.cast::<W>()) };
+    RawWaker::new(data, vtable::<W>())
+}
+
+unsafe fn wake_waker<W: ArcWake>(data: *const ()) {
+    // SAFETY: See above. The `Waker` is consumed, so its count is ours.
+    let target = unsafe { Arc::from_raw(data.cast::<W>()) };
+    W::wake(target);
+}
+
+unsafe fn wake_by_ref_waker<W: ArcWake>(data: *const ()) {
+    // SAFETY: See above. The `Waker` keeps its count, so the `Arc` must not be
+    // dropped.
+    let target = ManuallyDrop::new(unsafe { Arc::from_raw(data.cast::<W>()) });
+    W::wake_by_ref(&target);
+}
+
+unsafe fn drop_waker<W: ArcWake>(data: *const ()) {
+    // SAFETY: See above. Releases the dropped `Waker`'s count.
+    drop(unsafe { Arc::from_raw(data.cast::<W>()) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[derive(Default)]
+    struct Counter {
+        wakes: AtomicUsize,
+    }
+
+    impl ArcWake for Counter {
+        fn wake_by_ref(this: &Arc<Self>) {
+            this.wakes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_waker_clone_wake_drop() {
+        let mut target = Arc::new(Counter::default());
+        let waker1 = waker(target.clone());
+        let waker2 = waker1.clone();
+        assert!(waker1.will_wake(&waker2));
+        assert!(Arc::get_mut(&mut target).is_none());
+
+        waker1.wake_by_ref();
+        waker2.wake_by_ref();
+        assert_eq!(target.wakes.load(Ordering::SeqCst), 2);
+
+        // Consumes its reference, the other waker still holds one.
+        waker1.wake();
+        assert_eq!(target.wakes.load(Ordering::SeqCst), 3);
+        assert!(Arc::get_mut(&mut target).is_none());
+
+        drop(waker2);
+        assert!(Arc::get_mut(&mut target).is_some());
+    }
+
+    struct Owned {
+        wakes: std::sync::Arc<AtomicUsize>,
+    }
+
+    impl ArcWake for Owned {
+        fn wake_by_ref(this: &Arc<Self>) {
+            this.wakes.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn wake(this: Arc<Self>) {
+            // Overridden, to drop the last reference from inside `wake`.
+            Self::wake_by_ref(&this);
+            drop(this);
+        }
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_waker_across_threads() {
+        let wakes = std::sync::Arc::new(AtomicUsize::new(0));
+        let waker = waker(Arc::new(Owned {
+            wakes: wakes.clone(),
+        }));
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                let waker = waker.clone();
+                s.spawn(move || {
+                    waker.wake_by_ref();
+                    waker.wake();
+                });
+            }
+        });
+        assert_eq!(wakes.load(Ordering::SeqCst), 8);
+
+        // The last reference, so the target is freed here.
+        waker.wake();
+        assert_eq!(std::sync::Arc::strong_count(&wakes), 1);
+    }
+}
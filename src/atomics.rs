@@ -22,19 +22,66 @@
 //! [Ordering]: std::sync::atomic::Ordering
 //! [C++20 atomics]: https://en.cppreference.com/w/cpp/atomic/memory_order.html
 
-use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
 use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::cell::SyncUnsafeCell;
+
+mod atomic_bit_set;
+mod atomic_cell;
+mod backoff;
+mod cache_padded;
+mod condvar;
+pub(crate) mod futex;
+mod futex_mutex;
+mod lazy_lock;
+mod lock_order;
+mod once;
+mod once_lock;
+mod parker;
+mod poison;
+mod rwlock;
+mod semaphore;
+mod sharded;
+mod wait_group;
+
+pub use atomic_bit_set::{AtomicBitSet, AtomicBitSetIter};
+pub use atomic_cell::{AtomicCell, NoUninit};
+pub use backoff::Backoff;
+pub use cache_padded::CachePadded;
+pub use condvar::Condvar;
+pub use futex_mutex::{FutexMutex, FutexMutexGuard};
+pub use lazy_lock::LazyLock;
+pub use once::{Once, OnceState};
+pub use once_lock::OnceLock;
+pub use parker::{Parker, Unparker};
+pub use poison::{LockResult, PoisonError, TryLockError, TryLockResult};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+pub use sharded::Sharded;
+pub use wait_group::WaitGroup;
+
 pub struct Mutex<T> {
-    v: UnsafeCell<T>,
+    /// Only provides interior mutability, all synchronization is done through
+    /// `lock`.
+    v: SyncUnsafeCell<T>,
     lock: AtomicBool,
+    /// Set when a holder panics, see `poison`.
+    poison: poison::Flag,
+    /// See `lock_order`.
+    id: lock_order::LockId,
 }
 
-// SAFETY: Access to the inner `UnsafeCell` is locked behind an `AtomicBool`.
+// SAFETY: Access to the inner `SyncUnsafeCell` is locked behind an
+// `AtomicBool`.
 //
-// `T` needs to be `Send` because the lock can be acquired from multiple threads
-// and those threads might move the value. `T` does not have to be `Sync` since
-// a reference to the inner value `T` is never given out.
+// `SyncUnsafeCell<T>` is only `Sync` for `T: Sync`, which is stricter than
+// needed here. `T` needs to be `Send` because the lock can be acquired from
+// multiple threads and those threads might move the value. `T` does not have
+// to be `Sync` since a reference to the inner value `T` is never given out.
 unsafe impl<T: Send> Sync for Mutex<T> {}
 
 impl<T> Mutex<T> {
@@ -43,8 +90,10 @@ impl<T> Mutex<T> {
 
     pub fn new(val: T) -> Self {
         Self {
-            v: UnsafeCell::new(val),
+            v: SyncUnsafeCell::new(val),
             lock: AtomicBool::new(Self::UNLOCKED),
+            poison: poison::Flag::new(),
+            id: lock_order::LockId::new(),
         }
     }
 
@@ -72,8 +121,7 @@ impl<T> Mutex<T> {
         // interrupted between load and store, letting another thread acquire
         // the lock improperly.
 
-        // Simulates preemption scenario.
-        // std::thread::yield_now();
+        // `test_broken_with_lock` below has `sync_test` find that schedule.
 
         self.lock.store(Self::LOCKED, Ordering::Relaxed);
 
@@ -87,7 +135,27 @@ impl<T> Mutex<T> {
     }
     */
 
+    /// Runs `f` with the lock held.
+    ///
+    /// Panics if the mutex is poisoned, propagating the panic of the previous
+    /// holder. Use `lock` to recover the value instead.
+    #[track_caller]
     pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        match self.lock() {
+            Ok(mut guard) => f(&mut guard),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Acquires the lock, spinning until it is available. The lock is released
+    /// when the returned guard is dropped.
+    ///
+    /// Returns a `PoisonError` wrapping the guard if a previous holder
+    /// panicked.
+    #[track_caller]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        self.id.check();
+
         // Attempt to acquire the lock using an atomic compare-and-swap (CAS)
         // operation. `compare_exchange_weak` takes four arguments:
         //
@@ -164,26 +232,232 @@ impl<T> Mutex<T> {
             // `Relaxed` ordering is fine here because we don’t need
             // any synchronization guarantees, we’re just observing the lock
             // state.
+            //
+            // Spinning flat out still keeps a whole core busy for as long as
+            // the lock is held, so `Backoff` spins exponentially longer between
+            // checks and eventually yields to let a preempted holder run.
+            let mut backoff = Backoff::new();
             while self.lock.load(Ordering::Relaxed) == Self::LOCKED {
-                std::hint::spin_loop()
+                backoff.snooze();
             }
         }
 
-        // SAFETY: At this point, we believe we hold the lock, so we can safely
-        // create a mutable reference to the inner value.
-        let ret = f(unsafe { &mut *self.v.get() });
+        // SAFETY: The lock is held.
+        unsafe { MutexGuard::new(self) }
+    }
 
-        // After the closure finishes, we release the lock using
-        // `Ordering::Release`.
-        //
-        // This ensures that all writes performed inside the critical section
-        // become observable to any thread that subsequently acquires the lock
-        // with `Ordering::Acquire` or stronger. With a weaker ordering, another
-        // thread might acquire the lock and not see the updates made here, even
-        // though they happened before the lock was released.
-        self.lock.store(Self::UNLOCKED, Ordering::Release);
+    /// Acquires the lock only if it is currently free, without spinning.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        // The strong `compare_exchange`: a spurious failure here would be
+        // reported to the caller as contention when there was none.
+        if self
+            .lock
+            .compare_exchange(
+                Self::UNLOCKED,
+                Self::LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return Err(TryLockError::WouldBlock);
+        }
 
-        ret
+        // SAFETY: The lock is held.
+        Ok(unsafe { MutexGuard::new(self) }?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Marks the value as repaired, so later lockers no longer see a
+    /// `PoisonError`.
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.v.into_inner();
+
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+/// Gives access to the value of a locked `Mutex`, unlocking it on drop.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    poison: poison::Guard,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// # Safety
+    ///
+    /// The lock of `mutex` must be held by the caller, and is handed over to
+    /// the guard.
+    unsafe fn new(mutex: &'a Mutex<T>) -> LockResult<Self> {
+        mutex.id.acquired();
+        match mutex.poison.guard() {
+            Ok(poison) => Ok(MutexGuard { mutex, poison }),
+            Err(poison) => Err(PoisonError::new(MutexGuard { mutex, poison })),
+        }
+    }
+
+    /// Narrows the guard down to a part of the locked value, e.g. a field,
+    /// keeping the lock held until the returned guard is dropped.
+    ///
+    /// This is an associated function, so it doesn't shadow a `map` method on
+    /// the inner value.
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedMutexGuard<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        // The lock moves over to the new guard, so `orig` must not unlock it.
+        let mut orig = ManuallyDrop::new(orig);
+        let value = NonNull::from(f(&mut orig));
+        let mutex = orig.mutex;
+
+        MappedMutexGuard {
+            lock: &mutex.lock,
+            flag: &mutex.poison,
+            id: &mutex.id,
+            // SAFETY: `orig` is never dropped, so its `poison` is moved out
+            // exactly once.
+            poison: unsafe { std::ptr::read(&orig.poison) },
+            value,
+            _marker: PhantomData,
+        }
+    }
+}
+
+// SAFETY: Left to the auto-derived impl, `MutexGuard` would be `Sync` whenever
+// `Mutex<T>` is (`T: Send`). Sharing the guard hands out `&T` to other threads,
+// so it needs `T: Sync`. The explicit impl replaces the auto-derived one.
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The guard only exists while the lock is held, so no other
+        // reference to the inner value has been given out.
+        unsafe { &*self.mutex.v.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The guard only exists while the lock is held, so no other
+        // reference to the inner value has been given out.
+        unsafe { &mut *self.mutex.v.get() }
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.id.released();
+        release(&self.mutex.lock, &self.mutex.poison, &self.poison);
+    }
+}
+
+/// Shared by both guard types, which only differ in how they reach the value.
+fn release(lock: &AtomicBool, flag: &poison::Flag, poison: &poison::Guard) {
+    // Poisoning has to happen before the unlock, while no other thread can look
+    // at the flag.
+    flag.done(poison);
+
+    // When the guard is dropped, we release the lock using
+    // `Ordering::Release`.
+    //
+    // This ensures that all writes performed inside the critical section
+    // become observable to any thread that subsequently acquires the lock
+    // with `Ordering::Acquire` or stronger. With a weaker ordering, another
+    // thread might acquire the lock and not see the updates made here, even
+    // though they happened before the lock was released.
+    lock.store(Mutex::<()>::UNLOCKED, Ordering::Release);
+}
+
+/// A `MutexGuard` narrowed down to part of the locked value by
+/// `MutexGuard::map`.
+///
+/// The guard no longer knows the type of the whole value, so it holds the lock
+/// handle and a pointer into the value separately.
+pub struct MappedMutexGuard<'a, U: ?Sized> {
+    lock: &'a AtomicBool,
+    flag: &'a poison::Flag,
+    id: &'a lock_order::LockId,
+    poison: poison::Guard,
+    value: NonNull<U>,
+    /// Behaves like the `&mut U` it was created from.
+    _marker: PhantomData<&'a mut U>,
+}
+
+// SAFETY: Same bounds as `&mut U`, which is what the guard stands in for.
+// Releasing the lock from another thread is fine, `Mutex` doesn't track which
+// thread holds it.
+unsafe impl<U: ?Sized + Send> Send for MappedMutexGuard<'_, U> {}
+unsafe impl<U: ?Sized + Sync> Sync for MappedMutexGuard<'_, U> {}
+
+impl<'a, U: ?Sized> MappedMutexGuard<'a, U> {
+    /// Narrows the guard down further, see `MutexGuard::map`.
+    pub fn map<V: ?Sized, F>(orig: Self, f: F) -> MappedMutexGuard<'a, V>
+    where
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        let mut orig = ManuallyDrop::new(orig);
+        let value = NonNull::from(f(&mut orig));
+
+        MappedMutexGuard {
+            lock: orig.lock,
+            flag: orig.flag,
+            id: orig.id,
+            // SAFETY: `orig` is never dropped, so its `poison` is moved out
+            // exactly once.
+            poison: unsafe { std::ptr::read(&orig.poison) },
+            value,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<U: ?Sized> Deref for MappedMutexGuard<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: `value` points into the locked value, and the lock is held
+        // for as long as the guard exists.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedMutexGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: As above, and `&mut self` makes this the only reference.
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<U: ?Sized + std::fmt::Debug> std::fmt::Debug for MappedMutexGuard<'_, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<U: ?Sized> Drop for MappedMutexGuard<'_, U> {
+    fn drop(&mut self) {
+        self.id.released();
+        release(self.lock, self.flag, &self.poison);
     }
 }
 
@@ -321,6 +595,18 @@ pub fn atomic_sequentially_consistent() {
     let _z = z.load(Ordering::SeqCst);
 }
 
+/// ```compile_fail
+/// use std::cell::Cell;
+///
+/// use crust_of_rust::atomics::Mutex;
+///
+/// fn require_sync<T: Sync>(_: &T) {}
+///
+/// let mu = Mutex::new(Cell::new(42));
+/// require_sync(&mu.lock().unwrap());
+/// ```
+fn assert_guard_non_sync() {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -328,21 +614,189 @@ mod tests {
 
     #[test]
     fn test_mutex_valid() {
-        let mu: &'static _ = Box::leak(Box::new(Mutex::new(0)));
-        let mut handles: Vec<_> = (0..10)
-            .map(|_| {
-                thread::spawn(move || {
-                    for _ in 0..1000 {
-                        mu.with_lock(|v| *v += 1);
-                    }
-                })
+        static MU: LazyLock<Mutex<i32>> = LazyLock::new(|| Mutex::new(0));
+        let mu = &*MU;
+
+        let wg = WaitGroup::new();
+        for _ in 0..10 {
+            let wg = wg.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    mu.with_lock(|v| *v += 1);
+                }
+                drop(wg);
+            });
+        }
+        wg.wait();
+
+        assert_eq!(mu.with_lock(|v| *v), 10 * 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "lost an update")]
+    fn test_broken_with_lock() {
+        use crate::sync_test::{self, atomic, thread};
+        use std::sync::Arc;
+
+        // The commented out `with_lock` above, over `sync_test` atomics so
+        // that every access is a point where another thread can be scheduled.
+        struct BrokenMutex {
+            lock: atomic::AtomicBool,
+            v: atomic::AtomicUsize,
+        }
+
+        impl BrokenMutex {
+            fn with_lock(&self, f: impl FnOnce(&atomic::AtomicUsize)) {
+                while self.lock.load(Ordering::Relaxed) != Mutex::<()>::UNLOCKED {
+                    thread::yield_now();
+                }
+                self.lock.store(Mutex::<()>::LOCKED, Ordering::Relaxed);
+                f(&self.v);
+                self.lock.store(Mutex::<()>::UNLOCKED, Ordering::Relaxed);
+            }
+        }
+
+        sync_test::model(|| {
+            let mu = Arc::new(BrokenMutex {
+                lock: atomic::AtomicBool::new(false),
+                v: atomic::AtomicUsize::new(0),
+            });
+
+            let other = Arc::clone(&mu);
+            let t = thread::spawn(move || {
+                other.with_lock(|v| v.store(v.load(Ordering::Relaxed) + 1, Ordering::Relaxed));
+            });
+            mu.with_lock(|v| v.store(v.load(Ordering::Relaxed) + 1, Ordering::Relaxed));
+            t.join();
+
+            assert_eq!(mu.v.load(Ordering::Relaxed), 2, "lost an update");
+        });
+    }
+
+    #[test]
+    fn test_mutex_guard() {
+        let mu = Mutex::new(vec![1]);
+
+        fn push_locked(mu: &Mutex<Vec<i32>>, v: i32) -> MutexGuard<'_, Vec<i32>> {
+            let mut guard = mu.lock().unwrap();
+            guard.push(v);
+            guard
+        }
+
+        {
+            let guard = push_locked(&mu, 2);
+            match guard.last() {
+                Some(2) => assert_eq!(guard.len(), 2),
+                _ => unreachable!(),
+            }
+        } // unlocked here
+
+        thread::scope(|s| {
+            for i in 0..4 {
+                let mu = &mu;
+                s.spawn(move || mu.lock().unwrap().push(i));
+            }
+        });
+
+        assert_eq!(mu.with_lock(|v| v.len()), 6);
+    }
+
+    #[test]
+    fn test_mutex_guard_map() {
+        let mu = Mutex::new((String::from("a"), vec![1]));
+
+        {
+            let guard = mu.lock().unwrap();
+            let mut name = MutexGuard::map(guard, |(name, _)| name);
+            name.push('b');
+
+            // Still locked through the mapped guard.
+            assert!(matches!(mu.try_lock(), Err(TryLockError::WouldBlock)));
+
+            let mut first = MappedMutexGuard::map(name, |name| &mut name[..1]);
+            first.make_ascii_uppercase();
+            assert_eq!(format!("{first:?}"), r#""A""#);
+        }
+
+        thread::scope(|s| {
+            for i in 2..6 {
+                let mu = &mu;
+                s.spawn(move || MutexGuard::map(mu.lock().unwrap(), |(_, v)| v).push(i));
+            }
+        });
+
+        let (name, v) = mu.into_inner().unwrap();
+        assert_eq!(name, "Ab");
+        assert_eq!(v.len(), 5);
+    }
+
+    #[test]
+    fn test_mutex_mapped_guard_poison() {
+        let mu = Mutex::new((0, 0));
+
+        let _ = thread::scope(|s| {
+            s.spawn(|| {
+                let mut first = MutexGuard::map(mu.lock().unwrap(), |(a, _)| a);
+                *first += 1;
+                panic!("holding a mapped guard");
             })
-            .collect();
+            .join()
+        });
 
-        for handle in handles {
-            handle.join().unwrap();
+        assert!(mu.is_poisoned());
+        assert_eq!(mu.into_inner().unwrap_err().into_inner(), (1, 0));
+    }
+
+    #[test]
+    fn test_mutex_try_lock() {
+        let mu = Mutex::new(1);
+
+        {
+            let mut guard = mu.try_lock().unwrap();
+            *guard += 1;
+            assert!(matches!(mu.try_lock(), Err(TryLockError::WouldBlock)));
+            thread::scope(|s| {
+                s.spawn(|| assert!(mu.try_lock().is_err()));
+            });
         }
 
-        assert_eq!(mu.with_lock(|v| *v), 10 * 1000);
+        assert_eq!(*mu.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_mutex_poison() {
+        let mu = Mutex::new(vec![1, 2]);
+
+        let result = thread::scope(|s| {
+            s.spawn(|| {
+                mu.with_lock(|v| {
+                    v.push(3);
+                    panic!("half-way through an update");
+                })
+            })
+            .join()
+        });
+        assert!(result.is_err());
+        assert!(mu.is_poisoned());
+
+        // The data is still reachable, but only explicitly.
+        let guard = mu.lock().unwrap_err().into_inner();
+        assert_eq!(*guard, [1, 2, 3]);
+        drop(guard);
+        assert!(matches!(mu.try_lock(), Err(TryLockError::Poisoned(_))));
+
+        mu.clear_poison();
+        mu.lock().unwrap().pop();
+        assert_eq!(mu.into_inner().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "poisoned lock")]
+    fn test_mutex_with_lock_poisoned() {
+        let mu = Mutex::new(0);
+
+        let _ = thread::scope(|s| s.spawn(|| mu.with_lock(|_| panic!())).join());
+
+        mu.with_lock(|v| *v += 1);
     }
 }
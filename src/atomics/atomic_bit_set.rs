@@ -0,0 +1,191 @@
+//! A fixed-size set of bits that can be updated from several threads at once.
+//!
+//! The bits are packed into `AtomicUsize` words, and every update is a single
+//! `fetch_or` / `fetch_and` on the word holding the bit, so updates to
+//! different bits never get in each other's way, even within a word. The
+//! previous value of the word tells each caller what the bit was before its
+//! own update, which is what makes `test_and_set` usable to claim a slot: of
+//! several threads setting the same bit, exactly one sees it clear.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const BITS: usize = usize::BITS as usize;
+
+pub struct AtomicBitSet {
+    words: Box<[AtomicUsize]>,
+    len: usize,
+}
+
+impl AtomicBitSet {
+    /// Creates a set of `len` bits, all clear.
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: (0..len.div_ceil(BITS))
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            len,
+        }
+    }
+
+    /// The number of bits, set or not.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets bit `i`.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn set(&self, i: usize) {
+        self.test_and_set(i);
+    }
+
+    /// Sets bit `i`, returning whether it was already set.
+    ///
+    /// `AcqRel`, like `test_and_clear`: a thread that sets a bit to publish
+    /// something and a thread that claims the bit synchronize through it.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn test_and_set(&self, i: usize) -> bool {
+        let (word, mask) = self.locate(i);
+        word.fetch_or(mask, Ordering::AcqRel) & mask != 0
+    }
+
+    /// Clears bit `i`.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn clear(&self, i: usize) {
+        self.test_and_clear(i);
+    }
+
+    /// Clears bit `i`, returning whether it was set.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn test_and_clear(&self, i: usize) -> bool {
+        let (word, mask) = self.locate(i);
+        word.fetch_and(!mask, Ordering::AcqRel) & mask != 0
+    }
+
+    /// Returns whether bit `i` is set.
+    ///
+    /// Panics if `i` is out of bounds.
+    pub fn test(&self, i: usize) -> bool {
+        let (word, mask) = self.locate(i);
+        word.load(Ordering::Acquire) & mask != 0
+    }
+
+    /// Iterates over the indices of the set bits, in increasing order.
+    ///
+    /// Each word is loaded once, when the iterator gets to it. Bits changed
+    /// concurrently may or may not show up, but every index yielded was set at
+    /// some point during the iteration.
+    pub fn iter(&self) -> AtomicBitSetIter<'_> {
+        AtomicBitSetIter {
+            words: self.words.iter().enumerate(),
+            base: 0,
+            current: 0,
+        }
+    }
+
+    fn locate(&self, i: usize) -> (&AtomicUsize, usize) {
+        assert!(
+            i < self.len,
+            "index out of bounds: the len is {} but the index is {i}",
+            self.len
+        );
+        (&self.words[i / BITS], 1 << (i % BITS))
+    }
+}
+
+impl fmt::Debug for AtomicBitSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a AtomicBitSet {
+    type Item = usize;
+    type IntoIter = AtomicBitSetIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the set bits of an `AtomicBitSet`, see `AtomicBitSet::iter`.
+pub struct AtomicBitSetIter<'a> {
+    words: std::iter::Enumerate<std::slice::Iter<'a, AtomicUsize>>,
+    /// Index of bit 0 of `current`.
+    base: usize,
+    /// The bits of the current word that haven't been yielded yet.
+    current: usize,
+}
+
+impl Iterator for AtomicBitSetIter<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.current == 0 {
+            let (i, word) = self.words.next()?;
+            self.base = i * BITS;
+            self.current = word.load(Ordering::Acquire);
+        }
+
+        let bit = self.current.trailing_zeros() as usize;
+        // Clear the lowest set bit.
+        self.current &= self.current - 1;
+        Some(self.base + bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_atomic_bit_set() {
+        let set = AtomicBitSet::new(130);
+        assert_eq!(set.len(), 130);
+        assert_eq!(set.iter().next(), None);
+
+        for i in [0, 63, 64, 129] {
+            assert!(!set.test_and_set(i));
+        }
+        assert!(set.test_and_set(64));
+        assert!(set.test(63) && !set.test(62));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 63, 64, 129]);
+
+        assert!(set.test_and_clear(63));
+        assert!(!set.test_and_clear(63));
+        set.clear(0);
+        assert_eq!(format!("{set:?}"), "{64, 129}");
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn test_atomic_bit_set_out_of_bounds() {
+        // The backing word has room, but the bit isn't part of the set.
+        AtomicBitSet::new(10).set(10);
+    }
+
+    #[test]
+    fn test_atomic_bit_set_claims() {
+        let set = AtomicBitSet::new(256);
+
+        // Every thread tries to claim every bit, each bit is won exactly once.
+        let claimed: usize = thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (0..256).filter(|&i| !set.test_and_set(i)).count()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        assert_eq!(claimed, 256);
+        assert_eq!(set.iter().count(), 256);
+    }
+}
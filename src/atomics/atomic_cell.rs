@@ -0,0 +1,358 @@
+//! `Cell` for multi-threaded code: a value of any type that can be loaded,
+//! stored and swapped atomically.
+//!
+//! `NoUninit` types whose size and alignment match a native atomic integer are
+//! accessed through that integer, reinterpreting their bytes. Everything else
+//! falls back to a small global table of spinlocks, picked by the cell's
+//! address ("striped" locking), so no per-cell lock has to be stored.
+//!
+//! Reinterpreting bytes means compare-and-swap compares representations, so
+//! values that are `==` but differ in bits need a retry (see
+//! `compare_exchange`). It also means every byte must be initialized, which
+//! padding isn't: a `(u8, u16)` fits an `AtomicU32`, but reading its bits as a
+//! `u32` is undefined behavior. Unlike `crossbeam`'s `AtomicCell`, which leaves
+//! that to the caller, only types implementing `NoUninit` take the native path,
+//! picked by specialization on it.
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::num::{NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering};
+
+use super::Backoff;
+
+#[repr(transparent)]
+pub struct AtomicCell<T> {
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: All accesses are atomic, either natively or under a lock. Values are
+// moved in and out from any thread, but never shared by reference.
+unsafe impl<T: Send> Send for AtomicCell<T> {}
+unsafe impl<T: Send> Sync for AtomicCell<T> {}
+
+/// Types with no uninitialized bytes (no padding), so any value can be read
+/// as an integer of the same size.
+///
+/// # Safety
+///
+/// Every byte of every value of the type must be initialized, and any bits
+/// read out of a value must be a valid value when read back.
+pub unsafe trait NoUninit {}
+
+macro_rules! no_uninit {
+    ($($t:ty),*) => {
+        // SAFETY: Primitives, with no padding.
+        $(unsafe impl NoUninit for $t {})*
+    };
+}
+
+no_uninit!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+no_uninit!(f32, f64, bool, char);
+no_uninit!(NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize);
+no_uninit!(Option<NonZeroU8>, Option<NonZeroU16>, Option<NonZeroU32>);
+no_uninit!(Option<NonZeroU64>, Option<NonZeroUsize>);
+
+// SAFETY: Just an address (and metadata for unsized `T`, but those are too big
+// for a native atomic anyway).
+unsafe impl<T: ?Sized> NoUninit for *const T {}
+// SAFETY: As above.
+unsafe impl<T: ?Sized> NoUninit for *mut T {}
+// SAFETY: Elements are laid out back to back, with no padding in between.
+unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {}
+
+/// Whether `T` is known to be `NoUninit`, for code generic over any `T`.
+trait MaybeNoUninit {
+    const NO_UNINIT: bool;
+}
+
+impl<T> MaybeNoUninit for T {
+    default const NO_UNINIT: bool = false;
+}
+
+impl<T: NoUninit> MaybeNoUninit for T {
+    const NO_UNINIT: bool = true;
+}
+
+/// Whether `T` can be accessed as an `A`: no uninitialized bytes, same size,
+/// and aligned at least as strictly (the cell is `repr(transparent)`, so it
+/// has `T`'s alignment).
+const fn can_transmute<T, A>() -> bool {
+    <T as MaybeNoUninit>::NO_UNINIT
+        && mem::size_of::<T>() == mem::size_of::<A>()
+        && mem::align_of::<T>() >= mem::align_of::<A>()
+}
+
+/// Runs `$native` with `$a` bound to the cell viewed as a native atomic if `$t`
+/// fits one, `$fallback` otherwise. The checks are on constants, so only one
+/// of the branches survives optimization for each `T`.
+macro_rules! atomic {
+    ($t:ty, $ptr:expr, $a:ident, $native:expr, $fallback:expr) => {
+        atomic!(@try $t, $ptr, $a, $native, $fallback; AtomicU8, AtomicU16, AtomicU32, AtomicU64)
+    };
+    (@try $t:ty, $ptr:expr, $a:ident, $native:expr, $fallback:expr; $atomic:ty $(, $rest:ty)*) => {
+        if can_transmute::<$t, $atomic>() {
+            // SAFETY: No uninitialized bytes, size and alignment match, and all
+            // accesses to the cell go through this atomic view.
+            let $a: &$atomic = unsafe { &*($ptr as *const $atomic) };
+            $native
+        } else {
+            atomic!(@try $t, $ptr, $a, $native, $fallback; $($rest),*)
+        }
+    };
+    (@try $t:ty, $ptr:expr, $a:ident, $native:expr, $fallback:expr;) => {
+        $fallback
+    };
+}
+
+impl<T> AtomicCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Whether operations on `AtomicCell<T>` use native atomics rather than
+    /// the lock table.
+    pub const fn is_lock_free() -> bool {
+        can_transmute::<T, AtomicU8>()
+            || can_transmute::<T, AtomicU16>()
+            || can_transmute::<T, AtomicU32>()
+            || can_transmute::<T, AtomicU64>()
+    }
+
+    pub fn store(&self, value: T) {
+        // Dropping the old value is what makes this different from a swap.
+        drop(self.swap(value));
+    }
+
+    pub fn swap(&self, value: T) -> T {
+        atomic! {
+            T, self.value.get(), a,
+            {
+                // SAFETY: Same size and no padding, checked by `atomic!`.
+                // `value`'s bits now live in the cell, so it must not be
+                // dropped here.
+                let bits = unsafe { mem::transmute_copy(&value) };
+                mem::forget(value);
+                let old = a.swap(bits, Ordering::AcqRel);
+                // SAFETY: The cell only ever holds bits of a valid `T`.
+                unsafe { mem::transmute_copy(&old) }
+            },
+            {
+                let _guard = lock(self.value.get() as usize);
+                // SAFETY: The stripe lock for this address is held.
+                unsafe { mem::replace(&mut *self.value.get(), value) }
+            }
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+}
+
+impl<T: Copy> AtomicCell<T> {
+    pub fn load(&self) -> T {
+        atomic! {
+            T, self.value.get(), a,
+            {
+                let bits = a.load(Ordering::Acquire);
+                // SAFETY: The cell only ever holds bits of a valid `T`.
+                unsafe { mem::transmute_copy(&bits) }
+            },
+            {
+                let _guard = lock(self.value.get() as usize);
+                // SAFETY: The stripe lock for this address is held.
+                unsafe { *self.value.get() }
+            }
+        }
+    }
+}
+
+impl<T: Copy + Eq> AtomicCell<T> {
+    /// Stores `new` if the value is equal to `current`, returning the previous
+    /// value in both cases: `Ok` if it was replaced, `Err` if not.
+    pub fn compare_exchange(&self, mut current: T, new: T) -> Result<T, T> {
+        atomic! {
+            T, self.value.get(), a,
+            loop {
+                // SAFETY: Same size and no padding, checked by `atomic!`.
+                let (current_bits, new_bits) =
+                    unsafe { (mem::transmute_copy(&current), mem::transmute_copy(&new)) };
+
+                match a.compare_exchange(current_bits, new_bits, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => break Ok(current),
+                    Err(previous_bits) => {
+                        // SAFETY: The cell only ever holds bits of a valid `T`.
+                        let previous: T = unsafe { mem::transmute_copy(&previous_bits) };
+
+                        // The bits differed, but the values may still be equal
+                        // (e.g, two representations of the same value). Retry
+                        // with the exact bits that are in the cell.
+                        if previous != current {
+                            break Err(previous);
+                        }
+                        current = previous;
+                    }
+                }
+            },
+            {
+                let _guard = lock(self.value.get() as usize);
+                // SAFETY: The stripe lock for this address is held.
+                let value = unsafe { &mut *self.value.get() };
+                if *value == current {
+                    Ok(mem::replace(value, new))
+                } else {
+                    Err(*value)
+                }
+            }
+        }
+    }
+}
+
+impl<T: Default> Default for AtomicCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy + std::fmt::Debug> std::fmt::Debug for AtomicCell<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AtomicCell")
+            .field("value", &self.load())
+            .finish()
+    }
+}
+
+/// A prime, so cells laid out at regular strides spread over all the locks.
+const STRIPES: usize = 67;
+
+static LOCKS: [AtomicBool; STRIPES] = [const { AtomicBool::new(false) }; STRIPES];
+
+struct StripeGuard {
+    lock: &'static AtomicBool,
+}
+
+/// Locks the stripe for the cell at `addr`. Unrelated cells may share a
+/// stripe, which costs contention but never correctness.
+fn lock(addr: usize) -> StripeGuard {
+    let lock = &LOCKS[addr % STRIPES];
+
+    let mut backoff = Backoff::new();
+    while lock
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        while lock.load(Ordering::Relaxed) {
+            backoff.snooze();
+        }
+    }
+
+    StripeGuard { lock }
+}
+
+impl Drop for StripeGuard {
+    fn drop(&mut self) {
+        self.lock.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_atomic_cell_native() {
+        assert!(AtomicCell::<u32>::is_lock_free());
+        assert!(AtomicCell::<Option<std::num::NonZeroU64>>::is_lock_free());
+
+        let cell = AtomicCell::new(7u32);
+        assert_eq!(cell.swap(8), 7);
+        assert_eq!(cell.compare_exchange(7, 9), Err(8));
+        assert_eq!(cell.compare_exchange(8, 9), Ok(8));
+        assert_eq!(cell.load(), 9);
+    }
+
+    #[test]
+    fn test_atomic_cell_fallback() {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        struct Big([u64; 4]);
+
+        assert!(!AtomicCell::<Big>::is_lock_free());
+
+        let cell = AtomicCell::new(Big([1; 4]));
+        cell.store(Big([2; 4]));
+        assert_eq!(
+            cell.compare_exchange(Big([2; 4]), Big([3; 4])),
+            Ok(Big([2; 4]))
+        );
+        assert_eq!(
+            format!("{cell:?}"),
+            "AtomicCell { value: Big([3, 3, 3, 3]) }"
+        );
+    }
+
+    #[test]
+    fn test_atomic_cell_padding_uses_locks() {
+        // Fits an `AtomicU32`, but the padding byte can't be read as one.
+        let cell = AtomicCell::new((1u8, 2u16));
+        assert!(!AtomicCell::<(u8, u16)>::is_lock_free());
+        assert_eq!(cell.compare_exchange((1, 2), (3, 4)), Ok((1, 2)));
+        assert_eq!(cell.load(), (3, 4));
+
+        // Same size, no padding.
+        assert!(AtomicCell::<[u32; 1]>::is_lock_free());
+        assert!(AtomicCell::<*const u8>::is_lock_free());
+        // No padding either, but not known to be `NoUninit`.
+        assert!(!AtomicCell::<std::sync::Arc<u8>>::is_lock_free());
+    }
+
+    #[test]
+    fn test_atomic_cell_drops_values() {
+        let value = std::sync::Arc::new(());
+        let cell = AtomicCell::new(value.clone());
+
+        cell.store(value.clone());
+        assert_eq!(std::sync::Arc::strong_count(&value), 2);
+        drop(cell.swap(value.clone()));
+        drop(cell);
+        assert_eq!(std::sync::Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_atomic_cell_concurrent_counters() {
+        let native = AtomicCell::new(0u64);
+        let locked = AtomicCell::new((0u64, 0u64));
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        let mut cur = native.load();
+                        while let Err(actual) = native.compare_exchange(cur, cur + 1) {
+                            cur = actual;
+                        }
+
+                        let mut cur = locked.load();
+                        while let Err(actual) = locked.compare_exchange(cur, (cur.0 + 1, cur.1 + 1))
+                        {
+                            // Both halves always move together.
+                            assert_eq!(actual.0, actual.1);
+                            cur = actual;
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(native.into_inner(), 2000);
+        assert_eq!(locked.into_inner(), (2000, 2000));
+    }
+}
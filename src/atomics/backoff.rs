@@ -0,0 +1,115 @@
+//! Bounded exponential backoff for spin-wait loops.
+//!
+//! Spinning on a contended lock with a bare `spin_loop` keeps a core busy for
+//! as long as the lock is held. Backing off exponentially reduces the traffic
+//! on the lock's cache line, and falling back to `yield_now` lets the holder
+//! run if it was preempted on the same core.
+
+use std::thread;
+
+/// Shared by every spinning primitive in the crate, so they all wait the same
+/// way:
+///
+/// ```
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// use crust_of_rust::atomics::Backoff;
+///
+/// fn wait_until_set(flag: &AtomicBool) {
+///     let mut backoff = Backoff::new();
+///     while !flag.load(Ordering::Acquire) {
+///         if backoff.is_completed() {
+///             // Spinning stopped paying off, block instead.
+///             std::thread::park_timeout(std::time::Duration::from_millis(1));
+///         } else {
+///             backoff.snooze();
+///         }
+///     }
+/// }
+///
+/// wait_until_set(&AtomicBool::new(true));
+/// ```
+pub struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    /// Past this step, `spin` stops doubling the number of spins.
+    const SPIN_LIMIT: u32 = 6;
+    /// Past this step, waiting any longer should block instead of spinning or
+    /// yielding, see `is_completed`.
+    const YIELD_LIMIT: u32 = 10;
+
+    pub fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    /// Starts backing off from the beginning again, e.g. after making progress.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    /// Backs off in a lock-free loop that is retrying a failed CAS. Only ever
+    /// spins, since another thread made progress to make the CAS fail.
+    pub fn spin(&mut self) {
+        for _ in 0..1 << self.step.min(Self::SPIN_LIMIT) {
+            std::hint::spin_loop();
+        }
+
+        if self.step <= Self::SPIN_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Backs off in a loop waiting for another thread (e.g, a lock holder) to
+    /// make progress. Spins exponentially longer, then yields the time slice.
+    pub fn snooze(&mut self) {
+        if self.step <= Self::SPIN_LIMIT {
+            for _ in 0..1 << self.step {
+                std::hint::spin_loop();
+            }
+        } else {
+            thread::yield_now();
+        }
+
+        if self.step <= Self::YIELD_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /// Whether backing off has stopped paying off, and the caller should block
+    /// (park the thread, wait on a futex) if it can.
+    pub fn is_completed(&self) -> bool {
+        self.step > Self::YIELD_LIMIT
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_completes() {
+        let mut backoff = Backoff::new();
+
+        let mut snoozes = 0;
+        while !backoff.is_completed() {
+            backoff.snooze();
+            snoozes += 1;
+        }
+        assert_eq!(snoozes, Backoff::YIELD_LIMIT + 1);
+
+        // `spin` alone never asks the caller to block.
+        backoff.reset();
+        for _ in 0..100 {
+            backoff.spin();
+        }
+        assert!(!backoff.is_completed());
+    }
+}
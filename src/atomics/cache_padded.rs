@@ -0,0 +1,96 @@
+//! Padding values out to a cache line of their own, to avoid false sharing.
+//!
+//! Caches move memory around in lines (usually 64 bytes), and coherence is
+//! tracked per line: before a core can write to a line, every other core's copy
+//! of it is invalidated. Two atomics that share a line therefore contend even
+//! if no thread ever touches both, each write by one core evicting the line
+//! from the core using the other. This is false sharing, and it can make
+//! independent counters or locks scale as badly as a single shared one.
+//!
+//! `CachePadded` aligns (and so pads) its value to the line size, so two of
+//! them never share a line. On x86_64 that is 128 bytes rather than 64: the
+//! spatial prefetcher pulls in lines in pairs, so neighbours on the same 128
+//! byte pair still interfere. Some aarch64 cores (e.g Apple's) have 128 byte
+//! lines outright.
+//!
+//! Padding isn't free. It multiplies the size of small values many times over,
+//! which costs cache capacity, so it is only worth it for data that is written
+//! often by different threads.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+#[cfg_attr(
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    ),
+    repr(align(128))
+)]
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    )),
+    repr(align(64))
+)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    pub const fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachePadded")
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_cache_padded_no_shared_lines() {
+        let counters: [CachePadded<AtomicUsize>; 2] = Default::default();
+
+        assert!(align_of::<CachePadded<AtomicUsize>>() >= 64);
+        let a = &*counters[0] as *const AtomicUsize as usize;
+        let b = &*counters[1] as *const AtomicUsize as usize;
+        assert!(b - a >= align_of::<CachePadded<AtomicUsize>>());
+    }
+}
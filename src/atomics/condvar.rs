@@ -0,0 +1,125 @@
+//! A condition variable over the crate's own `Mutex`, built on `futex`.
+//!
+//! The condvar is just a notification counter. A waiter reads the counter
+//! while still holding the mutex, unlocks, and sleeps only if the counter is
+//! unchanged. A notifier bumps the counter after updating the shared state
+//! (under the mutex), so a notification sent after the waiter read the counter
+//! always changes it and is never lost, whether it lands before or after the
+//! waiter falls asleep.
+//!
+//! The counter could in theory wrap all the way around (2^32 notifications)
+//! between the read and the sleep, making a waiter miss a wakeup. In practice
+//! that can't happen in the handful of instructions in between.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::futex;
+use super::{LockResult, MutexGuard};
+
+pub struct Condvar {
+    counter: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            counter: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlocks the guard's mutex and blocks until notified, then locks it
+    /// again.
+    ///
+    /// May return without a notification (a spurious wakeup), so the condition
+    /// being waited for must be re-checked, see `wait_while`.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        // Read before unlocking: any notification from here on is for us.
+        let counter = self.counter.load(Ordering::Relaxed);
+
+        let mutex = guard.mutex;
+        drop(guard);
+
+        futex::wait(&self.counter, counter);
+
+        mutex.lock()
+    }
+
+    /// Blocks while `condition` holds, absorbing spurious wakeups.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        while condition(&mut guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    pub fn notify_one(&self) {
+        // `Relaxed` is enough: the mutex orders the waiter's read of the
+        // shared state with the notifier's update of it.
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        futex::wake_one(&self.counter);
+    }
+
+    pub fn notify_all(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        futex::wake_all(&self.counter);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atomics::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_condvar_notify_one() {
+        let queue = Mutex::new(Vec::new());
+        let not_empty = Condvar::new();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                queue.lock().unwrap().push(42);
+                not_empty.notify_one();
+            });
+
+            let mut q = not_empty
+                .wait_while(queue.lock().unwrap(), |q| q.is_empty())
+                .unwrap();
+            assert_eq!(q.pop(), Some(42));
+        });
+    }
+
+    #[test]
+    fn test_condvar_notify_all() {
+        let ready = Mutex::new(false);
+        let cvar = Condvar::new();
+        let woken = Mutex::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    drop(cvar.wait_while(ready.lock().unwrap(), |r| !*r).unwrap());
+                    *woken.lock().unwrap() += 1;
+                });
+            }
+
+            thread::sleep(Duration::from_millis(20));
+            *ready.lock().unwrap() = true;
+            cvar.notify_all();
+        });
+
+        assert_eq!(woken.into_inner().unwrap(), 4);
+    }
+}
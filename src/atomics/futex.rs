@@ -0,0 +1,218 @@
+//! Blocking on the value of an atomic, the building block for locks that park
+//! waiting threads instead of spinning.
+//!
+//! On Linux this is the `futex` syscall: `wait` puts the thread to sleep only
+//! if the atomic still holds the expected value, checked atomically by the
+//! kernel, and `wake_*` wakes threads sleeping on the atomic's address.
+//! Elsewhere the same contract is emulated with `thread::park` and a global
+//! table of parked threads.
+//!
+//! Both implementations allow spurious wakeups, so callers always re-check the
+//! atomic in a loop.
+
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+/// Blocks the current thread while `atomic` holds `expected`.
+///
+/// Returns immediately if the value differs, and may return spuriously.
+pub(crate) fn wait(atomic: &AtomicU32, expected: u32) {
+    imp::wait(atomic, expected, None)
+}
+
+/// Like `wait`, but gives up after `timeout`. Callers re-check the atomic
+/// and the clock either way, so this doesn't report which one happened.
+pub(crate) fn wait_timeout(atomic: &AtomicU32, expected: u32, timeout: Duration) {
+    imp::wait(atomic, expected, Some(timeout))
+}
+
+/// Wakes at most one thread blocked in `wait` on `atomic`.
+pub(crate) fn wake_one(atomic: &AtomicU32) {
+    imp::wake(atomic, 1)
+}
+
+/// Wakes every thread blocked in `wait` on `atomic`.
+pub(crate) fn wake_all(atomic: &AtomicU32) {
+    imp::wake(atomic, i32::MAX)
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "arm"
+    )
+))]
+mod imp {
+    use std::ffi::c_long;
+    use std::sync::atomic::AtomicU32;
+    use std::time::Duration;
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_FUTEX: c_long = 202;
+    #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+    const SYS_FUTEX: c_long = 240;
+    #[cfg(target_arch = "aarch64")]
+    const SYS_FUTEX: c_long = 98;
+
+    const FUTEX_WAIT: i32 = 0;
+    const FUTEX_WAKE: i32 = 1;
+    /// The futex is only used by this process, which lets the kernel skip
+    /// looking up shared mappings.
+    const FUTEX_PRIVATE_FLAG: i32 = 128;
+
+    /// `struct timespec` as the `futex` syscall takes it: both fields are
+    /// `long` on the architectures above.
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+
+    unsafe extern "C" {
+        // Provided by libc, which std already links against.
+        fn syscall(num: c_long, ...) -> c_long;
+    }
+
+    pub(super) fn wait(atomic: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+        // `FUTEX_WAIT` takes a relative timeout. Saturate instead of
+        // overflowing `tv_sec`, which would be a wait in the past.
+        let timespec = timeout.map(|t| Timespec {
+            tv_sec: t.as_secs().try_into().unwrap_or(c_long::MAX),
+            tv_nsec: t.subsec_nanos() as c_long,
+        });
+        let timespec_ptr = timespec
+            .as_ref()
+            .map_or(std::ptr::null(), |t| t as *const Timespec);
+
+        // SAFETY: `atomic` is a valid, aligned `u32` for the duration of the
+        // call, and `timespec_ptr` is null (wait indefinitely) or points to a
+        // live `Timespec`. An `EAGAIN` (value changed), `EINTR` (signal) or
+        // `ETIMEDOUT` error is just an early return.
+        unsafe {
+            syscall(
+                SYS_FUTEX,
+                atomic.as_ptr(),
+                FUTEX_WAIT | FUTEX_PRIVATE_FLAG,
+                expected,
+                timespec_ptr,
+            );
+        }
+    }
+
+    pub(super) fn wake(atomic: &AtomicU32, count: i32) {
+        // SAFETY: `FUTEX_WAKE` only uses the address of `atomic` as a key.
+        unsafe {
+            syscall(
+                SYS_FUTEX,
+                atomic.as_ptr(),
+                FUTEX_WAKE | FUTEX_PRIVATE_FLAG,
+                count,
+            );
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "x86",
+        target_arch = "aarch64",
+        target_arch = "arm"
+    )
+)))]
+mod imp {
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+
+    /// Parked threads keyed by the address of the atomic they wait on. The
+    /// table lock plays the role of the kernel's futex bucket lock: checking
+    /// the value and registering happen under it, and so does waking, so a
+    /// wake can't slip in between the check and the park.
+    static PARKED: Mutex<Vec<(usize, Thread)>> = Mutex::new(Vec::new());
+
+    pub(super) fn wait(atomic: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+        let key = atomic.as_ptr() as usize;
+        let me = thread::current();
+
+        {
+            let mut parked = PARKED.lock().unwrap();
+            if atomic.load(Ordering::Relaxed) != expected {
+                return;
+            }
+            parked.push((key, me.clone()));
+        }
+
+        // An `unpark` issued before this point makes `park` return at once.
+        match timeout {
+            Some(timeout) => thread::park_timeout(timeout),
+            None => thread::park(),
+        }
+
+        // Deregister in case this was a spurious wakeup or a timeout.
+        PARKED
+            .lock()
+            .unwrap()
+            .retain(|(k, t)| !(*k == key && t.id() == me.id()));
+    }
+
+    pub(super) fn wake(atomic: &AtomicU32, count: i32) {
+        let key = atomic.as_ptr() as usize;
+        let mut parked = PARKED.lock().unwrap();
+
+        let mut woken = 0;
+        parked.retain(|(k, t)| {
+            if *k == key && woken < count {
+                woken += 1;
+                t.unpark();
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_futex_wait_wake() {
+        let state = AtomicU32::new(0);
+
+        // Value already differs, so this must not block.
+        wait(&state, 1);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                while state.load(Ordering::Acquire) == 0 {
+                    wait(&state, 0);
+                }
+            });
+
+            thread::sleep(Duration::from_millis(20));
+            state.store(1, Ordering::Release);
+            wake_all(&state);
+        });
+    }
+
+    #[test]
+    fn test_futex_wait_timeout() {
+        let state = AtomicU32::new(0);
+
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_millis(20) {
+            wait_timeout(&state, 0, Duration::from_millis(5));
+        }
+        assert_eq!(state.load(Ordering::Relaxed), 0);
+    }
+}
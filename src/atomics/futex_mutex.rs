@@ -0,0 +1,289 @@
+//! A mutex that puts waiting threads to sleep, using the classic three-state
+//! futex design from Ulrich Drepper's "Futexes Are Tricky".
+//!
+//! The state is one of:
+//!
+//!  - `UNLOCKED` (0)
+//!  - `LOCKED` (1): held, and nobody is waiting
+//!  - `CONTENDED` (2): held, and there may be threads blocked in `futex::wait`
+//!
+//! Tracking `CONTENDED` separately lets an uncontended `unlock` skip the wake
+//! syscall entirely, so the fast path is a single CAS to lock and a single swap
+//! to unlock, same as the spin lock.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use super::futex;
+use super::lock_order::LockId;
+use super::{Backoff, TryLockError, TryLockResult};
+use crate::cell::SyncUnsafeCell;
+
+pub struct FutexMutex<T> {
+    state: AtomicU32,
+    v: SyncUnsafeCell<T>,
+    /// See `lock_order`.
+    id: LockId,
+}
+
+// SAFETY: Same as the spin `Mutex`: access to the value is serialized by
+// `state`, and the value may be moved by whichever thread holds the lock.
+unsafe impl<T: Send> Sync for FutexMutex<T> {}
+
+impl<T> FutexMutex<T> {
+    const UNLOCKED: u32 = 0;
+    const LOCKED: u32 = 1;
+    const CONTENDED: u32 = 2;
+
+    pub const fn new(val: T) -> Self {
+        Self {
+            state: AtomicU32::new(Self::UNLOCKED),
+            v: SyncUnsafeCell::new(val),
+            id: LockId::new(),
+        }
+    }
+
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// Acquires the lock, blocking the thread while it is held elsewhere.
+    #[track_caller]
+    pub fn lock(&self) -> FutexMutexGuard<'_, T> {
+        self.id.check();
+
+        if self
+            .state
+            .compare_exchange(
+                Self::UNLOCKED,
+                Self::LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            self.lock_contended(None);
+        }
+
+        FutexMutexGuard::new(self)
+    }
+
+    /// Like `lock`, but gives up with `WouldBlock` if the lock couldn't be
+    /// acquired within `timeout`.
+    #[track_caller]
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<FutexMutexGuard<'_, T>> {
+        // A timeout too large to represent is as good as no timeout.
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => Ok(self.lock()),
+        }
+    }
+
+    /// Like `lock`, but gives up with `WouldBlock` if the lock couldn't be
+    /// acquired by `deadline`.
+    #[track_caller]
+    pub fn try_lock_until(&self, deadline: Instant) -> TryLockResult<FutexMutexGuard<'_, T>> {
+        // It may wait until the deadline, which is a deadlock as good as any
+        // if the deadline is far off.
+        self.id.check();
+
+        if let Ok(guard) = self.try_lock() {
+            return Ok(guard);
+        }
+
+        if self.lock_contended(Some(deadline)) {
+            Ok(FutexMutexGuard::new(self))
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Returns whether the lock was acquired, which is always the case without
+    /// a `deadline`.
+    #[cold]
+    fn lock_contended(&self, deadline: Option<Instant>) -> bool {
+        // Critical sections are often short, so spin for a bit before paying
+        // for two syscalls (sleep and wake). Only while `LOCKED`: if it is
+        // `CONTENDED` others are already sleeping and we'd just be late.
+        let mut backoff = Backoff::new();
+        while self.state.load(Ordering::Relaxed) == Self::LOCKED && !backoff.is_completed() {
+            backoff.snooze();
+        }
+
+        if self
+            .state
+            .compare_exchange(
+                Self::UNLOCKED,
+                Self::LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            return true;
+        }
+
+        // From here on we take the lock as `CONTENDED` even if nobody else is
+        // waiting, since we can't tell whether we were the only sleeper. The
+        // cost is at most one unnecessary wake on unlock.
+        //
+        // `swap` returning `UNLOCKED` means we acquired it; otherwise we've
+        // marked it contended, so the holder will wake someone on unlock, and
+        // sleeping on `CONTENDED` can't miss that wake.
+        while self.state.swap(Self::CONTENDED, Ordering::Acquire) != Self::UNLOCKED {
+            match deadline {
+                None => futex::wait(&self.state, Self::CONTENDED),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        // Giving up leaves the lock marked `CONTENDED` even
+                        // if we were the last waiter. Like above, that only
+                        // costs the holder one unnecessary wake.
+                        return false;
+                    }
+                    futex::wait_timeout(&self.state, Self::CONTENDED, deadline - now);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Acquires the lock only if it is currently free, without blocking.
+    ///
+    /// `FutexMutex` doesn't poison, so this only ever fails with `WouldBlock`.
+    pub fn try_lock(&self) -> TryLockResult<FutexMutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(
+                Self::UNLOCKED,
+                Self::LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .map(|_| FutexMutexGuard::new(self))
+            .map_err(|_| TryLockError::WouldBlock)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+}
+
+pub struct FutexMutexGuard<'a, T> {
+    mutex: &'a FutexMutex<T>,
+}
+
+impl<'a, T> FutexMutexGuard<'a, T> {
+    /// Takes over the lock, which the caller has just acquired.
+    fn new(mutex: &'a FutexMutex<T>) -> Self {
+        mutex.id.acquired();
+        Self { mutex }
+    }
+}
+
+// SAFETY: See `MutexGuard`, sharing the guard hands out `&T`.
+unsafe impl<T: Sync> Sync for FutexMutexGuard<'_, T> {}
+
+impl<T> Deref for FutexMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The guard only exists while the lock is held.
+        unsafe { &*self.mutex.v.get() }
+    }
+}
+
+impl<T> DerefMut for FutexMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The guard only exists while the lock is held.
+        unsafe { &mut *self.mutex.v.get() }
+    }
+}
+
+impl<T> Drop for FutexMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.id.released();
+
+        // `Release` publishes the critical section to the next `Acquire`. Only
+        // a `CONTENDED` lock can have sleepers, so only then is a syscall
+        // needed.
+        if self
+            .mutex
+            .state
+            .swap(FutexMutex::<T>::UNLOCKED, Ordering::Release)
+            == FutexMutex::<T>::CONTENDED
+        {
+            futex::wake_one(&self.mutex.state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_futex_mutex_counter() {
+        let mu = FutexMutex::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *mu.lock() += 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(mu.into_inner(), 8 * 1000);
+    }
+
+    #[test]
+    fn test_futex_mutex_long_critical_section() {
+        let mu = FutexMutex::new(Vec::new());
+
+        thread::scope(|s| {
+            let guard = mu.lock();
+
+            for i in 0..4 {
+                let mu = &mu;
+                // These run out of backoff and go to sleep on the futex.
+                s.spawn(move || mu.lock().push(i));
+            }
+
+            thread::sleep(Duration::from_millis(50));
+            assert!(matches!(mu.try_lock(), Err(TryLockError::WouldBlock)));
+            drop(guard);
+        });
+
+        assert_eq!(mu.lock().len(), 4);
+    }
+
+    #[test]
+    fn test_futex_mutex_try_lock_for() {
+        let mu = FutexMutex::new(0);
+
+        thread::scope(|s| {
+            let guard = mu.lock();
+
+            s.spawn(|| {
+                let start = Instant::now();
+                let result = mu.try_lock_for(Duration::from_millis(30));
+                assert!(matches!(result, Err(TryLockError::WouldBlock)));
+                assert!(start.elapsed() >= Duration::from_millis(30));
+
+                // Released well within this deadline.
+                let deadline = Instant::now() + Duration::from_secs(10);
+                *mu.try_lock_until(deadline).ok().unwrap() += 1;
+            });
+
+            thread::sleep(Duration::from_millis(60));
+            drop(guard);
+        });
+
+        assert_eq!(mu.into_inner(), 1);
+    }
+}
@@ -0,0 +1,127 @@
+//! A value initialized on first access from any thread, the `Sync` counterpart
+//! of `cell::LazyCell`. Usable in `static`s, since `new` is `const`.
+
+use std::ops::Deref;
+
+use super::OnceLock;
+use crate::cell::SyncUnsafeCell;
+
+pub struct LazyLock<T, F = fn() -> T> {
+    cell: OnceLock<T>,
+    /// Only accessed from inside the `OnceLock` initializer, which runs on one
+    /// thread at a time. `None` once taken, which poisons the `LazyLock` if
+    /// the initializer panics.
+    init: SyncUnsafeCell<Option<F>>,
+}
+
+// SAFETY: The value is shared like in `OnceLock`. `F` is never shared, only
+// moved to (and called on) whichever thread initializes, so it needs `Send`
+// rather than the `Sync` the auto-derived impl would ask for.
+unsafe impl<T: Send + Sync, F: Send> Sync for LazyLock<T, F> {}
+
+impl<T, F> LazyLock<T, F>
+where
+    F: FnOnce() -> T,
+{
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceLock::new(),
+            init: SyncUnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Forces initialization, returning a reference to the value. Threads
+    /// racing here block until the single initializer is done.
+    ///
+    /// Takes `this` instead of `self` so it is not confused with a method on
+    /// `T` through `Deref`.
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| {
+            // SAFETY: Initializers of the `OnceLock` never run concurrently.
+            match unsafe { (*this.init.get()).take() } {
+                Some(init) => init(),
+                None => panic!("LazyLock initializer panicked previously"),
+            }
+        })
+    }
+
+    /// Returns the value if it's initialized, or the initializer otherwise.
+    ///
+    /// Panics if the `LazyLock` is poisoned.
+    pub fn into_inner(this: Self) -> Result<T, F> {
+        match this.cell.into_inner() {
+            Some(value) => Ok(value),
+            None => Err(this
+                .init
+                .into_inner()
+                .expect("LazyLock initializer panicked previously")),
+        }
+    }
+}
+
+impl<T, F> Deref for LazyLock<T, F>
+where
+    F: FnOnce() -> T,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        LazyLock::force(self)
+    }
+}
+
+impl<T: Default> Default for LazyLock<T> {
+    fn default() -> Self {
+        LazyLock::new(T::default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    static LAZY: LazyLock<Vec<usize>> = LazyLock::new(|| {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(20));
+        (0..100).collect()
+    });
+
+    #[test]
+    // Using MIRI
+    fn test_lazy_lock_race() {
+        thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| assert_eq!(LAZY.len(), 100));
+            }
+        });
+
+        assert_eq!(*LazyLock::force(&LAZY).last().unwrap(), 99);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_lazy_lock_into_inner() {
+        let lazy: LazyLock<i32> = LazyLock::new(|| 42);
+        let init = LazyLock::into_inner(lazy).unwrap_err();
+        assert_eq!(init(), 42);
+
+        let lazy: LazyLock<i32> = LazyLock::new(|| 42);
+        assert_eq!(*lazy, 42);
+        assert_eq!(LazyLock::into_inner(lazy).ok(), Some(42));
+    }
+
+    #[test]
+    fn test_lazy_lock_poisoned() {
+        let lazy: LazyLock<i32> = LazyLock::new(|| panic!("boom"));
+
+        for _ in 0..2 {
+            let result = thread::scope(|s| s.spawn(|| *lazy).join());
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Lock-order checking for the crate's locks, enabled by the
+//! `debug_lock_order` feature.
+//!
+//! Two threads that take the same two locks in opposite orders (A then B, and B
+//! then A) can each end up holding one lock while waiting forever for the other.
+//! Whether that happens depends on timing, so tests can pass for a long time
+//! before it does. Instead of waiting for the unlucky interleaving, every
+//! blocking acquisition records "held before" pairs against the locks the
+//! thread already holds, and panics as soon as a pair shows up the other way
+//! around, even if the two orders were observed at different times.
+//!
+//! Only pairs are tracked, so a cycle through three or more locks (A→B, B→C,
+//! C→A) goes unnoticed.
+//!
+//! Locks are identified by an id handed out on first use rather than by their
+//! address, since a dropped lock's address can be reused by an unrelated one.
+//! Without the feature `LockId` is zero-sized and every hook is a no-op.
+
+pub(crate) use imp::LockId;
+
+#[cfg(feature = "debug_lock_order")]
+mod imp {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::panic::Location;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, PoisonError};
+
+    /// Ids start at 1, 0 marks a lock that hasn't been used yet.
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+    /// `(a, b)` maps to where `b` was first acquired while holding `a`.
+    ///
+    /// Entries are never removed: ids aren't reused, so a stale entry for a
+    /// dropped lock is just never looked up again.
+    static ORDER: Mutex<BTreeMap<(usize, usize), &'static Location<'static>>> =
+        Mutex::new(BTreeMap::new());
+
+    thread_local! {
+        /// Ids of the locks held by this thread, in acquisition order.
+        static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(crate) struct LockId {
+        id: AtomicUsize,
+    }
+
+    impl LockId {
+        pub(crate) const fn new() -> Self {
+            Self {
+                id: AtomicUsize::new(0),
+            }
+        }
+
+        fn get(&self) -> usize {
+            let id = self.id.load(Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+
+            // Racing first users agree on whichever id is stored first, the
+            // other one is just skipped.
+            let new = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            match self
+                .id
+                .compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => new,
+                Err(id) => id,
+            }
+        }
+
+        /// Called before blocking on the lock, panics if it is being taken in
+        /// the opposite order to some earlier acquisition.
+        ///
+        /// Non-blocking acquisitions skip this: a `try_lock` that fails can't
+        /// deadlock.
+        #[track_caller]
+        pub(crate) fn check(&self) {
+            let id = self.get();
+            let caller = Location::caller();
+
+            let inversion = HELD.with_borrow(|held| {
+                let mut order = ORDER.lock().unwrap_or_else(PoisonError::into_inner);
+                for &h in held.iter().filter(|&&h| h != id) {
+                    if let Some(&at) = order.get(&(id, h)) {
+                        return Some((h, at));
+                    }
+                    order.entry((h, id)).or_insert(caller);
+                }
+                None
+            });
+
+            if let Some((h, at)) = inversion {
+                panic!(
+                    "lock order inversion: lock #{id} acquired at {caller} while holding lock \
+                     #{h}, but lock #{h} was acquired while holding lock #{id} at {at}"
+                );
+            }
+        }
+
+        /// Called once the lock is held, however it was acquired.
+        pub(crate) fn acquired(&self) {
+            let id = self.get();
+            HELD.with_borrow_mut(|held| held.push(id));
+        }
+
+        /// Called when a guard releases the lock.
+        pub(crate) fn released(&self) {
+            let id = self.get();
+            // A guard sent to and dropped on another thread isn't on that
+            // thread's stack, and this thread's stack may already be gone if
+            // the guard is dropped by a thread-local destructor.
+            let _ = HELD.try_with(|held| {
+                let mut held = held.borrow_mut();
+                if let Some(i) = held.iter().rposition(|&h| h == id) {
+                    held.remove(i);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(not(feature = "debug_lock_order"))]
+mod imp {
+    pub(crate) struct LockId;
+
+    impl LockId {
+        pub(crate) const fn new() -> Self {
+            Self
+        }
+
+        #[inline]
+        pub(crate) fn check(&self) {}
+
+        #[inline]
+        pub(crate) fn acquired(&self) {}
+
+        #[inline]
+        pub(crate) fn released(&self) {}
+    }
+}
+
+#[cfg(all(test, feature = "debug_lock_order"))]
+mod tests {
+    use crate::atomics::{FutexMutex, Mutex, RwLock};
+
+    #[test]
+    fn test_lock_order_consistent() {
+        let a = Mutex::new(0);
+        let b = RwLock::new(0);
+
+        for _ in 0..2 {
+            let _a = a.lock().unwrap();
+            let _b = b.read();
+            // Re-entering a read lock is not an ordering problem.
+            let _b2 = b.read();
+        }
+
+        // Taking either one alone is always fine.
+        drop(b.write());
+        drop(a.lock().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion")]
+    fn test_lock_order_inversion() {
+        let a = Mutex::new(0);
+        let b = Mutex::new(0);
+
+        {
+            let _a = a.lock().unwrap();
+            let _b = b.lock().unwrap();
+        }
+
+        // Never deadlocks on its own, but would with the block above running
+        // on another thread.
+        let _b = b.lock().unwrap();
+        let _a = a.lock().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion")]
+    fn test_lock_order_inversion_across_threads() {
+        let a = FutexMutex::new(0);
+        let b = RwLock::new(0);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _a = a.lock();
+                let _b = b.read();
+            });
+        });
+
+        let _b = b.write();
+        let _a = a.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion")]
+    fn test_lock_order_inversion_timed() {
+        let a = FutexMutex::new(0);
+        let b = FutexMutex::new(0);
+
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+
+        // A timed acquisition blocks too, until its deadline.
+        let _b = b.lock();
+        let _a = a.try_lock_for(std::time::Duration::from_secs(1));
+    }
+}
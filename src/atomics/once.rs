@@ -0,0 +1,241 @@
+//! Running an initialization routine exactly once, across threads.
+//!
+//! The state moves through:
+//!
+//!  - `INCOMPLETE` -> `RUNNING`: one thread won the race to initialize
+//!  - `RUNNING` -> `COMPLETE`: initialization returned
+//!  - `RUNNING` -> `POISONED`: initialization panicked, and can be retried
+//!
+//! On top of that, the `QUEUED` bit is set by threads that go to sleep on the
+//! futex, so whoever leaves `RUNNING` knows whether it has to make a wake
+//! syscall. It is set in any state other than `COMPLETE` (e.g, `OnceLock::wait`
+//! sleeps before anyone started running), so it's carried over into
+//! `RUNNING`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::futex;
+
+const INCOMPLETE: u32 = 0;
+const POISONED: u32 = 1;
+const RUNNING: u32 = 2;
+const COMPLETE: u32 = 3;
+/// Flag bit, set when there may be threads sleeping on the state.
+const QUEUED: u32 = 4;
+
+pub struct Once {
+    state: AtomicU32,
+}
+
+/// Passed to the closure of `call_once_force`.
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// Whether a previous initialization attempt panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    /// Runs `f` if no call has completed yet. Concurrent callers block until
+    /// the running call finishes, and all writes made by `f` are visible to
+    /// every caller once it returns.
+    ///
+    /// Panics if a previous `f` panicked (the `Once` is poisoned).
+    pub fn call_once(&self, f: impl FnOnce()) {
+        // Fast path, without creating the closure below.
+        if self.is_completed() {
+            return;
+        }
+
+        let mut f = Some(f);
+        self.call(false, &mut |_| f.take().unwrap()());
+    }
+
+    /// Like `call_once`, but also runs `f` if the `Once` is poisoned, letting
+    /// it retry a failed initialization.
+    pub fn call_once_force(&self, f: impl FnOnce(&OnceState)) {
+        if self.is_completed() {
+            return;
+        }
+
+        let mut f = Some(f);
+        self.call(true, &mut |state| f.take().unwrap()(state));
+    }
+
+    pub fn is_completed(&self) -> bool {
+        // `Acquire` pairs with the `Release` that set `COMPLETE`, so a `true`
+        // here comes with the writes of the initializer.
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Blocks until a call has completed, without running anything itself.
+    pub(crate) fn wait(&self, ignore_poison: bool) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            match state & !QUEUED {
+                COMPLETE => return,
+                POISONED if !ignore_poison => panic!("Once instance has previously been poisoned"),
+                _ => state = self.sleep(state),
+            }
+        }
+    }
+
+    /// Not generic over the closure so the slow path is only compiled once.
+    #[cold]
+    fn call(&self, ignore_poison: bool, f: &mut dyn FnMut(&OnceState)) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            match state & !QUEUED {
+                COMPLETE => return,
+                POISONED if !ignore_poison => panic!("Once instance has previously been poisoned"),
+                base @ (INCOMPLETE | POISONED) => {
+                    if let Err(actual) = self.state.compare_exchange_weak(
+                        state,
+                        RUNNING | (state & QUEUED),
+                        Ordering::Acquire,
+                        Ordering::Acquire,
+                    ) {
+                        state = actual;
+                        continue;
+                    }
+
+                    // Poisons the `Once` if `f` unwinds, so waiters don't block
+                    // forever.
+                    let mut guard = CompletionGuard {
+                        state: &self.state,
+                        set_state_on_drop_to: POISONED,
+                    };
+                    f(&OnceState {
+                        poisoned: base == POISONED,
+                    });
+                    guard.set_state_on_drop_to = COMPLETE;
+                    return;
+                }
+                _ => state = self.sleep(state),
+            }
+        }
+    }
+
+    /// Sleeps until the state changes from `state`, returns the new state.
+    fn sleep(&self, state: u32) -> u32 {
+        // Tell whoever changes the state next that there's someone to wake.
+        if state & QUEUED == 0
+            && let Err(actual) = self.state.compare_exchange_weak(
+                state,
+                state | QUEUED,
+                Ordering::Relaxed,
+                Ordering::Acquire,
+            )
+        {
+            return actual;
+        }
+
+        futex::wait(&self.state, state | QUEUED);
+        self.state.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct CompletionGuard<'a> {
+    state: &'a AtomicU32,
+    set_state_on_drop_to: u32,
+}
+
+impl Drop for CompletionGuard<'_> {
+    fn drop(&mut self) {
+        // `Release` publishes the initializer's writes to every `Acquire` load
+        // that sees `COMPLETE`.
+        if self
+            .state
+            .swap(self.set_state_on_drop_to, Ordering::Release)
+            & QUEUED
+            != 0
+        {
+            futex::wake_all(self.state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_once_runs_once() {
+        static ONCE: Once = Once::new();
+        static mut VALUE: usize = 0;
+        let calls = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    ONCE.call_once(|| {
+                        thread::sleep(Duration::from_millis(20));
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        // SAFETY: Only the single initializer writes, and every
+                        // read happens after `call_once` returns.
+                        unsafe { VALUE = 42 };
+                    });
+
+                    // SAFETY: See above.
+                    assert_eq!(unsafe { VALUE }, 42);
+                });
+            }
+        });
+
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(ONCE.is_completed());
+    }
+
+    #[test]
+    fn test_once_wait() {
+        let once = Once::new();
+
+        thread::scope(|s| {
+            // Sleeps before anyone started running the initializer.
+            let waiter = s.spawn(|| once.wait(false));
+
+            thread::sleep(Duration::from_millis(20));
+            once.call_once(|| {});
+            waiter.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_once_poison() {
+        let once = Once::new();
+
+        let result = thread::scope(|s| s.spawn(|| once.call_once(|| panic!())).join());
+        assert!(result.is_err());
+        assert!(!once.is_completed());
+
+        let result = thread::scope(|s| s.spawn(|| once.call_once(|| {})).join());
+        assert!(result.is_err());
+
+        let mut retried = false;
+        once.call_once_force(|state| {
+            assert!(state.is_poisoned());
+            retried = true;
+        });
+        assert!(retried);
+        assert!(once.is_completed());
+    }
+}
@@ -0,0 +1,202 @@
+//! A thread-safe cell that can be written to only once, the `Sync` counterpart
+//! of `cell::OnceCell`.
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+
+use super::Once;
+
+pub struct OnceLock<T> {
+    once: Once,
+    /// Initialized once `once` has completed, and never written to after.
+    value: UnsafeCell<MaybeUninit<T>>,
+    /// `MaybeUninit` doesn't own a `T` as far as dropck is concerned, but the
+    /// `Drop` impl below may drop one.
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: `get` hands out `&T` to several threads, so `T: Sync`. A `T` created
+// on one thread can be dropped on another (whichever drops the `OnceLock`), so
+// `T: Send`.
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    pub const fn new() -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            // SAFETY: Completed, so the value was written, and `is_completed`
+            // synchronized with the write.
+            Some(unsafe { self.get_unchecked() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.once.is_completed() {
+            // SAFETY: Completed, and `&mut self` rules out other references.
+            Some(unsafe { (*self.value.get()).assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    /// Stores `value` if the cell is empty, otherwise hands it back.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// Returns the value, initializing it with `f` if the cell is empty.
+    ///
+    /// If several threads race, exactly one runs its `f` and the others block
+    /// until it is done. If `f` panics, the panic propagates and the cell stays
+    /// empty, so a later call can try again.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+
+        // `force`, so a panicked initializer doesn't make the cell unusable.
+        self.once.call_once_force(|_| {
+            // SAFETY: The `Once` guarantees only one initializer runs, and
+            // nobody reads the value before it completes.
+            unsafe { (*self.value.get()).write(f()) };
+        });
+
+        // SAFETY: `call_once_force` only returns once the value is written.
+        unsafe { self.get_unchecked() }
+    }
+
+    /// Blocks until another thread has initialized the cell.
+    pub fn wait(&self) -> &T {
+        self.once.wait(true);
+        // SAFETY: `wait` only returns once the value is written.
+        unsafe { self.get_unchecked() }
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+
+    /// Empties the cell, returning the value if there was one.
+    pub fn take(&mut self) -> Option<T> {
+        if self.once.is_completed() {
+            self.once = Once::new();
+            // SAFETY: It was completed, so the value is initialized, and the
+            // fresh `Once` keeps it from being read or dropped again.
+            Some(unsafe { (*self.value.get()).assume_init_read() })
+        } else {
+            None
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The `Once` must have completed.
+    unsafe fn get_unchecked(&self) -> &T {
+        // SAFETY: Guaranteed by the caller.
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            // SAFETY: Completed, so the value is initialized.
+            unsafe { (*self.value.get()).assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_once_lock_get_or_init_race() {
+        let cell = OnceLock::new();
+        let calls = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (cell, calls) = (&cell, &calls);
+                s.spawn(move || {
+                    let value = cell.get_or_init(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        thread::sleep(Duration::from_millis(10));
+                        i
+                    });
+                    assert_eq!(cell.get(), Some(value));
+                });
+            }
+        });
+
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(cell.into_inner().is_some());
+    }
+
+    #[test]
+    fn test_once_lock_set_and_wait() {
+        let cell = OnceLock::new();
+        assert_eq!(cell.get(), None);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| *cell.wait());
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(cell.set(String::from("a").len()), Ok(()));
+            assert_eq!(waiter.join().unwrap(), 1);
+        });
+
+        assert_eq!(cell.set(2), Err(2));
+    }
+
+    #[test]
+    fn test_once_lock_init_panic_retry() {
+        let cell = OnceLock::new();
+
+        let result = thread::scope(|s| s.spawn(|| cell.get_or_init(|| panic!())).join().is_err());
+        assert!(result);
+        assert_eq!(cell.get(), None);
+
+        assert_eq!(*cell.get_or_init(|| 5), 5);
+    }
+
+    #[test]
+    fn test_once_lock_drops_value() {
+        let value = std::sync::Arc::new(());
+        let mut cell = OnceLock::new();
+        assert!(cell.get_mut().is_none());
+
+        assert!(cell.set(value.clone()).is_ok());
+        assert_eq!(std::sync::Arc::strong_count(&value), 2);
+
+        // `take` leaves the cell empty, so it's not dropped twice.
+        drop(cell.take());
+        cell.set(value.clone()).unwrap();
+        drop(cell);
+        assert_eq!(std::sync::Arc::strong_count(&value), 1);
+    }
+}
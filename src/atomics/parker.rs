@@ -0,0 +1,174 @@
+//! Thread parking as a standalone primitive: a `Parker` owned by the thread
+//! that blocks, and any number of `Unparker`s to wake it.
+//!
+//! Like `thread::park`, a wakeup is remembered as a token: `unpark` before
+//! `park` makes that `park` return immediately, so a waiter that checks a
+//! condition, finds it false and then parks can't miss a notification sent in
+//! between. The state is:
+//!
+//!  - `EMPTY`: no token, nobody parked
+//!  - `NOTIFIED`: a token is available
+//!  - `PARKED`: the owner is (about to be) sleeping on the futex
+//!
+//! `park` decrements (`NOTIFIED -> EMPTY` consumes the token, `EMPTY ->
+//! PARKED` goes to sleep) and `unpark` swaps in `NOTIFIED`, so each transition
+//! is a single atomic operation.
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::futex;
+
+const EMPTY: u32 = 0;
+const NOTIFIED: u32 = 1;
+/// `EMPTY - 1`, so `park` can get there with a decrement.
+const PARKED: u32 = u32::MAX;
+
+struct Inner {
+    state: AtomicU32,
+}
+
+/// Blocks the thread that owns it until an `Unparker` hands it a token.
+pub struct Parker {
+    inner: Arc<Inner>,
+    /// Only one thread may park at a time, so `Parker` isn't `Sync`.
+    _not_sync: PhantomData<std::cell::Cell<()>>,
+}
+
+/// Wakes the associated `Parker`, cheap to clone and send anywhere.
+#[derive(Clone)]
+pub struct Unparker {
+    inner: Arc<Inner>,
+}
+
+impl Parker {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: AtomicU32::new(EMPTY),
+            }),
+            _not_sync: PhantomData,
+        }
+    }
+
+    pub fn unparker(&self) -> Unparker {
+        Unparker {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Blocks until a token is available, then consumes it.
+    pub fn park(&self) {
+        let state = &self.inner.state;
+
+        // `Acquire` so whatever the unparker did before `unpark` is visible
+        // once we return.
+        if state.fetch_sub(1, Ordering::Acquire) == NOTIFIED {
+            return;
+        }
+
+        loop {
+            futex::wait(state, PARKED);
+
+            // Spurious wakeups leave the state `PARKED`, keep sleeping.
+            if state
+                .compare_exchange(NOTIFIED, EMPTY, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+}
+
+impl Default for Parker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Unparker {
+    /// Makes a token available, waking the `Parker` if it is parked. Tokens
+    /// don't accumulate: several `unpark`s before a `park` release it once.
+    pub fn unpark(&self) {
+        // `Release` pairs with the `Acquire` in `park`. Only make a syscall if
+        // the owner actually went to sleep.
+        if self.inner.state.swap(NOTIFIED, Ordering::Release) == PARKED {
+            futex::wake_one(&self.inner.state);
+        }
+    }
+}
+
+/// ```compile_fail
+/// use crust_of_rust::atomics::Parker;
+///
+/// fn require_sync<T: Sync>(_: T) {}
+///
+/// require_sync(Parker::new());
+/// ```
+fn assert_parker_non_sync() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parker_token_before_park() {
+        let parker = Parker::new();
+        let unparker = parker.unparker();
+
+        // Both are remembered as a single token.
+        unparker.unpark();
+        unparker.unpark();
+        parker.park();
+
+        assert_eq!(parker.inner.state.load(Ordering::Relaxed), EMPTY);
+    }
+
+    #[test]
+    fn test_parker_wakes_sleeping_thread() {
+        let parker = Parker::new();
+        let unparker = parker.unparker();
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                done.store(true, Ordering::Relaxed);
+                unparker.unpark();
+            });
+
+            while !done.load(Ordering::Relaxed) {
+                parker.park();
+            }
+        });
+    }
+
+    #[test]
+    fn test_parker_no_missed_wakeups() {
+        // Ping-pong: each side parks until the other hands it the next round.
+        // A single missed wakeup deadlocks the test.
+        const ROUNDS: usize = 2000;
+
+        let (ping, pong) = (Parker::new(), Parker::new());
+        let (wake_ping, wake_pong) = (ping.unparker(), pong.unparker());
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for _ in 0..ROUNDS {
+                    pong.park();
+                    wake_ping.unpark();
+                }
+            });
+
+            for _ in 0..ROUNDS {
+                wake_pong.unpark();
+                ping.park();
+            }
+        });
+    }
+}
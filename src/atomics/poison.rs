@@ -0,0 +1,134 @@
+//! Lock poisoning, mirroring `std::sync`.
+//!
+//! A lock is poisoned when a thread panics while holding it, since the panic
+//! may have left the protected data half-updated. Later lockers still get the
+//! guard, but wrapped in a `PoisonError` they have to handle explicitly.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
+
+/// The lock was acquired, but a previous holder panicked. The guard is still
+/// available through `into_inner` for callers that can repair the data.
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+impl<G> PoisonError<G> {
+    pub fn new(guard: G) -> Self {
+        Self { guard }
+    }
+
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+// Not derived, so it doesn't require `G: Debug` (guards rarely are).
+impl<G> fmt::Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+impl<G> std::error::Error for PoisonError<G> {}
+
+impl<G> fmt::Display for PoisonError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "poisoned lock: another task failed inside")
+    }
+}
+
+/// Returned by the `try_*` locking methods.
+pub enum TryLockError<G> {
+    Poisoned(PoisonError<G>),
+    /// The lock is held elsewhere, acquiring it would have blocked.
+    WouldBlock,
+}
+
+impl<G> From<PoisonError<G>> for TryLockError<G> {
+    fn from(err: PoisonError<G>) -> Self {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<G> fmt::Debug for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::Poisoned(err) => f.debug_tuple("Poisoned").field(err).finish(),
+            TryLockError::WouldBlock => f.write_str("WouldBlock"),
+        }
+    }
+}
+
+impl<G> std::error::Error for TryLockError<G> {}
+
+impl<G> fmt::Display for TryLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::Poisoned(err) => err.fmt(f),
+            TryLockError::WouldBlock => {
+                write!(f, "try_lock failed because the operation would block")
+            }
+        }
+    }
+}
+
+/// The poison bit of a lock.
+pub(crate) struct Flag {
+    failed: AtomicBool,
+}
+
+impl Flag {
+    pub(crate) const fn new() -> Self {
+        Self {
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Called right after acquiring the lock. `Relaxed` is enough since the
+    /// flag is only written while the lock is held, and acquiring the lock
+    /// already synchronized with the previous holder.
+    pub(crate) fn guard(&self) -> Result<Guard, Guard> {
+        let guard = Guard {
+            panicking: thread::panicking(),
+        };
+
+        if self.get() { Err(guard) } else { Ok(guard) }
+    }
+
+    /// Called right before releasing the lock, poisons it if the holder
+    /// started panicking while holding it.
+    pub(crate) fn done(&self, guard: &Guard) {
+        // A guard acquired during unwinding (e.g, in a `Drop` impl) doesn't
+        // poison the lock for a panic that was already in flight.
+        if !guard.panicking && thread::panicking() {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn get(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn clear(&self) {
+        self.failed.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Remembers whether the holder was already panicking when it took the lock.
+pub(crate) struct Guard {
+    panicking: bool,
+}
@@ -0,0 +1,248 @@
+//! A spinning reader-writer lock built on a single `AtomicUsize`.
+//!
+//! The top bit of the state marks a writer, the remaining bits count readers,
+//! so both kinds of lock are taken with one CAS on the same word.
+//!
+//! Readers never wait for a writer that is itself waiting, so a steady stream
+//! of readers can starve writers. Fixing that needs a "writer waiting" bit that
+//! new readers respect, at the cost of readers blocking behind a writer.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Backoff;
+use super::lock_order::LockId;
+use crate::cell::SyncUnsafeCell;
+
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    v: SyncUnsafeCell<T>,
+    /// See `lock_order`.
+    id: LockId,
+}
+
+// SAFETY: Readers on several threads share `&T`, so `T: Sync`. A writer gets
+// `&mut T` on any thread and may move the value out, so `T: Send`.
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    const WRITER: usize = !(usize::MAX >> 1);
+
+    pub const fn new(val: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            v: SyncUnsafeCell::new(val),
+            id: LockId::new(),
+        }
+    }
+
+    /// Acquires a shared lock, spinning while a writer holds the lock.
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.id.check();
+
+        let mut backoff = Backoff::new();
+        loop {
+            match self.try_read_state() {
+                Ok(()) => return RwLockReadGuard::new(self),
+                // Another reader got in between our load and CAS, so progress
+                // is being made: retry soon.
+                Err(state) if state & Self::WRITER == 0 => backoff.spin(),
+                // A writer holds the lock; wait for it.
+                Err(_) => backoff.snooze(),
+            }
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & Self::WRITER == 0 {
+            match self.add_reader(state) {
+                Ok(()) => return Some(RwLockReadGuard::new(self)),
+                Err(actual) => state = actual,
+            }
+        }
+        None
+    }
+
+    /// Acquires the exclusive lock, spinning until there are no readers or
+    /// writers.
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.id.check();
+
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+
+            // Spin on a load, as in `Mutex::lock`, to keep the cache line
+            // shared while waiting.
+            while self.state.load(Ordering::Relaxed) != 0 {
+                backoff.snooze();
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        // `Acquire` pairs with the `Release` in every guard's drop, so the
+        // writer sees the completed critical sections of everyone before it.
+        self.state
+            .compare_exchange(0, Self::WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard::new(self))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.v.into_inner()
+    }
+
+    fn try_read_state(&self) -> Result<(), usize> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & Self::WRITER != 0 {
+            return Err(state);
+        }
+        self.add_reader(state)
+    }
+
+    /// Registers a reader, if `state` (which has no writer) is still current.
+    fn add_reader(&self, state: usize) -> Result<(), usize> {
+        // All the other bits counting readers means guards are being leaked.
+        if state == Self::WRITER - 1 {
+            std::process::abort();
+        }
+
+        // `Acquire` on success so the reader sees the last writer's critical
+        // section.
+        self.state
+            .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| ())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> RwLockReadGuard<'a, T> {
+    /// Takes over a reader registered in `lock.state`.
+    fn new(lock: &'a RwLock<T>) -> Self {
+        lock.id.acquired();
+        Self { lock }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The reader count is non-zero while the guard lives, so no
+        // writer can get in.
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.id.released();
+        // `Release` so the next writer can't be reordered before our reads.
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> RwLockWriteGuard<'a, T> {
+    /// Takes over the writer bit set in `lock.state`.
+    fn new(lock: &'a RwLock<T>) -> Self {
+        lock.id.acquired();
+        Self { lock }
+    }
+}
+
+// SAFETY: See `MutexGuard`, sharing the guard hands out `&T`.
+unsafe impl<T: Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The writer bit is set while the guard lives.
+        unsafe { &*self.lock.v.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The writer bit is set while the guard lives.
+        unsafe { &mut *self.lock.v.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.id.released();
+        // `Release` publishes the writes to the next reader or writer.
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_rwlock_exclusion() {
+        let lock = RwLock::new(1);
+
+        {
+            let a = lock.read();
+            let b = lock.try_read().unwrap();
+            assert_eq!(*a + *b, 2);
+            assert!(lock.try_write().is_none());
+        }
+
+        {
+            let mut w = lock.write();
+            *w += 1;
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+        }
+
+        assert_eq!(lock.into_inner(), 2);
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_rwlock_no_torn_reads() {
+        let lock = RwLock::new([0u64; 16]);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1..=1000 {
+                    let mut w = lock.write();
+                    // Written one element at a time; a reader overlapping with
+                    // this loop would see a mix of `i - 1` and `i`.
+                    for x in w.iter_mut() {
+                        *x = i;
+                    }
+                }
+            });
+
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let r = lock.read();
+                        assert!(r.iter().all(|&x| x == r[0]));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(lock.into_inner(), [1000; 16]);
+    }
+}
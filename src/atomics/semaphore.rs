@@ -0,0 +1,196 @@
+//! A counting semaphore: at most `permits` holders at a time, with waiters
+//! sleeping on the permit count through `futex`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::futex;
+
+pub struct Semaphore {
+    permits: AtomicU32,
+    /// Threads that are (about to be) sleeping in `acquire`, so releasing a
+    /// permit only makes a wake syscall when somebody could be woken.
+    waiters: AtomicU32,
+}
+
+impl Semaphore {
+    pub const fn new(permits: u32) -> Self {
+        Self {
+            permits: AtomicU32::new(permits),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// Takes a permit, blocking until one is available. The permit is given
+    /// back when the returned guard is dropped.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_raw();
+        SemaphorePermit { sem: self }
+    }
+
+    /// Like `acquire`, but the permit keeps the semaphore alive instead of
+    /// borrowing it, so it can be moved into another thread or a task.
+    pub fn acquire_owned(self: &Arc<Self>) -> OwnedSemaphorePermit {
+        self.acquire_raw();
+        OwnedSemaphorePermit { sem: self.clone() }
+    }
+
+    /// Takes a permit only if one is available right now.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        // Not `then_some`: the permit would be built (and dropped, giving back
+        // a permit that was never taken) even on failure.
+        self.try_acquire_raw()
+            .then(|| SemaphorePermit { sem: self })
+    }
+
+    /// Adds `n` permits, e.g. to grow a pool, or to give back permits that
+    /// were `forget`-ed.
+    pub fn add_permits(&self, n: u32) {
+        // Releasing, paired with the `Acquire` in `try_acquire_raw`, so
+        // whatever the releasing holder did happens-before the next holder
+        // starts.
+        //
+        // And `SeqCst`, like the accesses to `waiters` and `permits` in
+        // `acquire_raw`: an access that isn't `SeqCst` takes no part in the
+        // single total order, so only with all four in it is the
+        // store-buffering outcome ruled out, where `acquire` reads the old
+        // permit count and we read the old waiter count, each missing the
+        // other's update. With it, either we see the waiter and wake it, or it
+        // sees the permit (and `futex::wait` returns right away).
+        self.permits.fetch_add(n, Ordering::SeqCst);
+
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            if n == 1 {
+                futex::wake_one(&self.permits);
+            } else {
+                futex::wake_all(&self.permits);
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> u32 {
+        self.permits.load(Ordering::Relaxed)
+    }
+
+    fn acquire_raw(&self) {
+        while !self.try_acquire_raw() {
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            // Only sleeps if there are still no permits, a permit added after
+            // the failed attempt makes this return immediately.
+            if self.permits.load(Ordering::SeqCst) == 0 {
+                futex::wait(&self.permits, 0);
+            }
+            self.waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn try_acquire_raw(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        while permits > 0 {
+            match self.permits.compare_exchange_weak(
+                permits,
+                permits - 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => permits = actual,
+            }
+        }
+        false
+    }
+}
+
+pub struct SemaphorePermit<'a> {
+    sem: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Drops the permit without giving it back, permanently shrinking the
+    /// semaphore by one.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.sem.add_permits(1);
+    }
+}
+
+pub struct OwnedSemaphorePermit {
+    sem: Arc<Semaphore>,
+}
+
+impl OwnedSemaphorePermit {
+    pub fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.sem
+    }
+}
+
+impl Drop for OwnedSemaphorePermit {
+    fn drop(&mut self) {
+        self.sem.add_permits(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_semaphore_limits_concurrency() {
+        let sem = Semaphore::new(3);
+        let active = AtomicU32::new(0);
+        let max_active = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| {
+                    let _permit = sem.acquire();
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert!(max_active.load(Ordering::SeqCst) <= 3);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn test_semaphore_try_acquire_and_forget() {
+        let sem = Semaphore::new(1);
+
+        let permit = sem.try_acquire().unwrap();
+        assert!(sem.try_acquire().is_none());
+        permit.forget();
+        assert!(sem.try_acquire().is_none());
+
+        sem.add_permits(2);
+        let _a = sem.try_acquire().unwrap();
+        let _b = sem.try_acquire().unwrap();
+        assert_eq!(sem.available_permits(), 0);
+    }
+
+    #[test]
+    fn test_semaphore_owned_permit() {
+        let sem = Arc::new(Semaphore::new(1));
+        let permit = sem.acquire_owned();
+
+        let waiter = {
+            let sem = sem.clone();
+            thread::spawn(move || drop(sem.acquire()))
+        };
+
+        thread::sleep(Duration::from_millis(20));
+        thread::spawn(move || drop(permit)).join().unwrap();
+        waiter.join().unwrap();
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
@@ -0,0 +1,137 @@
+//! Splitting data that would sit behind one lock across `N` locks.
+//!
+//! With a single `Mutex`, every thread serializes on the same lock (and the
+//! same cache line) even when they work on unrelated keys. `Sharded` keeps `N`
+//! independent values, each behind its own lock, and picks one by hashing the
+//! key, so threads only contend when their keys land in the same shard. This is
+//! how concurrent hash maps are commonly built: a `Sharded<HashMap<K, V>, N>`
+//! is already most of one.
+//!
+//! Each shard is `CachePadded`, otherwise neighbouring locks would share cache
+//! lines and threads working on different shards would still slow each other
+//! down (see `cache_padded`).
+//!
+//! Anything that needs to see all the data at once (a total length, an
+//! iteration) has to visit the shards one by one, and doesn't get a consistent
+//! snapshot across them.
+
+use std::fmt;
+use std::hash::{BuildHasher, Hash, RandomState};
+
+use super::{CachePadded, Mutex};
+
+pub struct Sharded<T, const N: usize> {
+    shards: [CachePadded<Mutex<T>>; N],
+    hasher: RandomState,
+}
+
+impl<T, const N: usize> Sharded<T, N> {
+    /// Creates the shards by calling `f` with the index of each.
+    pub fn from_fn(mut f: impl FnMut(usize) -> T) -> Self {
+        const { assert!(N > 0, "`Sharded` needs at least one shard") };
+
+        Self {
+            shards: std::array::from_fn(|i| CachePadded::new(Mutex::new(f(i)))),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Returns the index of the shard `key` belongs to.
+    ///
+    /// Stable for the lifetime of this `Sharded`, but differs between
+    /// instances: the hasher is randomly seeded, like `HashMap`'s.
+    pub fn shard_index<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        (self.hasher.hash_one(key) % N as u64) as usize
+    }
+
+    /// Runs `f` with the lock of `key`'s shard held.
+    ///
+    /// Panics if the shard's mutex is poisoned, like `Mutex::with_lock`.
+    #[track_caller]
+    pub fn with_shard<K: Hash + ?Sized, R>(&self, key: &K, f: impl FnOnce(&mut T) -> R) -> R {
+        self.shards[self.shard_index(key)].with_lock(f)
+    }
+
+    /// Runs `f` on each shard in turn, holding one lock at a time.
+    #[track_caller]
+    pub fn for_each_shard(&self, mut f: impl FnMut(&mut T)) {
+        for shard in &self.shards {
+            shard.with_lock(&mut f);
+        }
+    }
+
+    /// Panics if any shard's mutex is poisoned.
+    pub fn into_shards(self) -> [T; N] {
+        self.shards.map(|shard| {
+            shard
+                .into_inner()
+                .into_inner()
+                .expect("a shard's mutex was poisoned")
+        })
+    }
+}
+
+impl<T: Default, const N: usize> Sharded<T, N> {
+    pub fn new() -> Self {
+        Self::from_fn(|_| T::default())
+    }
+}
+
+impl<T: Default, const N: usize> Default for Sharded<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Sharded<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sharded")
+            .field("shards", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread;
+
+    #[test]
+    fn test_sharded_map() {
+        let map: Sharded<HashMap<u32, u32>, 8> = Sharded::new();
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for k in (t * 1000)..(t + 1) * 1000 {
+                        map.with_shard(&k, |shard| shard.insert(k, k * 2));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(
+            map.with_shard(&1234, |shard| shard.get(&1234).copied()),
+            Some(2468)
+        );
+
+        let mut len = 0;
+        map.for_each_shard(|shard| len += shard.len());
+        assert_eq!(len, 4000);
+
+        // Every key ended up in the shard `shard_index` points at.
+        let index = map.shard_index(&3999);
+        assert!(map.into_shards()[index].contains_key(&3999));
+    }
+
+    #[test]
+    fn test_sharded_from_fn() {
+        let sharded: Sharded<Vec<usize>, 4> = Sharded::from_fn(|i| vec![i]);
+
+        let mut seen = Vec::new();
+        sharded.for_each_shard(|shard| seen.extend_from_slice(shard));
+        assert_eq!(seen, [0, 1, 2, 3]);
+    }
+}
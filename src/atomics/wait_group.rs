@@ -0,0 +1,162 @@
+//! A Go-style `WaitGroup` for fork-join code: every participant holds a clone,
+//! and `wait` blocks until all the others have been dropped.
+//!
+//! The count of live clones is a single atomic. Waiters register an `Unparker`
+//! before giving up their own clone, so whoever drops the count to zero is
+//! guaranteed to find them and wake them up.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Mutex, Parker, Unparker};
+
+struct Inner {
+    count: AtomicUsize,
+    waiters: Mutex<Vec<Unparker>>,
+}
+
+/// Blocks in `wait` until every other clone of the group has been dropped.
+///
+/// ```
+/// use std::thread;
+///
+/// use crust_of_rust::atomics::WaitGroup;
+///
+/// let wg = WaitGroup::new();
+///
+/// for _ in 0..4 {
+///     let wg = wg.clone();
+///     thread::spawn(move || {
+///         // Do some work, then drop `wg` to signal that we're done.
+///         drop(wg);
+///     });
+/// }
+///
+/// wg.wait();
+/// ```
+pub struct WaitGroup {
+    inner: Arc<Inner>,
+}
+
+impl WaitGroup {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                count: AtomicUsize::new(1),
+                waiters: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Drops this clone and blocks until all other clones are dropped too.
+    ///
+    /// Several clones may wait at once, they're all released together.
+    pub fn wait(self) {
+        let parker = Parker::new();
+        let inner = Arc::clone(&self.inner);
+
+        // Registered before our own clone is dropped, so the thread that
+        // brings the count to zero has to find it.
+        inner
+            .waiters
+            .with_lock(|waiters| waiters.push(parker.unparker()));
+
+        // If ours was the last clone, this wakes us (and any other waiters)
+        // right away.
+        drop(self);
+
+        // `Acquire` pairs with the decrements in `drop`, so the work every
+        // participant did before dropping its clone is visible once we return.
+        while inner.count.load(Ordering::Acquire) != 0 {
+            parker.park();
+        }
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for WaitGroup {
+    fn clone(&self) -> Self {
+        // Cloning needs an existing clone, so the count can't be zero here and
+        // no waiter can be released early.
+        self.inner.count.fetch_add(1, Ordering::Relaxed);
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl Drop for WaitGroup {
+    fn drop(&mut self) {
+        // `Release` publishes this participant's work, `Acquire` makes the
+        // last one see everybody else's before waking the waiters.
+        if self.inner.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let waiters = self.inner.waiters.with_lock(std::mem::take);
+            for waiter in waiters {
+                waiter.unpark();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for WaitGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitGroup")
+            .field("count", &self.inner.count.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait_group_waits_for_all() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let wg = WaitGroup::new();
+
+        for i in 0..8 {
+            let wg = wg.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(i * 5));
+                done.fetch_add(1, Ordering::Relaxed);
+                drop(wg);
+            });
+        }
+
+        wg.wait();
+        assert_eq!(done.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn test_wait_group_several_waiters() {
+        let wg = WaitGroup::new();
+        let last = wg.clone();
+
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let wg = wg.clone();
+                thread::spawn(move || wg.wait())
+            })
+            .collect();
+
+        // None of them can get through while `wg` and `last` are alive.
+        thread::sleep(Duration::from_millis(20));
+        assert!(waiters.iter().all(|waiter| !waiter.is_finished()));
+
+        drop(wg);
+        drop(last);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+}
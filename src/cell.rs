@@ -1,10 +1,17 @@
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::ops::Deref;
 
 /// `Cell` allows for interior mutability through a shared reference because no
 /// other threads can have a reference to the same Cell and no reference to the
 /// inner `T` is ever exposed.
-#[derive(Debug)]
-pub struct Cell<T> {
+///
+/// `#[repr(transparent)]` guarantees `Cell<T>` has the same layout as `T` (as
+/// does `UnsafeCell<T>`), which is what makes it sound to reinterpret a `&mut T`
+/// as a `&Cell<T>`, or a `&Cell<[T]>` as a `&[Cell<T>]`. Without it, the
+/// compiler would be free to lay out the wrapper differently.
+#[repr(transparent)]
+pub struct Cell<T: ?Sized> {
     /// Only `safe` way in Rust to perform interior mutability through a shared
     /// reference.
     value: UnsafeCell<T>,
@@ -14,7 +21,7 @@ pub struct Cell<T> {
 // impl<T> !Sync for Cell<T> {}
 
 impl<T> Cell<T> {
-    pub fn new(value: T) -> Self {
+    pub const fn new(value: T) -> Self {
         Self {
             value: UnsafeCell::new(value),
         }
@@ -28,6 +35,118 @@ impl<T> Cell<T> {
             *self.value.get() = value;
         }
     }
+
+    /// Replaces the inner value, returning the old one.
+    ///
+    /// Moving the value out (instead of copying it like `get`) is what makes
+    /// `Cell` usable with non-`Copy` types.
+    pub fn replace(&self, value: T) -> T {
+        // SAFETY: Same as `set`, no reference to the inner `T` outlives this
+        // call.
+        unsafe { std::mem::replace(&mut *self.value.get(), value) }
+    }
+
+    /// Swaps the values of two `Cell`s.
+    pub fn swap(&self, other: &Self) {
+        // Swapping a cell with itself is a no-op.
+        if std::ptr::eq(self, other) {
+            return;
+        }
+
+        // SAFETY: Both cells are `!Sync` and no reference to either inner `T`
+        // is ever given out, so nothing observes the values being swapped.
+        unsafe { std::ptr::swap(self.value.get(), other.value.get()) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Cell<T> {
+    /// Returns a mutable reference to the inner value.
+    ///
+    /// Safe to hand out a reference here since `&mut self` guarantees no other
+    /// reference to the `Cell` exists (and so no `set` can happen) while it is
+    /// live.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Treats a unique reference as a `Cell`, so the value can be mutated
+    /// through shared references for as long as the `&mut T` is borrowed.
+    pub fn from_mut(t: &mut T) -> &Cell<T> {
+        // SAFETY: `Cell<T>` has the same layout as `T`. The `&mut T` is
+        // borrowed for the lifetime of the returned `&Cell<T>`, so nothing
+        // else can observe the value while it's being mutated through the
+        // `Cell`.
+        unsafe { &*(t as *mut T as *const Cell<T>) }
+    }
+}
+
+impl<T> Cell<[T]> {
+    /// Projects a `Cell` of a slice into a slice of `Cell`s, so each element
+    /// can be mutated individually.
+    pub fn as_slice_of_cells(&self) -> &[Cell<T>] {
+        // SAFETY: `Cell<[T]>` has the same layout as `[T]`, and `Cell<T>` the
+        // same as `T`, so `[Cell<T>]` has the same layout as `Cell<[T]>`. The
+        // cast keeps the length. Mutating an element through its `Cell` is
+        // just a mutation of part of the outer `Cell`'s value, which is
+        // allowed through a shared reference.
+        unsafe { &*(self as *const Cell<[T]> as *const [Cell<T>]) }
+    }
+}
+
+impl<T, const N: usize> Cell<[T; N]> {
+    /// Projects a `Cell` of an array into an array of `Cell`s, keeping the
+    /// length in the type.
+    pub fn as_array_of_cells(&self) -> &[Cell<T>; N] {
+        // SAFETY: `Cell<[T; N]>` has the same layout as `[T; N]`, which is `N`
+        // consecutive `T`s, and `Cell<T>` the same as `T`, so this is the
+        // same transmute between transparent wrappers as
+        // `as_slice_of_cells`, just without a length to carry in the pointer.
+        unsafe { &*(self as *const Cell<[T; N]> as *const [Cell<T>; N]) }
+    }
+}
+
+impl<T> Cell<T>
+where
+    T: Default,
+{
+    /// Takes the inner value, leaving `T::default()` in its place.
+    pub fn take(&self) -> T {
+        self.replace(T::default())
+    }
+
+    /// Calls `f` with a reference to the inner value, without requiring
+    /// `T: Copy`.
+    ///
+    /// Handing out a `&T` that points into the `Cell` would be unsound: `f`
+    /// could `set` the same `Cell` (e.g. through an `Rc`) while the reference
+    /// is live. Instead, the value is moved out for the duration of the call,
+    /// leaving `T::default()` behind, and moved back in afterwards. Any access
+    /// to the `Cell` from within `f` sees the default, and a value `set` from
+    /// within `f` is overwritten when the original is put back. If `f` panics,
+    /// the default is left in the `Cell`.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let value = self.take();
+        let ret = f(&value);
+        self.set(value);
+        ret
+    }
+}
+
+impl<T> Cell<T>
+where
+    T: Clone + Default,
+{
+    /// Returns a clone of the inner value.
+    ///
+    /// Goes through `with`, since `T::clone` is arbitrary code that could
+    /// access the `Cell` as well.
+    pub fn get_clone(&self) -> T {
+        self.with(T::clone)
+    }
 }
 
 impl<T> Cell<T>
@@ -39,6 +158,259 @@ where
         // via `Cell::set`.
         unsafe { *self.value.get() }
     }
+
+    /// Replaces the inner value with `f` applied to it, returning the new
+    /// value.
+    ///
+    /// Saves the `get` + `set` pair for read-modify-write updates like
+    /// counters. `f` gets a copy, so it can't observe a half-updated value even
+    /// if it accesses the `Cell` itself.
+    pub fn update(&self, f: impl FnOnce(T) -> T) -> T {
+        let new = f(self.get());
+        self.set(new);
+        new
+    }
+}
+
+// The traits below need to read the value, which is only possible through
+// `get` for `T: Copy`, since `Cell` never hands out a `&T`.
+
+impl<T: Copy> Clone for Cell<T> {
+    fn clone(&self) -> Self {
+        Cell::new(self.get())
+    }
+}
+
+impl<T: PartialEq + Copy> PartialEq for Cell<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl<T: Eq + Copy> Eq for Cell<T> {}
+
+impl<T: PartialOrd + Copy> PartialOrd for Cell<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.get().partial_cmp(&other.get())
+    }
+}
+
+impl<T: Ord + Copy> Ord for Cell<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.get().cmp(&other.get())
+    }
+}
+
+impl<T: fmt::Debug + Copy> fmt::Debug for Cell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cell").field("value", &self.get()).finish()
+    }
+}
+
+impl<T: Default> Default for Cell<T> {
+    fn default() -> Self {
+        Cell::new(T::default())
+    }
+}
+
+impl<T> From<T> for Cell<T> {
+    fn from(value: T) -> Self {
+        Cell::new(value)
+    }
+}
+
+/// `OnceCell` is a cell that can be written to at most once, after which the
+/// value can be borrowed directly.
+///
+/// It fills the gap between `Cell` and `RefCell`: unlike `Cell` it hands out
+/// `&T` (so `T` doesn't need to be `Copy`), and unlike `RefCell` there is no
+/// borrow flag to track, since a value that is never mutated again can be
+/// shared forever.
+#[derive(Debug)]
+pub struct OnceCell<T> {
+    /// `None` until initialized. Once `Some`, it is never written to again,
+    /// which is what makes handing out `&T` sound.
+    inner: UnsafeCell<Option<T>>,
+}
+
+// Implied by `UnsafeCell`, which is already `!Sync`.
+// impl<T> !Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            inner: UnsafeCell::new(None),
+        }
+    }
+
+    /// Returns the value, or `None` if the cell hasn't been initialized yet.
+    pub fn get(&self) -> Option<&T> {
+        // SAFETY: The only write is the `None` -> `Some` transition in `set`,
+        // while there is no value to hold a reference to.
+        unsafe { (*self.inner.get()).as_ref() }
+    }
+
+    /// Initializes the cell with `value`, or gives it back if the cell was
+    /// already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.get().is_some() {
+            return Err(value);
+        }
+
+        // SAFETY: The cell is empty, so no `&T` has been handed out, and
+        // `OnceCell` is `!Sync` so no other thread can be writing.
+        unsafe { *self.inner.get() = Some(value) };
+        Ok(())
+    }
+
+    /// Returns the value, initializing it with `f` first if the cell is
+    /// empty.
+    ///
+    /// Panics if `f` initializes the cell itself (reentrant initialization).
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, std::convert::Infallible>(f())) {
+            Ok(value) => value,
+        }
+    }
+
+    /// Like `get_or_init`, but leaves the cell empty if `f` fails.
+    pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+
+        let value = f()?;
+
+        // `f` is arbitrary code that may have initialized the cell through a
+        // shared reference, and handed out a `&T` to that value. Overwriting
+        // it would invalidate that reference, `set` refuses to.
+        assert!(self.set(value).is_ok(), "OnceCell initialized reentrantly");
+
+        Ok(self.get().unwrap())
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.inner.into_inner()
+    }
+
+    /// Takes the value out, leaving the cell uninitialized.
+    ///
+    /// Needs `&mut self`, so no `&T` from `get` can still be live.
+    pub fn take(&mut self) -> Option<T> {
+        self.inner.get_mut().take()
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `LazyCell` is a value that is initialized on first access, by an
+/// initializer given up front.
+///
+/// `Deref` forces initialization, so it can be used like a `T` everywhere.
+pub struct LazyCell<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    /// `None` once the initializer has been taken. That happens right before
+    /// it runs, so if it panics, the `LazyCell` is left with neither a value
+    /// nor an initializer (poisoned), and every later access panics too.
+    init: Cell<Option<F>>,
+}
+
+impl<T, F> LazyCell<T, F>
+where
+    F: FnOnce() -> T,
+{
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+
+    /// Forces initialization, returning a reference to the value.
+    ///
+    /// Takes `this` instead of `self` so it is not confused with a method on
+    /// `T` through `Deref`.
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(init) => init(),
+            None => panic!("LazyCell initializer panicked previously"),
+        })
+    }
+
+    /// Returns the value if it's initialized, or the initializer otherwise.
+    ///
+    /// Panics if the `LazyCell` is poisoned.
+    pub fn into_inner(this: Self) -> Result<T, F> {
+        match this.cell.into_inner() {
+            Some(value) => Ok(value),
+            None => Err(this
+                .init
+                .into_inner()
+                .expect("LazyCell initializer panicked previously")),
+        }
+    }
+}
+
+impl<T, F> Deref for LazyCell<T, F>
+where
+    F: FnOnce() -> T,
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        LazyCell::force(self)
+    }
+}
+
+impl<T: Default> Default for LazyCell<T> {
+    fn default() -> Self {
+        LazyCell::new(T::default)
+    }
+}
+
+/// `SyncUnsafeCell` is an `UnsafeCell` that is `Sync` whenever `T` is.
+///
+/// `UnsafeCell` is always `!Sync`, which conflates two separate things:
+/// interior mutability (what `UnsafeCell` provides), and thread safety (which
+/// depends on how the interior mutability is used). This only provides the
+/// former, all synchronization is the caller's responsibility. Types built on
+/// it (e.g. a lock) still decide their own `Sync` bounds, without having to
+/// opt back in from `!Sync`.
+#[repr(transparent)]
+pub struct SyncUnsafeCell<T: ?Sized> {
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: Every access to the inner value goes through the raw pointer from
+// `get`, so the caller must already guarantee the absence of data races.
+unsafe impl<T: ?Sized + Sync> Sync for SyncUnsafeCell<T> {}
+
+impl<T> SyncUnsafeCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SyncUnsafeCell<T> {
+    /// Returns a raw pointer to the inner value, which may be read or written
+    /// as long as the caller prevents concurrent conflicting accesses.
+    pub const fn get(&self) -> *mut T {
+        self.value.get()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
 }
 
 /// ```compile_fail
@@ -110,4 +482,225 @@ mod tests {
         c.set(-3);
         assert_eq!(c.get(), -3);
     }
+
+    #[test]
+    fn test_cell_update() {
+        let c = Cell::new(5);
+        assert_eq!(c.update(|n| n * 2), 10);
+        assert_eq!(c.get(), 10);
+    }
+
+    #[test]
+    fn test_cell_replace_take() {
+        let c = Cell::new(String::from("hello"));
+        assert_eq!(c.replace(String::from("world")), "hello");
+        assert_eq!(c.take(), "world");
+        assert_eq!(c.into_inner(), "");
+    }
+
+    #[test]
+    fn test_cell_swap() {
+        let a = Cell::new(vec![1]);
+        let b = Cell::new(vec![2, 3]);
+
+        a.swap(&b);
+        a.swap(&a);
+        assert_eq!(a.into_inner(), vec![2, 3]);
+        assert_eq!(b.into_inner(), vec![1]);
+    }
+
+    #[test]
+    fn test_cell_get_mut() {
+        let mut c = Cell::new(String::from("hello"));
+        c.get_mut().push_str(" world");
+        assert_eq!(c.into_inner(), "hello world");
+    }
+
+    #[test]
+    fn test_cell_from_mut() {
+        let mut x = 5;
+        let c = Cell::from_mut(&mut x);
+        let (a, b) = (c, c);
+
+        a.set(b.get() + 1);
+        assert_eq!(x, 6);
+    }
+
+    #[test]
+    fn test_cell_slice_sort() {
+        let mut v = [5, 3, 4, 1, 2];
+
+        // Bubble sort over shared references, each element is a `Cell` that
+        // can be swapped with its neighbour without a `&mut [T]`.
+        let cells = Cell::from_mut(&mut v[..]).as_slice_of_cells();
+        for end in (1..cells.len()).rev() {
+            for w in cells[..=end].windows(2) {
+                if w[0].get() > w[1].get() {
+                    w[0].swap(&w[1]);
+                }
+            }
+        }
+
+        assert_eq!(v, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_once_cell_set_get() {
+        let cell = OnceCell::new();
+        assert!(cell.get().is_none());
+
+        assert!(cell.set(String::from("hello")).is_ok());
+        let value = cell.get().unwrap();
+
+        // A second `set` must not invalidate `value`.
+        assert_eq!(cell.set(String::from("world")), Err(String::from("world")));
+        assert_eq!(value, "hello");
+    }
+
+    #[test]
+    fn test_once_cell_get_or_init() {
+        let cell = OnceCell::new();
+        let calls = Cell::new(0);
+
+        for _ in 0..3 {
+            let value = cell.get_or_init(|| {
+                calls.set(calls.get() + 1);
+                vec![1, 2, 3]
+            });
+            assert_eq!(value.len(), 3);
+        }
+
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_once_cell_get_or_try_init() {
+        let mut cell: OnceCell<i32> = OnceCell::new();
+
+        assert_eq!(cell.get_or_try_init(|| Err("failed")), Err("failed"));
+        assert!(cell.get().is_none());
+
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(42)), Ok(&42));
+        assert_eq!(cell.take(), Some(42));
+        assert_eq!(cell.into_inner(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_once_cell_reentrant_init() {
+        let cell = OnceCell::new();
+        cell.get_or_init(|| {
+            cell.set(1).unwrap();
+            2
+        });
+    }
+
+    #[test]
+    fn test_lazy_cell_deref() {
+        let calls = Cell::new(0);
+        let lazy = LazyCell::new(|| {
+            calls.set(calls.get() + 1);
+            String::from("hello")
+        });
+        assert_eq!(calls.get(), 0);
+
+        assert_eq!(lazy.len(), 5);
+        assert_eq!(*LazyCell::force(&lazy), "hello");
+        assert_eq!(calls.get(), 1);
+        assert_eq!(LazyCell::into_inner(lazy).ok().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_lazy_cell_into_inner_uninit() {
+        let lazy: LazyCell<i32> = LazyCell::new(|| 42);
+        let init = LazyCell::into_inner(lazy).unwrap_err();
+        assert_eq!(init(), 42);
+    }
+
+    #[test]
+    fn test_lazy_cell_poisoned() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let lazy: LazyCell<i32> = LazyCell::new(|| panic!());
+
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| *lazy)).is_err());
+        // The initializer is gone, so the second access can't retry it.
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| *lazy)).is_err());
+    }
+
+    #[test]
+    fn test_cell_traits() {
+        let a = Cell::new(1);
+        let b = a.clone();
+        b.set(2);
+
+        assert_eq!(a, Cell::from(1));
+        assert!(a < b);
+        assert_eq!(Cell::<i32>::default().get(), 0);
+        assert_eq!(format!("{b:?}"), "Cell { value: 2 }");
+
+        #[derive(Debug, Default, PartialEq)]
+        struct Counters {
+            hits: Cell<u32>,
+            misses: Cell<u32>,
+        }
+
+        let counters = Counters::default();
+        counters.hits.update(|n| n + 1);
+        assert_eq!(
+            counters,
+            Counters {
+                hits: Cell::new(1),
+                misses: Cell::new(0)
+            }
+        );
+    }
+
+    #[test]
+    fn test_sync_unsafe_cell() {
+        use std::thread;
+
+        static COUNTER: SyncUnsafeCell<usize> = SyncUnsafeCell::new(0);
+
+        // `Sync`, so it can be shared as a `static`, but the writes still
+        // need to be synchronized by the caller, here by joining.
+        thread::spawn(|| unsafe { *COUNTER.get() += 1 })
+            .join()
+            .unwrap();
+
+        // SAFETY: The only other access happened-before the join.
+        assert_eq!(unsafe { *COUNTER.get() }, 1);
+    }
+
+    #[test]
+    fn test_cell_with() {
+        let c = Cell::new(String::from("hello"));
+
+        assert_eq!(c.with(|s| s.len()), 5);
+        assert_eq!(c.get_clone(), "hello");
+
+        // Reentrant accesses see the default value, and the original is put
+        // back afterwards.
+        let len = c.with(|s| {
+            assert_eq!(c.get_clone(), "");
+            c.set(String::from("lost"));
+            s.len()
+        });
+        assert_eq!(len, 5);
+        assert_eq!(c.into_inner(), "hello");
+    }
+
+    #[test]
+    fn test_cell_array_of_cells() {
+        let grid = Cell::new([0_u8; 4]);
+        let [a, b, c, d] = grid.as_array_of_cells();
+
+        // Each element can be mutated on its own through a shared reference.
+        a.set(1);
+        d.set(4);
+        b.swap(d);
+        c.update(|n| n + 3);
+
+        assert_eq!(grid.get(), [1, 4, 3, 0]);
+    }
 }
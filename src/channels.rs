@@ -4,11 +4,19 @@
 //! MPSC (Multiple producer, single consumer) channels allow for many-to-one
 //! communication between multiple senders and one receiver (fan-in pattern).
 //!
+//! MPMC (Multiple producer, multiple consumer) channels also allow the receiver
+//! to be cloned, with every value delivered to exactly one of the receivers
+//! (work distribution). The channels here are MPMC, but optimized for the
+//! single receiver case.
+//!
 //! Flavors of channels:
 //!
 //! - Synchronous: Channel where `send()` can block, buffer is bounded.
 //!     - Mutex + Condvar + Queue (VecDeque)
-//!     - Atomic Queue + thread::park + thread::Thread::unpark
+//!     - Atomic Queue + thread::park + thread::Thread::unpark (see `park`, which
+//!       is unbounded)
+//!     - Ring buffer of slots with sequence numbers + futex (see `array`)
+//!     - SPSC only: ring buffer with one index per side (see `spsc`)
 //!
 //! - Asynchronous (non-blocking): Channel where `send()` cannot block, buffer
 //!   is unbounded.
@@ -25,11 +33,42 @@
 //!   `send()`. Can be used for notifying on caught signals, signaling threads
 //!   to terminate, etc.
 //!     - Atomic Option + thread signaling
+//!
+//! - Slots: Bounded channel of preallocated values that are written and read
+//!   in place, and recycled (see `slots`). For large payloads.
+//!     - Mutex + Condvar + queue of slot indices
+//!
+//! - Watch: A single slot that senders overwrite, receivers only ever see the
+//!   latest value (see `watch`).
+//!     - RwLock + version counter + Condvar
+//!
+//! - Timer: Bounded channel fed by a timer thread rather than a sender, once
+//!   (`after`) or periodically (`tick`). For timeouts in `select!` (see
+//!   `timer`).
+//!     - Heap of deadlines + Condvar::wait_timeout
 
-use std::collections::VecDeque;
-use std::sync::{Arc, Condvar, Mutex};
+use std::cell::Cell;
+use std::collections::{VecDeque, vec_deque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+use crate::async_await::stream::Stream;
+use crate::atomics::{Parker, Unparker};
+
+pub mod array;
+pub mod park;
+
+pub mod select;
+pub mod slots;
+pub mod spsc;
+pub mod timer;
+pub mod watch;
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct RecvError {}
 
 impl std::error::Error for RecvError {}
@@ -40,6 +79,110 @@ impl std::fmt::Display for RecvError {
     }
 }
 
+/// Returned by `send` when the `Receiver` is gone, with the value that couldn't
+/// be sent.
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+// Not derived, so it doesn't require `T: Debug` (and `unwrap` works on any
+// send).
+impl<T> std::fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SendError: sending on a closed channel")
+    }
+}
+
+/// Returned by `try_send`, with the value that couldn't be sent.
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full (or, for a rendezvous channel, no receiver is
+    /// waiting).
+    Full(T),
+    /// Every `Receiver` is gone.
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(val) | TrySendError::Disconnected(val) => val,
+        }
+    }
+}
+
+// Not derived, for the same reason as `SendError`'s.
+impl<T> std::fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> std::error::Error for TrySendError<T> {}
+
+impl<T> std::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "TrySendError: channel full"),
+            TrySendError::Disconnected(_) => {
+                write!(f, "TrySendError: sending on a closed channel")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value is waiting, but the channel is still open.
+    Empty,
+    /// No value is waiting, and every `Sender` is gone.
+    Disconnected,
+}
+
+impl std::error::Error for TryRecvError {}
+
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "TryRecvError: channel empty"),
+            TryRecvError::Disconnected => write!(f, "TryRecvError: channel disconnected"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// No value arrived in time, but the channel is still open.
+    Timeout,
+    /// No value is waiting, and every `Sender` is gone.
+    Disconnected,
+}
+
+impl std::error::Error for RecvTimeoutError {}
+
+impl std::fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => {
+                write!(f, "RecvTimeoutError: timed out waiting on channel")
+            }
+            RecvTimeoutError::Disconnected => {
+                write!(f, "RecvTimeoutError: channel disconnected")
+            }
+        }
+    }
+}
+
 struct Inner<T> {
     /// So we can have FIFO communication over the channel.
     queue: VecDeque<T>,
@@ -51,11 +194,208 @@ struct Inner<T> {
     /// could not potentially notify any blocked `recv` when dropping the last
     /// `Sender`.
     senders: usize,
+    /// Values taken off `queue` so far, which lets a rendezvous `send` tell
+    /// when its own value has been received.
+    received: usize,
+    /// Once all `Receiver`s are dropped, sends fail.
+    receivers: usize,
+    /// Threads blocked in `select!` on this channel, by waiter id. Unparked on
+    /// every send and on disconnect, see `select`.
+    selectors: Vec<(usize, Unparker)>,
+    /// Set by `Receiver::close`.
+    closed: bool,
+    /// The part of the bound taken up by the values in `queue`, see
+    /// `Shared::size`.
+    used: usize,
+    /// Receivers blocked in `recv` or `recv_deadline`, which a rendezvous
+    /// `try_send` can hand a value to.
+    blocked: usize,
+    /// Pending `recv_async` futures, by id, oldest first. Each send takes one
+    /// out to wake it, a disconnect takes all of them.
+    tasks: Vec<(usize, Waker)>,
+    /// `SyncSender`s blocked for room, by id, oldest first. Only the oldest
+    /// one may send next, see `Shared::wait_for_room`.
+    send_waiters: VecDeque<(usize, Unparker)>,
+}
+
+impl<T> Inner<T> {
+    /// Whether sends fail.
+    fn send_closed(&self) -> bool {
+        self.receivers == 0 || self.closed
+    }
+
+    /// Whether receives fail once the queue is empty.
+    fn recv_closed(&self) -> bool {
+        self.senders == 0 || self.closed
+    }
+
+    fn wake_selectors(&self) {
+        for (_, unparker) in &self.selectors {
+            unparker.unpark();
+        }
+    }
+
+    /// Unregisters the oldest pending `recv_async`, to be woken once the lock
+    /// is released: a `Waker` may run arbitrary code, including code that uses
+    /// the channel.
+    fn take_task(&mut self) -> Option<Waker> {
+        if self.tasks.is_empty() {
+            None
+        } else {
+            Some(self.tasks.remove(0).1)
+        }
+    }
 }
 
+/// Ids for `recv_async` futures, so a dropped one can find its `Waker`.
+static NEXT_TASK: AtomicUsize = AtomicUsize::new(0);
+
+/// Ids for blocked `SyncSender`s, so one can leave `send_waiters` out of turn
+/// when the channel closes.
+static NEXT_SEND_WAITER: AtomicUsize = AtomicUsize::new(0);
+
 struct Shared<T> {
     mu: Mutex<Inner<T>>,
     avail: Condvar,
+    /// Waited on by rendezvous `SyncSender`s, for their value to be received.
+    /// Waiting for room goes through `Inner::send_waiters` instead.
+    handed_over: Condvar,
+    /// The bound of a `sync_channel` or `sized_channel`, `None` for `channel`.
+    cap: Option<usize>,
+    /// How much of `cap` a value uses: 1 for `sync_channel`, so that `cap`
+    /// counts values, or the user's function for `sized_channel`.
+    size: fn(&T) -> usize,
+}
+
+impl<T> Shared<T> {
+    /// Takes the next value off the shared queue, for the `Receiver` owning
+    /// `buf`.
+    fn take(&self, inner: &mut Inner<T>, buf: &mut VecDeque<T>) -> Option<T> {
+        let val = inner.queue.pop_front()?;
+        inner.received += 1;
+        inner.used -= self.size_of(&val);
+
+        // Values in the local buffer wouldn't count against the bound, so a
+        // bounded channel takes them one at a time. So does a cloned
+        // `Receiver`, which could otherwise sit on values other receivers are
+        // waiting for.
+        if self.cap.is_none() && inner.receivers == 1 && !inner.queue.is_empty() {
+            // If the shared queue is non-empty, swap it with the local buffer
+            // held by the `Receiver`, so future `recv` do not need to acquire
+            // the mutex.
+            inner.received += inner.queue.len();
+            std::mem::swap(buf, &mut inner.queue);
+        }
+
+        Some(val)
+    }
+
+    /// The part of `cap` that `val` uses, always 0 in an unbounded channel.
+    fn size_of(&self, val: &T) -> usize {
+        if self.cap.is_some() {
+            (self.size)(val)
+        } else {
+            0
+        }
+    }
+
+    /// Whether a value of `size` can be queued without going over `cap`.
+    fn has_room(&self, inner: &Inner<T>, size: usize) -> bool {
+        match self.cap {
+            None => true,
+            // A rendezvous channel still hands values over through the queue,
+            // one at a time. A value bigger than the whole bound is let through
+            // once the queue is empty, rather than never.
+            Some(cap) => inner.queue.is_empty() || inner.used + size <= cap,
+        }
+    }
+
+    /// Queues `val` and wakes a receiver, releasing the lock first.
+    fn push(&self, mut inner: MutexGuard<'_, Inner<T>>, val: T) {
+        inner.used += self.size_of(&val);
+        inner.queue.push_back(val);
+        inner.wake_selectors();
+        let task = inner.take_task();
+
+        // Ensure we drop the `MutexGuard` before notifying the `Receiver`,
+        // since it will attempt to reacquire the lock. If the notification
+        // happens before this function drops the Mutex, a deadlock can occur.
+        drop(inner);
+
+        // Notify a waiting `Receiver` there is a value in the queue. Only one,
+        // since only one of them can take it.
+        self.avail.notify_one();
+        if let Some(task) = task {
+            task.wake();
+        }
+    }
+
+    /// After a value is taken, lets blocked `SyncSender`s know there's room.
+    fn notify_senders(&self, inner: MutexGuard<'_, Inner<T>>) {
+        if self.cap.is_some() {
+            // Only the oldest waiter may take the room, see `wait_for_room`.
+            let next = inner.send_waiters.front().map(|(_, next)| next.clone());
+            drop(inner);
+
+            if let Some(next) = next {
+                next.unpark();
+            }
+            if self.cap == Some(0) {
+                self.handed_over.notify_all();
+            }
+        }
+    }
+
+    /// Wakes every blocked `SyncSender`, once sends fail.
+    fn notify_closed(&self, inner: MutexGuard<'_, Inner<T>>) {
+        let waiters: Vec<_> = inner.send_waiters.iter().map(|(_, w)| w.clone()).collect();
+        drop(inner);
+
+        for waiter in waiters {
+            waiter.unpark();
+        }
+        self.handed_over.notify_all();
+    }
+
+    /// Blocks a `SyncSender` until it is the oldest one waiting and there is
+    /// room for a value of `size`, or until sends fail.
+    ///
+    /// Waiting in line keeps a sender from being starved by others that keep
+    /// getting the lock first whenever room frees up, which a `Condvar` alone
+    /// can't rule out. It also means each value taken wakes exactly the sender
+    /// that gets to use the room, rather than all of them.
+    fn wait_for_room<'a>(
+        &'a self,
+        mut inner: MutexGuard<'a, Inner<T>>,
+        size: usize,
+    ) -> MutexGuard<'a, Inner<T>> {
+        let parker = Parker::new();
+        let id = NEXT_SEND_WAITER.fetch_add(1, Ordering::Relaxed);
+        inner.send_waiters.push_back((id, parker.unparker()));
+
+        loop {
+            // A wakeup between unlocking and parking isn't lost, the `Parker`
+            // keeps the token.
+            drop(inner);
+            parker.park();
+            inner = self.mu.lock().unwrap();
+
+            if inner.send_closed() {
+                inner.send_waiters.retain(|&(waiter, _)| waiter != id);
+                return inner;
+            }
+            let first = inner.send_waiters.front().map(|&(waiter, _)| waiter);
+            if first == Some(id) && self.has_room(&inner, size) {
+                inner.send_waiters.pop_front();
+                // Several values may have been taken at once (`drain`,
+                // `recv_many`), so the next in line checks for room too.
+                if let Some((_, next)) = inner.send_waiters.front() {
+                    next.unpark();
+                }
+                return inner;
+            }
+        }
+    }
 }
 
 /// Sender type of a channel.
@@ -83,28 +423,240 @@ impl<T> Drop for Sender<T> {
         let mut guard = self.inner.mu.lock().unwrap();
         guard.senders -= 1;
         let senders = guard.senders;
+        let mut tasks = Vec::new();
+        if senders == 0 {
+            guard.wake_selectors();
+            tasks = std::mem::take(&mut guard.tasks);
+        }
         drop(guard);
 
-        // Ensure any `Receivers` are awoken if this is the last `Sender`.
+        // Ensure any `Receivers` are awoken if this is the last `Sender`. All
+        // of them, since every one of them has to return an error.
         if senders == 0 {
-            self.inner.avail.notify_one();
+            self.inner.avail.notify_all();
+            for (_, task) in tasks {
+                task.wake();
+            }
         }
     }
 }
 
 impl<T> Sender<T> {
-    pub fn send(&self, val: T) {
+    /// Sends `val`, or gives it back if the `Receiver` is gone.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        // The only way to get a bounded `Sender` is through a `SyncSender`,
+        // whose `send` does the waiting.
+        debug_assert!(self.inner.cap.is_none());
+
         let mut inner = self.inner.mu.lock().unwrap();
-        inner.queue.push_back(val);
+        // Without this check, values sent after the `Receiver` is dropped
+        // would pile up in a queue nobody reads.
+        if inner.send_closed() {
+            return Err(SendError(val));
+        }
+        self.inner.push(inner, val);
 
-        // Ensure we drop the `MutexGuard` before notifying the `Receiver`,
-        // since it will attempt to reacquire the lock. If the notification
-        // happens before this function drops the Mutex, a deadlock can occur.
+        Ok(())
+    }
+
+    /// Sends every value of `vals`, in order, with a single lock acquisition
+    /// and a single round of wakeups, rather than one of each per value. The
+    /// values also end up next to each other in the channel, even with other
+    /// senders sending concurrently.
+    ///
+    /// If the `Receiver`s are gone, nothing is sent and the values are given
+    /// back.
+    pub fn send_all<I: IntoIterator<Item = T>>(&self, vals: I) -> Result<(), SendError<Vec<T>>> {
+        debug_assert!(self.inner.cap.is_none());
+
+        // Collected before locking, the iterator could run arbitrary code.
+        let vals: Vec<T> = vals.into_iter().collect();
+        if vals.is_empty() {
+            return Ok(());
+        }
+
+        let mut inner = self.inner.mu.lock().unwrap();
+        if inner.send_closed() {
+            return Err(SendError(vals));
+        }
+        let n = vals.len();
+        inner.queue.extend(vals);
+        inner.wake_selectors();
+        let tasks: Vec<_> = (0..n).map_while(|_| inner.take_task()).collect();
         drop(inner);
 
-        // Notify the waiting `Receiver` there is a value in the queue.
-        // `notify_one` is used since this is a `MPSC` channel.
-        self.inner.avail.notify_one();
+        // As many receivers as there are values could make progress.
+        if n == 1 {
+            self.inner.avail.notify_one();
+        } else {
+            self.inner.avail.notify_all();
+        }
+        for task in tasks {
+            task.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Whether every `Receiver` is gone (or one closed the channel), so that
+    /// any further send would fail.
+    ///
+    /// The channel can't reopen once that's the case, but a `false` may be
+    /// stale by the time it's acted on.
+    pub fn is_disconnected(&self) -> bool {
+        self.inner.mu.lock().unwrap().send_closed()
+    }
+
+    /// The number of `Receiver`s currently alive.
+    pub fn receiver_count(&self) -> usize {
+        self.inner.mu.lock().unwrap().receivers
+    }
+
+    /// Creates a `WeakSender`, which doesn't count as a sender: the channel
+    /// disconnects once every `Sender` is dropped, whatever `WeakSender`s are
+    /// left.
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+}
+
+/// A sender that doesn't keep the channel open, see `Sender::downgrade`.
+pub struct WeakSender<T> {
+    /// `Weak`, so that a forgotten `WeakSender` doesn't keep the queue alive
+    /// either.
+    inner: Weak<Shared<T>>,
+}
+
+impl<T> WeakSender<T> {
+    /// Returns a `Sender` if any other `Sender` is still alive.
+    ///
+    /// Once the channel has disconnected, it can't be reopened: there is no
+    /// way for receivers to notice it happening.
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let inner = self.inner.upgrade()?;
+
+        let mut guard = inner.mu.lock().unwrap();
+        if guard.senders == 0 {
+            return None;
+        }
+        guard.senders += 1;
+        drop(guard);
+
+        Some(Sender { inner })
+    }
+}
+
+// Not derived, that would require `T: Clone`.
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Weak::clone(&self.inner),
+        }
+    }
+}
+
+/// Sender type of a bounded channel, see `sync_channel`.
+pub struct SyncSender<T> {
+    /// Shares the sender count with `Sender`, only sending differs.
+    sender: Sender<T>,
+}
+
+// Not derived, that would require `T: Clone`.
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T> SyncSender<T> {
+    /// Sends `val`, blocking while the channel is full: it holds `cap` values,
+    /// or for a `sized_channel`, `val` would take it over budget. Gives `val`
+    /// back if the `Receiver` is gone, including while blocked.
+    ///
+    /// In a rendezvous channel (`cap == 0`), blocks until the receiver has
+    /// taken `val`.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let shared = &*self.sender.inner;
+        let cap = shared.cap.expect("a `SyncSender` always has a bound");
+        let size = shared.size_of(&val);
+        let mut inner = shared.mu.lock().unwrap();
+
+        // Senders already waiting go first, even if there's room now.
+        if !inner.send_closed()
+            && (!inner.send_waiters.is_empty() || !shared.has_room(&inner, size))
+        {
+            inner = shared.wait_for_room(inner, size);
+        }
+        if inner.send_closed() {
+            return Err(SendError(val));
+        }
+
+        // Everything queued ahead of us must have been received before our
+        // value is, and in a rendezvous channel the queue only ever holds one
+        // value.
+        let ours = inner.received + inner.queue.len() + 1;
+        shared.push(inner, val);
+
+        if cap == 0 {
+            let mut inner = shared.mu.lock().unwrap();
+            while inner.received < ours {
+                if inner.receivers == 0 {
+                    // Not received, so still the one value in the queue: the
+                    // `Receiver` leaves it there for us to take back.
+                    let val = inner.queue.pop_back().expect("unreceived value");
+                    inner.used -= shared.size_of(&val);
+                    return Err(SendError(val));
+                }
+                inner = shared.handed_over.wait(inner).unwrap();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// See `Sender::is_disconnected`.
+    pub fn is_disconnected(&self) -> bool {
+        self.sender.is_disconnected()
+    }
+
+    /// See `Sender::receiver_count`.
+    pub fn receiver_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Sends `val` only if that doesn't require blocking, otherwise gives it
+    /// back right away.
+    ///
+    /// A rendezvous channel never has room, a value is only accepted if a
+    /// receiver is already waiting for one. `try_send` doesn't wait for that
+    /// receiver to take it.
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.sender.inner;
+        let cap = shared.cap.expect("a `SyncSender` always has a bound");
+        let inner = shared.mu.lock().unwrap();
+
+        if inner.send_closed() {
+            return Err(TrySendError::Disconnected(val));
+        }
+        // Room that frees up belongs to the senders already waiting for it.
+        let room = if !inner.send_waiters.is_empty() {
+            false
+        } else if cap == 0 {
+            inner.queue.is_empty()
+                && (inner.blocked > 0 || !inner.tasks.is_empty() || !inner.selectors.is_empty())
+        } else {
+            shared.has_room(&inner, shared.size_of(&val))
+        };
+        if !room {
+            return Err(TrySendError::Full(val));
+        }
+
+        shared.push(inner, val);
+        Ok(())
     }
 }
 
@@ -113,15 +665,90 @@ pub struct Receiver<T> {
     /// `Arc` is used so the `Receiver` can share the same instance of
     /// `ChanInner` with all senders.
     inner: Arc<Shared<T>>,
-    /// Since out implementation uses only one `Receiver`, we can keep a local
-    /// buffer of all sent items to reduce the number of times we lock to access
-    /// the shared queue.
-    buf: VecDeque<T>,
+    /// While there is only one `Receiver`, we can keep a local buffer of all
+    /// sent items to reduce the number of times we lock to access the shared
+    /// queue.
+    ///
+    /// A `Cell` so that `clone`, which only gets `&self`, can hand the values
+    /// back to the shared queue.
+    buf: Cell<VecDeque<T>>,
+    /// The id `poll_next` registered with, kept between polls, see `Stream`.
+    task: Option<usize>,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        let mut inner = self.inner.mu.lock().unwrap();
+        inner.receivers += 1;
+
+        // Values buffered here were sent before anything still in the queue,
+        // they go back to the front for whichever receiver gets there first.
+        let mut buf = self.buf.take();
+        if !buf.is_empty() {
+            buf.append(&mut inner.queue);
+            inner.queue = buf;
+            let tasks = std::mem::take(&mut inner.tasks);
+            drop(inner);
+            self.inner.avail.notify_all();
+            for (_, task) in tasks {
+                task.wake();
+            }
+        }
+
+        Self {
+            inner: Arc::clone(&self.inner),
+            buf: Cell::new(VecDeque::new()),
+            task: None,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.task.take() {
+            self.cancel_task(id);
+        }
+
+        let mut inner = self.inner.mu.lock().unwrap();
+        inner.receivers -= 1;
+
+        if inner.receivers > 0 {
+            // As in `clone`, buffered values go to the remaining receivers.
+            let mut buf = std::mem::take(self.buf.get_mut());
+            if !buf.is_empty() {
+                buf.append(&mut inner.queue);
+                inner.queue = buf;
+                let tasks = std::mem::take(&mut inner.tasks);
+                drop(inner);
+                self.inner.avail.notify_all();
+                for (_, task) in tasks {
+                    task.wake();
+                }
+            }
+            return;
+        }
+
+        // Values nobody will receive are dropped now, rather than with the last
+        // `Sender`. A blocked rendezvous sender takes its value back instead,
+        // to return it in its `SendError`.
+        let unreceived = if self.inner.cap == Some(0) {
+            VecDeque::new()
+        } else {
+            inner.used = 0;
+            std::mem::take(&mut inner.queue)
+        };
+
+        // Wake blocked `SyncSender`s so they see the channel is closed.
+        self.inner.notify_closed(inner);
+
+        // Outside the lock, in case a value's `Drop` uses the channel.
+        drop(unreceived);
+    }
 }
 
 impl<T> Receiver<T> {
     pub fn recv(&mut self) -> Result<T, RecvError> {
-        if let Some(val) = self.buf.pop_front() {
+        if let Some(val) = self.buf.get_mut().pop_front() {
             return Ok(val);
         }
 
@@ -131,78 +758,1118 @@ impl<T> Receiver<T> {
         // condition is not met. The OS does not guarantee that `CondVar::wait`
         // will return only on a notify from another thread.
         loop {
-            match inner.queue.pop_front() {
+            match self.inner.take(&mut inner, self.buf.get_mut()) {
                 Some(val) => {
-                    // If the shared queue is non-empty, swap it with the local
-                    // buffer held by the `Receiver`, so future `recv` do not
-                    // need to acquire the mutex.
-                    if !inner.queue.is_empty() {
-                        std::mem::swap(&mut self.buf, &mut inner.queue);
-                    }
-
+                    self.inner.notify_senders(inner);
                     return Ok(val);
                 }
                 // Channel is closed.
-                None if inner.senders == 0 => return Err(RecvError {}),
+                None if inner.recv_closed() => return Err(RecvError {}),
                 None => {
                     // Blocks the current thread until `avail` is notified by
                     // another thread. It is given a `MutexGuard` so it can
                     // atomically release the lock, and reacquire it once
                     // notified.
+                    inner.blocked += 1;
                     inner = self.inner.avail.wait(inner).unwrap();
+                    inner.blocked -= 1;
                 }
             }
         }
     }
-}
-
-pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
-    let inner = Arc::new(Shared {
-        mu: Mutex::new(Inner {
-            queue: VecDeque::new(),
-            senders: 1,
-        }),
-        avail: Condvar::new(),
-    });
-
-    (
-        Sender {
-            inner: inner.clone(),
-        },
-        Receiver {
-            inner: inner.clone(),
-            buf: VecDeque::new(),
-        },
-    )
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_chan_ping_pong() {
-        let (tx, mut rx) = channel();
-        tx.send(42);
-        assert_eq!(rx.recv().unwrap(), 42)
+    /// Like `recv`, but gives up with `Timeout` if no value arrives within
+    /// `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_deadline(deadline),
+            // Too far in the future to represent, as good as waiting forever.
+            None => self.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        }
     }
 
-    #[test]
-    fn test_chan_closed_tx() {
-        // Drop the `Sender` immediately.
-        let (_, mut rx) = channel::<()>();
+    /// Like `recv`, but gives up with `Timeout` if no value arrives by
+    /// `deadline`.
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        if let Some(val) = self.buf.get_mut().pop_front() {
+            return Ok(val);
+        }
 
-        // Ensure this does not block the thread indefinitely.
-        assert!(rx.recv().is_err());
-    }
+        let mut inner = self.inner.mu.lock().unwrap();
+
+        loop {
+            match self.inner.take(&mut inner, self.buf.get_mut()) {
+                Some(val) => {
+                    self.inner.notify_senders(inner);
+                    return Ok(val);
+                }
+                None if inner.recv_closed() => return Err(RecvTimeoutError::Disconnected),
+                None => {
+                    // The remaining time is recomputed on every iteration: a
+                    // spurious wakeup must not restart the full timeout.
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+
+                    // Whether the wait timed out doesn't matter, a value that
+                    // arrived just in time is still taken on the next
+                    // iteration and the deadline is checked after that.
+                    inner.blocked += 1;
+                    inner = self
+                        .inner
+                        .avail
+                        .wait_timeout(inner, deadline - now)
+                        .unwrap()
+                        .0;
+                    inner.blocked -= 1;
+                }
+            }
+        }
+    }
+
+    /// Closes the channel for sending, for every receiver: sends fail from now
+    /// on, as if all receivers were gone, but values already sent can still be
+    /// received. Once they have been, receives fail instead of waiting.
+    ///
+    /// A rendezvous sender blocked until its value is received keeps waiting
+    /// for that.
+    pub fn close(&self) {
+        let mut inner = self.inner.mu.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+        inner.closed = true;
+        inner.wake_selectors();
+        let tasks = std::mem::take(&mut inner.tasks);
+
+        // Receivers waiting on an empty queue, and senders on a full one, all
+        // have to give up.
+        self.inner.notify_closed(inner);
+        self.inner.avail.notify_all();
+        for (_, task) in tasks {
+            task.wake();
+        }
+    }
+
+    /// The number of `Sender`s and `SyncSender`s currently alive. Once it is 0,
+    /// it stays 0, and receives fail as soon as the queue is empty.
+    pub fn sender_count(&self) -> usize {
+        self.inner.mu.lock().unwrap().senders
+    }
+
+    /// Takes every value currently in the channel, without blocking, locking
+    /// the shared queue at most once.
+    pub fn drain(&mut self) -> vec_deque::IntoIter<T> {
+        let mut vals = std::mem::take(self.buf.get_mut());
+
+        let mut inner = self.inner.mu.lock().unwrap();
+        if !inner.queue.is_empty() {
+            inner.received += inner.queue.len();
+            inner.used = 0;
+            if vals.is_empty() {
+                std::mem::swap(&mut vals, &mut inner.queue);
+            } else {
+                vals.append(&mut inner.queue);
+            }
+            self.inner.notify_senders(inner);
+        }
+
+        vals.into_iter()
+    }
+
+    /// Blocks until a value is available, then moves up to `limit` values into
+    /// `buf` with a single lock acquisition.
+    ///
+    /// Returns how many values were moved, which is only 0 once the channel is
+    /// disconnected (or if `limit` is 0).
+    pub fn recv_many(&mut self, buf: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+
+        // Already received, and sent before anything in the shared queue.
+        let local = self.buf.get_mut();
+        if !local.is_empty() {
+            let n = limit.min(local.len());
+            buf.extend(local.drain(..n));
+            return n;
+        }
+
+        let mut inner = self.inner.mu.lock().unwrap();
+        loop {
+            if !inner.queue.is_empty() {
+                let n = limit.min(inner.queue.len());
+                let inner_ref = &mut *inner;
+                for val in inner_ref.queue.drain(..n) {
+                    inner_ref.used -= self.inner.size_of(&val);
+                    buf.push(val);
+                }
+                inner.received += n;
+                self.inner.notify_senders(inner);
+                return n;
+            }
+            if inner.recv_closed() {
+                return 0;
+            }
+
+            inner.blocked += 1;
+            inner = self.inner.avail.wait(inner).unwrap();
+            inner.blocked -= 1;
+        }
+    }
+
+    /// Like `recv`, but waits by returning `Poll::Pending` instead of blocking
+    /// the thread, for use from `async` code.
+    ///
+    /// The future registers its task's `Waker` with the channel, and each send
+    /// wakes one registered task. Dropping the future before it completes
+    /// unregisters it, so a cancelled receive never swallows a wakeup that
+    /// another task needed.
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T> {
+        // A receive left pending by `poll_next` would otherwise keep taking
+        // wakeups meant for this one.
+        if let Some(id) = self.task.take() {
+            self.cancel_task(id);
+        }
+        RecvFuture { rx: self, id: None }
+    }
+
+    /// Receives a value if one is waiting, or registers the task to be woken
+    /// for one otherwise, under `id`. Shared by `RecvFuture` and `poll_next`.
+    fn poll_recv(
+        &mut self,
+        id: &mut Option<usize>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<T, RecvError>> {
+        // Nothing can be buffered while registered: the buffer only fills when
+        // this receiver takes a value.
+        if let Some(val) = self.buf.get_mut().pop_front() {
+            return Poll::Ready(Ok(val));
+        }
+
+        let shared = Arc::clone(&self.inner);
+        let mut inner = shared.mu.lock().unwrap();
+        match shared.take(&mut inner, self.buf.get_mut()) {
+            Some(val) => {
+                unregister(id, &mut inner);
+                shared.notify_senders(inner);
+                Poll::Ready(Ok(val))
+            }
+            None if inner.recv_closed() => {
+                unregister(id, &mut inner);
+                Poll::Ready(Err(RecvError {}))
+            }
+            None => {
+                let id = *id.get_or_insert_with(|| NEXT_TASK.fetch_add(1, Ordering::Relaxed));
+                // The task may have moved to another `Waker` since the last
+                // poll, or have been woken and unregistered by a send.
+                match inner.tasks.iter_mut().find(|(task, _)| *task == id) {
+                    Some((_, waker)) => waker.clone_from(cx.waker()),
+                    None => inner.tasks.push((id, cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Unregisters a receive that won't be polled again.
+    fn cancel_task(&self, id: usize) {
+        let mut inner = self.inner.mu.lock().unwrap();
+        let registered = inner.tasks.len();
+        inner.tasks.retain(|&(task, _)| task != id);
+
+        // Not registered anymore means a send already woke us for its value.
+        // We won't take it, so the wakeup goes to the next task in line.
+        let task = if inner.tasks.len() == registered && !inner.queue.is_empty() {
+            inner.take_task()
+        } else {
+            None
+        };
+        drop(inner);
+        if let Some(task) = task {
+            task.wake();
+        }
+    }
+
+    /// Receives a value if one is waiting, without blocking.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        // Values in the local buffer were sent before anything in the shared
+        // queue, and don't need the lock.
+        if let Some(val) = self.buf.get_mut().pop_front() {
+            return Ok(val);
+        }
+
+        let mut inner = self.inner.mu.lock().unwrap();
+        match self.inner.take(&mut inner, self.buf.get_mut()) {
+            Some(val) => {
+                self.inner.notify_senders(inner);
+                Ok(val)
+            }
+            None if inner.recv_closed() => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+/// Future returned by `Receiver::recv_async`.
+pub struct RecvFuture<'a, T> {
+    rx: &'a mut Receiver<T>,
+    /// Set once the future has registered its `Waker`.
+    id: Option<usize>,
+}
+
+fn unregister<T>(id: &mut Option<usize>, inner: &mut Inner<T>) {
+    if let Some(id) = id.take() {
+        inner.tasks.retain(|&(task, _)| task != id);
+    }
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.rx.poll_recv(&mut this.id, cx)
+    }
+}
+
+impl<T> Drop for RecvFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.rx.cancel_task(id);
+        }
+    }
+}
+
+// The values in `buf` are never pinned, so neither is the receiver, whatever
+// `T` is.
+impl<T> Unpin for Receiver<T> {}
+
+/// A `Receiver` is also a stream of the values sent, which ends once the
+/// channel is disconnected. Waiting works as in `recv_async`, except that the
+/// registration lives in the receiver between polls, and is only dropped with
+/// it (or by the next `recv_async`).
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let mut id = this.task.take();
+        let poll = this.poll_recv(&mut id, cx);
+        this.task = id;
+        poll.map(Result::ok)
+    }
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    new(None, |_| 1)
+}
+
+/// Creates a bounded channel, whose `send` blocks while `cap` values are
+/// waiting to be received.
+///
+/// With a `cap` of 0 this is a rendezvous channel: every `send` blocks until
+/// the value is received.
+pub fn sync_channel<T>(cap: usize) -> (SyncSender<T>, Receiver<T>) {
+    let (sender, receiver) = new(Some(cap), |_| 1);
+    (SyncSender { sender }, receiver)
+}
+
+/// Creates a channel bounded by the total size of the values waiting in it,
+/// rather than by their number: `send` blocks while the new value's `size`
+/// would take the total over `budget`.
+///
+/// This keeps memory use in check when values vary a lot in size (e.g. byte
+/// buffers, with `Vec::len` as `size`). A value bigger than the whole `budget`
+/// is let through once the channel is empty, rather than never. `size` is
+/// called again when the value is received, and must give the same answer.
+///
+/// Panics if `budget` is 0.
+pub fn sized_channel<T>(budget: usize, size: fn(&T) -> usize) -> (SyncSender<T>, Receiver<T>) {
+    assert!(budget > 0, "a sized channel needs a budget of at least 1");
+
+    let (sender, receiver) = new(Some(budget), size);
+    (SyncSender { sender }, receiver)
+}
+
+fn new<T>(cap: Option<usize>, size: fn(&T) -> usize) -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Shared {
+        mu: Mutex::new(Inner {
+            queue: VecDeque::new(),
+            senders: 1,
+            received: 0,
+            receivers: 1,
+            selectors: Vec::new(),
+            closed: false,
+            used: 0,
+            blocked: 0,
+            tasks: Vec::new(),
+            send_waiters: VecDeque::new(),
+        }),
+        avail: Condvar::new(),
+        handed_over: Condvar::new(),
+        cap,
+        size,
+    });
+
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver {
+            inner: inner.clone(),
+            buf: Cell::new(VecDeque::new()),
+            task: None,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_chan_ping_pong() {
+        let (tx, mut rx) = channel();
+        tx.send(42).unwrap();
+        assert_eq!(rx.recv().unwrap(), 42)
+    }
+
+    #[test]
+    fn test_chan_closed_tx() {
+        // Drop the `Sender` immediately.
+        let (_, mut rx) = channel::<()>();
+
+        // Ensure this does not block the thread indefinitely.
+        assert!(rx.recv().is_err());
+    }
 
     #[test]
     fn test_chan_closed_rx() {
         // Drop the `Receiver` immediately.
         let (tx, _) = channel();
 
-        // This implementation does not notify Senders when the channel is
-        // effectively closed.
-        tx.send(42);
+        // The value isn't queued up for nobody, it comes back.
+        assert_eq!(tx.send(42), Err(SendError(42)));
+    }
+
+    #[test]
+    fn test_sync_chan_blocks_when_full() {
+        let (tx, mut rx) = sync_channel(2);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+
+        thread::scope(|s| {
+            let sender = s.spawn(|| tx.send(3).unwrap());
+
+            // The third value doesn't fit until one is received.
+            thread::sleep(Duration::from_millis(20));
+            assert!(!sender.is_finished());
+
+            assert_eq!(rx.recv().unwrap(), 1);
+            sender.join().unwrap();
+        });
+
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(rx.recv().unwrap(), 3);
+        drop(tx);
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn test_sync_chan_rendezvous() {
+        let (tx, mut rx) = sync_channel(0);
+
+        thread::scope(|s| {
+            let sender = s.spawn(move || {
+                tx.send(1).unwrap();
+                tx.send(2).unwrap();
+            });
+
+            // Even the first value can't be left in the channel.
+            thread::sleep(Duration::from_millis(20));
+            assert!(!sender.is_finished());
+
+            assert_eq!(rx.recv().unwrap(), 1);
+            assert_eq!(rx.recv().unwrap(), 2);
+            sender.join().unwrap();
+        });
+
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn test_chan_try_recv() {
+        let (tx, mut rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        assert_eq!(rx.try_recv(), Ok(1));
+        drop(tx);
+
+        // Buffered values are still delivered after the senders are gone.
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_sync_chan_try_recv_makes_room() {
+        let (tx, mut rx) = sync_channel(1);
+        tx.send(1).unwrap();
+
+        thread::scope(|s| {
+            let sender = s.spawn(|| tx.send(2).unwrap());
+
+            let mut received = Vec::new();
+            while received.len() < 2 {
+                match rx.try_recv() {
+                    Ok(val) => received.push(val),
+                    Err(TryRecvError::Empty) => thread::yield_now(),
+                    Err(TryRecvError::Disconnected) => unreachable!(),
+                }
+            }
+            assert_eq!(received, [1, 2]);
+            sender.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_chan_recv_timeout() {
+        let (tx, mut rx) = channel();
+
+        let start = Instant::now();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(1).unwrap();
+            });
+
+            // Arrives well within the deadline.
+            let deadline = Instant::now() + Duration::from_secs(10);
+            assert_eq!(rx.recv_deadline(deadline), Ok(1));
+        });
+
+        // A deadline in the past still returns what's already there.
+        tx.send(2).unwrap();
+        assert_eq!(rx.recv_deadline(start), Ok(2));
+    }
+
+    #[test]
+    fn test_chan_recv_timeout_disconnect() {
+        let (tx, mut rx) = channel::<()>();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                drop(tx);
+            });
+
+            // Woken by the last `Sender` going away, long before the timeout.
+            let start = Instant::now();
+            assert_eq!(
+                rx.recv_timeout(Duration::from_secs(10)),
+                Err(RecvTimeoutError::Disconnected)
+            );
+            assert!(start.elapsed() < Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    fn test_sync_chan_closed_rx_while_blocked() {
+        let (tx, rx) = sync_channel(1);
+        tx.send(1).unwrap();
+
+        let (rendezvous_tx, rendezvous_rx) = sync_channel(0);
+
+        thread::scope(|s| {
+            let full = s.spawn(|| tx.send(2));
+            let unreceived = s.spawn(|| rendezvous_tx.send(3));
+
+            thread::sleep(Duration::from_millis(20));
+            drop(rx);
+            drop(rendezvous_rx);
+
+            assert_eq!(full.join().unwrap(), Err(SendError(2)));
+            assert_eq!(unreceived.join().unwrap(), Err(SendError(3)));
+        });
+    }
+
+    #[test]
+    fn test_chan_mpmc() {
+        let (tx, rx) = channel();
+
+        let received: Vec<i32> = thread::scope(|s| {
+            let receivers: Vec<_> = (0..3)
+                .map(|_| {
+                    let mut rx = rx.clone();
+                    s.spawn(move || {
+                        let mut received = Vec::new();
+                        while let Ok(val) = rx.recv() {
+                            received.push(val);
+                        }
+                        received
+                    })
+                })
+                .collect();
+            drop(rx);
+
+            for i in 0..1000 {
+                tx.send(i).unwrap();
+            }
+            drop(tx);
+
+            receivers
+                .into_iter()
+                .flat_map(|r| r.join().unwrap())
+                .collect()
+        });
+
+        // Every value went to exactly one receiver.
+        let mut received = received;
+        received.sort();
+        assert_eq!(received, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_chan_clone_returns_buffered() {
+        let (tx, mut rx) = channel();
+        for i in 1..=3 {
+            tx.send(i).unwrap();
+        }
+
+        // Moves 2 and 3 into `rx`'s local buffer.
+        assert_eq!(rx.recv().unwrap(), 1);
+
+        let mut rx2 = rx.clone();
+        assert_eq!(rx2.try_recv(), Ok(2));
+        drop(rx2);
+
+        tx.send(4).unwrap();
+        assert_eq!(rx.try_recv(), Ok(3));
+        assert_eq!(rx.try_recv(), Ok(4));
+    }
+
+    #[test]
+    fn test_select_blocks_until_ready() {
+        let (tx1, mut rx1) = channel::<i32>();
+        let (tx2, mut rx2) = sync_channel::<&str>(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                tx2.send("ready").unwrap();
+            });
+
+            let got = crate::select! {
+                recv(rx1) -> msg => format!("rx1: {msg:?}"),
+                recv(rx2) -> msg => {
+                    format!("rx2: {}", msg.unwrap())
+                }
+            };
+            assert_eq!(got, "rx2: ready");
+        });
+
+        // The waiter unregistered itself from both channels.
+        assert!(rx1.inner.mu.lock().unwrap().selectors.is_empty());
+        assert!(rx2.inner.mu.lock().unwrap().selectors.is_empty());
+        drop(tx1);
+    }
+
+    #[test]
+    fn test_select_loop_until_disconnected() {
+        let (tx1, mut rx1) = channel();
+        let (tx2, mut rx2) = channel();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..10 {
+                    tx1.send(i).unwrap();
+                    tx2.send(i * 10).unwrap();
+                }
+            });
+
+            let mut sum = 0;
+            loop {
+                crate::select! {
+                    recv(rx1) -> msg => match msg {
+                        Ok(v) => sum += v,
+                        // A disconnected receiver stays ready, so stop
+                        // selecting on it. `break` leaves the caller's loop.
+                        Err(_) => break,
+                    },
+                    recv(rx2) -> msg => sum += msg.unwrap(),
+                }
+            }
+            while let Ok(v) = rx2.recv() {
+                sum += v;
+            }
+            assert_eq!(sum, 45 + 450);
+        });
+    }
+
+    /// Counts its wakeups, or unparks a thread.
+    struct TestWaker {
+        woken: AtomicUsize,
+        thread: thread::Thread,
+    }
+
+    impl std::task::Wake for TestWaker {
+        fn wake(self: Arc<Self>) {
+            self.woken.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    fn test_waker() -> (Arc<TestWaker>, Waker) {
+        let waker = Arc::new(TestWaker {
+            woken: AtomicUsize::new(0),
+            thread: thread::current(),
+        });
+        (Arc::clone(&waker), Waker::from(waker))
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let (_, waker) = test_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = std::pin::pin!(fut);
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(val) => return val,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_chan_recv_async() {
+        let (tx, mut rx) = sync_channel(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                tx.send(1).unwrap();
+                tx.send(2).unwrap();
+                drop(tx);
+            });
+
+            let got = block_on(async {
+                let a = rx.recv_async().await.unwrap();
+                let b = rx.recv_async().await.unwrap();
+                (a, b, rx.recv_async().await.is_err())
+            });
+            assert_eq!(got, (1, 2, true));
+        });
+    }
+
+    #[test]
+    fn test_chan_recv_async_cancelled() {
+        let (tx, mut rx1) = channel();
+        let mut rx2 = rx1.clone();
+        let ((woken1, waker1), (woken2, waker2)) = (test_waker(), test_waker());
+
+        let mut fut1 = Box::pin(rx1.recv_async());
+        let mut fut2 = Box::pin(rx2.recv_async());
+        assert!(
+            fut1.as_mut()
+                .poll(&mut Context::from_waker(&waker1))
+                .is_pending()
+        );
+        assert!(
+            fut2.as_mut()
+                .poll(&mut Context::from_waker(&waker2))
+                .is_pending()
+        );
+
+        // The first registered is woken, but gives up on the value: the wakeup
+        // moves on to the other future.
+        tx.send(1).unwrap();
+        assert_eq!(woken1.woken.load(Ordering::SeqCst), 1);
+        assert_eq!(woken2.woken.load(Ordering::SeqCst), 0);
+        drop(fut1);
+        assert_eq!(woken2.woken.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            fut2.as_mut().poll(&mut Context::from_waker(&waker2)),
+            Poll::Ready(Ok(1))
+        );
+        drop(fut2);
+
+        // A cancelled future is out of line entirely.
+        let mut fut2 = Box::pin(rx2.recv_async());
+        let mut fut1 = Box::pin(rx1.recv_async());
+        assert!(
+            fut2.as_mut()
+                .poll(&mut Context::from_waker(&waker2))
+                .is_pending()
+        );
+        assert!(
+            fut1.as_mut()
+                .poll(&mut Context::from_waker(&waker1))
+                .is_pending()
+        );
+        drop(fut2);
+        tx.send(2).unwrap();
+        assert_eq!(woken1.woken.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            fut1.as_mut().poll(&mut Context::from_waker(&waker1)),
+            Poll::Ready(Ok(2))
+        );
+        drop(fut1);
+        assert!(rx1.inner.mu.lock().unwrap().tasks.is_empty());
+    }
+
+    #[test]
+    fn test_sync_chan_try_send() {
+        let (tx, mut rx) = sync_channel(1);
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(tx.try_send(3), Ok(()));
+
+        drop(rx);
+        assert_eq!(tx.try_send(4).unwrap_err().into_inner(), 4);
+        assert_eq!(tx.try_send(5), Err(TrySendError::Disconnected(5)));
+    }
+
+    #[test]
+    fn test_sync_chan_try_send_rendezvous() {
+        let (tx, mut rx) = sync_channel(0);
+        // Nobody is waiting to receive.
+        assert_eq!(tx.try_send(1), Err(TrySendError::Full(1)));
+
+        thread::scope(|s| {
+            let handle = s.spawn(|| rx.recv());
+            loop {
+                match tx.try_send(2) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(_)) => thread::yield_now(),
+                    Err(err) => panic!("{err}"),
+                }
+            }
+            assert_eq!(handle.join().unwrap(), Ok(2));
+        });
+    }
+
+    #[test]
+    fn test_chan_drain() {
+        let (tx, mut rx) = sync_channel(3);
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+
+        // Draining makes room for all of `cap` again.
+        assert_eq!(rx.drain().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(rx.drain().next(), None);
+        for i in 3..6 {
+            tx.try_send(i).unwrap();
+        }
+        assert_eq!(rx.recv(), Ok(3));
+        assert_eq!(rx.drain().collect::<Vec<_>>(), [4, 5]);
+    }
+
+    #[test]
+    fn test_chan_recv_many() {
+        let (tx, mut rx) = channel();
+        let mut buf = Vec::new();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                for i in 0..5 {
+                    tx.send(i).unwrap();
+                }
+            });
+
+            // Blocks for the first value, though the rest may not be sent yet.
+            let mut n = rx.recv_many(&mut buf, 3);
+            assert!((1..=3).contains(&n));
+            while buf.len() < 5 {
+                n = rx.recv_many(&mut buf, 3);
+                assert!(n > 0);
+            }
+        });
+        assert_eq!(buf, [0, 1, 2, 3, 4]);
+
+        drop(tx);
+        assert_eq!(rx.recv_many(&mut buf, 3), 0);
+    }
+
+    #[test]
+    fn test_chan_counts() {
+        let (tx, rx) = channel::<()>();
+        let tx2 = tx.clone();
+        let rx2 = rx.clone();
+        assert_eq!((rx.sender_count(), tx.receiver_count()), (2, 2));
+
+        drop(tx2);
+        drop(rx);
+        assert_eq!((rx2.sender_count(), tx.receiver_count()), (1, 1));
+        assert!(!tx.is_disconnected());
+
+        drop(rx2);
+        assert!(tx.is_disconnected());
+    }
+
+    #[test]
+    fn test_sync_chan_producer_stops_early() {
+        let (tx, mut rx) = sync_channel(1);
+
+        let producer = thread::spawn(move || {
+            let mut sent = 0;
+            while !tx.is_disconnected() {
+                if tx.try_send(sent).is_ok() {
+                    sent += 1;
+                }
+                thread::yield_now();
+            }
+            sent
+        });
+
+        assert_eq!(rx.recv(), Ok(0));
+        assert_eq!(rx.recv(), Ok(1));
+        drop(rx);
+        assert!(producer.join().unwrap() >= 2);
+    }
+
+    #[test]
+    fn test_chan_weak_sender() {
+        let (tx, mut rx) = channel();
+        let weak = tx.downgrade();
+
+        weak.upgrade().unwrap().send(1).unwrap();
+        assert_eq!(rx.sender_count(), 1);
+        assert_eq!(rx.recv(), Ok(1));
+
+        // Weak senders don't keep the channel open, nor reopen it.
+        drop(tx);
+        assert_eq!(rx.recv(), Err(RecvError {}));
+        assert!(weak.clone().upgrade().is_none());
+
+        drop(rx);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_chan_close() {
+        let (tx, mut rx) = channel();
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+
+        rx.close();
+        assert!(tx.is_disconnected());
+        assert_eq!(tx.send(3), Err(SendError(3)));
+
+        // Values sent before closing are still delivered, then it's over even
+        // though `tx` is alive.
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv(), Err(RecvError {}));
+    }
+
+    #[test]
+    fn test_sync_chan_close_wakes_blocked() {
+        let (tx, mut rx) = sync_channel(1);
+        let mut rx2 = rx.clone();
+
+        thread::scope(|s| {
+            tx.send(1).unwrap();
+            let sender = s.spawn(|| tx.send(2));
+
+            thread::sleep(Duration::from_millis(20));
+            rx.close();
+            assert_eq!(sender.join().unwrap(), Err(SendError(2)));
+        });
+
+        assert_eq!(rx2.recv(), Ok(1));
+        thread::scope(|s| {
+            let receiver = s.spawn(|| rx2.recv());
+            assert_eq!(receiver.join().unwrap(), Err(RecvError {}));
+        });
+    }
+
+    #[test]
+    fn test_select_dynamic_set() {
+        let (senders, mut receivers): (Vec<_>, Vec<_>) = (0..4).map(|_| channel()).unzip();
+        let mut received = Vec::new();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for (i, tx) in senders.iter().enumerate().rev() {
+                    thread::sleep(Duration::from_millis(5));
+                    tx.send(i).unwrap();
+                }
+            });
+
+            while received.len() < 4 {
+                let mut sel = select::Select::new();
+                for rx in &receivers {
+                    sel.recv(rx);
+                }
+                let index = sel.ready();
+                drop(sel);
+
+                // Only this thread receives, so a ready receiver that isn't
+                // disconnected has a value for us.
+                received.push(receivers[index].try_recv().unwrap());
+            }
+        });
+        assert_eq!(received, [3, 2, 1, 0]);
+
+        // Disconnected receivers are ready too, and are taken in turns.
+        let (tx1, rx1) = channel::<()>();
+        let (tx2, rx2) = channel::<()>();
+        drop((tx1, tx2));
+        let mut sel = select::Select::new();
+        sel.recv(&rx1);
+        sel.recv(&rx2);
+        assert_eq!([sel.ready(), sel.ready(), sel.ready()], [0, 1, 0]);
+    }
+
+    #[test]
+    fn test_chan_send_all() {
+        let (tx, mut rx) = channel();
+        let tx2 = tx.clone();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    tx2.send(-1).unwrap();
+                }
+            });
+            tx.send_all(0..100).unwrap();
+        });
+
+        // The batch isn't interleaved with the other sender's values: it's
+        // all in one run, wherever that run starts.
+        let all: Vec<_> = rx.drain().collect();
+        assert_eq!(all.len(), 200);
+        let start = all.iter().position(|&x| x == 0).unwrap();
+        assert_eq!(all[start..start + 100], (0..100).collect::<Vec<_>>());
+
+        drop(rx);
+        assert_eq!(tx.send_all([1, 2]), Err(SendError(vec![1, 2])));
+    }
+
+    /// Timing only, compares a looped `send` with `send_all` under contention
+    /// from a busy receiver. Run with
+    /// `cargo test --release -- --ignored --nocapture bench_chan_send_all`.
+    #[test]
+    #[ignore]
+    fn bench_chan_send_all() {
+        const SENDERS: usize = 4;
+        const PER_SENDER: usize = 100_000;
+        const BATCH: usize = 64;
+
+        fn run(batched: bool) -> Duration {
+            let (tx, mut rx) = channel();
+            let start = Instant::now();
+            thread::scope(|s| {
+                for _ in 0..SENDERS {
+                    let tx = tx.clone();
+                    s.spawn(move || {
+                        for chunk in (0..PER_SENDER).collect::<Vec<_>>().chunks(BATCH) {
+                            if batched {
+                                tx.send_all(chunk.iter().copied()).unwrap();
+                            } else {
+                                for &i in chunk {
+                                    tx.send(i).unwrap();
+                                }
+                            }
+                        }
+                    });
+                }
+                drop(tx);
+
+                let mut received = 0;
+                while rx.recv().is_ok() {
+                    received += 1;
+                }
+                assert_eq!(received, SENDERS * PER_SENDER);
+            });
+            start.elapsed()
+        }
+
+        let looped = run(false);
+        let batched = run(true);
+        eprintln!("looped send: {looped:?}, send_all in batches of {BATCH}: {batched:?}");
+    }
+
+    #[test]
+    fn test_sized_chan_budget() {
+        let (tx, mut rx) = sized_channel(10, Vec::<u8>::len);
+
+        tx.try_send(vec![0; 6]).unwrap();
+        assert_eq!(tx.try_send(vec![1; 5]), Err(TrySendError::Full(vec![1; 5])));
+        tx.try_send(vec![2; 4]).unwrap();
+        assert!(tx.try_send(vec![]).is_ok());
+
+        assert_eq!(rx.recv().unwrap().len(), 6);
+        tx.try_send(vec![3; 5]).unwrap();
+        assert_eq!(rx.drain().map(|v| v.len()).collect::<Vec<_>>(), [4, 0, 5]);
+
+        // Too big for the budget, but alone in the channel.
+        tx.try_send(vec![4; 100]).unwrap();
+        assert!(tx.try_send(vec![5; 1]).is_err());
+    }
+
+    #[test]
+    fn test_sized_chan_blocks_sender() {
+        let (tx, mut rx) = sized_channel(8, String::len);
+
+        thread::scope(|s| {
+            let sender = s.spawn(move || {
+                for word in ["abcd", "efgh", "ijklmnop", "q"] {
+                    tx.send(word.to_string()).unwrap();
+                }
+            });
+
+            thread::sleep(Duration::from_millis(20));
+            // The first two fill the budget, the third waits for both.
+            assert!(!sender.is_finished());
+            let mut received = String::new();
+            while let Ok(word) = rx.recv() {
+                received += &word;
+            }
+            assert_eq!(received, "abcdefghijklmnopq");
+        });
+    }
+
+    #[test]
+    fn test_sync_chan_senders_fifo() {
+        let (tx, mut rx) = sync_channel(1);
+        tx.send(0).unwrap();
+
+        thread::scope(|s| {
+            // Blocks first, so it must be the first to get room, however eager
+            // the other sender is.
+            let victim = tx.clone();
+            s.spawn(move || victim.send(-1).unwrap());
+            thread::sleep(Duration::from_millis(20));
+            s.spawn(move || {
+                for i in 1..=100 {
+                    tx.send(i).unwrap();
+                }
+            });
+            thread::sleep(Duration::from_millis(20));
+
+            assert_eq!(rx.recv(), Ok(0));
+            assert_eq!(rx.recv(), Ok(-1));
+            for i in 1..=100 {
+                assert_eq!(rx.recv(), Ok(i));
+            }
+            assert_eq!(rx.recv(), Err(RecvError {}));
+        });
     }
 }
@@ -0,0 +1,453 @@
+//! A bounded MPMC channel over a preallocated ring of slots (Dmitry Vyukov's
+//! bounded queue, as used by crossbeam), with no `Mutex` and no `VecDeque`.
+//!
+//! `tail` is the position of the next send and `head` of the next receive.
+//! A position is a slot index in the low bits plus a lap count above them:
+//! `one_lap` is the smallest power of two greater than `cap`, and moving past
+//! the last slot moves to index 0 of the next lap. Every slot carries a stamp
+//! saying what it is ready for:
+//!
+//! - `stamp == pos`: empty, ready for the send at `pos`.
+//! - `stamp == pos + 1`: full, ready for the receive at `pos`.
+//!
+//! (Counting positions up by one and using slot `pos % cap` instead would make
+//! those two cases collide for `cap == 1`: "full at 0" and "empty at 1" are
+//! the same stamp. Keeping the index below `one_lap` leaves a gap.)
+//!
+//! A sender claims a position by CASing `tail` forward, but only once the
+//! slot's stamp says it's empty. It then writes the value and publishes it by
+//! storing `pos + 1` (`Release`). A receiver does the same with `head`, and
+//! frees the slot for the next lap by storing `pos + one_lap`. The stamps are what
+//! keep a fast sender from overwriting a slot whose previous value a slow
+//! receiver is still reading, and they make "full" and "empty" visible without
+//! comparing `head` and `tail`, which can't be read together atomically.
+//!
+//! Blocking uses two futex words, `sent` and `received`, bumped after every
+//! send and receive. A thread that finds the channel empty (or full) announces
+//! itself in a waiter count, reads the word, tries once more, and only then
+//! waits for the word to change. The other side bumps the word before checking
+//! the waiter count, so either the waiter's last try sees the value (or free
+//! slot), or the other side sees the waiter and wakes it.
+//!
+//! Laps wrap around, which is fine: stamps and positions are only compared for
+//! equality, and a slot can't fall behind by a whole `usize` worth of laps.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use super::{RecvError, SendError, TryRecvError, TrySendError};
+use crate::atomics::{Backoff, CachePadded, futex};
+
+struct Slot<T> {
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct Shared<T> {
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+    slots: Box<[Slot<T>]>,
+    /// The smallest power of two greater than the capacity, see the module
+    /// docs.
+    one_lap: usize,
+    /// Futex word bumped after every send, and when the last sender leaves.
+    sent: AtomicU32,
+    /// Receivers blocked (or about to block) on `sent`.
+    recv_waiters: AtomicUsize,
+    /// Futex word bumped after every receive, and when the last receiver
+    /// leaves.
+    received: AtomicU32,
+    /// Senders blocked (or about to block) on `received`.
+    send_waiters: AtomicUsize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+}
+
+// SAFETY: A value is written by the sender that claimed its slot and read by
+// the receiver that claimed it after that, the stamps order the two.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    /// The slot `pos` refers to, and the position after it.
+    fn locate(&self, pos: usize) -> (&Slot<T>, usize) {
+        let index = pos & (self.one_lap - 1);
+        let lap = pos & !(self.one_lap - 1);
+
+        let next = if index + 1 < self.slots.len() {
+            pos + 1
+        } else {
+            lap.wrapping_add(self.one_lap)
+        };
+        (&self.slots[index], next)
+    }
+
+    fn try_push(&self, val: T) -> Result<(), T> {
+        let mut backoff = Backoff::new();
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let (slot, next) = self.locate(tail);
+            // `Acquire` pairs with the receiver's store freeing the slot, its
+            // read of the previous value happens before our write.
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == tail {
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    next,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: Winning the CAS gave us this slot until we
+                        // publish it below.
+                        unsafe { (*slot.value.get()).write(val) };
+                        slot.stamp.store(tail + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => {
+                        tail = current;
+                        backoff.spin();
+                    }
+                }
+            } else if stamp.wrapping_add(self.one_lap) == tail + 1 {
+                // The slot still holds the value from the previous lap. Unless
+                // `head` moved on in the meantime (and that receive just hasn't
+                // freed it yet), the channel is full.
+                if self.head.load(Ordering::Relaxed).wrapping_add(self.one_lap) == tail {
+                    return Err(val);
+                }
+                backoff.spin();
+                tail = self.tail.load(Ordering::Relaxed);
+            } else {
+                // Another sender claimed `tail` already.
+                backoff.snooze();
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn try_pop(&self) -> Option<T> {
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let (slot, next) = self.locate(head);
+            // `Acquire` pairs with the sender's `Release`, so the value is
+            // visible.
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == head + 1 {
+                match self.head.compare_exchange_weak(
+                    head,
+                    next,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: Winning the CAS gave us this slot, and the
+                        // stamp says its value was published.
+                        let val = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.stamp
+                            .store(head.wrapping_add(self.one_lap), Ordering::Release);
+                        return Some(val);
+                    }
+                    Err(current) => {
+                        head = current;
+                        backoff.spin();
+                    }
+                }
+            } else if stamp == head {
+                // Not sent yet. Unless a send claimed it and is still writing,
+                // the channel is empty.
+                if self.tail.load(Ordering::Relaxed) == head {
+                    return None;
+                }
+                backoff.spin();
+                head = self.head.load(Ordering::Relaxed);
+            } else {
+                // Another receiver claimed `head` already.
+                backoff.snooze();
+                head = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Bumps `word` and wakes a thread waiting on it, if there is one.
+fn notify(word: &AtomicU32, waiters: &AtomicUsize) {
+    // `SeqCst` on both sides, see the module docs.
+    word.fetch_add(1, Ordering::SeqCst);
+    if waiters.load(Ordering::SeqCst) > 0 {
+        futex::wake_one(word);
+    }
+}
+
+/// Blocks until `word` changes, unless `attempt` succeeds after announcing
+/// ourselves in `waiters`. May return `None` spuriously.
+fn wait<R>(
+    word: &AtomicU32,
+    waiters: &AtomicUsize,
+    attempt: impl FnOnce() -> Option<R>,
+) -> Option<R> {
+    waiters.fetch_add(1, Ordering::SeqCst);
+    let seen = word.load(Ordering::SeqCst);
+    let res = attempt();
+    if res.is_none() {
+        futex::wait(word, seen);
+    }
+    waiters.fetch_sub(1, Ordering::Relaxed);
+    res
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Every send completed before the last handle was dropped, so the
+        // values left are exactly those between `head` and `tail`.
+        while self.try_pop().is_some() {}
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `val`, blocking while the channel is full. Gives `val` back if
+    /// every `Receiver` is gone, including while blocked.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let mut val = Some(val);
+        let attempt = |val: &mut Option<T>| match self.try_send(val.take().unwrap()) {
+            Ok(()) => Some(Ok(())),
+            Err(TrySendError::Disconnected(v)) => Some(Err(SendError(v))),
+            Err(TrySendError::Full(v)) => {
+                *val = Some(v);
+                None
+            }
+        };
+
+        loop {
+            if let Some(res) = attempt(&mut val) {
+                return res;
+            }
+            let shared = &*self.shared;
+            if let Some(res) = wait(&shared.received, &shared.send_waiters, || attempt(&mut val)) {
+                return res;
+            }
+        }
+    }
+
+    /// Sends `val` if there is room, without blocking.
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        if shared.receivers.load(Ordering::Relaxed) == 0 {
+            return Err(TrySendError::Disconnected(val));
+        }
+
+        shared.try_push(val).map_err(TrySendError::Full)?;
+        notify(&shared.sent, &shared.recv_waiters);
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // `Release` so a receiver that sees 0 also sees every value sent.
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.sent.fetch_add(1, Ordering::SeqCst);
+            futex::wake_all(&self.shared.sent);
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks until a value is available, or fails once all senders are gone
+    /// and the channel is empty.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let attempt = || match self.try_recv() {
+            Ok(val) => Some(Ok(val)),
+            Err(TryRecvError::Disconnected) => Some(Err(RecvError {})),
+            Err(TryRecvError::Empty) => None,
+        };
+
+        loop {
+            if let Some(res) = attempt() {
+                return res;
+            }
+            let shared = &*self.shared;
+            if let Some(res) = wait(&shared.sent, &shared.recv_waiters, attempt) {
+                return res;
+            }
+        }
+    }
+
+    /// Receives a value if one is waiting, without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+        if let Some(val) = shared.try_pop() {
+            notify(&shared.received, &shared.send_waiters);
+            return Ok(val);
+        }
+
+        // The last sender may have sent a value just before leaving, which
+        // the `Acquire` here makes visible to another try.
+        if shared.senders.load(Ordering::Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        match shared.try_pop() {
+            Some(val) => {
+                notify(&shared.received, &shared.send_waiters);
+                Ok(val)
+            }
+            None => Err(TryRecvError::Disconnected),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.shared.receivers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.shared.received.fetch_add(1, Ordering::SeqCst);
+            futex::wake_all(&self.shared.received);
+        }
+    }
+}
+
+/// Creates a channel holding up to `cap` values, all allocated up front.
+///
+/// Panics if `cap` is 0, there is no rendezvous flavor.
+pub fn channel<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(cap > 0, "an array channel needs a capacity of at least 1");
+
+    let shared = Arc::new(Shared {
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+        slots: (0..cap)
+            .map(|i| Slot {
+                stamp: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect(),
+        one_lap: (cap + 1).next_power_of_two(),
+        sent: AtomicU32::new(0),
+        recv_waiters: AtomicUsize::new(0),
+        received: AtomicU32::new(0),
+        send_waiters: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_array_chan_full_and_empty() {
+        let (tx, rx) = channel(2);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // Several laps around the ring.
+        for i in 0..10 {
+            tx.try_send(2 * i).unwrap();
+            tx.try_send(2 * i + 1).unwrap();
+            assert_eq!(tx.try_send(-1), Err(TrySendError::Full(-1)));
+            assert_eq!(rx.try_recv(), Ok(2 * i));
+            assert_eq!(rx.try_recv(), Ok(2 * i + 1));
+        }
+
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_array_chan_mpmc_stress() {
+        const PER_SENDER: usize = 10_000;
+        let (tx, rx) = channel(4);
+
+        let total: usize = thread::scope(|s| {
+            for _ in 0..4 {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for i in 0..PER_SENDER {
+                        tx.send(i).unwrap();
+                    }
+                });
+            }
+            drop(tx);
+
+            let receivers: Vec<_> = (0..4)
+                .map(|_| {
+                    let rx = rx.clone();
+                    s.spawn(move || {
+                        let mut sum = 0;
+                        while let Ok(i) = rx.recv() {
+                            sum += i;
+                        }
+                        sum
+                    })
+                })
+                .collect();
+            receivers.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        assert_eq!(total, 4 * (PER_SENDER * (PER_SENDER - 1) / 2));
+    }
+
+    #[test]
+    fn test_array_chan_disconnect_wakes_blocked() {
+        let (tx, rx) = channel(1);
+        tx.send(Arc::new(0)).unwrap();
+
+        // A sender blocked on a full channel gets its value back.
+        let val = Arc::new(1);
+        let blocked = {
+            let val = Arc::clone(&val);
+            thread::spawn(move || tx.send(val).unwrap_err().0)
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(rx);
+        assert!(Arc::ptr_eq(&blocked.join().unwrap(), &val));
+
+        // As does a receiver blocked on an empty one, and unreceived values are
+        // dropped with the channel.
+        let (tx, rx) = channel::<Arc<i32>>(1);
+        let blocked = thread::spawn(move || rx.recv());
+        thread::sleep(Duration::from_millis(20));
+        drop(tx);
+        assert!(blocked.join().unwrap().is_err());
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+}
@@ -0,0 +1,289 @@
+//! The "Atomic Queue + thread::park + Thread::unpark" flavor: an unbounded
+//! MPSC channel with no `Mutex` and no `Condvar`.
+//!
+//! Senders push onto a linked list with a CAS on its head. The receiver is the
+//! only consumer, so instead of popping nodes one at a time it swaps the whole
+//! list out and reverses it into a local buffer. A node is never read by
+//! anyone but the thread that unlinked it, which sidesteps both ABA and the
+//! reclamation problem of a general lock-free queue (see `lock_free`).
+//!
+//! When the list is empty the receiver parks. The lost wakeup race is the
+//! interesting part: a sender may push between the receiver finding the list
+//! empty and the receiver parking. The receiver therefore publishes its
+//! `Thread` in `waiter` (the waiting flag) *before* checking the list one last
+//! time, and a sender checks `waiter` *after* pushing. With both sides
+//! `SeqCst`, at least one of them sees the other: either the receiver finds
+//! the value, or the sender finds the receiver and unparks it. An unpark that
+//! arrives after the receiver already found the value just makes a later
+//! `park` return early, which the loop in `recv` tolerates.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::thread::{self, Thread};
+
+use super::{RecvError, SendError, TryRecvError};
+
+struct Node<T> {
+    value: T,
+    next: *mut Node<T>,
+}
+
+struct Shared<T> {
+    /// Most recently sent value first.
+    head: AtomicPtr<Node<T>>,
+    /// The parked (or about to park) receiver, boxed so it fits in an atomic
+    /// pointer. Whoever swaps it out owns the box.
+    waiter: AtomicPtr<Thread>,
+    senders: AtomicUsize,
+    /// Cleared when the `Receiver` is dropped, sends fail from then on.
+    receiver: AtomicBool,
+}
+
+// SAFETY: Values are moved in by `send` and out by `recv`, possibly on
+// different threads, but never shared. A `Thread` is `Send + Sync`.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    /// Unlinks everything sent so far, oldest first.
+    fn take_all(&self, buf: &mut VecDeque<T>) {
+        // Pairs with the CAS in `push`, so the values are visible.
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::SeqCst);
+        let start = buf.len();
+        while !node.is_null() {
+            // SAFETY: The swap above unlinked the list, so nobody else can
+            // reach its nodes.
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next;
+            buf.push_back(boxed.value);
+        }
+        // The list is newest first.
+        buf.make_contiguous()[start..].reverse();
+    }
+
+    fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value,
+            next: ptr::null_mut(),
+        }));
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: `node` isn't published until the CAS succeeds.
+            unsafe { (*node).next = head };
+            // `SeqCst` rather than `Release`, for the lost wakeup argument in
+            // the module docs.
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::SeqCst, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Unparks the receiver, if it is waiting.
+    fn wake(&self) {
+        let waiter = self.waiter.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !waiter.is_null() {
+            // SAFETY: Swapping it out made us its owner.
+            let thread = unsafe { Box::from_raw(waiter) };
+            thread.unpark();
+        }
+    }
+
+    /// Takes `waiter` back, if no sender got to it first.
+    fn unregister(&self) {
+        let waiter = self.waiter.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !waiter.is_null() {
+            // SAFETY: As in `wake`.
+            drop(unsafe { Box::from_raw(waiter) });
+        }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        // Values sent after the receiver's last `take_all`.
+        self.take_all(&mut VecDeque::new());
+        self.unregister();
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Never blocks. Fails if the `Receiver` is gone, though a value sent
+    /// while it is being dropped may be accepted and then dropped with the
+    /// channel.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        if !self.shared.receiver.load(Ordering::Relaxed) {
+            return Err(SendError(val));
+        }
+
+        self.shared.push(val);
+        self.shared.wake();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // `SeqCst` for the same reason as the push in `send`: the receiver
+        // checks `senders` after registering, we check `waiter` after this.
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.wake();
+        }
+    }
+}
+
+/// Not `Sync`: there is only ever one thread receiving, which is what lets it
+/// take the whole list at once.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    buf: Cell<VecDeque<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks until a value is available, or fails once all senders are gone
+    /// and every value has been received.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError {}),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let thread = Box::into_raw(Box::new(thread::current()));
+            self.shared.waiter.store(thread, Ordering::SeqCst);
+
+            // Anything sent (or any disconnect) after this check finds
+            // `waiter` set and unparks us.
+            if self.shared.head.load(Ordering::SeqCst).is_null()
+                && self.shared.senders.load(Ordering::SeqCst) != 0
+            {
+                thread::park();
+            }
+
+            // Either a sender already took it (and unparked us, or is about
+            // to), or this was a spurious wakeup and we take it back.
+            self.shared.unregister();
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let buf = self.buf.get_mut();
+        if let Some(val) = buf.pop_front() {
+            return Ok(val);
+        }
+
+        // Read before taking the list: the last sender pushed its values
+        // before it dropped, so they can't be missed.
+        let senders = self.shared.senders.load(Ordering::SeqCst);
+        self.shared.take_all(buf);
+        match buf.pop_front() {
+            Some(val) => Ok(val),
+            None if senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver.store(false, Ordering::Relaxed);
+    }
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        head: AtomicPtr::new(ptr::null_mut()),
+        waiter: AtomicPtr::new(ptr::null_mut()),
+        senders: AtomicUsize::new(1),
+        receiver: AtomicBool::new(true),
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver {
+            shared,
+            buf: Cell::new(VecDeque::new()),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_park_chan_order_across_senders() {
+        let (tx, mut rx) = channel();
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        tx.send((t, i)).unwrap();
+                    }
+                });
+            }
+            drop(tx);
+
+            // Values from any one sender arrive in the order they were sent.
+            let mut next = [0; 4];
+            while let Ok((t, i)) = rx.recv() {
+                assert_eq!(next[t], i);
+                next[t] += 1;
+            }
+            assert_eq!(next, [1000; 4]);
+        });
+    }
+
+    #[test]
+    fn test_park_chan_wakes_on_disconnect() {
+        let (tx, mut rx) = channel::<()>();
+
+        let handle = thread::spawn(move || rx.recv());
+        thread::sleep(Duration::from_millis(20));
+        drop(tx);
+
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_park_chan_closed_rx() {
+        let (tx, mut rx) = channel();
+        tx.send(Arc::new(1)).unwrap();
+        assert_eq!(rx.try_recv().map(|v| *v), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // Values still queued are dropped with the channel.
+        let val = Arc::new(2);
+        tx.send(Arc::clone(&val)).unwrap();
+        drop(rx);
+        assert!(tx.send(Arc::new(3)).is_err());
+        drop(tx);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+}
@@ -0,0 +1,316 @@
+//! Support for `select!`, blocking on several receivers at once.
+//!
+//! A thread can only block on one condvar at a time, so waiting on several
+//! channels needs a wakeup they can all deliver: the selecting thread registers
+//! the same `Unparker` with each channel, and every send (or disconnect) on any
+//! of them unparks it. The thread then checks every receiver again.
+//!
+//! Registering and then checking again before parking closes the race with a
+//! value sent in between the first check and the registration. A wakeup that
+//! arrives after the thread already found a value is harmless: the `Parker`
+//! keeps at most one token, and it is fresh for each select.
+//!
+//! `Select` is the same mechanism without the macro, for sets of channels that
+//! are only known at runtime.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Receiver, RecvError, Shared, TryRecvError};
+use crate::atomics::Parker;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A channel a `Waiter` can be removed from, whatever its value type.
+trait Registry {
+    fn unregister(&self, id: usize);
+}
+
+impl<T> Registry for Shared<T> {
+    fn unregister(&self, id: usize) {
+        let mut inner = self.mu.lock().unwrap();
+        inner.selectors.retain(|&(selector, _)| selector != id);
+    }
+}
+
+/// One blocked `select!`, registered with each of its channels while parked.
+// Only public for `select!`, like `try_recv`.
+#[doc(hidden)]
+pub struct Waiter<'a> {
+    id: usize,
+    parker: Parker,
+    registered: Vec<Arc<dyn Registry + 'a>>,
+}
+
+impl<'a> Waiter<'a> {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            parker: Parker::new(),
+            registered: Vec::new(),
+        }
+    }
+
+    pub fn register<T: 'a>(&mut self, rx: &Receiver<T>) {
+        let mut inner = rx.inner.mu.lock().unwrap();
+        inner.selectors.push((self.id, self.parker.unparker()));
+        drop(inner);
+
+        self.registered
+            .push(Arc::clone(&rx.inner) as Arc<dyn Registry + 'a>);
+    }
+
+    pub fn is_registered(&self) -> bool {
+        !self.registered.is_empty()
+    }
+
+    /// Blocks until a registered channel has something for us (or a stale
+    /// wakeup arrives), then unregisters from all of them.
+    pub fn park(&mut self) {
+        self.parker.park();
+        self.unregister_all();
+    }
+
+    pub fn unregister_all(&mut self) {
+        for shared in self.registered.drain(..) {
+            shared.unregister(self.id);
+        }
+    }
+}
+
+impl Default for Waiter<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.unregister_all();
+    }
+}
+
+/// `try_recv`, with a disconnected channel counting as ready.
+#[doc(hidden)]
+pub fn try_recv<T>(rx: &mut Receiver<T>) -> Option<Result<T, RecvError>> {
+    match rx.try_recv() {
+        Ok(val) => Some(Ok(val)),
+        Err(TryRecvError::Disconnected) => Some(Err(RecvError {})),
+        Err(TryRecvError::Empty) => None,
+    }
+}
+
+/// A receiver `Select` can wait on, whatever its value type.
+trait Handle<'a> {
+    /// Whether a receive wouldn't block: a value is waiting, or the channel is
+    /// disconnected.
+    fn is_ready(&self) -> bool;
+
+    fn register(&self, waiter: &mut Waiter<'a>);
+}
+
+impl<'a, T: 'a> Handle<'a> for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let buf = self.buf.take();
+        let buffered = !buf.is_empty();
+        self.buf.set(buf);
+        if buffered {
+            return true;
+        }
+
+        let inner = self.inner.mu.lock().unwrap();
+        !inner.queue.is_empty() || inner.recv_closed()
+    }
+
+    fn register(&self, waiter: &mut Waiter<'a>) {
+        waiter.register(self);
+    }
+}
+
+/// Waits on a dynamic set of receivers, like `select!` does on a fixed one.
+///
+/// Receivers are added with `recv`, which returns the index `ready` reports
+/// them by. Being ready means a receive won't block, not that it will succeed:
+/// with cloned receivers, another one may take the value first, so callers
+/// receive with `try_recv` and go back to waiting on `Empty`.
+///
+/// ```
+/// use crust_of_rust::channels::{TryRecvError, channel};
+/// use crust_of_rust::channels::select::Select;
+///
+/// let channels: Vec<_> = (0..3).map(|_| channel::<usize>()).collect();
+/// channels[2].0.send(42).unwrap();
+///
+/// let mut sel = Select::new();
+/// for (_, rx) in &channels {
+///     sel.recv(rx);
+/// }
+///
+/// let index = sel.ready();
+/// assert_eq!(index, 2);
+/// drop(sel);
+/// # let mut channels = channels;
+/// assert_eq!(channels[index].1.try_recv(), Ok(42));
+/// # assert_eq!(channels[index].1.try_recv(), Err(TryRecvError::Empty));
+/// ```
+pub struct Select<'a> {
+    handles: Vec<&'a dyn Handle<'a>>,
+    /// Where the next search for a ready receiver starts, moved past the last
+    /// one found so that a busy receiver can't starve the others.
+    next: usize,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Self {
+            handles: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Adds `rx` to the set, returning its index.
+    pub fn recv<T: 'a>(&mut self, rx: &'a Receiver<T>) -> usize {
+        self.handles.push(rx);
+        self.handles.len() - 1
+    }
+
+    /// Returns the index of a ready receiver, if there is one.
+    pub fn try_ready(&mut self) -> Option<usize> {
+        let len = self.handles.len();
+        let index = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&i| self.handles[i].is_ready())?;
+        self.next = (index + 1) % len;
+        Some(index)
+    }
+
+    /// Blocks until a receiver is ready, and returns its index.
+    ///
+    /// Panics if no receivers were added, that would block forever.
+    pub fn ready(&mut self) -> usize {
+        assert!(!self.handles.is_empty(), "no receivers to select on");
+
+        loop {
+            if let Some(index) = self.try_ready() {
+                return index;
+            }
+
+            let mut waiter = Waiter::new();
+            for handle in &self.handles {
+                handle.register(&mut waiter);
+            }
+            // Anything sent since the check above unparks us from now on.
+            if let Some(index) = self.try_ready() {
+                return index;
+            }
+            waiter.park();
+        }
+    }
+}
+
+impl Default for Select<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Blocks until one of several receivers has a value (or is disconnected),
+/// then runs the matching arm.
+///
+/// Each arm names a `Receiver` (by place, it is borrowed mutably) and a pattern
+/// for the `Result<T, RecvError>` it produced. An optional `default` arm runs
+/// instead of blocking when no receiver is ready. Receivers are checked in
+/// order, so an earlier busy receiver can starve later ones.
+///
+/// ```
+/// use crust_of_rust::channels::channel;
+/// use crust_of_rust::select;
+///
+/// let (tx1, mut rx1) = channel::<i32>();
+/// let (tx2, mut rx2) = channel::<&str>();
+///
+/// tx2.send("hi").unwrap();
+///
+/// select! {
+///     recv(rx1) -> msg => panic!("unexpected {msg:?}"),
+///     recv(rx2) -> msg => assert_eq!(msg.unwrap(), "hi"),
+/// }
+///
+/// let idle = select! {
+///     recv(rx1) -> _ => false,
+///     default => true,
+/// };
+/// assert!(idle);
+/// # drop((tx1, tx2));
+/// ```
+#[macro_export]
+macro_rules! select {
+    // Arms are collected one at a time. `rx` and `msg` are introduced by a
+    // different expansion for every arm, so hygiene keeps them apart even
+    // though they're spelled the same.
+    (@parse [$($arms:tt)*] recv($rx:expr) -> $res:pat => $body:block, $($rest:tt)*) => {
+        $crate::select!(@parse [$($arms)* (rx msg ($rx) ($res) ($body))] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] recv($rx:expr) -> $res:pat => $body:block $($rest:tt)*) => {
+        $crate::select!(@parse [$($arms)* (rx msg ($rx) ($res) ($body))] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] recv($rx:expr) -> $res:pat => $body:expr, $($rest:tt)*) => {
+        $crate::select!(@parse [$($arms)* (rx msg ($rx) ($res) ($body))] $($rest)*)
+    };
+    (@parse [$($arms:tt)*] recv($rx:expr) -> $res:pat => $body:expr) => {
+        $crate::select!(@parse [$($arms)* (rx msg ($rx) ($res) ($body))])
+    };
+    (@parse [$($arms:tt)*] default => $default:expr $(,)?) => {
+        $crate::select!(@expand [$($arms)*] { break true; } ($default))
+    };
+    (@parse [$($arms:tt)*]) => {
+        $crate::select!(@expand [$($arms)*] {} (::std::unreachable!()))
+    };
+
+    (@expand
+        [$(($rx:ident $msg:ident ($rx_expr:expr) ($res:pat) ($body:expr)))+]
+        $on_idle:block
+        ($default:expr)
+    ) => {{
+        $(
+            let $rx = &mut $rx_expr;
+            let mut $msg = ::std::option::Option::None;
+        )+
+        let mut waiter = $crate::channels::select::Waiter::new();
+
+        // `true` if nothing was ready and there is a `default` arm.
+        let idle = loop {
+            $(
+                if let ::std::option::Option::Some(m) = $crate::channels::select::try_recv($rx) {
+                    $msg = ::std::option::Option::Some(m);
+                    break false;
+                }
+            )+
+
+            if waiter.is_registered() {
+                // Everything was checked after registering, so anything sent
+                // since then unparks us.
+                waiter.park();
+            } else {
+                $on_idle
+                $(waiter.register(&*$rx);)+
+            }
+        };
+        waiter.unregister_all();
+
+        // The arms run outside the loop above, so a `break` or `continue` in
+        // them applies to the caller's loop.
+        match () {
+            _ if idle => $default,
+            $(_ if $msg.is_some() => {
+                let $res = $msg.unwrap();
+                $body
+            })+
+            _ => ::std::unreachable!(),
+        }
+    }};
+
+    ($($tokens:tt)+) => {
+        $crate::select!(@parse [] $($tokens)+)
+    };
+}
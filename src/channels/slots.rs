@@ -0,0 +1,353 @@
+//! A channel for large payloads that are written and read in place, instead of
+//! being moved through a queue.
+//!
+//! The channel owns a fixed set of preallocated values (slots), created once by
+//! `channel`. A sender reserves a free slot, fills it through a `SendGuard`,
+//! and publishes it with `SendGuard::send`. A receiver gets the slot through a
+//! `RecvGuard`, and dropping the guard hands the slot back to the senders. Only
+//! slot indices go through the queues, the payloads never move, and since a
+//! slot keeps its value when it is recycled, a buffer's allocation is reused
+//! for every message it carries.
+//!
+//! That last part is also the catch: a reserved slot still holds whatever the
+//! previous message left in it, so senders usually start by clearing it.
+//!
+//! The number of slots bounds the channel: `reserve` blocks while every slot is
+//! either queued or held by a guard.
+
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::{RecvError, SendError, TryRecvError, TrySendError};
+
+struct State {
+    /// Slots nobody holds, ready to be reserved.
+    free: Vec<usize>,
+    /// Sent slots, oldest first.
+    full: VecDeque<usize>,
+    senders: usize,
+    receivers: usize,
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<T>]>,
+    state: Mutex<State>,
+    /// Waited on by receivers, for a sent slot.
+    not_empty: Condvar,
+    /// Waited on by senders, for a free slot.
+    not_full: Condvar,
+}
+
+// SAFETY: A slot's index is in exactly one place at a time (the free list, the
+// queue, or a guard), and only a guard touches the slot. The mutex orders the
+// hand-offs between guards.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn release(&self, index: usize) {
+        self.state.lock().unwrap().free.push(index);
+        self.not_full.notify_one();
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Reserves a free slot to write the next message into, blocking while
+    /// there is none. Fails if every `Receiver` is gone, including while
+    /// blocked.
+    pub fn reserve(&self) -> Result<SendGuard<'_, T>, SendError<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.receivers == 0 {
+                return Err(SendError(()));
+            }
+            if let Some(index) = state.free.pop() {
+                return Ok(SendGuard {
+                    shared: &self.shared,
+                    index,
+                    _marker: PhantomData,
+                });
+            }
+            state = self.shared.not_full.wait(state).unwrap();
+        }
+    }
+
+    /// Reserves a free slot if there is one, without blocking.
+    pub fn try_reserve(&self) -> Result<SendGuard<'_, T>, TrySendError<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(TrySendError::Disconnected(()));
+        }
+        let index = state.free.pop().ok_or(TrySendError::Full(()))?;
+        Ok(SendGuard {
+            shared: &self.shared,
+            index,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+/// A reserved slot, see `Sender::reserve`. Dropping it without calling `send`
+/// gives the slot back unsent.
+pub struct SendGuard<'a, T> {
+    shared: &'a Shared<T>,
+    index: usize,
+    /// The guard hands out `&T` and `&mut T`, so it is only `Sync` if `T` is.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> SendGuard<'_, T> {
+    /// Queues the slot for the receivers.
+    ///
+    /// If every `Receiver` is gone by now, the slot is simply freed again.
+    pub fn send(self) {
+        let shared = self.shared;
+        let index = self.index;
+        std::mem::forget(self);
+
+        let mut state = shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            drop(state);
+            shared.release(index);
+            return;
+        }
+        state.full.push_back(index);
+        drop(state);
+        shared.not_empty.notify_one();
+    }
+}
+
+impl<T> Deref for SendGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: We hold the slot's index, see `Shared`.
+        unsafe { &*self.shared.slots[self.index].get() }
+    }
+}
+
+impl<T> DerefMut for SendGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: As in `deref`.
+        unsafe { &mut *self.shared.slots[self.index].get() }
+    }
+}
+
+impl<T> Drop for SendGuard<'_, T> {
+    fn drop(&mut self) {
+        self.shared.release(self.index);
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks until a slot is sent, or fails once every `Sender` is gone and
+    /// every sent slot has been received.
+    pub fn recv(&self) -> Result<RecvGuard<'_, T>, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(index) = state.full.pop_front() {
+                return Ok(RecvGuard {
+                    shared: &self.shared,
+                    index,
+                    _marker: PhantomData,
+                });
+            }
+            if state.senders == 0 {
+                return Err(RecvError {});
+            }
+            state = self.shared.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Receives a slot if one was sent, without blocking.
+    pub fn try_recv(&self) -> Result<RecvGuard<'_, T>, TryRecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.full.pop_front() {
+            Some(index) => Ok(RecvGuard {
+                shared: &self.shared,
+                index,
+                _marker: PhantomData,
+            }),
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            drop(state);
+            self.shared.not_full.notify_all();
+        }
+    }
+}
+
+/// A received slot, see `Receiver::recv`. Dropping it recycles the slot, value
+/// and all, for the senders to reserve again.
+pub struct RecvGuard<'a, T> {
+    shared: &'a Shared<T>,
+    index: usize,
+    /// The guard hands out `&T` and `&mut T`, so it is only `Sync` if `T` is.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> Deref for RecvGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: We hold the slot's index, see `Shared`.
+        unsafe { &*self.shared.slots[self.index].get() }
+    }
+}
+
+impl<T> DerefMut for RecvGuard<'_, T> {
+    /// Mutable too, so a receiver can take what it needs out of the slot
+    /// (e.g. with `mem::take`), at the cost of the reused allocation.
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: As in `deref`.
+        unsafe { &mut *self.shared.slots[self.index].get() }
+    }
+}
+
+impl<T> Drop for RecvGuard<'_, T> {
+    fn drop(&mut self) {
+        self.shared.release(self.index);
+    }
+}
+
+/// Creates a channel with `slots` preallocated values, each created by `init`.
+///
+/// Panics if `slots` is 0, nothing could ever be sent.
+pub fn channel<T>(slots: usize, mut init: impl FnMut() -> T) -> (Sender<T>, Receiver<T>) {
+    assert!(slots > 0, "a slot channel needs at least one slot");
+
+    let shared = Arc::new(Shared {
+        slots: (0..slots).map(|_| UnsafeCell::new(init())).collect(),
+        state: Mutex::new(State {
+            free: (0..slots).rev().collect(),
+            full: VecDeque::new(),
+            senders: 1,
+            receivers: 1,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_slots_in_place_and_recycled() {
+        let (tx, rx) = channel(1, || Vec::<u8>::with_capacity(1 << 20));
+
+        let mut slot = tx.reserve().unwrap();
+        let buf_ptr = slot.as_ptr();
+        slot.extend_from_slice(b"hello");
+        slot.send();
+
+        // The only slot is in flight.
+        assert!(matches!(tx.try_reserve(), Err(TrySendError::Full(()))));
+
+        let msg = rx.recv().unwrap();
+        assert_eq!(&msg[..], b"hello");
+        assert_eq!(msg.as_ptr(), buf_ptr);
+        drop(msg);
+
+        // Same allocation, with the previous message still in it.
+        let mut slot = tx.try_reserve().unwrap();
+        assert_eq!((slot.as_ptr(), &slot[..]), (buf_ptr, &b"hello"[..]));
+        slot.clear();
+        // Dropped unsent, it goes back to the free list.
+        drop(slot);
+        assert!(rx.try_recv().is_err());
+        assert!(tx.try_reserve().is_ok());
+    }
+
+    #[test]
+    fn test_slots_pipeline() {
+        let (tx, rx) = channel(2, || vec![0u64; 1024]);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    let mut slot = tx.reserve().unwrap();
+                    slot.fill(i);
+                    slot.send();
+                }
+            });
+
+            for i in 0..100 {
+                let msg = rx.recv().unwrap();
+                assert!(msg.iter().all(|&x| x == i));
+            }
+            assert!(rx.recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_slots_closed_rx() {
+        let (tx, rx) = channel(1, String::new);
+
+        let held = tx.reserve().unwrap();
+        thread::scope(|s| {
+            let blocked = s.spawn(|| tx.reserve().map(|_| ()));
+            thread::sleep(std::time::Duration::from_millis(20));
+            drop(rx);
+            assert_eq!(blocked.join().unwrap(), Err(SendError(())));
+        });
+        held.send();
+    }
+}
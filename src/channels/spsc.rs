@@ -0,0 +1,282 @@
+//! A bounded single-producer single-consumer channel: a ring buffer with one
+//! index per side, and no CAS anywhere.
+//!
+//! With only one sender and one receiver, each index has a single writer.
+//! `tail` (the next slot to write) only ever moves in `send`, `head` (the next
+//! slot to read) only in `recv`, and each side merely *reads* the other's
+//! index, to tell if the ring is full or empty. That's Lamport's queue, and it
+//! needs nothing stronger than `Acquire`/`Release`:
+//!
+//! - The sender writes a slot, then stores the new `tail` with `Release`. The
+//!   receiver loads `tail` with `Acquire` before reading the slot, so it sees
+//!   the value.
+//! - The receiver reads a slot, then stores the new `head` with `Release`. The
+//!   sender loads `head` with `Acquire` before reusing the slot, so the read
+//!   is done before the overwrite.
+//!
+//! Compare the MPMC `array` channel, where several threads race for the same
+//! index: there, claiming a slot takes a CAS, and a per-slot stamp is needed to
+//! publish it. Here, the index store itself does the publishing.
+//!
+//! The ring has one more slot than the capacity, so that full
+//! (`tail + 1 == head`) and empty (`tail == head`) look different. Each side
+//! also caches the other side's index and only reloads it when the cached
+//! value says full (or empty), which keeps the two cache lines (see
+//! `CachePadded`) from bouncing between the cores on every operation.
+//!
+//! Type-level enforcement is what makes all this sound: neither handle is
+//! `Clone`, and the caches are `Cell`s, so neither is `Sync` either. There is
+//! never more than one thread on each side.
+//!
+//! Blocking `send` and `recv` spin, yield and then sleep briefly, rather than
+//! park: a channel like this usually connects two busy threads, and waking
+//! each other would need the `SeqCst` handshake the other flavors use.
+
+use std::cell::{Cell, UnsafeCell};
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::{RecvError, SendError, TryRecvError, TrySendError};
+use crate::atomics::{Backoff, CachePadded};
+
+struct Shared<T> {
+    /// The next slot to read, written only by the `Receiver`.
+    head: CachePadded<AtomicUsize>,
+    /// The next slot to write, written only by the `Sender`.
+    tail: CachePadded<AtomicUsize>,
+    /// One more than the capacity.
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Set when either side is dropped.
+    disconnected: AtomicBool,
+}
+
+// SAFETY: Every slot is written by the sender before it publishes it through
+// `tail`, and read by the receiver before it frees it through `head`.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn next(&self, index: usize) -> usize {
+        if index + 1 == self.slots.len() {
+            0
+        } else {
+            index + 1
+        }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            // SAFETY: Slots from `head` up to `tail` hold sent values.
+            unsafe { self.slots[head].get_mut().assume_init_drop() };
+            head = self.next(head);
+        }
+    }
+}
+
+/// Waits the way the module docs describe.
+fn backoff(backoff: &mut Backoff) {
+    if backoff.is_completed() {
+        thread::park_timeout(Duration::from_millis(1));
+    } else {
+        backoff.snooze();
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+    /// The receiver's `head` as last seen, it only moves forward.
+    head: Cell<usize>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `val`, waiting while the channel is full. Gives `val` back if the
+    /// `Receiver` is gone.
+    pub fn send(&self, mut val: T) -> Result<(), SendError<T>> {
+        let mut wait = Backoff::new();
+        loop {
+            match self.try_send(val) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => val = v,
+            }
+            backoff(&mut wait);
+        }
+    }
+
+    /// Sends `val` if there is room, without waiting.
+    pub fn try_send(&self, val: T) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        if shared.disconnected.load(Ordering::Relaxed) {
+            return Err(TrySendError::Disconnected(val));
+        }
+
+        // Only we write `tail`.
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let next = shared.next(tail);
+        if next == self.head.get() {
+            // `Acquire` pairs with the `Release` in `try_recv`: the receiver
+            // is done reading the slot we're about to overwrite.
+            self.head.set(shared.head.load(Ordering::Acquire));
+            if next == self.head.get() {
+                return Err(TrySendError::Full(val));
+            }
+        }
+
+        // SAFETY: `tail` isn't published yet, so the receiver won't touch the
+        // slot, and `head` shows it's been read (or never written).
+        unsafe { (*shared.slots[tail].get()).write(val) };
+        shared.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // `Release`, so a receiver that sees it also sees our last `tail`.
+        self.shared.disconnected.store(true, Ordering::Release);
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// The sender's `tail` as last seen, it only moves forward.
+    tail: Cell<usize>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits until a value is available, or fails once the `Sender` is gone
+    /// and every value has been received.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut wait = Backoff::new();
+        loop {
+            match self.try_recv() {
+                Ok(val) => return Ok(val),
+                Err(TryRecvError::Disconnected) => return Err(RecvError {}),
+                Err(TryRecvError::Empty) => backoff(&mut wait),
+            }
+        }
+    }
+
+    /// Receives a value if one is waiting, without waiting.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let shared = &*self.shared;
+
+        // Only we write `head`.
+        let head = shared.head.load(Ordering::Relaxed);
+        if head == self.tail.get() {
+            // `Acquire` pairs with the `Release` in `try_send`, so the value
+            // in the slot is visible.
+            self.tail.set(shared.tail.load(Ordering::Acquire));
+            if head == self.tail.get() {
+                if !shared.disconnected.load(Ordering::Acquire) {
+                    return Err(TryRecvError::Empty);
+                }
+                // The sender may have sent one last value before leaving.
+                self.tail.set(shared.tail.load(Ordering::Acquire));
+                if head == self.tail.get() {
+                    return Err(TryRecvError::Disconnected);
+                }
+            }
+        }
+
+        // SAFETY: `tail` has moved past the slot, so it holds a value the
+        // sender won't touch until we move `head` past it.
+        let val = unsafe { (*shared.slots[head].get()).assume_init_read() };
+        shared.head.store(shared.next(head), Ordering::Release);
+        Ok(val)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.disconnected.store(true, Ordering::Release);
+    }
+}
+
+/// Creates a channel holding up to `cap` values.
+///
+/// Panics if `cap` is 0.
+pub fn channel<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(cap > 0, "an SPSC channel needs a capacity of at least 1");
+
+    let shared = Arc::new(Shared {
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+        slots: (0..=cap)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        disconnected: AtomicBool::new(false),
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+            head: Cell::new(0),
+        },
+        Receiver {
+            shared,
+            tail: Cell::new(0),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spsc_full_and_empty() {
+        let (tx, rx) = channel(2);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        for i in 0..5 {
+            tx.try_send(2 * i).unwrap();
+            tx.try_send(2 * i + 1).unwrap();
+            assert_eq!(tx.try_send(-1), Err(TrySendError::Full(-1)));
+            assert_eq!(rx.try_recv(), Ok(2 * i));
+            assert_eq!(rx.try_recv(), Ok(2 * i + 1));
+        }
+
+        tx.send(10).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv(), Ok(10));
+        assert_eq!(rx.recv(), Err(RecvError {}));
+    }
+
+    #[test]
+    fn test_spsc_in_order_across_threads() {
+        let (tx, rx) = channel(3);
+
+        let sender = thread::spawn(move || {
+            for i in 0..100_000 {
+                tx.send(i).unwrap();
+            }
+        });
+        for i in 0..100_000 {
+            assert_eq!(rx.recv(), Ok(i));
+        }
+        sender.join().unwrap();
+        assert_eq!(rx.recv(), Err(RecvError {}));
+    }
+
+    #[test]
+    fn test_spsc_closed_rx_drops_values() {
+        let (tx, rx) = channel(4);
+        let val = Arc::new(());
+        tx.send(Arc::clone(&val)).unwrap();
+        tx.send(Arc::clone(&val)).unwrap();
+        assert!(rx.recv().is_ok());
+
+        drop(rx);
+        assert!(tx.send(Arc::clone(&val)).is_err());
+        drop(tx);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+}
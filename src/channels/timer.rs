@@ -0,0 +1,255 @@
+//! Channels that deliver the time instead of values from a sender: `after`
+//! sends once a duration has passed, `tick` keeps sending every period.
+//!
+//! Both return an ordinary bounded `Receiver`, so a timer can sit in a
+//! `select!` next to real channels. That is how timeouts are written without
+//! `recv_timeout`:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use crust_of_rust::channels::{channel, timer};
+//! use crust_of_rust::select;
+//!
+//! let (tx, mut rx) = channel::<i32>();
+//! let mut timeout = timer::after(Duration::from_millis(10));
+//!
+//! let timed_out = select! {
+//!     recv(rx) -> _ => false,
+//!     recv(timeout) -> _ => true,
+//! };
+//! assert!(timed_out);
+//! # drop(tx);
+//! ```
+//!
+//! The senders are all held by one timer thread, spawned the first time a timer
+//! is created. It keeps them in a heap ordered by deadline and sleeps on a
+//! `Condvar` until the earliest one is due, or until a new timer comes before
+//! it. The thread lives for the rest of the process, idle while nothing is
+//! scheduled.
+//!
+//! A timer whose `Receiver` is dropped is only forgotten the next time it
+//! fires, since the thread finds out when the send fails.
+//!
+//! A deadline too far in the future for an `Instant` (e.g, `Duration::MAX`
+//! from now) is never scheduled, and its receiver never gets anything. The
+//! sender is still kept, so that the receiver doesn't see a disconnect either.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Receiver, SyncSender, TrySendError, sync_channel};
+
+struct Entry {
+    deadline: Instant,
+    /// `None` for `after`, which fires once.
+    period: Option<Duration>,
+    tx: SyncSender<Instant>,
+}
+
+// The heap is a max-heap, so these are reversed: the earliest deadline is on
+// top.
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+struct Timer {
+    entries: Mutex<BinaryHeap<Entry>>,
+    /// Senders of timers that never fire, kept to keep their receivers
+    /// connected.
+    never: Mutex<Vec<SyncSender<Instant>>>,
+    /// Signaled when a timer is added, in case it is due before the others.
+    added: Condvar,
+}
+
+impl Timer {
+    fn schedule(&self, entry: Entry) {
+        self.entries.lock().unwrap().push(entry);
+        self.added.notify_one();
+    }
+
+    fn never(&self, tx: SyncSender<Instant>) {
+        let mut never = self.never.lock().unwrap();
+        // Nothing is ever sent to find out, so check when adding instead.
+        never.retain(|tx| !tx.is_disconnected());
+        never.push(tx);
+    }
+
+    fn run(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let Some(next) = entries.peek() else {
+                entries = self.added.wait(entries).unwrap();
+                continue;
+            };
+            if next.deadline > now {
+                let timeout = next.deadline - now;
+                entries = self.added.wait_timeout(entries, timeout).unwrap().0;
+                continue;
+            }
+
+            let mut entry = entries.pop().expect("peeked entry");
+            // Never blocks: a `tick` nobody received in time is skipped rather
+            // than queued.
+            let fired = match entry.tx.try_send(now) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            };
+            if let (true, Some(period)) = (fired, entry.period) {
+                // Ticks missed while the thread was late are dropped too.
+                let next = entry
+                    .deadline
+                    .checked_add(period)
+                    .filter(|&next| next > now)
+                    .or_else(|| now.checked_add(period));
+                match next {
+                    Some(next) => {
+                        entry.deadline = next;
+                        entries.push(entry);
+                    }
+                    None => self.never(entry.tx),
+                }
+            }
+        }
+    }
+}
+
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+
+    TIMER.get_or_init(|| {
+        thread::Builder::new()
+            .name("timer".into())
+            // Waits for the `OnceLock` to be initialized.
+            .spawn(|| timer().run())
+            .expect("failed to spawn the timer thread");
+
+        Timer {
+            entries: Mutex::new(BinaryHeap::new()),
+            never: Mutex::new(Vec::new()),
+            added: Condvar::new(),
+        }
+    })
+}
+
+/// Creates a receiver that gets the current time once `duration` has passed,
+/// and is disconnected after that.
+pub fn after(duration: Duration) -> Receiver<Instant> {
+    let (tx, rx) = sync_channel(1);
+    match Instant::now().checked_add(duration) {
+        Some(deadline) => timer().schedule(Entry {
+            deadline,
+            period: None,
+            tx,
+        }),
+        // Too far in the future to represent, as good as never.
+        None => timer().never(tx),
+    }
+    rx
+}
+
+/// Creates a receiver that gets the current time every `period`, starting one
+/// `period` from now.
+///
+/// At most one tick waits in the channel: ticks that come while it is still
+/// unreceived are skipped, so a slow receiver doesn't fall further and further
+/// behind.
+///
+/// Panics if `period` is zero.
+pub fn tick(period: Duration) -> Receiver<Instant> {
+    assert!(!period.is_zero(), "a tick needs a non-zero period");
+
+    let (tx, rx) = sync_channel(1);
+    match Instant::now().checked_add(period) {
+        Some(deadline) => timer().schedule(Entry {
+            deadline,
+            period: Some(period),
+            tx,
+        }),
+        None => timer().never(tx),
+    }
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::{RecvError, TryRecvError, channel};
+    use crate::select;
+
+    #[test]
+    fn test_timer_after() {
+        let start = Instant::now();
+        let mut late = after(Duration::from_millis(40));
+        let mut early = after(Duration::from_millis(10));
+
+        let fired = early.recv().unwrap();
+        assert!(fired >= start + Duration::from_millis(10));
+        assert_eq!(late.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(early.recv(), Err(RecvError {}));
+
+        assert!(late.recv().unwrap() >= start + Duration::from_millis(40));
+        assert_eq!(late.recv(), Err(RecvError {}));
+    }
+
+    #[test]
+    fn test_timer_tick_in_select() {
+        let (tx, mut rx) = channel::<()>();
+        let mut ticks = tick(Duration::from_millis(5));
+        let mut timeout = after(Duration::from_millis(60));
+
+        let mut count = 0;
+        let mut last = Instant::now();
+        loop {
+            select! {
+                recv(rx) -> msg => panic!("unexpected {msg:?}"),
+                recv(ticks) -> at => {
+                    let at = at.unwrap();
+                    assert!(at > last);
+                    last = at;
+                    count += 1;
+                },
+                recv(timeout) -> _ => break,
+            }
+        }
+        // Loose bounds, the timer thread may be scheduled late.
+        assert!((2..=12).contains(&count), "{count} ticks");
+        drop(tx);
+    }
+
+    #[test]
+    fn test_timer_too_far_never_fires() {
+        let mut never = after(Duration::MAX);
+        let mut ticks = tick(Duration::MAX);
+        let mut timeout = after(Duration::from_millis(20));
+
+        select! {
+            recv(never) -> msg => panic!("unexpected {msg:?}"),
+            recv(ticks) -> msg => panic!("unexpected {msg:?}"),
+            recv(timeout) -> _ => {},
+        }
+        // Still connected, not just disconnected right away.
+        assert_eq!(never.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(ticks.try_recv(), Err(TryRecvError::Empty));
+    }
+}
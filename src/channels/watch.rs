@@ -0,0 +1,361 @@
+//! A watch channel: a single slot holding the latest value, rather than a
+//! queue.
+//!
+//! Every `send` overwrites the slot and bumps a version counter. Receivers
+//! don't take values out; they look at the current one with `borrow` and
+//! remember the version they last marked as seen, which is all `has_changed`
+//! and `changed` compare against. Intermediate values a receiver didn't look at
+//! in time are simply skipped, which suits configuration or status that only
+//! matters in its latest state.
+//!
+//! The value is behind a `RwLock` so that receivers can borrow it at the same
+//! time. The version lives with the other bookkeeping behind a `Mutex`, for the
+//! `Condvar` that `changed` blocks on. A send bumps it while still holding the
+//! write lock, so a reader holding a read lock always sees the version that
+//! belongs to the value it is reading.
+
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard};
+use std::task::{Context, Poll, Waker};
+
+use super::{RecvError, SendError};
+
+struct State {
+    /// Bumped by every `send`, starts at 0 for the initial value.
+    version: u64,
+    senders: usize,
+    receivers: usize,
+    /// Pending `changed_async` futures, by id. All of them are woken by a send
+    /// or a disconnect.
+    tasks: Vec<(usize, Waker)>,
+}
+
+struct Shared<T> {
+    value: RwLock<T>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl<T> Shared<T> {
+    /// Wakes everyone waiting in `changed` or `changed_async`.
+    fn notify(&self, mut state: std::sync::MutexGuard<'_, State>) {
+        let tasks = std::mem::take(&mut state.tasks);
+        drop(state);
+
+        self.changed.notify_all();
+        for (_, task) in tasks {
+            task.wake();
+        }
+    }
+}
+
+/// Ids for `changed_async` futures, so a dropped one can find its `Waker`.
+static NEXT_TASK: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Replaces the value, or gives `val` back if every `Receiver` is gone.
+    ///
+    /// Blocks while any receiver holds a `Ref` from `borrow`.
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let mut value = self.shared.value.write().unwrap();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(val));
+        }
+
+        let old = std::mem::replace(&mut *value, val);
+        state.version += 1;
+        drop(value);
+        self.shared.notify(state);
+
+        // Outside the locks, in case its `Drop` uses the channel.
+        drop(old);
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // Waiters have to find out that no change is coming.
+            self.shared.notify(state);
+        }
+    }
+}
+
+/// The current value of a watch channel, borrowed by `Receiver::borrow`.
+///
+/// Holds a read lock: senders block until it's dropped.
+pub struct Ref<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// The version last marked as seen.
+    seen: u64,
+}
+
+impl<T> Receiver<T> {
+    /// Borrows the current value, without marking it as seen.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            guard: self.shared.value.read().unwrap(),
+        }
+    }
+
+    /// Borrows the current value and marks it as seen.
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        let guard = self.shared.value.read().unwrap();
+        // The version can't move while we hold the read lock.
+        self.seen = self.shared.state.lock().unwrap().version;
+        Ref { guard }
+    }
+
+    /// Whether a value was sent since the last one marked as seen. Fails once
+    /// every `Sender` is gone, as no more changes can come.
+    pub fn has_changed(&self) -> Result<bool, RecvError> {
+        let state = self.shared.state.lock().unwrap();
+        if state.senders == 0 {
+            return Err(RecvError {});
+        }
+        Ok(state.version != self.seen)
+    }
+
+    /// Blocks until a value newer than the last one seen is sent, and marks it
+    /// as seen. Returns right away if there already is one.
+    ///
+    /// Fails if every `Sender` is gone first. A value sent just before the
+    /// last `Sender` was dropped still counts.
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.version != self.seen {
+                self.seen = state.version;
+                return Ok(());
+            }
+            if state.senders == 0 {
+                return Err(RecvError {});
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Like `changed`, but waits by returning `Poll::Pending` instead of
+    /// blocking the thread. Dropping the future unregisters its `Waker`.
+    pub fn changed_async(&mut self) -> Changed<'_, T> {
+        Changed { rx: self, id: None }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The clone starts out having seen the same version as `self`.
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receivers -= 1;
+    }
+}
+
+/// Future returned by `Receiver::changed_async`.
+pub struct Changed<'a, T> {
+    rx: &'a mut Receiver<T>,
+    /// Set once the future has registered its `Waker`.
+    id: Option<usize>,
+}
+
+impl<T> Future for Changed<'_, T> {
+    type Output = Result<(), RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.rx.shared.state.lock().unwrap();
+
+        let ready = if state.version != this.rx.seen {
+            this.rx.seen = state.version;
+            Ok(())
+        } else if state.senders == 0 {
+            Err(RecvError {})
+        } else {
+            let id = *this
+                .id
+                .get_or_insert_with(|| NEXT_TASK.fetch_add(1, Ordering::Relaxed));
+            // Woken tasks are unregistered, so this may be a new registration
+            // even on a later poll.
+            match state.tasks.iter_mut().find(|(task, _)| *task == id) {
+                Some((_, waker)) => waker.clone_from(cx.waker()),
+                None => state.tasks.push((id, cx.waker().clone())),
+            }
+            return Poll::Pending;
+        };
+
+        if let Some(id) = this.id.take() {
+            state.tasks.retain(|&(task, _)| task != id);
+        }
+        Poll::Ready(ready)
+    }
+}
+
+impl<T> Drop for Changed<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.rx.shared.state.lock().unwrap();
+            state.tasks.retain(|&(task, _)| task != id);
+        }
+    }
+}
+
+/// Creates a watch channel holding `initial`, which receivers start out having
+/// seen.
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        value: RwLock::new(initial),
+        state: Mutex::new(State {
+            version: 0,
+            senders: 1,
+            receivers: 1,
+            tasks: Vec::new(),
+        }),
+        changed: Condvar::new(),
+    });
+
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared, seen: 0 },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_watch_latest_value() {
+        let (tx, mut rx) = channel("a");
+        assert_eq!(*rx.borrow(), "a");
+        assert_eq!(rx.has_changed(), Ok(false));
+
+        // Only the latest value is kept.
+        tx.send("b").unwrap();
+        tx.send("c").unwrap();
+        assert_eq!(rx.has_changed(), Ok(true));
+        assert_eq!(*rx.borrow(), "c");
+        assert_eq!(rx.has_changed(), Ok(true));
+        assert_eq!(*rx.borrow_and_update(), "c");
+        assert_eq!(rx.has_changed(), Ok(false));
+
+        // A clone has seen what its original has.
+        let rx2 = rx.clone();
+        tx.send("d").unwrap();
+        assert_eq!(rx2.has_changed(), Ok(true));
+
+        drop((rx, rx2));
+        assert_eq!(tx.send("e"), Err(SendError("e")));
+        drop(tx);
+    }
+
+    #[test]
+    fn test_watch_changed_blocks() {
+        let (tx, mut rx) = channel(0);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 1..=3 {
+                    thread::sleep(Duration::from_millis(10));
+                    tx.send(i).unwrap();
+                }
+            });
+
+            // Every change is noticed eventually, though some values may be
+            // skipped. The last one is never missed, even when the sender is
+            // dropped right after sending it.
+            let mut last = 0;
+            while rx.changed().is_ok() {
+                let val = *rx.borrow();
+                assert!(val > last);
+                last = val;
+            }
+            assert_eq!(last, 3);
+        });
+        assert!(rx.has_changed().is_err());
+    }
+
+    struct CountWaker(AtomicUsize);
+
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_watch_changed_async() {
+        let (tx, mut rx1) = channel(0);
+        let mut rx2 = rx1.clone();
+        let count = Arc::new(CountWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&count));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut fut1 = Box::pin(rx1.changed_async());
+        let mut fut2 = Box::pin(rx2.changed_async());
+        assert!(fut1.as_mut().poll(&mut cx).is_pending());
+        assert!(fut2.as_mut().poll(&mut cx).is_pending());
+
+        // A cancelled wait is forgotten, every other waiter is woken.
+        drop(fut2);
+        tx.send(1).unwrap();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+        assert_eq!(fut1.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        drop(fut1);
+        assert_eq!(*rx1.borrow(), 1);
+
+        drop(tx);
+        let mut fut2 = Box::pin(rx2.changed_async());
+        assert_eq!(fut2.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+        drop(fut2);
+        assert!(rx1.shared.state.lock().unwrap().tasks.is_empty());
+        assert_eq!(
+            Box::pin(rx2.changed_async()).as_mut().poll(&mut cx),
+            Poll::Ready(Err(RecvError {}))
+        );
+    }
+}
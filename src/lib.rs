@@ -3,14 +3,22 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 #![feature(dropck_eyepatch)] // permanently unstable feature
+#![feature(layout_for_ptr)] // layouts of unsized `Rc` values from raw pointers
+#![feature(set_ptr_value)] // `with_metadata_of`, to build unsized `Rc`s
+#![feature(specialization)] // `AtomicCell` picking native atomics for `NoUninit` types
+#![allow(incomplete_features)] // `specialization`, only on a marker trait here
 
+pub mod arc;
 pub mod async_await;
 pub mod atomics;
 pub mod cell;
 pub mod channels;
 pub mod dropck;
 pub mod lifetimes;
+pub mod lock_free;
 pub mod macros;
 pub mod rc;
 pub mod refcell;
+pub mod shared;
+pub mod sync_test;
 pub mod variance;
@@ -0,0 +1,20 @@
+//! Lock-free data structures: every operation is a loop of CAS attempts on
+//! shared pointers, so a thread that is preempted (or killed) mid-operation
+//! never blocks the others, unlike with a `Mutex`.
+//!
+//! The hard part is not the CAS loops but memory reclamation: a node unlinked
+//! by one thread may still be read by another thread that loaded a pointer to
+//! it just before. Freeing it right away is a use-after-free, and reusing its
+//! address is the ABA problem (a stale CAS succeeds because the address
+//! matches, even though the node is a different one). Each structure documents
+//! how it defers freeing nodes until nobody can observe them.
+
+pub mod deque;
+pub mod epoch;
+mod epoch_stack;
+mod rcu_cell;
+mod stack;
+
+pub use epoch_stack::EpochStack;
+pub use rcu_cell::RcuCell;
+pub use stack::Stack;
@@ -0,0 +1,311 @@
+//! Chase-Lev work-stealing deque, with a fixed capacity.
+//!
+//! One `Worker` owns the deque and pushes and pops at the bottom, like a
+//! stack. Any number of `Stealer`s take from the top, the oldest end. The two
+//! ends are separate indices that only ever grow, `top` and `bottom`, and the
+//! values live in a ring buffer at `index & mask`.
+//!
+//! The owner's `push` needs no CAS at all, only a `Release` store of `bottom`.
+//! Stealers race each other (and the owner, for the last value) with a CAS on
+//! `top`. The owner's `pop` first claims the bottom value by decrementing
+//! `bottom`, then checks `top`. Only when they meet, at the last value, does it
+//! have to CAS `top` against the stealers. The `SeqCst` fences in `pop` and
+//! `steal` are what make "I moved `bottom`, then read `top`" and "I read
+//! `top`, then read `bottom`" agree on who gets the last value.
+//!
+//! A stealer reads a value *before* its CAS confirms that it owns it, and must
+//! forget the copy if the CAS fails. That read can race with the owner
+//! overwriting the slot after a wrap-around, in which case the CAS always
+//! fails and the torn copy is never used; like crossbeam, we read it through
+//! `ptr::read_volatile` and accept the race as benign.
+//!
+//! The original deque grows its buffer when full, which needs the old buffer
+//! to be reclaimed once no stealer can still be reading it (see `epoch`). This
+//! one has a fixed capacity instead, and `push` gives the value back when it's
+//! full, leaving the overflow to the caller.
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicIsize, Ordering};
+
+use crate::atomics::CachePadded;
+
+struct Inner<T> {
+    /// The next value to steal.
+    top: CachePadded<AtomicIsize>,
+    /// One past the last pushed value, written only by the `Worker`.
+    bottom: CachePadded<AtomicIsize>,
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// `buffer.len() - 1`, the length being a power of two.
+    mask: usize,
+}
+
+// SAFETY: Values are moved in by `push` and out by `pop` or `steal`, possibly
+// on different threads, but the CAS on `top` (and the owner claiming values
+// by moving `bottom`) makes sure only one thread takes each.
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    fn slot(&self, index: isize) -> *mut MaybeUninit<T> {
+        self.buffer[index as usize & self.mask].get()
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let bottom = *self.bottom.get_mut();
+        for index in *self.top.get_mut()..bottom {
+            // SAFETY: Slots from `top` up to `bottom` hold pushed values that
+            // weren't taken.
+            unsafe { (*self.slot(index)).assume_init_drop() };
+        }
+    }
+}
+
+/// The owning end of a deque, see `new`.
+///
+/// Not `Sync`, and not `Clone`: only one thread may push and pop.
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Worker<T> {
+    /// Pushes `val` at the bottom, or gives it back if the deque is full.
+    pub fn push(&self, val: T) -> Result<(), T> {
+        let inner = &*self.inner;
+        // Only we write `bottom`.
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        // `Acquire` pairs with the CAS in `steal`: the stealer is done with
+        // the slot we may be about to reuse.
+        let top = inner.top.load(Ordering::Acquire);
+        if bottom - top > inner.mask as isize {
+            return Err(val);
+        }
+
+        // SAFETY: The slot is past `bottom`, so no value lives in it and no
+        // stealer will take it before we publish it.
+        unsafe { (*inner.slot(bottom)).write(val) };
+        // `Release` publishes the value to the `Acquire` in `steal`.
+        inner.bottom.store(bottom + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Pops the most recently pushed value.
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed) - 1;
+        // Claims the bottom value, as far as stealers that come after this
+        // are concerned.
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+
+        if top > bottom {
+            // Empty, undo the claim.
+            inner.bottom.store(bottom + 1, Ordering::Relaxed);
+            return None;
+        }
+
+        if top == bottom {
+            // The last value: stealers may still be going for it, so whoever
+            // moves `top` first gets it.
+            let won = inner
+                .top
+                .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok();
+            inner.bottom.store(bottom + 1, Ordering::Relaxed);
+            if !won {
+                return None;
+            }
+        }
+
+        // SAFETY: Stealers stop short of `bottom` now (or lost the CAS for
+        // it), so the value is ours.
+        Some(unsafe { (*inner.slot(bottom)).assume_init_read() })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        let bottom = self.inner.bottom.load(Ordering::Relaxed);
+        let top = self.inner.top.load(Ordering::Relaxed);
+        (bottom - top).max(0) as usize
+    }
+}
+
+/// The result of `Stealer::steal`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    Empty,
+    Success(T),
+    /// Lost a race with another thread taking the same value. The deque may
+    /// well have more.
+    Retry,
+}
+
+/// A stealing end of a deque, see `new`. Clone it for every thief.
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Stealer<T> {
+    /// Takes the oldest value.
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &*self.inner;
+        let top = inner.top.load(Ordering::Acquire);
+        atomic::fence(Ordering::SeqCst);
+        // `Acquire` pairs with the `Release` in `push`, so the value is
+        // visible.
+        let bottom = inner.bottom.load(Ordering::Acquire);
+        if top >= bottom {
+            return Steal::Empty;
+        }
+
+        // SAFETY: Only a copy, which we forget unless the CAS below makes it
+        // ours. See the module docs for the race with the owner.
+        let val = unsafe { std::ptr::read_volatile(inner.slot(top)) };
+        match inner
+            .top
+            .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+        {
+            // SAFETY: Written by `push` before it published `bottom`.
+            Ok(_) => Steal::Success(unsafe { val.assume_init() }),
+            Err(_) => Steal::Retry,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let top = self.inner.top.load(Ordering::Acquire);
+        let bottom = self.inner.bottom.load(Ordering::Acquire);
+        top >= bottom
+    }
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// Creates a deque holding up to `cap` values, rounded up to a power of two.
+///
+/// Panics if `cap` is 0.
+pub fn new<T>(cap: usize) -> (Worker<T>, Stealer<T>) {
+    assert!(cap > 0, "a deque needs a capacity of at least 1");
+
+    let cap = cap.next_power_of_two();
+    let inner = Arc::new(Inner {
+        top: CachePadded::new(AtomicIsize::new(0)),
+        bottom: CachePadded::new(AtomicIsize::new(0)),
+        buffer: (0..cap)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        mask: cap - 1,
+    });
+
+    (
+        Worker {
+            inner: Arc::clone(&inner),
+            _not_sync: PhantomData,
+        },
+        Stealer { inner },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_deque_lifo_owner_fifo_stealer() {
+        let (worker, stealer) = new(4);
+        for i in 0..4 {
+            worker.push(i).unwrap();
+        }
+        assert_eq!(worker.push(4), Err(4));
+        assert_eq!(worker.len(), 4);
+
+        assert_eq!(stealer.steal(), Steal::Success(0));
+        assert_eq!(worker.pop(), Some(3));
+        assert_eq!(stealer.steal(), Steal::Success(1));
+        assert_eq!(worker.pop(), Some(2));
+        assert_eq!(worker.pop(), None);
+        assert_eq!(stealer.steal(), Steal::Empty);
+
+        // Wraps around the ring.
+        for i in 0..4 {
+            worker.push(i).unwrap();
+        }
+        assert_eq!(stealer.steal(), Steal::Success(0));
+        assert!(!stealer.is_empty());
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_deque_each_value_taken_once() {
+        const COUNT: usize = 10_000;
+        let (worker, stealer) = new::<usize>(64);
+        let taken: Vec<_> = (0..COUNT).map(|_| AtomicUsize::new(0)).collect();
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                let stealer = stealer.clone();
+                let taken = &taken;
+                s.spawn(move || {
+                    let mut misses = 0;
+                    while misses < 1000 {
+                        match stealer.steal() {
+                            Steal::Success(i) => {
+                                taken[i].fetch_add(1, Ordering::Relaxed);
+                                misses = 0;
+                            }
+                            Steal::Retry => {}
+                            Steal::Empty => {
+                                misses += 1;
+                                thread::yield_now();
+                            }
+                        }
+                    }
+                });
+            }
+
+            for i in 0..COUNT {
+                let mut val = i;
+                while let Err(v) = worker.push(val) {
+                    val = v;
+                    if let Some(j) = worker.pop() {
+                        taken[j].fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            while let Some(j) = worker.pop() {
+                taken[j].fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        assert!(taken.iter().all(|n| n.load(Ordering::Relaxed) == 1));
+    }
+
+    #[test]
+    fn test_deque_drops_leftovers() {
+        let val = Arc::new(());
+        let (worker, stealer) = new(8);
+        for _ in 0..5 {
+            worker.push(Arc::clone(&val)).unwrap();
+        }
+        drop(worker.pop());
+        assert!(matches!(stealer.steal(), Steal::Success(_)));
+        drop((worker, stealer));
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+}
@@ -0,0 +1,269 @@
+//! Epoch-based memory reclamation, in the style of `crossbeam-epoch`.
+//!
+//! Threads `pin` themselves before touching shared nodes, announcing the
+//! global epoch they observed. Unlinked nodes are not freed but handed to
+//! `Guard::defer_destroy`, tagged with the epoch they were retired in. The
+//! global epoch only advances when every pinned thread has observed the
+//! current one, so once it has moved two steps past a node's retirement, no
+//! pinned thread can still hold a pointer to it and it is freed.
+//!
+//! Compared to the `threads_in_pop` counting in `Stack`, a single slow thread
+//! only delays garbage from its own epoch instead of everything, and garbage
+//! doesn't depend on the structure ever being quiet. The price is a global
+//! registry of threads and a fence on every `pin`.
+//!
+//! Registration and the garbage list are behind plain mutexes: they're touched
+//! only when a thread starts or exits, on `defer`, and every `COLLECT_EVERY`
+//! pins, never on the lock-free fast path itself.
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Advances by one every time all pinned threads have caught up.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+static PARTICIPANTS: Mutex<Vec<Arc<Participant>>> = Mutex::new(Vec::new());
+
+type Deferred = Box<dyn FnOnce() + Send>;
+
+/// Deferred functions with the epoch they were retired in.
+static GARBAGE: Mutex<Vec<(usize, Deferred)>> = Mutex::new(Vec::new());
+
+/// Try to advance the epoch and collect garbage once per this many pins.
+const COLLECT_EVERY: usize = 64;
+
+struct Participant {
+    /// `0` when not pinned, otherwise `(epoch << 1) | 1`.
+    state: AtomicUsize,
+}
+
+struct Local {
+    participant: Arc<Participant>,
+    /// Nested `pin`s only announce the epoch once.
+    guards: Cell<usize>,
+    pins: Cell<usize>,
+}
+
+impl Local {
+    fn register() -> Self {
+        let participant = Arc::new(Participant {
+            state: AtomicUsize::new(0),
+        });
+        PARTICIPANTS.lock().unwrap().push(participant.clone());
+
+        Self {
+            participant,
+            guards: Cell::new(0),
+            pins: Cell::new(0),
+        }
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        PARTICIPANTS
+            .lock()
+            .unwrap()
+            .retain(|p| !Arc::ptr_eq(p, &self.participant));
+    }
+}
+
+thread_local! {
+    static LOCAL: Local = Local::register();
+}
+
+/// Keeps the current thread pinned: nodes it loads from a shared structure
+/// while the guard lives won't be freed under it.
+pub struct Guard {
+    /// Pinning is per thread, so the guard must stay on its thread.
+    _not_send: PhantomData<*mut ()>,
+}
+
+/// Pins the current thread, see `Guard`.
+pub fn pin() -> Guard {
+    LOCAL.with(|local| {
+        let guards = local.guards.get();
+        local.guards.set(guards + 1);
+
+        if guards == 0 {
+            let epoch = EPOCH.load(Ordering::Relaxed);
+            local
+                .participant
+                .state
+                .store((epoch << 1) | 1, Ordering::Relaxed);
+            // The announcement must be visible to `try_advance` before any of
+            // our loads of shared pointers, a store-load ordering that only a
+            // `SeqCst` fence provides.
+            atomic::fence(Ordering::SeqCst);
+
+            let pins = local.pins.get() + 1;
+            local.pins.set(pins);
+            if pins % COLLECT_EVERY == 0 {
+                collect();
+            }
+        }
+    });
+
+    Guard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Guard {
+    /// Runs `f` once no currently pinned thread can observe what it cleans up.
+    pub fn defer(&self, f: impl FnOnce() + Send + 'static) {
+        // Load after the caller unlinked the node: anyone pinned in an older
+        // epoch might have seen it, anyone pinning later can't.
+        let epoch = EPOCH.load(Ordering::SeqCst);
+        GARBAGE.lock().unwrap().push((epoch, Box::new(f)));
+    }
+
+    /// Like `defer`, for functions that aren't `Send` or `'static`.
+    ///
+    /// # Safety
+    ///
+    /// Running `f` on any thread, at any later point, must be sound: whatever
+    /// it borrows must outlive it, and whatever it captures must be fine to
+    /// use from another thread.
+    pub unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F) {
+        struct AssertSend<F>(F);
+        // SAFETY: Guaranteed by the caller.
+        unsafe impl<F> Send for AssertSend<F> {}
+
+        let f = AssertSend(f);
+        let f: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+            let f = f;
+            (f.0)()
+        });
+
+        // SAFETY: Only the lifetime is extended, which the caller guarantees
+        // is fine. The layout of the trait object is unchanged.
+        let f: Deferred = unsafe { mem::transmute(f) };
+        let epoch = EPOCH.load(Ordering::SeqCst);
+        GARBAGE.lock().unwrap().push((epoch, f));
+    }
+
+    /// Frees the `Box` behind `ptr` once no pinned thread can observe it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, be unreachable for threads that
+    /// pin from now on, and not be destroyed any other way. Dropping the `T`
+    /// must be fine on any thread, at any later point (e.g, it holds no
+    /// borrows, or its destructor doesn't use them).
+    pub unsafe fn defer_destroy<T>(&self, ptr: *mut T) {
+        /// `T` is erased behind a `'static` function pointer, so the closure
+        /// is `'static` even when `T` isn't.
+        unsafe fn drop_box<T>(ptr: *mut ()) {
+            // SAFETY: Guaranteed by the caller of `defer_destroy`.
+            drop(unsafe { Box::from_raw(ptr.cast::<T>()) });
+        }
+
+        struct Erased(*mut (), unsafe fn(*mut ()));
+        // SAFETY: Dropping on another thread is allowed by the caller.
+        unsafe impl Send for Erased {}
+
+        let erased = Erased(ptr.cast(), drop_box::<T>);
+        self.defer(move || {
+            let erased = erased;
+            // SAFETY: `erased.1` is `drop_box` for the type `erased.0` had.
+            unsafe { (erased.1)(erased.0) };
+        });
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // `try_with`: the guard may be dropped while thread-locals are being
+        // torn down, in which case there's nothing left to unpin.
+        let _ = LOCAL.try_with(|local| {
+            let guards = local.guards.get() - 1;
+            local.guards.set(guards);
+
+            if guards == 0 {
+                // `Release` so our accesses to shared nodes happen-before a
+                // collector that sees us unpinned.
+                local.participant.state.store(0, Ordering::Release);
+            }
+        });
+    }
+}
+
+/// Advances the epoch if all pinned threads observed the current one.
+fn try_advance() -> usize {
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    // Pairs with the fence in `pin`: either we see a thread's announcement,
+    // or it sees our (or a later) epoch.
+    atomic::fence(Ordering::SeqCst);
+
+    for participant in PARTICIPANTS.lock().unwrap().iter() {
+        let state = participant.state.load(Ordering::Relaxed);
+        if state & 1 == 1 && state >> 1 != epoch {
+            return epoch;
+        }
+    }
+
+    // `Acquire` above would be per-participant, a fence covers all of them.
+    atomic::fence(Ordering::Acquire);
+
+    match EPOCH.compare_exchange(epoch, epoch + 1, Ordering::Release, Ordering::Relaxed) {
+        Ok(_) => epoch + 1,
+        Err(actual) => actual,
+    }
+}
+
+/// Runs the deferred functions retired at least two epochs ago.
+fn collect() {
+    let epoch = try_advance();
+
+    let ready: Vec<Deferred> = {
+        let mut garbage = GARBAGE.lock().unwrap();
+        let (ready, rest) = mem::take(&mut *garbage)
+            .into_iter()
+            .partition(|(retired, _)| retired + 2 <= epoch);
+        *garbage = rest;
+        ready.into_iter().map(|(_, f)| f).collect()
+    };
+
+    // Outside the lock: destructors may defer more garbage.
+    for f in ready {
+        f();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_epoch_defer_runs_after_unpin() {
+        let ran = Arc::new(AtomicBool::new(false));
+
+        {
+            let guard = pin();
+            let flag = ran.clone();
+            guard.defer(move || flag.store(true, Ordering::Relaxed));
+
+            // Still pinned in the retirement epoch, so the epoch can advance at
+            // most once and the garbage must survive.
+            for _ in 0..COLLECT_EVERY * 4 {
+                drop(pin());
+            }
+            assert!(!ran.load(Ordering::Relaxed));
+        }
+
+        // Other tests pin concurrently, so this may take a few rounds.
+        for _ in 0..10_000 {
+            if ran.load(Ordering::Relaxed) {
+                return;
+            }
+            drop(pin());
+            std::thread::yield_now();
+        }
+        panic!("deferred function never ran");
+    }
+}
@@ -0,0 +1,169 @@
+//! `Stack` ported to epoch-based reclamation, for comparison.
+//!
+//! The algorithm is the same Treiber stack, but a popped node is handed to
+//! `Guard::defer_destroy` instead of being counted against `threads_in_pop`.
+//! Every operation just runs pinned, with no reclamation logic of its own.
+
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use super::epoch;
+use crate::atomics::Backoff;
+
+struct Node<T> {
+    value: ManuallyDrop<T>,
+    /// Never written after the node is published, so a plain pointer.
+    next: *mut Node<T>,
+}
+
+pub struct EpochStack<T> {
+    head: AtomicPtr<Node<T>>,
+}
+
+// SAFETY: Values are moved in by `push` and out by `pop`, possibly on
+// different threads, but never shared.
+unsafe impl<T: Send> Send for EpochStack<T> {}
+unsafe impl<T: Send> Sync for EpochStack<T> {}
+
+impl<T> EpochStack<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: ptr::null_mut(),
+        }));
+
+        // Pushing never dereferences shared nodes, so it doesn't need to pin.
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: `node` isn't published until the CAS succeeds.
+            unsafe { (*node).next = head };
+
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(actual) => {
+                    head = actual;
+                    backoff.spin();
+                }
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let guard = epoch::pin();
+
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.is_null() {
+                return None;
+            }
+
+            // SAFETY: We're pinned, so even if `head` has been popped since we
+            // loaded it, it hasn't been freed.
+            let next = unsafe { (*head).next };
+
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(actual) => {
+                    head = actual;
+                    backoff.spin();
+                }
+            }
+        }
+
+        // SAFETY: Winning the CAS makes us the only thread to touch `value`.
+        let value = unsafe { ManuallyDrop::take(&mut (*head).value) };
+        // SAFETY: `head` is unlinked, so threads pinning from now on can't
+        // reach it. Only the node's memory is freed: its value was taken, and
+        // `ManuallyDrop` keeps it from being dropped again.
+        unsafe { guard.defer_destroy(head) };
+
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+}
+
+impl<T> Default for EpochStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for EpochStack<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_epoch_stack_lifo() {
+        let stack = EpochStack::new();
+
+        stack.push(1);
+        stack.push(2);
+        assert_eq!(stack.pop(), Some(2));
+        stack.push(3);
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_epoch_stack_concurrent_push_pop() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 1000;
+
+        let stack = EpochStack::new();
+        let popped = std::sync::Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (stack, popped) = (&stack, &popped);
+                s.spawn(move || {
+                    let mut mine = Vec::new();
+                    for i in 0..PER_THREAD {
+                        stack.push(t * PER_THREAD + i);
+                        if i % 2 == 0
+                            && let Some(v) = stack.pop()
+                        {
+                            mine.push(v);
+                        }
+                    }
+                    popped.lock().unwrap().extend(mine);
+                });
+            }
+        });
+
+        let mut all = popped.into_inner().unwrap();
+        while let Some(v) = stack.pop() {
+            all.push(v);
+        }
+        all.sort();
+
+        assert_eq!(all, (0..THREADS * PER_THREAD).collect::<Vec<_>>());
+    }
+}
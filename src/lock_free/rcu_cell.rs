@@ -0,0 +1,177 @@
+//! A cell holding an `Arc<T>` that readers can snapshot without locking while
+//! writers replace it, in the spirit of read-copy-update (RCU) and the
+//! `arc-swap` crate.
+//!
+//! The tricky part is `load`: it reads the pointer and then increments the
+//! strong count, and a writer could swap the pointer out and drop the last
+//! `Arc` in between, so the increment would hit freed memory. Here the cell's
+//! own reference to a replaced value is released through epoch reclamation:
+//! `load` runs pinned, so the cell's reference (and with it the value) outlives
+//! any `load` that may have read the old pointer.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use super::epoch;
+use crate::arc::Arc;
+
+/// An `Arc<T>` that can be replaced while readers take snapshots of it, see
+/// the module docs.
+///
+/// A replaced value is released later, by whichever thread collects the
+/// epoch garbage, so `T` must be fine to drop there: `Send + Sync` like for
+/// any `Arc` that crosses threads, and `'static` so it borrows nothing that
+/// could be gone by then.
+///
+/// ```compile_fail
+/// use crust_of_rust::arc::Arc;
+/// use crust_of_rust::lock_free::RcuCell;
+///
+/// let name = String::from("borrowed");
+/// let cell = RcuCell::new(Arc::new(name.as_str()));
+/// cell.store(Arc::new("static"));
+/// ```
+///
+/// ```compile_fail
+/// use std::rc::Rc;
+///
+/// use crust_of_rust::arc::Arc;
+/// use crust_of_rust::lock_free::RcuCell;
+///
+/// let cell = RcuCell::new(Arc::new(Rc::new(1)));
+/// cell.store(Arc::new(Rc::new(2)));
+/// ```
+pub struct RcuCell<T> {
+    /// From `Arc::into_raw`, owning one strong count.
+    ptr: AtomicPtr<T>,
+    _marker: PhantomData<Arc<T>>,
+}
+
+// SAFETY: The cell hands out and takes in `Arc<T>`s from any thread, so it
+// needs exactly what `Arc<T>` needs to be sent or shared.
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns a snapshot of the current value. Later `store`s don't affect
+    /// it, and it keeps the value alive for as long as it's held.
+    pub fn load(&self) -> Arc<T> {
+        let _guard = epoch::pin();
+
+        // `Acquire` pairs with the `AcqRel` swap, so the value is fully
+        // initialized when we read it.
+        let ptr = self.ptr.load(Ordering::Acquire);
+
+        // SAFETY: We're pinned, and a replaced pointer's count is only
+        // released after every pinned thread has moved on, so the count is
+        // still at least one.
+        unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// Replaces the value. Readers that already loaded the old value keep it.
+    pub fn store(&self, value: Arc<T>) {
+        drop(self.swap(value));
+    }
+
+    /// Replaces the value, returning the previous one.
+    pub fn swap(&self, value: Arc<T>) -> Arc<T> {
+        let guard = epoch::pin();
+
+        let new = Arc::into_raw(value) as *mut T;
+        let old = self.ptr.swap(new, Ordering::AcqRel);
+
+        // SAFETY: The cell's count keeps `old` alive until the deferred
+        // release below. The returned `Arc` gets a count of its own, so it
+        // can be dropped right away without affecting concurrent loaders.
+        let previous = unsafe {
+            Arc::increment_strong_count(old);
+            Arc::from_raw(old)
+        };
+
+        // SAFETY: Takes over the count the cell owned, to release it once no
+        // `load` can still be about to increment it.
+        let owned = unsafe { Arc::from_raw(old) };
+        guard.defer(move || drop(owned));
+
+        previous
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self`, so no `load` is in flight, and the pointer owns
+        // a strong count.
+        drop(unsafe { Arc::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Debug)]
+    struct Config {
+        version: u32,
+        name: String,
+    }
+
+    #[test]
+    fn test_rcu_cell_snapshot() {
+        let cell = RcuCell::new(Arc::new(1));
+
+        let snapshot = cell.load();
+        cell.store(Arc::new(2));
+
+        assert_eq!(*snapshot, 1);
+        assert_eq!(*cell.load(), 2);
+        assert_eq!(*cell.swap(Arc::new(3)), 2);
+        assert_eq!(*cell.load(), 3);
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_rcu_cell_readers_and_writer() {
+        let cell = RcuCell::new(Arc::new(Config {
+            version: 0,
+            name: String::from("v0"),
+        }));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for version in 1..=500 {
+                    cell.store(Arc::new(Config {
+                        version,
+                        name: format!("v{version}"),
+                    }));
+                }
+            });
+
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    for _ in 0..1000 {
+                        let config = cell.load();
+                        // Every snapshot is internally consistent, and versions
+                        // never go backwards.
+                        assert_eq!(config.name, format!("v{}", config.version));
+                        assert!(config.version >= last);
+                        last = config.version;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(cell.load().version, 500);
+    }
+}
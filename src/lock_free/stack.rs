@@ -0,0 +1,286 @@
+//! Treiber stack: a singly linked list whose head is swapped with CAS.
+//!
+//! Reclamation uses a count of threads currently inside `pop` (from "C++
+//! Concurrency in Action"). A popped node is freed right away only if its
+//! popper is the only thread in `pop`, otherwise it goes on a pending list,
+//! which is freed by whichever popper next finds itself alone. No node is ever
+//! freed while a pop that might have loaded it is in flight, which rules out
+//! both use-after-free and ABA: an address can't be reused while a stale CAS
+//! could still compare against it.
+//!
+//! The catch is that under constant pop traffic the count may never drop to
+//! one, and the pending list grows without bound.
+
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::atomics::Backoff;
+
+struct Node<T> {
+    /// Moved out by the thread whose CAS unlinks the node, the node itself
+    /// may be freed much later.
+    value: ManuallyDrop<T>,
+    /// Atomic because a stale popper may read it while the node is being
+    /// chained onto the pending list. Only ever accessed `Relaxed`: the
+    /// `head` CAS orders everything else.
+    next: AtomicPtr<Node<T>>,
+}
+
+pub struct Stack<T> {
+    head: AtomicPtr<Node<T>>,
+    threads_in_pop: AtomicUsize,
+    /// Unlinked nodes that may still be read by a concurrent `pop`, chained
+    /// through `next`.
+    pending: AtomicPtr<Node<T>>,
+}
+
+// SAFETY: Values are moved in by `push` and out by `pop`, possibly on
+// different threads, but never shared.
+unsafe impl<T: Send> Send for Stack<T> {}
+unsafe impl<T: Send> Sync for Stack<T> {}
+
+impl<T> Stack<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+            threads_in_pop: AtomicUsize::new(0),
+            pending: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: `node` isn't published until the CAS succeeds.
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+
+            // `Release` publishes the node's contents to the `Acquire` in `pop`.
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(actual) => {
+                    head = actual;
+                    backoff.spin();
+                }
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        // Registered before loading `head`, so anyone who frees nodes sees us.
+        // `SeqCst` here and in `try_reclaim` puts this increment and the
+        // reclaimer's check in a single order with the `head` accesses.
+        self.threads_in_pop.fetch_add(1, Ordering::SeqCst);
+
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Ordering::SeqCst);
+        let node = loop {
+            if head.is_null() {
+                self.threads_in_pop.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+
+            // SAFETY: We're counted in `threads_in_pop`, so `head` can't have
+            // been freed since we loaded it, even if it's been popped.
+            let next = unsafe { (*head).next.load(Ordering::Relaxed) };
+
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break head,
+                Err(actual) => {
+                    head = actual;
+                    backoff.spin();
+                }
+            }
+        };
+
+        // SAFETY: Winning the CAS makes us the only thread to touch `value`.
+        let value = unsafe { ManuallyDrop::take(&mut (*node).value) };
+        // SAFETY: `node` is unlinked, and its value has been taken.
+        unsafe { self.try_reclaim(node) };
+
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Frees `node` (and the pending list) if no other thread is in `pop`,
+    /// otherwise defers it. Also leaves `pop`.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be unlinked from the stack, with its value already taken.
+    unsafe fn try_reclaim(&self, node: *mut Node<T>) {
+        if self.threads_in_pop.load(Ordering::SeqCst) == 1 {
+            // Alone: nobody else can have loaded `node`. Claim the pending
+            // list, but only free it if we're still alone after claiming, as
+            // a new popper may have arrived and loaded one of its nodes.
+            let pending = self.pending.swap(ptr::null_mut(), Ordering::SeqCst);
+
+            if self.threads_in_pop.fetch_sub(1, Ordering::SeqCst) == 1 {
+                // SAFETY: Nobody else is in `pop`, and the list is ours.
+                unsafe { free_list(pending) };
+            } else if !pending.is_null() {
+                // SAFETY: The list is ours.
+                unsafe {
+                    let mut last = pending;
+                    loop {
+                        let next = (*last).next.load(Ordering::Relaxed);
+                        if next.is_null() {
+                            break;
+                        }
+                        last = next;
+                    }
+                    self.defer_list(pending, last);
+                }
+            }
+
+            // SAFETY: Nobody loaded `node` before it was unlinked, and anyone
+            // arriving later can't reach it.
+            drop(unsafe { Box::from_raw(node) });
+        } else {
+            // SAFETY: `node` is unlinked. Its `next` still points into the
+            // stack, but `defer_list` only follows it from `last`, which it
+            // overwrites.
+            unsafe { self.defer_list(node, node) };
+            self.threads_in_pop.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Prepends the list from `first` to `last` to `pending`.
+    ///
+    /// # Safety
+    ///
+    /// The nodes must be unlinked and owned by the caller, and `last` must be
+    /// reachable from `first`.
+    unsafe fn defer_list(&self, first: *mut Node<T>, last: *mut Node<T>) {
+        let mut pending = self.pending.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: The list is owned by the caller.
+            unsafe { (*last).next.store(pending, Ordering::Relaxed) };
+            match self.pending.compare_exchange_weak(
+                pending,
+                first,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => pending = actual,
+            }
+        }
+    }
+}
+
+/// # Safety
+///
+/// The nodes must not be reachable by any other thread, and their values must
+/// have been taken.
+unsafe fn free_list<T>(mut node: *mut Node<T>) {
+    while !node.is_null() {
+        // SAFETY: Guaranteed by the caller. `ManuallyDrop` keeps the (already
+        // taken) value from being dropped again.
+        let boxed = unsafe { Box::from_raw(node) };
+        node = boxed.next.load(Ordering::Relaxed);
+    }
+}
+
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Stack<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+
+        // SAFETY: `&mut self`, so no pop is in flight.
+        unsafe { free_list(*self.pending.get_mut()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_stack_lifo() {
+        let stack = Stack::new();
+        assert!(stack.is_empty());
+
+        stack.push(1);
+        stack.push(2);
+        stack.push(3);
+
+        assert_eq!(stack.pop(), Some(3));
+        assert_eq!(stack.pop(), Some(2));
+        stack.push(4);
+        assert_eq!(stack.pop(), Some(4));
+        assert_eq!(stack.pop(), Some(1));
+        assert_eq!(stack.pop(), None);
+    }
+
+    #[test]
+    fn test_stack_drop_values() {
+        let value = std::sync::Arc::new(());
+        let stack = Stack::new();
+        for _ in 0..3 {
+            stack.push(value.clone());
+        }
+        drop(stack.pop());
+
+        drop(stack);
+        assert_eq!(std::sync::Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_stack_concurrent_push_pop() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 1000;
+
+        let stack = Stack::new();
+        let popped = std::sync::Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (stack, popped) = (&stack, &popped);
+                s.spawn(move || {
+                    let mut mine = Vec::new();
+                    for i in 0..PER_THREAD {
+                        stack.push(t * PER_THREAD + i);
+                        if i % 2 == 0
+                            && let Some(v) = stack.pop()
+                        {
+                            mine.push(v);
+                        }
+                    }
+                    popped.lock().unwrap().extend(mine);
+                });
+            }
+        });
+
+        let mut all = popped.into_inner().unwrap();
+        while let Some(v) = stack.pop() {
+            all.push(v);
+        }
+        all.sort();
+
+        // Every value came out exactly once.
+        assert_eq!(all, (0..THREADS * PER_THREAD).collect::<Vec<_>>());
+    }
+}
@@ -1,13 +1,23 @@
+use std::alloc::{self, Layout};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
 use std::ops::Deref;
-use std::ptr::NonNull;
+use std::pin::Pin;
+use std::process;
+use std::ptr::{self, NonNull};
 
 use crate::cell::Cell;
 
+pub mod cycles;
+
 /// Single-threaded, reference-counted smart pointer allowing multiple shared
 /// references to a value.
 #[derive(Debug)]
-pub struct Rc<T> {
+pub struct Rc<T: ?Sized> {
     /// Needs to be heap-allocated since it can be referenced from multiple
     /// regions of code.
     inner: NonNull<RcInner<T>>,
@@ -30,12 +40,24 @@ pub struct Rc<T> {
 // impl<T> !Send for Rc<T> {}
 // impl<T> !Sync for Rc<T> {}
 
-/// Enables the reference count to also be shared between cloned Rc's.
+/// Enables the reference counts to also be shared between cloned Rc's and
+/// Weak's.
+///
+/// `#[repr(C)]` so the counts always come first and the value is laid out at
+/// a predictable offset after them. This is what allows allocating an
+/// `RcInner` by hand for unsized values, where the layout is only known at
+/// runtime.
 #[derive(Debug)]
-struct RcInner<T> {
-    value: T,
+#[repr(C)]
+struct RcInner<T: ?Sized> {
     /// So it can be updated through a shared reference.
-    ref_count: Cell<usize>,
+    strong: Cell<usize>,
+    /// Number of `Weak`s, plus one implicit weak reference held collectively
+    /// by all `Rc`s. The allocation is freed only once this reaches zero, so
+    /// a `Weak` can still read `strong` after the value has been dropped.
+    weak: Cell<usize>,
+    /// Must be the last field since it may be unsized.
+    value: T,
 }
 
 impl<T> Rc<T> {
@@ -47,21 +69,280 @@ impl<T> Rc<T> {
                 inner: NonNull::new_unchecked(Box::into_raw(Box::new(RcInner {
                     value,
                     // Creating an `Rc` counts as a reference.
-                    ref_count: Cell::new(1),
+                    strong: Cell::new(1),
+                    weak: Cell::new(1),
                 }))),
                 _marker: PhantomData,
             }
         }
     }
+
+    /// Creates a new `Pin<Rc<T>>`, so the value is guaranteed to never move
+    /// again, even if `T: !Unpin`.
+    ///
+    /// Sound because the value lives in the heap allocation for its entire
+    /// lifetime, cloning or moving the `Rc` only copies the pointer. The
+    /// methods that could move the value back out (`try_unwrap`, `get_mut`,
+    /// `make_mut`) all need the `Rc` itself, which `Pin` doesn't give out.
+    pub fn pin(value: T) -> Pin<Self> {
+        // SAFETY: See above, the value is never moved out of the allocation
+        // while it's pinned.
+        unsafe { Pin::new_unchecked(Rc::new(value)) }
+    }
+
+    /// Creates a new `Rc` to a value that can hold a `Weak` to itself, which
+    /// is passed to `data_fn` while the value is being constructed.
+    ///
+    /// Upgrading the `Weak` inside `data_fn` returns `None`, since the value
+    /// doesn't exist yet.
+    pub fn new_cyclic(data_fn: impl FnOnce(&Weak<T>) -> T) -> Self {
+        // Allocate first with an uninitialized value, so there is an address
+        // for the `Weak` to point to. `strong` starts at zero so the `Weak`
+        // can't be upgraded to read the missing value.
+        let inner = Box::into_raw(Box::new(RcInner {
+            strong: Cell::new(0),
+            weak: Cell::new(1),
+            value: MaybeUninit::<T>::uninit(),
+        })) as *mut RcInner<T>;
+
+        // SAFETY: `Box::into_raw` never returns a null pointer.
+        //
+        // This `Weak` owns the implicit weak reference, so if `data_fn` panics
+        // dropping it frees the allocation. `MaybeUninit<T>` has the same
+        // layout as `T`, so the allocation matches an `RcInner<T>`.
+        let weak = Weak {
+            inner: unsafe { NonNull::new_unchecked(inner) },
+        };
+
+        let value = data_fn(&weak);
+
+        // SAFETY: The allocation is still live since `weak` is. Only now that
+        // the value is written can `strong` be incremented.
+        unsafe {
+            ptr::write(&raw mut (*inner).value, value);
+            (*inner).strong.set(1);
+        }
+
+        // The implicit weak reference is now held by the `Rc` instead.
+        mem::forget(weak);
+
+        Self {
+            inner: unsafe { NonNull::new_unchecked(inner) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the inner value if `this` is the only `Rc` to it, otherwise
+    /// gives back the same `Rc` in `Err`.
+    ///
+    /// Takes `this` instead of `self` so it is not confused with a method on
+    /// `T` through `Deref`.
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if this.strong().get() != 1 {
+            return Err(this);
+        }
+
+        // Prevent `Drop` from running, otherwise it would drop `T` after we've
+        // moved it out of the allocation.
+        let this = ManuallyDrop::new(this);
+
+        // SAFETY: This is the last `Rc`, so no one else can observe the value.
+        // Setting `strong` to zero below ensures no `Weak` can upgrade and read
+        // it again, so `T`'s destructor only ever runs on the moved-out copy.
+        let value = unsafe { ptr::read(&(*this.inner.as_ptr()).value) };
+        this.strong().set(0);
+
+        // Release the implicit weak reference held by the `Rc`s, freeing the
+        // allocation if there are no other `Weak`s.
+        drop(Weak { inner: this.inner });
+
+        Ok(value)
+    }
+
+    /// Returns the inner value if `this` is the last `Rc` to it, otherwise
+    /// just drops `this`.
+    ///
+    /// Unlike `try_unwrap`, calling this on every clone guarantees exactly one
+    /// of them gets the value, instead of the `Rc`s having to be handed back
+    /// and retried.
+    pub fn into_inner(this: Self) -> Option<T> {
+        // `Rc` is single-threaded, so the count can't change between the
+        // check and the unwrap, and the failed `Rc` is dropped right away.
+        Rc::try_unwrap(this).ok()
+    }
 }
 
-impl<T> Clone for Rc<T> {
-    fn clone(&self) -> Self {
-        // Increment the reference count.
+impl<T: ?Sized> Rc<T> {
+    /// Returns a mutable reference to the inner value if `this` is the only
+    /// `Rc` or `Weak` to it.
+    ///
+    /// Any other `Rc` (or upgraded `Weak`) could be used to obtain a `&T`
+    /// while the `&mut T` is live, so mutable access is only possible when
+    /// unique.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.strong().get() != 1 || this.weak().get() != 1 {
+            return None;
+        }
+
+        // SAFETY: This is the only `Rc`, and it is mutably borrowed for the
+        // lifetime of the returned reference, so no other reference to `T` can
+        // be created.
+        Some(unsafe { &mut (*this.inner.as_ptr()).value })
+    }
+
+    /// Consumes the `Rc`, returning a pointer to the value without
+    /// decrementing the strong count.
+    ///
+    /// The pointer is to the value itself, not the `RcInner` header, so it
+    /// can be handed to code that only knows about `T`. It must be passed back
+    /// to `Rc::from_raw` for the value to ever be dropped.
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+        Self::as_ptr(&this)
+    }
+
+    /// Consumes the `Rc` without decrementing the strong count, returning a
+    /// reference to the value that lives as long as `T` could.
+    ///
+    /// The forgotten count means the value is never dropped, even once every
+    /// other `Rc` is gone, so any `Rc`s and `Weak`s to it stay valid as well.
+    pub fn leak<'a>(this: Self) -> &'a T
+    where
+        T: 'a,
+    {
+        // SAFETY: The pointer came from `into_raw`, and since its strong count
+        // is never released, the value is never dropped or freed.
+        unsafe { &*Rc::into_raw(this) }
+    }
+
+    /// Returns a pointer to the value, without affecting the strong count.
+    pub fn as_ptr(this: &Self) -> *const T {
+        // SAFETY: We currently have an `Rc`, so the allocation is live. No
+        // reference to the value is created, only a pointer to the field.
+        unsafe { &raw const (*this.inner.as_ptr()).value }
+    }
+
+    /// Reconstructs an `Rc` from a pointer returned by `Rc::into_raw`, taking
+    /// back ownership of one strong count.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from `Rc::into_raw` (for the same `T`, or a type
+    /// that unsizes to `T`, like a concrete type for `dyn Trait`), and each
+    /// such pointer must only be passed to `from_raw` once.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
         unsafe {
-            let count = (*self.inner.as_ptr()).ref_count.get();
-            (*self.inner.as_ptr()).ref_count.set(count + 1);
+            // Walk back from the value to the start of the `RcInner`, keeping
+            // the metadata of `ptr` (if any).
+            let inner = ptr.byte_sub(Self::data_offset(ptr)) as *mut RcInner<T>;
+
+            Self {
+                inner: NonNull::new_unchecked(inner),
+                _marker: PhantomData,
+            }
         }
+    }
+
+    /// Offset of the value from the start of its `RcInner`.
+    ///
+    /// Since `RcInner` is `#[repr(C)]`, the value comes right after the
+    /// counts, rounded up to its alignment. For unsized values, the alignment
+    /// is only known through the pointer's metadata (e.g. the vtable).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a value allocated in an `RcInner`.
+    unsafe fn data_offset(ptr: *const T) -> usize {
+        let align = unsafe { mem::align_of_val_raw(ptr) };
+        let header = Layout::new::<RcInner<()>>();
+
+        header.size().next_multiple_of(align)
+    }
+
+    /// Creates a new `Weak` to the allocation, which does not keep the value
+    /// alive.
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        increment(this.weak());
+
+        Weak { inner: this.inner }
+    }
+
+    /// Returns `true` if both `Rc`s point to the same allocation, rather than
+    /// just comparing equal values.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        // Only compare addresses, the metadata of unsized values (e.g. the
+        // vtable of a trait object) isn't guaranteed to be unique.
+        ptr::addr_eq(this.inner.as_ptr(), other.inner.as_ptr())
+    }
+
+    /// Number of `Rc`s pointing to the allocation.
+    pub fn strong_count(this: &Self) -> usize {
+        this.strong().get()
+    }
+
+    /// Number of `Weak`s pointing to the allocation.
+    pub fn weak_count(this: &Self) -> usize {
+        // Excludes the implicit weak reference held by the `Rc`s.
+        this.weak().get() - 1
+    }
+
+    // Only the count fields are borrowed, never the whole `RcInner`, so these
+    // references don't alias the value while it is being mutated or dropped.
+
+    fn strong(&self) -> &Cell<usize> {
+        // SAFETY: We currently have an `Rc`, so the allocation is live.
+        unsafe { &(*self.inner.as_ptr()).strong }
+    }
+
+    fn weak(&self) -> &Cell<usize> {
+        // SAFETY: We currently have an `Rc`, so the allocation is live.
+        unsafe { &(*self.inner.as_ptr()).weak }
+    }
+}
+
+impl<T> Rc<T>
+where
+    T: Clone,
+{
+    /// Returns a mutable reference to the inner value, cloning it into a new
+    /// allocation first if other `Rc`s point to it (clone-on-write).
+    ///
+    /// The other `Rc`s keep pointing at the original value, so they never
+    /// observe the mutation. If only `Weak`s remain, the value is moved into a
+    /// new allocation instead, and the `Weak`s can no longer upgrade.
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        if this.strong().get() != 1 {
+            // Assigning drops the old `Rc`, decrementing the count of the
+            // shared allocation, which cannot reach zero since it was shared.
+            *this = Rc::new(T::clone(this));
+        } else if this.weak().get() != 1 {
+            // SAFETY: This is the last `Rc`. Setting `strong` to zero
+            // prevents the `Weak`s from upgrading and reading the moved-out
+            // value, and the old `Rc` is overwritten without being dropped.
+            unsafe {
+                let value = ptr::read(&(*this.inner.as_ptr()).value);
+                this.strong().set(0);
+
+                let weak = Weak { inner: this.inner };
+                ptr::write(this, Rc::new(value));
+
+                // Release the implicit weak reference held by the old `Rc`.
+                // Other `Weak`s exist, so this never frees the allocation.
+                drop(weak);
+            }
+        }
+
+        // SAFETY: `this` is now the only `Rc` to its allocation, with no
+        // `Weak`s, and it is mutably borrowed for the lifetime of the returned
+        // reference.
+        unsafe { &mut (*this.inner.as_ptr()).value }
+    }
+}
+
+impl<T: ?Sized> Clone for Rc<T> {
+    fn clone(&self) -> Self {
+        // Increment the reference count.
+        increment(self.strong());
 
         // `NonNull` implements `Copy` since it just wraps a raw pointer.
         Self {
@@ -71,33 +352,417 @@ impl<T> Clone for Rc<T> {
     }
 }
 
-impl<T> Deref for Rc<T> {
+impl<T: ?Sized> Deref for Rc<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        // SAFETY: self.inner is allocated though a Box that is only
-        // deallocated when the last `Rc` is dropped, but we currently have
-        // an `Rc`.
+        // SAFETY: The value is only dropped when the last `Rc` is dropped,
+        // but we currently have an `Rc`.
         unsafe { &(*self.inner.as_ptr()).value }
     }
 }
 
-impl<T> Drop for Rc<T> {
+impl<T: ?Sized> Drop for Rc<T> {
     fn drop(&mut self) {
+        let strong = self.strong().update(|n| n - 1);
+
+        // Drop the value once the last Rc is gone, since it is no longer
+        // referenced. The allocation itself may still be needed by `Weak`s.
+        if strong == 0 {
+            // SAFETY: This was the last `Rc` and `strong` is now zero, so no
+            // `Weak` can upgrade to access the value again.
+            unsafe { ptr::drop_in_place(&mut (*self.inner.as_ptr()).value) };
+
+            // Release the implicit weak reference held by the `Rc`s.
+            drop(Weak { inner: self.inner });
+        }
+    }
+}
+
+// The traits below forward to the value, so an `Rc<T>` behaves like a `T` when
+// compared, hashed or printed (two `Rc`s are equal if their values are,
+// `Rc::ptr_eq` compares the allocations).
+
+impl<T: ?Sized + PartialEq> PartialEq for Rc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Rc<T> {}
+
+impl<T: ?Sized + PartialOrd> PartialOrd for Rc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for Rc<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Hash> Hash for Rc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Rc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: Default> Default for Rc<T> {
+    fn default() -> Self {
+        Rc::new(T::default())
+    }
+}
+
+impl<T> From<T> for Rc<T> {
+    fn from(value: T) -> Self {
+        Rc::new(value)
+    }
+}
+
+/// Non-owning reference to an `Rc` allocation, which can be upgraded to an
+/// `Rc` while the value is still alive.
+///
+/// Used to break reference cycles, since a `Weak` doesn't contribute to the
+/// strong count that keeps the value alive.
+#[derive(Debug)]
+pub struct Weak<T: ?Sized> {
+    /// The allocation (not the value) stays live while any `Weak` exists.
+    ///
+    /// Set to `DANGLING` for a `Weak` created by `Weak::new`, which has no
+    /// allocation and must never be dereferenced.
+    inner: NonNull<RcInner<T>>,
+}
+
+/// Address of a `Weak` without an allocation.
+///
+/// Can't collide with a real `RcInner`, since the counts alone make it at
+/// least `usize`-aligned, and `usize::MAX` is not.
+const DANGLING: usize = usize::MAX;
+
+impl<T> Weak<T> {
+    /// Creates a `Weak` that was never associated with an allocation, so
+    /// `upgrade` always returns `None`.
+    ///
+    /// Useful as a placeholder, e.g. for the parent of a root node, without
+    /// having to allocate a value to point to.
+    pub const fn new() -> Self {
+        Self {
+            // SAFETY: `DANGLING` is non-zero.
+            inner: unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(DANGLING)) },
+        }
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized> Weak<T> {
+    /// Returns a new `Rc` if the value has not been dropped yet.
+    pub fn upgrade(&self) -> Option<Rc<T>> {
+        let strong = self.strong()?;
+        if strong.get() == 0 {
+            return None;
+        }
+
+        increment(strong);
+
+        Some(Rc {
+            inner: self.inner,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Number of `Rc`s pointing to the allocation.
+    pub fn strong_count(&self) -> usize {
+        self.strong().map_or(0, Cell::get)
+    }
+
+    // Both return `None` if there is no allocation to point to.
+
+    fn strong(&self) -> Option<&Cell<usize>> {
+        if self.inner.as_ptr().addr() == DANGLING {
+            return None;
+        }
+
+        // SAFETY: We currently have a `Weak`, so the allocation is live.
+        Some(unsafe { &(*self.inner.as_ptr()).strong })
+    }
+
+    fn weak(&self) -> Option<&Cell<usize>> {
+        if self.inner.as_ptr().addr() == DANGLING {
+            return None;
+        }
+
+        // SAFETY: We currently have a `Weak`, so the allocation is live.
+        Some(unsafe { &(*self.inner.as_ptr()).weak })
+    }
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        if let Some(weak) = self.weak() {
+            increment(weak);
+        }
+
+        Self { inner: self.inner }
+    }
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+    fn drop(&mut self) {
+        let Some(weak_count) = self.weak() else {
+            return;
+        };
+
+        let weak = weak_count.update(|n| n - 1);
+
+        // The last weak reference (including the implicit one held by the
+        // `Rc`s) frees the allocation, the value has already been dropped.
+        if weak == 0 {
+            // SAFETY: The layout is computed from the pointer's metadata
+            // without reading the (already dropped) value, and matches the
+            // layout the allocation was created with. Deallocating directly
+            // (rather than through `Box::from_raw`) avoids dropping the value a
+            // second time.
+            unsafe {
+                let layout = Layout::for_value_raw(self.inner.as_ptr());
+                alloc::dealloc(self.inner.as_ptr() as *mut u8, layout);
+            }
+        }
+    }
+}
+
+/// Increments a reference count, aborting the process if it would overflow.
+///
+/// Wrapping around to zero would let the next drop free the allocation while
+/// references to it are still live. Calling `mem::forget` on clones in a loop
+/// makes this reachable without ever running out of memory, so it must be
+/// guarded against for the unsafe code to be sound. Aborting rather than
+/// panicking matches std, since a caught panic would leave the same count
+/// reachable again.
+fn increment(count: &Cell<usize>) {
+    count.update(|n| n.checked_add(1).unwrap_or_else(|| process::abort()));
+}
+
+impl<T: ?Sized> Rc<T> {
+    /// Allocates an `RcInner<T>` with enough room for a value with
+    /// `value_layout`, initializing both counts to one.
+    ///
+    /// `mem_to_inner` turns the untyped allocation into a (possibly fat)
+    /// pointer to `RcInner<T>`, since the metadata of an unsized `T` (slice
+    /// length, vtable) can't be derived from the layout alone.
+    ///
+    /// # Safety
+    ///
+    /// The value is left uninitialized, it must be written before the `Rc`
+    /// is constructed. `mem_to_inner` must return a pointer to `mem` whose
+    /// metadata describes a value with `value_layout`.
+    unsafe fn allocate_for_layout(
+        value_layout: Layout,
+        mem_to_inner: impl FnOnce(*mut u8) -> *mut RcInner<T>,
+    ) -> NonNull<RcInner<T>> {
+        // Matches the `#[repr(C)]` layout of `RcInner<T>`, which is just the
+        // counts followed by the value (padded to its alignment).
+        let layout = Layout::new::<RcInner<()>>()
+            .extend(value_layout)
+            .expect("Rc allocation size overflow")
+            .0
+            .pad_to_align();
+
+        unsafe {
+            let mem = alloc::alloc(layout);
+            if mem.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+
+            let inner = mem_to_inner(mem);
+            ptr::write(&raw mut (*inner).strong, Cell::new(1));
+            ptr::write(&raw mut (*inner).weak, Cell::new(1));
+
+            NonNull::new_unchecked(inner)
+        }
+    }
+}
+
+impl<T> Rc<[T]> {
+    /// Allocates an `RcInner<[T]>` with room for `len` elements, which are
+    /// left uninitialized.
+    unsafe fn allocate_for_slice(len: usize) -> NonNull<RcInner<[T]>> {
+        let value_layout = Layout::array::<T>(len).expect("Rc allocation size overflow");
+
+        // SAFETY: The slice pointer has the same address as `mem`, and its
+        // length describes exactly `value_layout`. Casting a `*mut [T]` to a
+        // `*mut RcInner<[T]>` keeps the length as the metadata.
+        unsafe {
+            Self::allocate_for_layout(value_layout, |mem| {
+                ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut RcInner<[T]>
+            })
+        }
+    }
+
+    /// Moves the elements of `v` into a new `Rc<[T]>` with a bitwise copy.
+    ///
+    /// # Safety
+    ///
+    /// The caller must give up ownership of the elements, without dropping
+    /// them, since they are now owned by the `Rc`.
+    unsafe fn copy_from_slice(v: &[T]) -> Self {
+        unsafe {
+            let inner = Self::allocate_for_slice(v.len());
+            ptr::copy_nonoverlapping(
+                v.as_ptr(),
+                &raw mut (*inner.as_ptr()).value as *mut T,
+                v.len(),
+            );
+
+            Self {
+                inner,
+                _marker: PhantomData,
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> From<Box<T>> for Rc<T> {
+    fn from(value: Box<T>) -> Self {
+        Rc::from_box(value)
+    }
+}
+
+impl<T: ?Sized> Rc<T> {
+    /// Moves a boxed value into a new `Rc`, which works for any unsized value
+    /// (`Box<dyn Trait>`, `Box<[T]>`, `Box<str>`).
+    ///
+    /// This is the only way to build an `Rc<dyn Trait>` from an already boxed
+    /// trait object. From a concrete value, `rc_dyn!` avoids the extra `Box`
+    /// allocation.
+    pub fn from_box(value: Box<T>) -> Self {
+        let value_layout = Layout::for_value(&*value);
+        let box_ptr = Box::into_raw(value);
+
         unsafe {
-            match (*self.inner.as_ptr()).ref_count.get() {
-                // Drop the last Rc, since it is no longer referenced.
-                1 => {
-                    // Returned value is immediately dropped, `Box` handles
-                    // deallocation.
-                    let _ = Box::from_raw(self.inner.as_ptr());
-                }
-                n => (*self.inner.as_ptr()).ref_count.set(n - 1),
+            // SAFETY: Keeps the metadata of the `Box`, which describes a value
+            // with `value_layout`, but points at the new allocation.
+            let inner = Self::allocate_for_layout(value_layout, |mem| {
+                mem.with_metadata_of(box_ptr as *mut RcInner<T>)
+            });
+
+            // Move the value over byte-by-byte, the `Rc` now owns it.
+            ptr::copy_nonoverlapping(
+                box_ptr as *const u8,
+                &raw mut (*inner.as_ptr()).value as *mut u8,
+                value_layout.size(),
+            );
+
+            // Free the `Box` allocation without dropping the moved-out value.
+            // Zero-sized values are never allocated by `Box`.
+            if value_layout.size() != 0 {
+                alloc::dealloc(box_ptr as *mut u8, value_layout);
+            }
+
+            Self {
+                inner,
+                _marker: PhantomData,
             }
         }
     }
 }
 
+impl<T> From<Vec<T>> for Rc<[T]> {
+    fn from(mut value: Vec<T>) -> Self {
+        unsafe {
+            // SAFETY: The length is set to zero afterwards, so the `Vec` only
+            // frees its buffer without dropping the moved elements.
+            let rc = Self::copy_from_slice(&value);
+            value.set_len(0);
+            rc
+        }
+    }
+}
+
+impl From<&str> for Rc<str> {
+    fn from(value: &str) -> Self {
+        // SAFETY: `u8` is `Copy`, so there's no ownership to give up.
+        let rc = unsafe { Rc::<[u8]>::copy_from_slice(value.as_bytes()) };
+        let rc = ManuallyDrop::new(rc);
+
+        // `str` has the same layout as `[u8]`, and the bytes came from a
+        // `&str`, so they are valid UTF-8.
+        Self {
+            inner: unsafe { NonNull::new_unchecked(rc.inner.as_ptr() as *mut RcInner<str>) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl From<String> for Rc<str> {
+    fn from(value: String) -> Self {
+        Rc::from(&value[..])
+    }
+}
+
+impl Rc<dyn Any> {
+    /// Attempts to downcast to a concrete type, giving back the same `Rc` in
+    /// `Err` if the value is not a `T`.
+    pub fn downcast<T: Any>(self) -> Result<Rc<T>, Self> {
+        if !(*self).is::<T>() {
+            return Err(self);
+        }
+
+        // The strong count is transferred to the new `Rc`.
+        let this = ManuallyDrop::new(self);
+
+        // The value is a `T`, so the allocation is an `RcInner<T>`. Casting
+        // to a thin pointer just drops the vtable.
+        Ok(Rc {
+            inner: this.inner.cast::<RcInner<T>>(),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// Converts an `Rc<T>` into an `Rc<dyn Trait>` (or any other type `T`
+/// unsizes to), reusing the same allocation.
+///
+/// `Rc<T>` can't coerce to `Rc<dyn Trait>` on its own like std's `Rc`, since
+/// that needs the unstable `CoerceUnsized` trait. Raw pointers can always
+/// coerce though, so this round-trips through `Rc::into_raw` and
+/// `Rc::from_raw`, with the pointer picking up the vtable in between.
+///
+/// ```
+/// use std::fmt::Display;
+/// use crust_of_rust::rc::Rc;
+/// use crust_of_rust::rc_dyn;
+///
+/// let rc: Rc<dyn Display> = rc_dyn!(Rc::new(42) => dyn Display);
+/// assert_eq!(rc.to_string(), "42");
+/// ```
+#[macro_export]
+macro_rules! rc_dyn {
+    ($rc:expr => $ty:ty) => {{
+        let ptr = $crate::rc::Rc::into_raw($rc);
+        // The coercion happens here, through the type annotation. It has to be
+        // a separate statement, otherwise `$ty` would be inferred as the type
+        // of `$rc` instead.
+        let ptr: *const $ty = ptr;
+
+        // SAFETY: `ptr` came from `Rc::into_raw`, only unsized to `$ty`, and
+        // is only used once.
+        unsafe { $crate::rc::Rc::<$ty>::from_raw(ptr) }
+    }};
+}
+
 /// ```compile_fail
 /// use crust_of_rust::rc::Rc;
 ///
@@ -156,14 +821,14 @@ mod tests {
             let rc2 = rc1.clone();
             let rc3 = rc2.clone();
 
-            assert_eq!(dropped.get(), false);
+            assert!(!dropped.get());
 
             drop(rc3);
             drop(rc2);
-            assert_eq!(dropped.get(), false);
+            assert!(!dropped.get());
 
             drop(rc1);
-            assert_eq!(dropped.get(), true);
+            assert!(dropped.get());
         }
     }
 
@@ -179,4 +844,441 @@ mod tests {
 
         assert_eq!(rc[2], 3);
     }
+
+    #[test]
+    fn test_rc_try_unwrap_unique() {
+        let rc = Rc::new(String::from("hello"));
+        assert_eq!(Rc::try_unwrap(rc).unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_rc_try_unwrap_shared() {
+        let dropped = Cell::new(false);
+
+        let rc1 = Rc::new(DropCounter { dropped: &dropped });
+        let rc2 = rc1.clone();
+
+        // Shared, so the same `Rc` is handed back.
+        let rc1 = Rc::try_unwrap(rc1).err().unwrap();
+        drop(rc2);
+
+        // Now unique, the value is moved out without being dropped.
+        let value = Rc::try_unwrap(rc1).ok().unwrap();
+        assert!(!dropped.get());
+
+        drop(value);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn test_rc_get_mut() {
+        let mut rc1 = Rc::new(5);
+        *Rc::get_mut(&mut rc1).unwrap() += 1;
+        assert_eq!(*rc1, 6);
+
+        let rc2 = rc1.clone();
+        assert!(Rc::get_mut(&mut rc1).is_none());
+
+        drop(rc2);
+        assert_eq!(Rc::get_mut(&mut rc1), Some(&mut 6));
+    }
+
+    #[test]
+    fn test_rc_make_mut() {
+        let mut rc1 = Rc::new(String::from("hello"));
+        let rc2 = rc1.clone();
+
+        // Shared, so the value is cloned before being mutated.
+        Rc::make_mut(&mut rc1).push_str(" world");
+        assert_eq!(&*rc1, "hello world");
+        assert_eq!(&*rc2, "hello");
+
+        // Unique now, so the value is mutated in place.
+        let before = &*rc1 as *const String;
+        Rc::make_mut(&mut rc1).push('!');
+        assert_eq!(&*rc1, "hello world!");
+        assert_eq!(&*rc1 as *const String, before);
+    }
+
+    #[test]
+    fn test_rc_make_mut_weak() {
+        let mut rc = Rc::new(5);
+        let weak = Rc::downgrade(&rc);
+
+        // Unique strong reference, so the value is moved and the `Weak` is
+        // disassociated.
+        *Rc::make_mut(&mut rc) += 1;
+        assert_eq!(*rc, 6);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(Rc::weak_count(&rc), 0);
+    }
+
+    #[test]
+    fn test_rc_counts() {
+        let rc1 = Rc::new(1);
+        assert_eq!(Rc::strong_count(&rc1), 1);
+        assert_eq!(Rc::weak_count(&rc1), 0);
+
+        let rc2 = rc1.clone();
+        let weak = Rc::downgrade(&rc1);
+        assert_eq!(Rc::strong_count(&rc1), 2);
+        assert_eq!(Rc::weak_count(&rc2), 1);
+
+        drop(rc2);
+        assert_eq!(Rc::strong_count(&rc1), 1);
+
+        drop(weak);
+        assert_eq!(Rc::weak_count(&rc1), 0);
+    }
+
+    #[test]
+    fn test_rc_ptr_eq() {
+        let rc1 = Rc::new(5);
+        let rc2 = rc1.clone();
+        let rc3 = Rc::new(5);
+
+        assert!(Rc::ptr_eq(&rc1, &rc2));
+        assert!(!Rc::ptr_eq(&rc1, &rc3));
+    }
+
+    #[test]
+    fn test_rc_weak_upgrade() {
+        let dropped = Cell::new(false);
+
+        let rc = Rc::new(DropCounter { dropped: &dropped });
+        let weak = Rc::downgrade(&rc);
+
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!(weak.strong_count(), 2);
+        drop(upgraded);
+
+        // The value is dropped with the last `Rc`, even though a `Weak` keeps
+        // the allocation alive.
+        drop(rc);
+        assert!(dropped.get());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_rc_get_mut_weak() {
+        let mut rc = Rc::new(5);
+        let weak = Rc::downgrade(&rc);
+        assert!(Rc::get_mut(&mut rc).is_none());
+
+        drop(weak);
+        assert!(Rc::get_mut(&mut rc).is_some());
+    }
+
+    #[test]
+    fn test_rc_str() {
+        let rc1: Rc<str> = Rc::from("hello");
+        let rc2 = rc1.clone();
+        assert_eq!(&*rc2, "hello");
+
+        let rc3: Rc<str> = Rc::from(String::from("world"));
+        assert_eq!(&*rc3, "world");
+    }
+
+    #[test]
+    fn test_rc_slice_from_vec() {
+        let rc: Rc<[String]> = Rc::from(vec![String::from("a"), String::from("b")]);
+        assert_eq!(rc.len(), 2);
+        assert_eq!(rc[1], "b");
+
+        let weak = Rc::downgrade(&rc);
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_rc_slice_drop() {
+        let dropped = [Cell::new(false), Cell::new(false)];
+        let rc: Rc<[DropCounter]> = Rc::from(vec![
+            DropCounter {
+                dropped: &dropped[0],
+            },
+            DropCounter {
+                dropped: &dropped[1],
+            },
+        ]);
+
+        // Moving the elements out of the `Vec` must not drop them.
+        assert!(!dropped[0].get() && !dropped[1].get());
+
+        drop(rc);
+        assert!(dropped[0].get() && dropped[1].get());
+    }
+
+    #[test]
+    fn test_rc_from_box() {
+        let rc: Rc<[i32]> = Rc::from(vec![1, 2, 3].into_boxed_slice());
+        assert_eq!(&*rc, &[1, 2, 3]);
+
+        let rc: Rc<[()]> = Rc::from(vec![(); 4].into_boxed_slice());
+        assert_eq!(rc.len(), 4);
+    }
+
+    #[test]
+    fn test_rc_new_cyclic() {
+        struct Node {
+            this: Weak<Node>,
+            value: i32,
+        }
+
+        let rc = Rc::new_cyclic(|weak| {
+            // The value isn't constructed yet.
+            assert!(weak.upgrade().is_none());
+
+            Node {
+                this: weak.clone(),
+                value: 42,
+            }
+        });
+
+        let this = rc.this.upgrade().unwrap();
+        assert!(Rc::ptr_eq(&rc, &this));
+        assert_eq!(this.value, 42);
+        assert_eq!(Rc::strong_count(&rc), 2);
+        assert_eq!(Rc::weak_count(&rc), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rc_new_cyclic_panic() {
+        // Using MIRI, the allocation must still be freed.
+        let _ = Rc::<i32>::new_cyclic(|_| panic!());
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_rc_raw_round_trip() {
+        let dropped = Cell::new(false);
+
+        let rc1 = Rc::new(DropCounter { dropped: &dropped });
+        let rc2 = rc1.clone();
+
+        let ptr = Rc::into_raw(rc1);
+        assert_eq!(ptr, Rc::as_ptr(&rc2));
+        assert_eq!(Rc::strong_count(&rc2), 2);
+
+        // SAFETY: `ptr` came from `Rc::into_raw` and is only used once.
+        let rc1 = unsafe { Rc::from_raw(ptr) };
+        assert!(Rc::ptr_eq(&rc1, &rc2));
+
+        drop(rc1);
+        drop(rc2);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_rc_raw_round_trip_unsized() {
+        let rc: Rc<str> = Rc::from("hello");
+        let ptr = Rc::into_raw(rc);
+
+        // SAFETY: `ptr` came from `Rc::into_raw`, and points to the value.
+        assert_eq!(unsafe { &*ptr }, "hello");
+
+        // SAFETY: `ptr` came from `Rc::into_raw` and is only used once.
+        let rc = unsafe { Rc::from_raw(ptr) };
+        assert_eq!(&*rc, "hello");
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_rc_raw_over_aligned() {
+        #[repr(align(64))]
+        struct Aligned(u8);
+
+        let ptr = Rc::into_raw(Rc::new(Aligned(7)));
+        assert_eq!(ptr as usize % 64, 0);
+
+        // SAFETY: `ptr` came from `Rc::into_raw` and is only used once.
+        let rc = unsafe { Rc::from_raw(ptr) };
+        assert_eq!(rc.0, 7);
+    }
+
+    #[test]
+    fn test_rc_pin() {
+        use std::marker::PhantomPinned;
+
+        struct SelfRef {
+            value: i32,
+            _pin: PhantomPinned,
+        }
+
+        let pinned = Rc::pin(SelfRef {
+            value: 42,
+            _pin: PhantomPinned,
+        });
+        let clone = pinned.clone();
+
+        // Clones of a `Pin<Rc<T>>` point to the same, never-moving value.
+        assert!(std::ptr::eq(&*pinned, &*clone));
+        assert_eq!(clone.as_ref().get_ref().value, 42);
+    }
+
+    #[test]
+    fn test_rc_downcast() {
+        let registry: Vec<Rc<dyn Any>> = vec![
+            Rc::from(Box::new(42_i32) as Box<dyn Any>),
+            Rc::from(Box::new(String::from("hello")) as Box<dyn Any>),
+        ];
+
+        let mut found = Vec::new();
+        for entry in registry {
+            match entry.downcast::<String>() {
+                Ok(s) => found.push(s),
+                Err(entry) => assert_eq!(*entry.downcast::<i32>().unwrap(), 42),
+            }
+        }
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(&*found[0], "hello");
+    }
+
+    #[test]
+    fn test_rc_downcast_shared() {
+        let rc: Rc<dyn Any> = Rc::from(Box::new(vec![1, 2, 3]) as Box<dyn Any>);
+        let clone = rc.clone();
+
+        // The strong count moves over to the downcast `Rc`.
+        let concrete = rc.downcast::<Vec<i32>>().unwrap();
+        assert_eq!(Rc::strong_count(&concrete), 2);
+        assert!(Rc::ptr_eq(
+            &concrete,
+            &clone.downcast::<Vec<i32>>().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_rc_forwarded_traits() {
+        use std::collections::HashMap;
+
+        let a = Rc::new(1);
+        let b = Rc::from(2);
+        assert_eq!(a, Rc::new(1));
+        assert!(a < b);
+        assert_eq!(a.cmp(&b), Ordering::Less);
+        assert_eq!(b.to_string(), "2");
+        assert_eq!(*Rc::<i32>::default(), 0);
+
+        let mut map = HashMap::new();
+        map.insert(Rc::<str>::from("key"), 42);
+        assert_eq!(map[&Rc::<str>::from("key")], 42);
+    }
+
+    #[test]
+    fn test_rc_overflow_aborts() {
+        use std::process::Command;
+
+        const CHILD: &str = "CRUST_RC_OVERFLOW_CHILD";
+
+        // Re-run only this test in a child process, since the expected abort
+        // would otherwise take down the whole test harness.
+        if std::env::var_os(CHILD).is_some() {
+            let rc = Rc::new(());
+            rc.strong().set(usize::MAX);
+            let _ = rc.clone();
+            unreachable!();
+        }
+
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "rc::tests::test_rc_overflow_aborts"])
+            .env(CHILD, "1")
+            .status()
+            .unwrap();
+        // A panic (e.g. the `unreachable!`) exits with 101 instead.
+        assert!(!status.success());
+        assert_ne!(status.code(), Some(101));
+    }
+
+    #[test]
+    fn test_rc_into_inner() {
+        let rc1 = Rc::new(String::from("hello"));
+        let rc2 = rc1.clone();
+
+        assert_eq!(Rc::into_inner(rc1), None);
+        assert_eq!(Rc::into_inner(rc2).as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn test_rc_leak() {
+        let dropped = Cell::new(false);
+
+        let rc = Rc::new(DropCounter { dropped: &dropped });
+        let weak = Rc::downgrade(&rc);
+        let leaked = Rc::leak(rc.clone());
+        drop(rc);
+
+        // The leaked count keeps the value alive.
+        assert!(!dropped.get());
+        assert!(std::ptr::eq(&*weak.upgrade().unwrap(), leaked));
+
+        let s: &'static str = Rc::leak(Rc::<str>::from("static"));
+        assert_eq!(s, "static");
+    }
+
+    #[test]
+    fn test_rc_weak_new() {
+        let weak: Weak<String> = Weak::new();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+
+        // Cloning and dropping must not touch a (non-existent) allocation.
+        let clone = weak.clone();
+        drop(weak);
+        assert!(clone.upgrade().is_none());
+        assert!(Weak::<i32>::default().upgrade().is_none());
+    }
+
+    #[test]
+    fn test_rc_dyn() {
+        use std::fmt::Display;
+
+        let dropped = Cell::new(false);
+
+        struct Shape<'a>(DropCounter<'a>, &'static str);
+
+        impl Display for Shape<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.1)
+            }
+        }
+
+        let rc = Rc::new(Shape(DropCounter { dropped: &dropped }, "circle"));
+        let clone = rc.clone();
+
+        let shapes: Vec<Rc<dyn Display>> = vec![
+            rc_dyn!(rc => dyn Display),
+            Rc::from_box(Box::new(42) as Box<dyn Display>),
+        ];
+
+        assert_eq!(shapes[0].to_string(), "circle");
+        assert_eq!(shapes[1].to_string(), "42");
+        assert_eq!(Rc::strong_count(&shapes[0]), 2);
+
+        // Dropped through the vtable once both the concrete and `dyn` `Rc`s
+        // are gone.
+        drop(clone);
+        assert!(!dropped.get());
+        drop(shapes);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn test_rc_dyn_fn() {
+        let counter = Rc::new(Cell::new(0));
+        let captured = counter.clone();
+
+        let f: Rc<dyn Fn() -> i32> = rc_dyn!(Rc::new(move || {
+            captured.set(captured.get() + 1);
+            captured.get()
+        }) => dyn Fn() -> i32);
+
+        assert_eq!(f(), 1);
+        assert_eq!(f.clone()(), 2);
+        assert_eq!(counter.get(), 2);
+    }
 }
@@ -0,0 +1,219 @@
+//! Opt-in diagnostic for finding `Rc` reference cycles that would leak.
+//!
+//! `Rc` can't free values that (directly or indirectly) point back to
+//! themselves, since each value in the cycle keeps the next one's strong count
+//! above zero. This walks a graph of registered `Rc`s, and reports the ones
+//! that are only kept alive by other registered `Rc`s, meaning nothing outside
+//! the graph can reach them anymore.
+//!
+//! Only types that implement `Trace` take part, and only while registered with
+//! a `CycleDetector`, so there is no cost to any other `Rc`.
+
+use super::{Rc, Weak};
+
+/// Types that can report the `Rc`s they own, so the detector can follow the
+/// edges of the object graph.
+pub trait Trace {
+    /// Calls `tracer.visit` with every `Rc` directly owned by `self`.
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+/// Collects the outgoing edges of a single value during `Trace::trace`.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    edges: Vec<*const ()>,
+}
+
+impl Tracer {
+    pub fn visit<T: ?Sized>(&mut self, rc: &Rc<T>) {
+        // The address is enough to identify an allocation, the metadata of an
+        // unsized value is dropped.
+        self.edges.push(Rc::as_ptr(rc) as *const ());
+    }
+}
+
+/// Type-erased view of a registered node, so `Rc`s of different types can be
+/// registered in the same detector.
+trait Node {
+    /// Returns the address, strong count and outgoing edges of the value, or
+    /// `None` if it has already been dropped.
+    fn snapshot(&self) -> Option<Snapshot>;
+}
+
+struct Snapshot {
+    addr: *const (),
+    strong: usize,
+    edges: Vec<*const ()>,
+}
+
+impl<T: Trace> Node for Weak<T> {
+    fn snapshot(&self) -> Option<Snapshot> {
+        // Read the count before upgrading, since upgrading adds a reference.
+        let strong = self.strong_count();
+        let rc = self.upgrade()?;
+
+        let mut tracer = Tracer::default();
+        rc.trace(&mut tracer);
+
+        Some(Snapshot {
+            addr: Rc::as_ptr(&rc) as *const (),
+            strong,
+            edges: tracer.edges,
+        })
+    }
+}
+
+/// Registry of `Rc`s to check for leaked cycles.
+///
+/// Only `Weak`s are stored, so registering never keeps a value alive or
+/// changes its strong count.
+#[derive(Default)]
+pub struct CycleDetector {
+    nodes: Vec<Box<dyn Node>>,
+}
+
+impl CycleDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `rc` to the graph, returning the index it is reported under.
+    pub fn register<T: Trace + 'static>(&mut self, rc: &Rc<T>) -> usize {
+        self.nodes.push(Box::new(Rc::downgrade(rc)));
+        self.nodes.len() - 1
+    }
+
+    /// Returns the indices of registered values that are still alive but can
+    /// no longer be reached from outside the graph, in registration order.
+    ///
+    /// Works in two passes:
+    ///
+    /// - Count the references each value receives from other registered
+    ///   values. If its strong count is higher, the rest must be held from
+    ///   outside the graph (e.g. a local variable), so it is a root.
+    /// - Mark everything reachable from a root. Whatever is left is only kept
+    ///   alive by references from within the graph, which is a leak.
+    ///
+    /// Values reachable from unregistered `Rc`s owned by the graph can't be
+    /// seen, so every `Rc` involved in a cycle must be registered.
+    pub fn leaks(&self) -> Vec<usize> {
+        let nodes: Vec<_> = self.nodes.iter().map(|node| node.snapshot()).collect();
+        let index_of = |addr: *const ()| {
+            nodes
+                .iter()
+                .position(|node| node.as_ref().is_some_and(|node| node.addr == addr))
+        };
+
+        let mut internal = vec![0; nodes.len()];
+        for node in nodes.iter().flatten() {
+            for &edge in &node.edges {
+                if let Some(i) = index_of(edge) {
+                    internal[i] += 1;
+                }
+            }
+        }
+
+        let mut reachable = vec![false; nodes.len()];
+        let mut stack: Vec<_> = nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| node.as_ref().filter(|n| n.strong > internal[i]).map(|_| i))
+            .collect();
+
+        while let Some(i) = stack.pop() {
+            if std::mem::replace(&mut reachable[i], true) {
+                continue;
+            }
+
+            if let Some(node) = &nodes[i] {
+                stack.extend(node.edges.iter().filter_map(|&edge| index_of(edge)));
+            }
+        }
+
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, node)| node.is_some() && !reachable[*i])
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::refcell::RefCell;
+
+    struct Node {
+        children: RefCell<Vec<Rc<Node>>>,
+    }
+
+    impl Node {
+        fn new() -> Rc<Self> {
+            Rc::new(Self {
+                children: RefCell::new(Vec::new()),
+            })
+        }
+    }
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            for child in self.children.borrow().iter() {
+                tracer.visit(child);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cycles_no_leak() {
+        let mut detector = CycleDetector::new();
+
+        let parent = Node::new();
+        let child = Node::new();
+        parent.children.borrow_mut().push(child.clone());
+
+        detector.register(&parent);
+        detector.register(&child);
+
+        // Both are still reachable from locals.
+        assert!(detector.leaks().is_empty());
+
+        // Still reachable through `parent`.
+        drop(child);
+        assert!(detector.leaks().is_empty());
+
+        drop(parent);
+        assert!(detector.leaks().is_empty());
+    }
+
+    #[test]
+    fn test_cycles_leak() {
+        let mut detector = CycleDetector::new();
+
+        let a = Node::new();
+        let b = Node::new();
+        let c = Node::new();
+        a.children.borrow_mut().push(b.clone());
+        b.children.borrow_mut().push(a.clone());
+        // Not part of the cycle, but only reachable from it.
+        b.children.borrow_mut().push(c.clone());
+
+        detector.register(&a);
+        detector.register(&b);
+        detector.register(&c);
+        assert!(detector.leaks().is_empty());
+
+        drop(c);
+        drop(b);
+        // `a` keeps the whole graph reachable.
+        assert!(detector.leaks().is_empty());
+
+        let weak = Rc::downgrade(&a);
+        drop(a);
+        assert_eq!(detector.leaks(), vec![0, 1, 2]);
+
+        // Break the cycle by hand so the test itself doesn't leak.
+        weak.upgrade().unwrap().children.borrow_mut().clear();
+        assert!(detector.leaks().is_empty());
+    }
+}
@@ -1,8 +1,15 @@
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
+#[cfg(feature = "debug_refcell")]
+use std::panic::Location;
+use std::ptr::NonNull;
 
 use crate::cell::Cell;
 
+pub mod atomic;
+
 /// `RefCell` allows for interior mutability through a shared reference with
 /// dynamic borrow-checking and ensures no other threads can have a reference to
 /// the same `RefCell` and the inner `T` is not mutably aliased.
@@ -12,11 +19,21 @@ pub struct RefCell<T> {
     inner: UnsafeCell<T>,
     /// Wrapped in `Cell` so updates can occur through a shared reference.
     references: Cell<isize>,
+    /// Where the outstanding borrow started (the first of them, for shared
+    /// borrows), reported when a conflicting borrow panics.
+    #[cfg(feature = "debug_refcell")]
+    borrowed_at: Cell<Option<&'static Location<'static>>>,
 }
 
 // Implied by `UnsafeCell`, which is already `!Sync`.
 // impl<T> !Sync for RefCell<T> {}
 
+// SAFETY: Moving the `RefCell` to another thread moves the `T` with it. Any
+// outstanding `Ref`/`RefMut` borrows the cell, so it can't be moved while one
+// is alive, and the non-atomic borrow counter is only ever touched by the
+// thread that currently owns the cell.
+unsafe impl<T: Send> Send for RefCell<T> {}
+
 impl<T> RefCell<T> {
     /// Sentinel value indicating a mutable borrow is live.
     const MUTABLE_BORROW: isize = -1;
@@ -25,85 +42,307 @@ impl<T> RefCell<T> {
         Self {
             inner: UnsafeCell::new(value),
             references: Cell::new(0),
+            #[cfg(feature = "debug_refcell")]
+            borrowed_at: Cell::new(None),
         }
     }
 
+    /// Panics if the value is currently mutably borrowed.
     #[allow(clippy::should_implement_trait)]
+    #[track_caller]
     pub fn borrow(&self) -> Ref<'_, T> {
-        assert!(
-            self.references.get() != Self::MUTABLE_BORROW,
-            "RefCell is already borrowed mutably"
-        );
-
-        let prev = self.references.get();
-        self.references.set(prev + 1);
-
-        // SAFETY: No mutable references to `T` have been given out.
-        Ref { parent: self }
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(e) => panic!("{e}"),
+        }
     }
 
+    /// Panics if the value is currently borrowed.
     #[allow(clippy::should_implement_trait)]
+    #[track_caller]
     pub fn borrow_mut(&self) -> RefMut<'_, T> {
-        assert!(self.references.get() == 0, "RefCell is already borrowed");
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Non-panicking variant of `borrow`, so callers can recover from a
+    /// borrow conflict.
+    #[track_caller]
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+        if self.references.get() == Self::MUTABLE_BORROW {
+            return Err(BorrowError {
+                #[cfg(feature = "debug_refcell")]
+                borrowed_at: self.borrowed_at.get(),
+            });
+        }
+
+        #[cfg(feature = "debug_refcell")]
+        if self.references.get() == 0 {
+            self.borrowed_at.set(Some(Location::caller()));
+        }
+
+        self.references.update(|n| n + 1);
+
+        Ok(Ref {
+            // SAFETY: No mutable references to `T` have been given out.
+            value: unsafe { &*self.inner.get() },
+            references: &self.references,
+        })
+    }
+
+    /// Non-panicking variant of `borrow_mut`, so callers can recover from a
+    /// borrow conflict.
+    #[track_caller]
+    pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowMutError> {
+        if self.references.get() != 0 {
+            return Err(BorrowMutError {
+                #[cfg(feature = "debug_refcell")]
+                borrowed_at: self.borrowed_at.get(),
+            });
+        }
+
+        #[cfg(feature = "debug_refcell")]
+        self.borrowed_at.set(Some(Location::caller()));
+
         self.references.set(Self::MUTABLE_BORROW);
 
-        // SAFETY: No other references to `T` have been given out.
-        RefMut { parent: self }
+        Ok(RefMut {
+            // SAFETY: `UnsafeCell::get` never returns null, and no other
+            // references to `T` have been given out.
+            value: unsafe { NonNull::new_unchecked(self.inner.get()) },
+            references: &self.references,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RefCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Stands in for the value while it's mutably borrowed, since reading
+        /// it then would alias the `RefMut`.
+        struct BorrowedPlaceholder;
+
+        impl fmt::Debug for BorrowedPlaceholder {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("<borrowed>")
+            }
+        }
+
+        match self.try_borrow() {
+            Ok(value) => f.debug_struct("RefCell").field("value", &&*value).finish(),
+            Err(_) => f
+                .debug_struct("RefCell")
+                .field("value", &BorrowedPlaceholder)
+                .finish(),
+        }
+    }
+}
+
+/// Returned by `RefCell::try_borrow` when the value is mutably borrowed.
+#[derive(Debug)]
+pub struct BorrowError {
+    #[cfg(feature = "debug_refcell")]
+    borrowed_at: Option<&'static Location<'static>>,
+}
+
+impl std::error::Error for BorrowError {}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RefCell is already borrowed mutably")?;
+
+        #[cfg(feature = "debug_refcell")]
+        if let Some(location) = self.borrowed_at {
+            write!(f, " (borrowed at {location})")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returned by `RefCell::try_borrow_mut` when the value is borrowed.
+#[derive(Debug)]
+pub struct BorrowMutError {
+    #[cfg(feature = "debug_refcell")]
+    borrowed_at: Option<&'static Location<'static>>,
+}
+
+impl std::error::Error for BorrowMutError {}
+
+impl fmt::Display for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RefCell is already borrowed")?;
+
+        #[cfg(feature = "debug_refcell")]
+        if let Some(location) = self.borrowed_at {
+            write!(f, " (first borrowed at {location})")?;
+        }
+
+        Ok(())
     }
 }
 
 /// Essentially a smart pointer that transparently points to the inner `T`
 /// (`Deref`), and has additional semantics when dropping.
-pub struct Ref<'a, T> {
-    parent: &'a RefCell<T>,
+pub struct Ref<'a, T: ?Sized> {
+    value: &'a T,
+    /// The parent's borrow counter, kept separately so the guard can be
+    /// projected onto part of the value.
+    references: &'a Cell<isize>,
 }
 
-impl<T> Deref for Ref<'_, T> {
+impl<'a, T: ?Sized> Ref<'a, T> {
+    /// Projects the borrow onto a component of the value, handing back the
+    /// original guard if `f` returns `None`.
+    ///
+    /// An associated function (`Ref::filter_map(...)`) so it can't shadow a
+    /// method on `T`.
+    pub fn filter_map<U: ?Sized, F>(orig: Ref<'a, T>, f: F) -> Result<Ref<'a, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(orig.value) {
+            Some(value) => {
+                let references = orig.references;
+                // The shared borrow moves over to the new guard.
+                std::mem::forget(orig);
+                Ok(Ref { value, references })
+            }
+            None => Err(orig),
+        }
+    }
+
+    /// Forgets the guard and returns a plain reference for the cell's
+    /// lifetime.
+    ///
+    /// The shared borrow is never released, so the cell can't be mutably
+    /// borrowed again: the borrow flag keeps protecting the leaked reference
+    /// after the guard is gone.
+    pub fn leak(orig: Ref<'a, T>) -> &'a T {
+        let value = orig.value;
+        std::mem::forget(orig);
+        value
+    }
+}
+
+impl<T: ?Sized> Deref for Ref<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        // SAFETY: `Ref` is only created when no mutable references to `T` have
-        // been given out.
-        unsafe { &*self.parent.inner.get() }
+        self.value
     }
 }
 
-impl<T> Drop for Ref<'_, T> {
+impl<T: ?Sized> Drop for Ref<'_, T> {
     fn drop(&mut self) {
-        let refs = self.parent.references.get();
-        self.parent.references.set(refs - 1);
+        self.references.update(|n| n - 1);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Ref<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Ref<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
     }
 }
 
 /// Essentially a smart pointer that transparently points to the inner `T`
 /// (`Deref` and `DerefMut`), and has additional semantics when dropping.
-pub struct RefMut<'a, T> {
-    parent: &'a RefCell<T>,
+pub struct RefMut<'a, T: ?Sized> {
+    /// A raw pointer rather than `&'a mut T`, so `filter_map` can hand the
+    /// guard back after `f` has looked at the value.
+    value: NonNull<T>,
+    references: &'a Cell<isize>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: ?Sized> RefMut<'a, T> {
+    /// Projects the mutable borrow onto a component of the value, handing back
+    /// the original guard if `f` returns `None`.
+    pub fn filter_map<U: ?Sized, F>(mut orig: RefMut<'a, T>, f: F) -> Result<RefMut<'a, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        // SAFETY: `orig` holds the only borrow of the value, and the reference
+        // passed to `f` is gone by the time `orig` is used again.
+        match f(unsafe { orig.value.as_mut() }) {
+            Some(value) => {
+                let references = orig.references;
+                // The mutable borrow moves over to the new guard.
+                std::mem::forget(orig);
+                Ok(RefMut {
+                    value: NonNull::from(value),
+                    references,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(orig),
+        }
+    }
 }
 
-impl<T> Deref for RefMut<'_, T> {
+impl<T: ?Sized> Deref for RefMut<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
         // SAFETY: `RefMut` is only created when no other references to `T`
         // have been given out.
-        unsafe { &*self.parent.inner.get() }
+        unsafe { self.value.as_ref() }
     }
 }
 
-impl<T> DerefMut for RefMut<'_, T> {
+impl<T: ?Sized> DerefMut for RefMut<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         // SAFETY: `RefMut` is only created when no other references to `T`
         // have been given out.
-        unsafe { &mut *self.parent.inner.get() }
+        unsafe { self.value.as_mut() }
     }
 }
 
-impl<T> Drop for RefMut<'_, T> {
+impl<T: ?Sized> Drop for RefMut<'_, T> {
     fn drop(&mut self) {
         // Since `RefMut` should be the only reference to the `RefCell`, after
         // dropping there should be no more references.
-        self.parent.references.set(0);
+        self.references.set(0);
+    }
+}
+
+/// ```compile_fail
+/// use crust_of_rust::refcell::RefCell;
+///
+/// fn require_sync<T: Sync>(_: T) {}
+///
+/// require_sync(RefCell::new(42));
+/// ```
+fn assert_non_sync() {}
+
+/// ```compile_fail
+/// use crust_of_rust::refcell::RefCell;
+///
+/// let cell = RefCell::new(42);
+///
+/// std::thread::scope(|s| {
+///     s.spawn(|| *cell.borrow_mut() += 1);
+/// });
+/// ```
+fn assert_non_shareable() {}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
     }
 }
 
@@ -177,4 +416,136 @@ mod tests {
 
         assert_eq!(*cell.borrow(), 201);
     }
+
+    #[test]
+    fn test_refcell_try_borrow() {
+        let cell = RefCell::new(1);
+
+        {
+            let _shared = cell.try_borrow().unwrap();
+            assert!(cell.try_borrow().is_ok());
+            assert!(cell.try_borrow_mut().is_err());
+        }
+
+        {
+            let _mut_borrow = cell.try_borrow_mut().unwrap();
+            let err = cell.try_borrow().err().unwrap();
+            assert!(
+                err.to_string()
+                    .starts_with("RefCell is already borrowed mutably")
+            );
+            assert!(cell.try_borrow_mut().is_err());
+        }
+
+        // Failed attempts must not affect the borrow state.
+        *cell.try_borrow_mut().unwrap() += 1;
+        assert_eq!(*cell.borrow(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "debug_refcell")]
+    fn test_refcell_borrowed_at() {
+        let cell = RefCell::new(1);
+
+        let line = line!() + 1;
+        let _shared = cell.borrow();
+        let _shared2 = cell.borrow();
+
+        let err = cell.try_borrow_mut().err().unwrap();
+        let expected = format!("(first borrowed at {}:{line}:", file!());
+        assert!(err.to_string().contains(&expected));
+    }
+
+    #[test]
+    fn test_ref_filter_map() {
+        use std::collections::HashMap;
+
+        let cell = RefCell::new(HashMap::from([("a", 1)]));
+
+        let a = Ref::filter_map(cell.borrow(), |m| m.get("a")).ok().unwrap();
+        assert_eq!(*a, 1);
+        assert!(cell.try_borrow_mut().is_err());
+        drop(a);
+
+        let orig = Ref::filter_map(cell.borrow(), |m| m.get("b"))
+            .err()
+            .unwrap();
+        assert_eq!(orig.len(), 1);
+        drop(orig);
+
+        // Both outcomes release the borrow exactly once.
+        assert!(cell.try_borrow_mut().is_ok());
+    }
+
+    #[test]
+    fn test_refmut_filter_map() {
+        let cell = RefCell::new(vec![1, 2, 3]);
+
+        {
+            let mut first = RefMut::filter_map(cell.borrow_mut(), |v| v.first_mut())
+                .ok()
+                .unwrap();
+            *first += 10;
+            assert!(cell.try_borrow().is_err());
+        }
+
+        {
+            let mut orig = RefMut::filter_map(cell.borrow_mut(), |v| v.get_mut(5))
+                .err()
+                .unwrap();
+            orig.push(4);
+        }
+
+        assert_eq!(*cell.borrow(), [11, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_refcell_send() {
+        let cell = RefCell::new(vec![1]);
+
+        let cell = std::thread::spawn(move || {
+            cell.borrow_mut().push(2);
+            cell
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(*cell.borrow(), [1, 2]);
+    }
+
+    #[test]
+    fn test_ref_leak() {
+        let cell = RefCell::new(5);
+
+        let leaked: &i32 = Ref::leak(cell.borrow());
+        assert_eq!(*leaked, 5);
+
+        // Further shared borrows are fine, but the cell is shared forever.
+        assert_eq!(*cell.borrow(), 5);
+        assert!(cell.try_borrow_mut().is_err());
+        assert_eq!(*leaked, 5);
+    }
+
+    #[test]
+    fn test_refcell_debug() {
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Wrapper {
+            cell: RefCell<i32>,
+        }
+
+        let w = Wrapper {
+            cell: RefCell::new(7),
+        };
+        assert_eq!(format!("{w:?}"), "Wrapper { cell: RefCell { value: 7 } }");
+
+        let shared = w.cell.borrow();
+        assert_eq!(format!("{:?}", w.cell), "RefCell { value: 7 }");
+        assert_eq!(format!("{shared:?} {shared}"), "7 7");
+        drop(shared);
+
+        let mut_borrow = w.cell.borrow_mut();
+        assert_eq!(format!("{:?}", w.cell), "RefCell { value: <borrowed> }");
+        assert_eq!(format!("{mut_borrow:?} {mut_borrow}"), "7 7");
+    }
 }
@@ -0,0 +1,296 @@
+//! A `Sync` counterpart to `RefCell` whose borrow flag is an `AtomicUsize`.
+//!
+//! Like `RefCell`, conflicting borrows are a bug in the caller and panic
+//! instead of blocking, which is what separates it from `atomics::Mutex`. It is
+//! useful when borrows are known not to overlap (e.g, phases of a parallel
+//! algorithm) but the compiler can't prove it.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The top bit of the flag marks a live mutable borrow, the remaining bits
+/// count shared borrows.
+const WRITER: usize = !(usize::MAX >> 1);
+
+pub struct AtomicRefCell<T: ?Sized> {
+    borrow: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: Shared borrows hand out `&T` to many threads at once, so `T: Sync`.
+// A mutable borrow can be taken from any thread, which may then move or swap
+// out the value, so `T: Send`. The flag itself is atomic.
+unsafe impl<T: ?Sized + Send + Sync> Sync for AtomicRefCell<T> {}
+
+impl<T> AtomicRefCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            borrow: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AtomicRefCell<T> {
+    /// Panics if the value is currently mutably borrowed.
+    #[track_caller]
+    pub fn borrow(&self) -> AtomicRef<'_, T> {
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Panics if the value is currently borrowed.
+    #[track_caller]
+    pub fn borrow_mut(&self) -> AtomicRefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    pub fn try_borrow(&self) -> Result<AtomicRef<'_, T>, BorrowError> {
+        // A CAS loop instead of an unconditional `fetch_add`, so a failed
+        // attempt never touches the flag. With `fetch_add` and an undo, a
+        // writer releasing in between would leave a stray shared count behind.
+        let mut current = self.borrow.load(Ordering::Relaxed);
+        loop {
+            if current & WRITER != 0 {
+                return Err(BorrowError {});
+            }
+
+            // Leave the top bit free for `WRITER`; this many live guards can
+            // only come from leaking them.
+            if current == WRITER - 1 {
+                std::process::abort();
+            }
+
+            // `Acquire` on success pairs with the `Release` store of the last
+            // mutable borrow, so its writes are visible through this borrow.
+            match self.borrow.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+
+        Ok(AtomicRef {
+            // SAFETY: The shared count is non-zero, so no mutable borrow can
+            // be taken until this guard is dropped.
+            value: unsafe { &*self.value.get() },
+            borrow: &self.borrow,
+        })
+    }
+
+    pub fn try_borrow_mut(&self) -> Result<AtomicRefMut<'_, T>, BorrowMutError> {
+        // `Acquire` on success pairs with the `Release` of every previous
+        // borrow, so this borrow happens-after all earlier accesses.
+        if self
+            .borrow
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(BorrowMutError {});
+        }
+
+        Ok(AtomicRefMut {
+            // SAFETY: `UnsafeCell::get` never returns null, and the `WRITER`
+            // bit keeps out every other borrow until this guard is dropped.
+            value: unsafe { NonNull::new_unchecked(self.value.get()) },
+            borrow: &self.borrow,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// Returned by `AtomicRefCell::try_borrow` when the value is mutably
+/// borrowed.
+///
+/// Unlike `RefCell`'s, it doesn't say where the value was borrowed, even with
+/// `debug_refcell`: the borrow can be on another thread, which could replace a
+/// location stored next to the flag before it's read.
+#[derive(Debug)]
+pub struct BorrowError {}
+
+impl std::error::Error for BorrowError {}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AtomicRefCell is already borrowed mutably")
+    }
+}
+
+/// Returned by `AtomicRefCell::try_borrow_mut` when the value is borrowed.
+///
+/// Doesn't say where either, see `BorrowError`.
+#[derive(Debug)]
+pub struct BorrowMutError {}
+
+impl std::error::Error for BorrowMutError {}
+
+impl fmt::Display for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AtomicRefCell is already borrowed")
+    }
+}
+
+pub struct AtomicRef<'a, T: ?Sized> {
+    value: &'a T,
+    borrow: &'a AtomicUsize,
+}
+
+impl<T: ?Sized> Deref for AtomicRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<T: ?Sized> Drop for AtomicRef<'_, T> {
+    fn drop(&mut self) {
+        // `Release` so a later mutable borrow can't be reordered before reads
+        // made through this guard.
+        self.borrow.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub struct AtomicRefMut<'a, T: ?Sized> {
+    value: NonNull<T>,
+    borrow: &'a AtomicUsize,
+    _marker: PhantomData<&'a mut T>,
+}
+
+// SAFETY: Sharing the guard only gives out `&T`.
+unsafe impl<T: ?Sized + Sync> Sync for AtomicRefMut<'_, T> {}
+
+impl<T: ?Sized> Deref for AtomicRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The `WRITER` bit is set for as long as this guard lives.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for AtomicRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The `WRITER` bit is set for as long as this guard lives.
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for AtomicRefMut<'_, T> {
+    fn drop(&mut self) {
+        // `Release` publishes the writes made through this guard to whoever
+        // borrows next with `Acquire`.
+        self.borrow.store(0, Ordering::Release);
+    }
+}
+
+/// ```compile_fail
+/// use crust_of_rust::refcell::atomic::AtomicRefCell;
+///
+/// fn require_sync<T: Sync>(_: T) {}
+///
+/// require_sync(AtomicRefCell::new(std::cell::Cell::new(42)));
+/// ```
+fn assert_non_sync_inner() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atomic_refcell_borrows() {
+        let cell = AtomicRefCell::new(1);
+
+        {
+            let a = cell.borrow();
+            let b = cell.borrow();
+            assert_eq!(*a + *b, 2);
+            assert!(cell.try_borrow_mut().is_err());
+        }
+
+        {
+            let mut m = cell.borrow_mut();
+            *m += 1;
+            assert!(cell.try_borrow().is_err());
+            assert!(cell.try_borrow_mut().is_err());
+        }
+
+        assert_eq!(*cell.borrow(), 2);
+        assert_eq!(cell.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_atomic_refcell_errors() {
+        let cell = AtomicRefCell::new(0);
+
+        let shared = cell.borrow();
+        let err = cell.try_borrow_mut().err().unwrap();
+        assert_eq!(err.to_string(), "AtomicRefCell is already borrowed");
+        drop(shared);
+
+        let _mut_borrow = cell.borrow_mut();
+        let err = cell.try_borrow().err().unwrap();
+        assert_eq!(err.to_string(), "AtomicRefCell is already borrowed mutably");
+    }
+
+    #[test]
+    #[should_panic(expected = "AtomicRefCell is already borrowed")]
+    fn test_atomic_refcell_conflict_panics() {
+        let cell = AtomicRefCell::new(0);
+        let _shared = cell.borrow();
+        let _mut_borrow = cell.borrow_mut();
+    }
+
+    #[test]
+    // Using MIRI
+    fn test_atomic_refcell_threads() {
+        let cell = AtomicRefCell::new(Vec::new());
+
+        std::thread::scope(|s| {
+            for i in 0..8 {
+                let cell = &cell;
+                s.spawn(move || {
+                    // Retry on conflict; every thread gets its write in
+                    // eventually.
+                    let mut backoff = crate::atomics::Backoff::new();
+                    loop {
+                        if let Ok(mut v) = cell.try_borrow_mut() {
+                            v.push(i);
+                            break;
+                        }
+                        backoff.snooze();
+                    }
+
+                    if let Ok(v) = cell.try_borrow() {
+                        assert!(v.contains(&i));
+                    }
+                });
+            }
+        });
+
+        let mut v = cell.into_inner();
+        v.sort();
+        assert_eq!(v, (0..8).collect::<Vec<_>>());
+    }
+}
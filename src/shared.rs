@@ -0,0 +1,136 @@
+//! `Shared` composes the crate's `Rc` and `RefCell` into the common "shared
+//! ownership with interior mutability" handle.
+//!
+//! Neither primitive is enough on its own: `Rc` only hands out `&T`, and a
+//! `RefCell` on its own has a single owner. `Rc<RefCell<T>>` gives every owner
+//! dynamically borrow-checked mutable access to the same value, and `Shared`
+//! just removes the nesting from call sites.
+
+use crate::rc::Rc;
+use crate::refcell::{BorrowError, BorrowMutError, Ref, RefCell, RefMut};
+
+/// Cloneable handle to a value that all clones can borrow and mutate.
+pub struct Shared<T> {
+    inner: Rc<RefCell<T>>,
+}
+
+// Implied by `Rc`, which is already `!Send` and `!Sync`.
+//
+// impl<T> !Send for Shared<T> {}
+// impl<T> !Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(value)),
+        }
+    }
+
+    /// Panics if any clone currently holds a mutable borrow.
+    #[track_caller]
+    pub fn borrow(&self) -> Ref<'_, T> {
+        self.inner.borrow()
+    }
+
+    /// Panics if any clone currently holds a borrow.
+    #[track_caller]
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        self.inner.borrow_mut()
+    }
+
+    #[track_caller]
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+        self.inner.try_borrow()
+    }
+
+    #[track_caller]
+    pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowMutError> {
+        self.inner.try_borrow_mut()
+    }
+
+    /// Returns `true` if both handles refer to the same value.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// ```compile_fail
+/// use crust_of_rust::shared::Shared;
+///
+/// fn require_send<T: Send>(_: T) {}
+///
+/// require_send(Shared::new(42));
+/// ```
+fn assert_non_send() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_clone_mutate() {
+        let a = Shared::new(vec![1]);
+        let b = a.clone();
+
+        b.borrow_mut().push(2);
+        assert_eq!(*a.borrow(), vec![1, 2]);
+        assert!(a.ptr_eq(&b));
+        assert!(!a.ptr_eq(&Shared::new(vec![1, 2])));
+    }
+
+    #[test]
+    fn test_shared_multiple_borrows() {
+        let a = Shared::new(5);
+        let b = a.clone();
+
+        let x = a.borrow();
+        let y = b.borrow();
+        assert_eq!(*x + *y, 10);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_shared_conflicting_borrow() {
+        let a = Shared::new(5);
+        let b = a.clone();
+
+        let _x = a.borrow();
+        let _y = b.borrow_mut();
+    }
+
+    #[test]
+    fn test_shared_try_borrow() {
+        let a = Shared::new(5);
+        let b = a.clone();
+
+        let x = a.borrow_mut();
+        assert!(b.try_borrow().is_err());
+        assert!(b.try_borrow_mut().is_err());
+
+        drop(x);
+        assert_eq!(*b.try_borrow().unwrap(), 5);
+    }
+
+    #[test]
+    #[cfg(feature = "debug_refcell")]
+    fn test_shared_borrowed_at() {
+        let a = Shared::new(5);
+        let b = a.clone();
+
+        // The location is the caller's, not the wrapper's.
+        let line = line!() + 1;
+        let _x = a.borrow_mut();
+
+        let err = b.try_borrow().err().unwrap();
+        let expected = format!("(borrowed at {}:{line}:", file!());
+        assert!(err.to_string().contains(&expected));
+    }
+}
@@ -0,0 +1,187 @@
+//! A small harness for exploring thread interleavings, in the spirit of
+//! [loom].
+//!
+//! Concurrency bugs usually need an unlucky interleaving to show up, which a
+//! plain test may run into once in a thousand runs, or never on a given
+//! machine. `model` instead runs a closure over and over, each time under a
+//! different schedule, until every way its threads can interleave has been
+//! tried:
+//!
+//!  - Threads are spawned with `sync_test::thread::spawn`, and only one of them
+//!    runs at a time.
+//!  - Every operation on a `sync_test::atomic` type is a yield point, where the
+//!    scheduler may switch to any other runnable thread.
+//!  - Each execution records the choices made at the yield points. The next
+//!    one replays them up to the last choice with an untried option, tries
+//!    that, and continues from there (a depth-first search over schedules).
+//!
+//! The number of schedules grows exponentially with the number of yield
+//! points, so models have to stay small: two or three threads doing a handful
+//! of operations each. `model_random` samples schedules instead, for models too
+//! big to explore completely.
+//!
+//! Only interleavings are explored. Every atomic operation behaves as if it
+//! were `SeqCst`, so bugs that need a weaker ordering to be observed (a
+//! `Relaxed` store that should be `Release`) go unnoticed.
+//!
+//! [loom]: https://docs.rs/loom
+
+use std::sync::Arc;
+
+pub mod atomic;
+mod rt;
+pub mod thread;
+
+/// Runs `f` under every possible schedule, panicking on the first one where a
+/// thread panics, threads deadlock, or a thread spins forever.
+///
+/// Returns the number of schedules explored.
+pub fn model<F>(f: F) -> usize
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let f: Arc<dyn Fn() + Send + Sync> = Arc::new(f);
+    let mut path = Vec::new();
+    let mut schedules = 0;
+
+    loop {
+        schedules += 1;
+        let outcome = rt::run(path, None, Arc::clone(&f));
+        if let Some(failure) = outcome.failure {
+            fail(&format!("schedule {schedules}"), &outcome.path, &failure);
+        }
+
+        // Move on to the next untried option of the deepest choice that has
+        // one, dropping the choices after it since they may no longer come up.
+        path = outcome.path;
+        loop {
+            match path.last_mut() {
+                Some(last) if last.chosen + 1 < last.options => {
+                    last.chosen += 1;
+                    break;
+                }
+                Some(_) => {
+                    path.pop();
+                }
+                None => return schedules,
+            }
+        }
+    }
+}
+
+/// Runs `f` under `iterations` random schedules, panicking like `model`.
+///
+/// The schedules only depend on `seed`, so a failure can be reproduced by
+/// running again with the same seed.
+pub fn model_random<F>(seed: u64, iterations: usize, f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let f: Arc<dyn Fn() + Send + Sync> = Arc::new(f);
+
+    for i in 0..iterations {
+        // splitmix64, so that nearby seeds and iterations give unrelated
+        // schedules. The generator can't start from 0.
+        let mut z = seed.wrapping_add((i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        let rng = (z ^ (z >> 31)) | 1;
+
+        let outcome = rt::run(Vec::new(), Some(rng), Arc::clone(&f));
+        if let Some(failure) = outcome.failure {
+            fail(
+                &format!("iteration {i} with seed {seed}"),
+                &outcome.path,
+                &failure,
+            );
+        }
+    }
+}
+
+fn fail(which: &str, path: &[rt::Branch], failure: &str) -> ! {
+    let choices: Vec<_> = path.iter().map(|branch| branch.chosen).collect();
+    panic!("sync_test: {which} failed: {failure}\nchoices made: {choices:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::atomic::{AtomicBool, AtomicUsize};
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_model_explores_both_orders() {
+        let seen = Arc::new(Mutex::new(BTreeSet::new()));
+
+        let s = Arc::clone(&seen);
+        let schedules = model(move || {
+            let flag = Arc::new(AtomicBool::new(false));
+
+            let f = Arc::clone(&flag);
+            let t = thread::spawn(move || f.store(true, Ordering::Relaxed));
+            s.lock().unwrap().insert(flag.load(Ordering::Relaxed));
+            t.join();
+        });
+
+        assert!(schedules > 1);
+        assert_eq!(*seen.lock().unwrap(), BTreeSet::from([false, true]));
+    }
+
+    /// Two threads incrementing a counter with `lock` held.
+    fn locked_increments(lock: fn(&AtomicBool)) -> impl Fn() + Send + Sync + 'static {
+        move || {
+            let state = Arc::new((AtomicBool::new(false), AtomicUsize::new(0)));
+
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let state = Arc::clone(&state);
+                    thread::spawn(move || {
+                        lock(&state.0);
+                        // Not a `fetch_add`, so that another thread in the
+                        // critical section would lose an update.
+                        let v = state.1.load(Ordering::Relaxed);
+                        state.1.store(v + 1, Ordering::Relaxed);
+                        state.0.store(false, Ordering::Release);
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                handle.join();
+            }
+            assert_eq!(state.1.load(Ordering::Relaxed), 2, "lost an update");
+        }
+    }
+
+    #[test]
+    fn test_model_cas_lock() {
+        model(locked_increments(|lock| {
+            while lock
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                while lock.load(Ordering::Relaxed) {
+                    thread::yield_now();
+                }
+            }
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "lost an update")]
+    fn test_model_random_finds_race() {
+        // Checks the lock and takes it in two separate steps.
+        model_random(
+            0,
+            1000,
+            locked_increments(|lock| {
+                while lock.load(Ordering::Relaxed) {
+                    thread::yield_now();
+                }
+                lock.store(true, Ordering::Relaxed);
+            }),
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Drop-in replacements for `std::sync::atomic` types, with a yield point
+//! before every operation.
+//!
+//! Outside of a model they behave exactly like the `std` types.
+
+use std::sync::atomic::Ordering;
+
+use super::rt;
+
+macro_rules! atomic {
+    ($(#[$attr:meta])* $name:ident($std:ty, $t:ty)) => {
+        $(#[$attr])*
+        #[derive(Debug, Default)]
+        pub struct $name($std);
+
+        impl $name {
+            pub const fn new(v: $t) -> Self {
+                Self(<$std>::new(v))
+            }
+
+            pub fn into_inner(self) -> $t {
+                self.0.into_inner()
+            }
+
+            pub fn load(&self, order: Ordering) -> $t {
+                rt::branch();
+                self.0.load(order)
+            }
+
+            pub fn store(&self, v: $t, order: Ordering) {
+                rt::branch();
+                self.0.store(v, order)
+            }
+
+            pub fn swap(&self, v: $t, order: Ordering) -> $t {
+                rt::branch();
+                self.0.swap(v, order)
+            }
+
+            pub fn compare_exchange(
+                &self,
+                current: $t,
+                new: $t,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$t, $t> {
+                rt::branch();
+                self.0.compare_exchange(current, new, success, failure)
+            }
+
+            /// Never fails spuriously, a model only explores interleavings.
+            pub fn compare_exchange_weak(
+                &self,
+                current: $t,
+                new: $t,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$t, $t> {
+                self.compare_exchange(current, new, success, failure)
+            }
+        }
+    };
+}
+
+macro_rules! atomic_int {
+    ($(#[$attr:meta])* $name:ident($std:ty, $t:ty)) => {
+        atomic!($(#[$attr])* $name($std, $t));
+
+        impl $name {
+            pub fn fetch_add(&self, v: $t, order: Ordering) -> $t {
+                rt::branch();
+                self.0.fetch_add(v, order)
+            }
+
+            pub fn fetch_sub(&self, v: $t, order: Ordering) -> $t {
+                rt::branch();
+                self.0.fetch_sub(v, order)
+            }
+        }
+    };
+}
+
+atomic!(AtomicBool(std::sync::atomic::AtomicBool, bool));
+atomic_int!(AtomicU32(std::sync::atomic::AtomicU32, u32));
+atomic_int!(AtomicUsize(std::sync::atomic::AtomicUsize, usize));
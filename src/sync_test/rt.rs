@@ -0,0 +1,347 @@
+//! The scheduler behind `model`.
+//!
+//! Every virtual thread is a real thread, but only the one named by
+//! `State::active` is allowed to run. At each yield point the running thread
+//! picks who runs next (maybe itself), hands over, and blocks until it is
+//! picked again. Handing over through a `Mutex` also means every thread sees
+//! everything the previous one did, which is why weak memory orderings aren't
+//! modeled.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Yield points a single execution may take before it is assumed to be stuck.
+const MAX_STEPS: usize = 100_000;
+
+thread_local! {
+    static CONTEXT: RefCell<Option<(Arc<Execution>, usize)>> = const { RefCell::new(None) };
+}
+
+/// Panic payload unwinding virtual threads out of an execution that has
+/// already failed. Raised with `resume_unwind`, so the panic hook stays quiet.
+struct Abort;
+
+/// One scheduling decision: which of `options` runnable threads was picked.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Branch {
+    pub(super) chosen: usize,
+    pub(super) options: usize,
+}
+
+pub(super) struct Outcome {
+    /// The decisions made, in order.
+    pub(super) path: Vec<Branch>,
+    pub(super) failure: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Runnable,
+    Joining(usize),
+    Done,
+}
+
+struct Thread {
+    status: Status,
+    /// Set by `yield_now` until the thread does something else, so spinning
+    /// threads hand over to threads that can make progress first.
+    yielded: bool,
+}
+
+struct State {
+    threads: Vec<Thread>,
+    active: usize,
+    /// Decisions to replay, then extended with new ones as they're made.
+    path: Vec<Branch>,
+    pos: usize,
+    /// Picks new decisions at random instead of taking the first option.
+    rng: Option<u64>,
+    steps: usize,
+    failure: Option<String>,
+    handles: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl State {
+    fn choose(&mut self, options: usize) -> usize {
+        if options == 1 {
+            return 0;
+        }
+
+        let chosen = match self.path.get(self.pos) {
+            Some(branch) => {
+                assert_eq!(
+                    branch.options, options,
+                    "sync_test: the model behaved differently when replayed, it must be \
+                     deterministic apart from scheduling"
+                );
+                branch.chosen
+            }
+            None => {
+                let chosen = match &mut self.rng {
+                    Some(rng) => (next_random(rng) % options as u64) as usize,
+                    None => 0,
+                };
+                self.path.push(Branch { chosen, options });
+                chosen
+            }
+        };
+
+        self.pos += 1;
+        chosen
+    }
+
+    fn runnable(&self, filter: impl Fn(usize, &Thread) -> bool) -> Vec<usize> {
+        (0..self.threads.len())
+            .filter(|&id| self.threads[id].status == Status::Runnable)
+            .filter(|&id| filter(id, &self.threads[id]))
+            .collect()
+    }
+
+    fn all_done(&self) -> bool {
+        self.threads.iter().all(|t| t.status == Status::Done)
+    }
+}
+
+struct Execution {
+    state: Mutex<State>,
+    cv: Condvar,
+}
+
+impl Execution {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Virtual threads panicking while holding the lock is expected: the
+        // panic is the failure being reported.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn start(
+        self: &Arc<Self>,
+        id: usize,
+        f: Box<dyn FnOnce() + Send>,
+    ) -> std::thread::JoinHandle<()> {
+        let exec = Arc::clone(self);
+        std::thread::spawn(move || {
+            CONTEXT.set(Some((Arc::clone(&exec), id)));
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                exec.wait_turn(exec.lock(), id);
+                f();
+            }));
+
+            let mut state = exec.lock();
+            if let Err(payload) = result
+                && !payload.is::<Abort>()
+                && state.failure.is_none()
+            {
+                state.failure = Some(message(&*payload));
+            }
+            exec.finish(state, id);
+
+            CONTEXT.set(None);
+        })
+    }
+
+    /// Blocks until `me` is picked to run.
+    fn wait_turn(&self, mut state: MutexGuard<'_, State>, me: usize) {
+        loop {
+            if state.failure.is_some() {
+                drop(state);
+                panic::resume_unwind(Box::new(Abort));
+            }
+            if state.active == me {
+                return;
+            }
+            state = self.cv.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Hands over to one of `candidates` and waits to be picked again.
+    fn switch(&self, mut state: MutexGuard<'_, State>, me: usize, candidates: Vec<usize>) {
+        state.steps += 1;
+        if state.steps > MAX_STEPS {
+            self.fail(
+                state,
+                format!(
+                    "no progress after {MAX_STEPS} steps, is a thread spinning without \
+                     `thread::yield_now`?"
+                ),
+            );
+        }
+        if candidates.is_empty() {
+            self.fail(state, "deadlock: every thread is blocked in `join`".into());
+        }
+
+        let i = state.choose(candidates.len());
+        state.active = candidates[i];
+        self.cv.notify_all();
+        self.wait_turn(state, me);
+    }
+
+    fn fail(&self, mut state: MutexGuard<'_, State>, failure: String) -> ! {
+        state.failure.get_or_insert(failure);
+        self.cv.notify_all();
+        drop(state);
+        panic::resume_unwind(Box::new(Abort));
+    }
+
+    fn finish(&self, mut state: MutexGuard<'_, State>, id: usize) {
+        state.threads[id].status = Status::Done;
+        for thread in &mut state.threads {
+            if thread.status == Status::Joining(id) {
+                thread.status = Status::Runnable;
+            }
+        }
+
+        if state.failure.is_none() && !state.all_done() {
+            let candidates = state.runnable(|_, _| true);
+            if candidates.is_empty() {
+                state.failure = Some("deadlock: every thread is blocked in `join`".into());
+            } else {
+                let i = state.choose(candidates.len());
+                state.active = candidates[i];
+            }
+        }
+
+        self.cv.notify_all();
+    }
+}
+
+/// Runs `f` once as the first virtual thread, following `path` and then
+/// extending it.
+pub(super) fn run(path: Vec<Branch>, rng: Option<u64>, f: Arc<dyn Fn() + Send + Sync>) -> Outcome {
+    let exec = Arc::new(Execution {
+        state: Mutex::new(State {
+            threads: vec![Thread {
+                status: Status::Runnable,
+                yielded: false,
+            }],
+            active: 0,
+            path,
+            pos: 0,
+            rng,
+            steps: 0,
+            failure: None,
+            handles: Vec::new(),
+        }),
+        cv: Condvar::new(),
+    });
+
+    let root = exec.start(0, Box::new(move || f()));
+
+    let mut state = exec.lock();
+    state.handles.push(root);
+    while state.failure.is_none() && !state.all_done() {
+        state = exec.cv.wait(state).unwrap_or_else(PoisonError::into_inner);
+    }
+    drop(state);
+
+    // After a failure the other threads are still unwinding.
+    while let Some(handle) = exec.lock().handles.pop() {
+        let _ = handle.join();
+    }
+
+    let mut state = exec.lock();
+    let pos = state.pos;
+    state.path.truncate(pos);
+    Outcome {
+        path: std::mem::take(&mut state.path),
+        failure: state.failure.take(),
+    }
+}
+
+fn current() -> Option<(Arc<Execution>, usize)> {
+    CONTEXT.with_borrow(|context| context.clone())
+}
+
+/// A point where any runnable thread may be scheduled next. Does nothing
+/// outside of a model.
+pub(super) fn branch() {
+    // Code running during an `Abort` unwind (e.g. a guard's `Drop`) doesn't
+    // get scheduled anymore: the execution is over and waiting would only
+    // raise a second panic.
+    if std::thread::panicking() {
+        return;
+    }
+    let Some((exec, me)) = current() else {
+        return;
+    };
+
+    let mut state = exec.lock();
+    state.threads[me].yielded = false;
+    let candidates = state.runnable(|_, _| true);
+    exec.switch(state, me, candidates);
+}
+
+/// Lets another thread run, preferring ones that aren't spinning themselves.
+/// Returns `false` outside of a model.
+pub(super) fn yield_now() -> bool {
+    if std::thread::panicking() {
+        return true;
+    }
+    let Some((exec, me)) = current() else {
+        return false;
+    };
+
+    let mut state = exec.lock();
+    state.threads[me].yielded = true;
+    let mut candidates = state.runnable(|id, t| id != me && !t.yielded);
+    if candidates.is_empty() {
+        candidates = state.runnable(|id, _| id != me);
+    }
+    if candidates.is_empty() {
+        candidates.push(me);
+    }
+    exec.switch(state, me, candidates);
+    true
+}
+
+pub(super) fn spawn(f: Box<dyn FnOnce() + Send>) -> usize {
+    let (exec, _) = current().expect("`sync_test::thread::spawn` called outside of a model");
+
+    let mut state = exec.lock();
+    let id = state.threads.len();
+    state.threads.push(Thread {
+        status: Status::Runnable,
+        yielded: false,
+    });
+    let handle = exec.start(id, f);
+    state.handles.push(handle);
+    drop(state);
+
+    // The new thread may run first.
+    branch();
+    id
+}
+
+/// Blocks until the thread `id` has finished.
+pub(super) fn join(id: usize) {
+    let (exec, me) =
+        current().expect("`sync_test::thread::JoinHandle::join` called outside of a model");
+
+    let mut state = exec.lock();
+    if state.threads[id].status != Status::Done {
+        state.threads[me].status = Status::Joining(id);
+        let candidates = state.runnable(|_, _| true);
+        exec.switch(state, me, candidates);
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "a thread panicked".into()
+    }
+}
+
+/// xorshift64*, plenty for picking schedules.
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
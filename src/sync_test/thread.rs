@@ -0,0 +1,54 @@
+//! Virtual threads, scheduled by the model they're spawned in.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use super::rt;
+
+pub struct JoinHandle<T> {
+    id: usize,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+/// Spawns a virtual thread in the current model.
+///
+/// Panics if called outside of `model` or `model_random`.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = Arc::clone(&result);
+    let id = rt::spawn(Box::new(move || {
+        let value = f();
+        *slot.lock().unwrap_or_else(PoisonError::into_inner) = Some(value);
+    }));
+
+    JoinHandle { id, result }
+}
+
+impl<T> JoinHandle<T> {
+    /// Waits for the thread to finish and returns its result.
+    ///
+    /// Unlike `std`, there is no `Err` to handle: a panicking thread fails the
+    /// whole model.
+    pub fn join(self) -> T {
+        rt::join(self.id);
+        self.result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .expect("joined thread finished without a result")
+    }
+}
+
+/// Lets other threads run. Spin loops in a model must call this rather than
+/// `std::hint::spin_loop`, otherwise the spinning thread may keep getting
+/// scheduled and the thread it waits for never makes progress.
+///
+/// Outside of a model this is `std::thread::yield_now`.
+pub fn yield_now() {
+    if !rt::yield_now() {
+        std::thread::yield_now();
+    }
+}